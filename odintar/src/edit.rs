@@ -0,0 +1,323 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::tar_header::{entry_size, padded_size, BLOCK_SIZE};
+use crate::{lz4, ArchiveIndex, IndexEntry, Metadata, OdinTar, OdinTarError};
+
+/// File name recorded in the metadata of archives that didn't have one before.
+const DEFAULT_FILE_NAME: &str = "archive.tar";
+
+/// Something that can provide new contents for an archive entry.
+///
+/// Sources must be seekable, as their size has to be known before their contents are written.
+pub trait EntrySource: Read + Seek {}
+impl<T: Read + Seek> EntrySource for T {}
+
+/// New contents for an archive entry.
+pub struct NewContents<'a> {
+    source: Box<dyn EntrySource + 'a>,
+    compress: bool,
+}
+
+impl<'a> NewContents<'a> {
+    /// Take the contents from the given source.
+    ///
+    /// If `compress` is set, they are LZ4-compressed before being stored in the archive.
+    /// Otherwise, they are stored as-is.
+    pub fn new(source: impl EntrySource + 'a, compress: bool) -> NewContents<'a> {
+        return NewContents {
+            source: Box::new(source),
+            compress,
+        };
+    }
+}
+
+/// Describes how to derive a new archive from an existing one.
+///
+/// Entries that are neither replaced nor removed are copied over verbatim, in their original order.
+/// Added entries are appended at the end.
+#[derive(Default)]
+pub struct ArchiveEdit<'a> {
+    replacements: Vec<(String, NewContents<'a>)>,
+    removals: Vec<String>,
+    additions: Vec<(String, NewContents<'a>)>,
+    file_name: Option<String>,
+}
+
+impl<'a> ArchiveEdit<'a> {
+    /// Create an edit that doesn't change anything yet.
+    pub fn new() -> ArchiveEdit<'a> {
+        return ArchiveEdit::default();
+    }
+
+    /// Replace the contents of the existing entry with the given name.
+    pub fn replace(&mut self, name: &str, contents: NewContents<'a>) -> &mut Self {
+        self.replacements.push((String::from(name), contents));
+        return self;
+    }
+
+    /// Remove the existing entry with the given name.
+    pub fn remove(&mut self, name: &str) -> &mut Self {
+        self.removals.push(String::from(name));
+        return self;
+    }
+
+    /// Add a new entry with the given name.
+    pub fn add(&mut self, name: &str, contents: NewContents<'a>) -> &mut Self {
+        self.additions.push((String::from(name), contents));
+        return self;
+    }
+
+    /// Set the original file name recorded in the new archive's metadata.
+    ///
+    /// If not set, the name from the existing archive's metadata is kept.
+    pub fn file_name(&mut self, name: &str) -> &mut Self {
+        self.file_name = Some(String::from(name));
+        return self;
+    }
+
+    /// Check that the edit makes sense for an archive with the given contents.
+    fn check(&self, index: &ArchiveIndex) -> Result<(), OdinTarError> {
+        for name in self
+            .replacements
+            .iter()
+            .map(|(n, _)| n)
+            .chain(self.removals.iter())
+        {
+            if index.get(name).is_none() {
+                return Err(OdinTarError::EntryNotFound(name.clone()));
+            }
+        }
+        let mut added: Vec<&str> = Vec::new();
+        for (name, _) in &self.additions {
+            let exists = index.get(name).is_some() && !self.removals.contains(name);
+            if exists || added.contains(&name.as_str()) {
+                return Err(OdinTarError::DuplicateEntry(name.clone()));
+            }
+            added.push(name);
+        }
+        return Ok(());
+    }
+}
+
+/// `Write` adapter that hashes and counts everything passing through it.
+struct HashingWriter<'a> {
+    inner: &'a mut dyn Write,
+    ctx: md5::Context,
+    written: u64,
+}
+
+impl Write for HashingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.ctx.consume(&buf[..written]);
+        self.written += written as u64;
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+impl<R: Read + Seek> OdinTar<R> {
+    /// Write a copy of this archive with the given changes applied to `out`.
+    ///
+    /// The contents are streamed, so memory usage doesn't depend on the archive size.
+    /// The new archive gets a metadata trailer with the correct size and checksum,
+    /// keeping all other fields of the existing metadata. Plain tar archives gain a trailer.
+    ///
+    /// Returns the new archive's metadata.
+    pub fn rewrite(
+        &mut self,
+        mut edit: ArchiveEdit<'_>,
+        out: &mut dyn Write,
+    ) -> Result<Metadata, OdinTarError> {
+        let index = self.index()?;
+        edit.check(&index)?;
+        let old_metadata = match self.metadata() {
+            Ok(metadata) => Some(metadata),
+            Err(OdinTarError::Unverified) => None,
+            Err(e) => return Err(e),
+        };
+
+        let mut w = HashingWriter {
+            inner: out,
+            ctx: md5::Context::new(),
+            written: 0,
+        };
+
+        for entry in index.entries() {
+            if edit.removals.contains(&entry.name) {
+                continue;
+            }
+            let replacement = edit
+                .replacements
+                .iter_mut()
+                .find(|(name, _)| *name == entry.name);
+            match replacement {
+                Some((_, contents)) => {
+                    copy_extension_headers(&mut self.reader, entry, &mut w)?;
+                    // Keep the original header's metadata, such as permissions and timestamps
+                    let mut header_block = [0u8; BLOCK_SIZE as usize];
+                    self.reader.read_exact(&mut header_block)?;
+                    let header = tar::Header::from_byte_slice(&header_block).clone();
+                    write_entry(&mut w, header, contents)?;
+                }
+                None => {
+                    // Copy header and padded contents verbatim
+                    self.reader.seek(SeekFrom::Start(entry.header_offset))?;
+                    let len = (entry.data_offset - entry.header_offset) + padded_size(entry.size);
+                    let copied = io::copy(&mut (&mut self.reader).take(len), &mut w)?;
+                    if copied != len {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                }
+            }
+        }
+        for (name, contents) in edit.additions.iter_mut() {
+            let mut header = tar::Header::new_ustar();
+            header
+                .set_path(name.as_str())
+                .map_err(|_| OdinTarError::InvalidEntryName(name.clone()))?;
+            header.set_mode(0o644);
+            header.set_entry_type(tar::EntryType::Regular);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            header.set_mtime(now);
+            write_entry(&mut w, header, contents)?;
+        }
+        // Two empty blocks terminate the archive
+        w.write_all(&[0u8; 2 * BLOCK_SIZE as usize])?;
+        self.reader.rewind()?;
+
+        // Everything except the checksum line itself is covered by the checksum
+        let mut metadata = Metadata {
+            build_id: 0,
+            orig_size: w.written,
+            md5: String::new(),
+            orig_file_name: String::from(DEFAULT_FILE_NAME),
+            info_lines: Vec::new(),
+        };
+        if let Some(old) = old_metadata {
+            metadata.build_id = old.build_id;
+            metadata.orig_file_name = old.orig_file_name;
+            metadata.info_lines = old.info_lines;
+        }
+        if let Some(file_name) = edit.file_name {
+            metadata.orig_file_name = file_name;
+        }
+        w.write_all(metadata.build_info().as_bytes())?;
+        metadata.md5 = format!("{:x}", w.ctx.compute());
+        w.inner
+            .write_all(format!("{}  {}\n", metadata.md5, metadata.orig_file_name).as_bytes())?;
+        w.inner.flush()?;
+
+        return Ok(metadata);
+    }
+}
+
+/// Copy the extension headers in front of an entry's own header, such as GNU long names, verbatim.
+///
+/// Leaves `reader` at the entry's own header.
+fn copy_extension_headers<R: Read + Seek>(
+    reader: &mut R,
+    entry: &IndexEntry,
+    w: &mut dyn Write,
+) -> Result<(), OdinTarError> {
+    let own_header_offset = entry.data_offset - BLOCK_SIZE;
+    let mut offset = entry.header_offset;
+    reader.seek(SeekFrom::Start(offset))?;
+    while offset < own_header_offset {
+        let mut header_block = [0u8; BLOCK_SIZE as usize];
+        reader.read_exact(&mut header_block)?;
+        let size = entry_size(&header_block, offset)?;
+        if size > own_header_offset - offset - BLOCK_SIZE {
+            return Err(OdinTarError::MalformedTarHeader(offset));
+        }
+        let mut data = vec![0u8; padded_size(size) as usize];
+        reader.read_exact(&mut data)?;
+
+        // The size recorded there would no longer match the new contents
+        let header = tar::Header::from_byte_slice(&header_block);
+        if header.entry_type().is_pax_local_extensions()
+            && tar::PaxExtensions::new(&data[..size as usize])
+                .any(|e| e.is_ok_and(|e| e.key() == Ok("size")))
+        {
+            return Err(OdinTarError::PaxSizeRecord(entry.name.clone()));
+        }
+
+        w.write_all(&header_block)?;
+        w.write_all(&data)?;
+        offset += BLOCK_SIZE + data.len() as u64;
+    }
+    return Ok(());
+}
+
+/// Write a tar entry with the given header template and new contents.
+fn write_entry(
+    w: &mut dyn Write,
+    mut header: tar::Header,
+    contents: &mut NewContents<'_>,
+) -> Result<(), OdinTarError> {
+    let src = &mut contents.source;
+    let src_size = src.seek(SeekFrom::End(0))?;
+    src.rewind()?;
+
+    // Compressed size has to be known up front for the header,
+    // so compress into a scratch file first and copy from there.
+    let mut compressed: Option<File> = None;
+    let size = if contents.compress {
+        // Unnamed, so it's gone once closed
+        let mut scratch = tempfile::tempfile()?;
+        lz4::compress(&mut (&mut *src).take(src_size), src_size, &mut scratch)?;
+        let size = scratch.stream_position()?;
+        scratch.rewind()?;
+        compressed = Some(scratch);
+        size
+    } else {
+        src_size
+    };
+
+    header.set_size(size);
+    header.set_cksum();
+    w.write_all(header.as_bytes())?;
+
+    let mut counter = CountingWriter {
+        inner: w,
+        written: 0,
+    };
+    match compressed.as_mut() {
+        Some(scratch) => io::copy(scratch, &mut counter)?,
+        None => io::copy(&mut (&mut *src).take(src_size), &mut counter)?,
+    };
+    if counter.written != size {
+        // Source changed while we were reading it
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    let padding = (padded_size(size) - size) as usize;
+    w.write_all(&vec![0u8; padding])?;
+    return Ok(());
+}
+
+/// `Write` adapter that counts what passes through it.
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    written: u64,
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
@@ -0,0 +1,185 @@
+//! Decryption of Samsung's encrypted firmware packages (.zip.enc2 and .zip.enc4).
+//!
+//! Both formats are the plain firmware zip encrypted with AES-128 in ECB mode and PKCS#7-padded.
+//! They only differ in how the key is derived. The decrypted zip is opened with `FirmwarePackage`.
+//!
+//! Only .enc2 keys can be derived offline, from the model, region and version strings.
+//! .enc4 keys also need the package's logic value, which only Samsung's update service hands out,
+//! along with the download. It's not stored in the package, so a .zip.enc4 file on its own can't be decrypted.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+
+use crate::OdinTarError;
+
+/// AES block size in bytes.
+const BLOCK_SIZE: usize = 16;
+/// Amount of ciphertext decrypted at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Encryption scheme of a firmware package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncVersion {
+    /// Key derived from region, model and version. Used by older packages.
+    Enc2,
+    /// Key derived from the version and the logic value the update server hands out with the package.
+    Enc4,
+}
+
+impl EncVersion {
+    /// Determine the encryption scheme from a file name's extension, if it has a known one.
+    pub fn from_file_name(name: &str) -> Option<EncVersion> {
+        let name = name.to_ascii_lowercase();
+        if name.ends_with(".enc2") {
+            return Some(EncVersion::Enc2);
+        } else if name.ends_with(".enc4") {
+            return Some(EncVersion::Enc4);
+        }
+        return None;
+    }
+}
+
+/// Derive the key of a .enc2 package.
+pub fn enc2_key(model: &str, region: &str, version: &str) -> [u8; 16] {
+    return md5::compute(format!("{region}:{model}:{version}")).0;
+}
+
+/// Derive the key of a .enc4 package.
+///
+/// `version` is the full four-part firmware version (e.g. `A405FNXXU4CVK1/A405FNOXM4CVK1/A405FNXXU4CVK1/A405FNXXU4CVK1`),
+/// `logic_value` the `LOGIC_VALUE_FACTORY` value of the package's binary information.
+/// That value comes from the update service and can't be derived from the model, region or version,
+/// so it has to be kept from the download.
+///
+/// Fails with `OdinTarError::InvalidKeyMaterial` if `version` is too short to pick characters from.
+pub fn enc4_key(version: &str, logic_value: &str) -> Result<[u8; 16], OdinTarError> {
+    let version = version.as_bytes();
+    let mut key_material: Vec<u8> = Vec::with_capacity(logic_value.len());
+    // Every character of the logic value selects one character of the version
+    for c in logic_value.bytes() {
+        let idx = usize::from(c & 0x0f);
+        let selected = version.get(idx).ok_or(OdinTarError::InvalidKeyMaterial)?;
+        key_material.push(*selected);
+    }
+    return Ok(md5::compute(key_material).0);
+}
+
+/// Reader yielding the plaintext of an encrypted firmware package.
+///
+/// Supports seeking, so the output can be handed to anything expecting a seekable archive.
+pub struct Decryptor<R: Read + Seek> {
+    reader: R,
+    cipher: Aes128,
+    /// Length of the plaintext, excluding padding.
+    len: u64,
+    /// Current position in the plaintext.
+    pos: u64,
+    buf: Vec<u8>,
+}
+
+impl<R: Read + Seek> Decryptor<R> {
+    /// Wrap the given encrypted reader.
+    ///
+    /// The last block is decrypted immediately to determine the plaintext length.
+    /// A malformed padding, which usually means the key is wrong, results in `OdinTarError::DecryptionError`.
+    pub fn new(mut reader: R, key: [u8; 16]) -> Result<Decryptor<R>, OdinTarError> {
+        let cipher = Aes128::new(&GenericArray::from(key));
+        let encrypted_len = reader.seek(SeekFrom::End(0))?;
+        if encrypted_len == 0 || encrypted_len % BLOCK_SIZE as u64 != 0 {
+            return Err(OdinTarError::DecryptionError);
+        }
+
+        let mut last = [0; BLOCK_SIZE];
+        reader.seek(SeekFrom::End(-(BLOCK_SIZE as i64)))?;
+        reader.read_exact(&mut last)?;
+        cipher.decrypt_block(GenericArray::from_mut_slice(&mut last));
+        let padding = last[BLOCK_SIZE - 1];
+        if padding == 0
+            || usize::from(padding) > BLOCK_SIZE
+            || last[BLOCK_SIZE - usize::from(padding)..]
+                .iter()
+                .any(|b| *b != padding)
+        {
+            return Err(OdinTarError::DecryptionError);
+        }
+
+        reader.rewind()?;
+        return Ok(Decryptor {
+            reader,
+            cipher,
+            len: encrypted_len - u64::from(padding),
+            pos: 0,
+            buf: vec![0; CHUNK_SIZE],
+        });
+    }
+
+    /// Length of the decrypted data.
+    pub fn len(&self) -> u64 {
+        return self.len;
+    }
+
+    /// Whether the decrypted data is empty.
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    /// Return the wrapped reader.
+    pub fn into_inner(self) -> R {
+        return self.reader;
+    }
+}
+
+impl<R: Read + Seek> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len - self.pos.min(self.len);
+        if buf.is_empty() || remaining == 0 {
+            return Ok(0);
+        }
+
+        // ECB blocks are independent, so just decrypt the blocks covering the requested range
+        let block_start = self.pos - self.pos % BLOCK_SIZE as u64;
+        let skip = (self.pos - block_start) as usize;
+        let wanted = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let chunk_len = (skip + wanted).next_multiple_of(BLOCK_SIZE).min(CHUNK_SIZE);
+
+        self.reader.seek(SeekFrom::Start(block_start))?;
+        let chunk = &mut self.buf[..chunk_len];
+        self.reader.read_exact(chunk)?;
+        for block in chunk.chunks_exact_mut(BLOCK_SIZE) {
+            self.cipher
+                .decrypt_block(GenericArray::from_mut_slice(block));
+        }
+
+        let n = wanted.min(chunk_len - skip);
+        buf[..n].copy_from_slice(&chunk[skip..skip + n]);
+        self.pos += n as u64;
+        return Ok(n);
+    }
+}
+
+impl<R: Read + Seek> Seek for Decryptor<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                return Ok(new_pos);
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Seek to a negative position",
+                ))
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use core::fmt;
 use std::io;
 use std::num::{ParseIntError, TryFromIntError};
 use std::string::FromUtf8Error;
@@ -8,8 +9,50 @@ use std::sync::Arc;
 pub enum OdinTarError {
     /// An error encountered when reading the underlying tar archive.
     IoError(Arc<io::Error>),
-    /// An error encountered when reading Odin's metadata in the archive.
+    /// The Odin metadata trailer could not be located in the archive.
     MetadataError,
+    /// The data following the end of the tar archive is too large to be an Odin metadata trailer.
+    ///
+    /// The argument is the length of that data.
+    MetadataTooLarge(u64),
+    /// The archive is a plain tar without an Odin metadata trailer, so its contents can't be verified.
+    Unverified,
+    /// A field required by the Odin metadata format is missing.
+    ///
+    /// The argument is the name of the missing field.
+    MissingMetadataField(&'static str),
+    /// A line of the Odin metadata could not be parsed.
+    ///
+    /// The argument is the offending line.
+    MalformedMetadataLine(String),
+    /// A tar header in the archive is malformed.
+    ///
+    /// The argument is the offset of the header into the archive.
+    MalformedTarHeader(u64),
+    /// No entry with the given name exists in the archive.
+    EntryNotFound(String),
+    /// An entry with the given name already exists in the archive.
+    DuplicateEntry(String),
+    /// The given name can't be stored in a tar header.
+    InvalidEntryName(String),
+    /// The entry with the given name can't be replaced, as a PAX header records its size.
+    PaxSizeRecord(String),
+    /// A sparse image is malformed.
+    ///
+    /// The argument describes the problem.
+    InvalidSparseImage(&'static str),
+    /// The dynamic partition metadata of a super image is malformed.
+    ///
+    /// The argument describes the problem.
+    InvalidLpMetadata(&'static str),
+    /// No logical partition with the given name exists in the super image.
+    LogicalPartitionNotFound(String),
+    /// The data to decrypt is malformed, or the key is wrong.
+    DecryptionError,
+    /// A firmware package is not a valid zip file, or its contents are corrupted.
+    PackageError(Arc<zip::result::ZipError>),
+    /// The strings given for key derivation are too short to derive a key from.
+    InvalidKeyMaterial,
     /// Checksum mismatch between Odin's metadata and the actual contents.
     ChecksumError(String, String),
     /// Invalid UTF-8 in the Odin metadata.
@@ -20,12 +63,100 @@ pub enum OdinTarError {
     IntParseError(ParseIntError),
 }
 
+impl fmt::Display for OdinTarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OdinTarError::IoError(_) => write!(f, "failed to read the archive"),
+            OdinTarError::MetadataError => write!(f, "Odin metadata trailer not found"),
+            OdinTarError::MetadataTooLarge(len) => {
+                write!(
+                    f,
+                    "{len} bytes after the archive are too many for an Odin metadata trailer"
+                )
+            }
+            OdinTarError::Unverified => {
+                write!(
+                    f,
+                    "archive has no Odin metadata, its contents can't be verified"
+                )
+            }
+            OdinTarError::MissingMetadataField(field) => {
+                write!(f, "Odin metadata is missing the {field} field")
+            }
+            OdinTarError::MalformedMetadataLine(line) => {
+                write!(f, "malformed Odin metadata line {line:?}")
+            }
+            OdinTarError::MalformedTarHeader(offset) => {
+                write!(f, "malformed tar header at offset {offset}")
+            }
+            OdinTarError::EntryNotFound(name) => write!(f, "no entry named {name} in the archive"),
+            OdinTarError::DuplicateEntry(name) => {
+                write!(f, "an entry named {name} already exists in the archive")
+            }
+            OdinTarError::InvalidEntryName(name) => {
+                write!(f, "{name:?} can't be stored in a tar header")
+            }
+            OdinTarError::PaxSizeRecord(name) => {
+                write!(f, "{name} can't be replaced, a PAX header records its size")
+            }
+            OdinTarError::InvalidSparseImage(why) => write!(f, "invalid sparse image: {why}"),
+            OdinTarError::InvalidLpMetadata(why) => {
+                write!(f, "invalid dynamic partition metadata: {why}")
+            }
+            OdinTarError::LogicalPartitionNotFound(name) => {
+                write!(f, "no logical partition named {name} in the super image")
+            }
+            OdinTarError::DecryptionError => {
+                write!(
+                    f,
+                    "decryption failed, the data is malformed or the key is wrong"
+                )
+            }
+            OdinTarError::PackageError(_) => write!(f, "malformed firmware package"),
+            OdinTarError::InvalidKeyMaterial => {
+                write!(f, "too little key material to derive a key")
+            }
+            OdinTarError::ChecksumError(expected, actual) => write!(
+                f,
+                "checksum mismatch, metadata says {expected} but contents are {actual}"
+            ),
+            OdinTarError::EncodingError(_) => write!(f, "Odin metadata is not valid UTF-8"),
+            OdinTarError::IntConversionError(_) => {
+                write!(
+                    f,
+                    "integer conversion failed, this is probably a bug in odintar"
+                )
+            }
+            OdinTarError::IntParseError(_) => write!(f, "failed to parse an integer"),
+        }
+    }
+}
+
+impl std::error::Error for OdinTarError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OdinTarError::IoError(e) => Some(e.as_ref()),
+            OdinTarError::PackageError(e) => Some(e.as_ref()),
+            OdinTarError::EncodingError(e) => Some(e),
+            OdinTarError::IntConversionError(e) => Some(e),
+            OdinTarError::IntParseError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl From<io::Error> for OdinTarError {
     fn from(value: io::Error) -> Self {
         return OdinTarError::IoError(Arc::from(value));
     }
 }
 
+impl From<zip::result::ZipError> for OdinTarError {
+    fn from(value: zip::result::ZipError) -> Self {
+        return OdinTarError::PackageError(Arc::from(value));
+    }
+}
+
 impl From<FromUtf8Error> for OdinTarError {
     fn from(value: FromUtf8Error) -> Self {
         return OdinTarError::EncodingError(value);
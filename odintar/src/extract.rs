@@ -0,0 +1,148 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::{Compression, ImageReader, Metadata, OdinTar, OdinTarError};
+
+/// Name of the manifest file written next to the extracted files.
+pub const MANIFEST_FILE_NAME: &str = "manifest.txt";
+
+/// How archive contents are processed while extracting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExtractOptions {
+    /// Decompress LZ4-compressed entries, dropping the `.lz4` extension.
+    pub decompress: bool,
+    /// Convert sparse images into raw ones. Only applies to uncompressed or decompressed entries.
+    pub unsparse: bool,
+}
+
+/// A single extracted file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Name of the entry in the archive.
+    pub entry_name: String,
+    /// Name of the extracted file.
+    pub file_name: String,
+    /// Size of the extracted file.
+    pub size: u64,
+    /// SHA-256 of the extracted file, as lowercase hex.
+    pub sha256: String,
+}
+
+/// Record of an extraction, linking the extracted files back to the archive they came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractManifest {
+    /// Odin metadata of the archive, `None` for plain tar archives.
+    pub metadata: Option<Metadata>,
+    /// The extracted files, in archive order.
+    pub files: Vec<ManifestEntry>,
+}
+
+impl fmt::Display for ExtractManifest {
+    /// Formats the manifest the way it's written to disk.
+    ///
+    /// Archive metadata comes first as key:value lines, like in the Odin trailer.
+    /// It's followed by one line per file: SHA-256, file name, size and archive entry name, separated by two spaces.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(metadata) = &self.metadata {
+            writeln!(f, "original_file_name:{}", metadata.orig_file_name)?;
+            writeln!(f, "BUILD_ID:{}", metadata.build_id)?;
+            writeln!(f, "md5:{}", metadata.md5)?;
+        }
+        for file in &self.files {
+            writeln!(
+                f,
+                "{}  {}  {}  {}",
+                file.sha256, file.file_name, file.size, file.entry_name
+            )?;
+        }
+        return Ok(());
+    }
+}
+
+/// Writer computing the SHA-256 of everything passing through it.
+struct Sha256Writer<W: Write> {
+    inner: W,
+    ctx: Sha256,
+    written: u64,
+}
+
+impl<W: Write> Write for Sha256Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.ctx.update(&buf[..written]);
+        self.written += written as u64;
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+impl<R: Read + Seek> OdinTar<R> {
+    /// Extract all files in the archive into the directory `dir`, which must exist.
+    ///
+    /// A manifest of the extracted files is written to `MANIFEST_FILE_NAME` in the same directory and returned.
+    /// Existing files are overwritten, but two entries extracting to the same file name are an error.
+    pub fn extract(
+        &mut self,
+        dir: &Path,
+        options: ExtractOptions,
+    ) -> Result<ExtractManifest, OdinTarError> {
+        let metadata = match self.metadata() {
+            Ok(metadata) => Some(metadata),
+            Err(OdinTarError::Unverified) => None,
+            Err(e) => return Err(e),
+        };
+        let index = self.index()?;
+
+        let mut files: Vec<ManifestEntry> = Vec::with_capacity(index.len());
+        for entry in index.entries() {
+            let (compression, file_name) = if options.decompress {
+                (entry.compression, entry.uncompressed_name())
+            } else {
+                (Compression::None, entry.name.as_str())
+            };
+            // Odin archives are flat, refuse anything that could escape the target directory
+            let is_plain_name = Path::new(file_name).file_name()
+                == Some(std::ffi::OsStr::new(file_name))
+                && file_name != MANIFEST_FILE_NAME;
+            if !is_plain_name {
+                return Err(OdinTarError::InvalidEntryName(entry.name.clone()));
+            }
+            if files.iter().any(|f| f.file_name == file_name) {
+                return Err(OdinTarError::DuplicateEntry(file_name.to_string()));
+            }
+
+            let reader = self.entry_reader(entry)?;
+            let mut reader = ImageReader::new(reader, compression, options.unsparse)?;
+            let mut w = Sha256Writer {
+                inner: BufWriter::new(File::create(dir.join(file_name))?),
+                ctx: Sha256::new(),
+                written: 0,
+            };
+            io::copy(&mut reader, &mut w)?;
+            w.flush()?;
+
+            files.push(ManifestEntry {
+                entry_name: entry.name.clone(),
+                file_name: file_name.to_string(),
+                size: w.written,
+                sha256: w
+                    .ctx
+                    .finalize()
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect(),
+            });
+        }
+
+        let manifest = ExtractManifest { metadata, files };
+        std::fs::write(dir.join(MANIFEST_FILE_NAME), manifest.to_string())?;
+        return Ok(manifest);
+    }
+}
@@ -0,0 +1,71 @@
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+
+use lz4_flex::frame::FrameDecoder;
+
+use crate::{is_sparse_image, Compression, OdinTarError, SparseReader};
+
+/// Sequential reader over the decoded contents of an image, i.e. after LZ4 decompression and unsparsing.
+///
+/// Neither of those allow random access, so seeking is only supported in the forward direction, by skipping data.
+/// This is enough for parsers that read their structures front to back.
+pub struct ImageReader<'a> {
+    inner: Box<dyn Read + 'a>,
+    pos: u64,
+}
+
+impl<'a> ImageReader<'a> {
+    /// Decode contents read from `reader`.
+    ///
+    /// Contents are decompressed according to `compression`, and unsparsed if `unsparse` is set and they're a sparse image.
+    pub(crate) fn new<R: Read + 'a>(
+        reader: R,
+        compression: Compression,
+        unsparse: bool,
+    ) -> Result<ImageReader<'a>, OdinTarError> {
+        let decompressed: Box<dyn Read + 'a> = match compression {
+            Compression::Lz4 => Box::new(FrameDecoder::new(reader)),
+            Compression::None => Box::new(reader),
+        };
+        let mut decompressed = BufReader::new(decompressed);
+        let inner: Box<dyn Read + 'a> = if unsparse && is_sparse_image(decompressed.fill_buf()?) {
+            Box::new(SparseReader::new(decompressed)?)
+        } else {
+            Box::new(decompressed)
+        };
+        return Ok(ImageReader { inner, pos: 0 });
+    }
+}
+
+impl Read for ImageReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        return Ok(n);
+    }
+}
+
+impl Seek for ImageReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos: Option<u64> = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(p) => self.pos.checked_add_signed(p),
+            SeekFrom::End(_) => None,
+        };
+        match new_pos {
+            Some(new_pos) if new_pos >= self.pos => {
+                let len = new_pos - self.pos;
+                let skipped = io::copy(&mut (&mut *self).take(len), &mut io::sink())?;
+                if skipped != len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                return Ok(new_pos);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Image contents can only be read front to back",
+                ))
+            }
+        }
+    }
+}
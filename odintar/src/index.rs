@@ -0,0 +1,191 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::tar_header::padded_size;
+use crate::OdinTarError;
+
+/// How the contents of an archive entry are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Contents are stored as-is.
+    None,
+    /// Contents are an LZ4 frame. Odin marks these with a `.lz4` extension.
+    Lz4,
+}
+
+impl Compression {
+    /// Guess the compression based on the entry's file name, the way Odin does.
+    pub fn from_file_name(name: &str) -> Compression {
+        match Path::new(name).extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("lz4") => return Compression::Lz4,
+            _ => return Compression::None,
+        }
+    }
+}
+
+/// A single file in an Odin archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Name of the file, as stored in the archive.
+    pub name: String,
+    /// Offset of the entry's first tar header into the archive.
+    ///
+    /// This includes GNU long name and PAX extension headers in front of the entry's own header,
+    /// which is the last one before `data_offset`.
+    pub header_offset: u64,
+    /// Offset of the entry's contents into the archive.
+    pub data_offset: u64,
+    /// Size of the entry's contents as stored in the archive, i.e. before decompression.
+    pub size: u64,
+    /// How the entry's contents are compressed.
+    pub compression: Compression,
+}
+
+impl IndexEntry {
+    /// Name of the file with the compression extension (if any) stripped.
+    ///
+    /// This is the name that PIT flash filenames are matched against, e.g. `boot.img` for `boot.img.lz4`.
+    pub fn uncompressed_name(&self) -> &str {
+        match self.compression {
+            Compression::Lz4 => {
+                return self
+                    .name
+                    .get(..self.name.len() - ".lz4".len())
+                    .unwrap_or(&self.name)
+            }
+            Compression::None => return &self.name,
+        }
+    }
+}
+
+/// Index of all regular files in an Odin archive.
+///
+/// Building this requires only reading the tar headers, not the file contents.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ArchiveIndex {
+    entries: Vec<IndexEntry>,
+}
+
+impl ArchiveIndex {
+    /// Build the index by walking the tar headers of the given archive.
+    pub(crate) fn build<R: Read + Seek>(reader: &mut R) -> Result<ArchiveIndex, OdinTarError> {
+        reader.rewind()?;
+        let mut entries: Vec<IndexEntry> = Vec::new();
+        {
+            let mut archive = tar::Archive::new(&mut *reader);
+            // Extension headers aren't returned as entries, they start where the previous entry ended
+            let mut next_header_offset: u64 = 0;
+            for entry in archive.entries_with_seek()? {
+                let entry = entry?;
+                let header_offset = next_header_offset;
+                next_header_offset = entry.raw_file_position() + padded_size(entry.size());
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = String::from_utf8(entry.path_bytes().into_owned())?;
+                entries.push(IndexEntry {
+                    compression: Compression::from_file_name(&name),
+                    name,
+                    header_offset,
+                    data_offset: entry.raw_file_position(),
+                    size: entry.size(),
+                });
+            }
+        }
+        reader.rewind()?;
+
+        return Ok(ArchiveIndex { entries });
+    }
+
+    /// All entries, in the order they appear in the archive.
+    pub fn entries(&self) -> &[IndexEntry] {
+        return &self.entries;
+    }
+
+    /// Look up an entry by it's name in the archive.
+    pub fn get(&self, name: &str) -> Option<&IndexEntry> {
+        return self.entries.iter().find(|e| e.name == name);
+    }
+
+    /// Number of entries in the archive.
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    /// Returns whether the archive contains no files.
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+}
+
+/// Reader over the contents of a single archive entry.
+///
+/// Reads and seeks are bounded to the entry, so it behaves like a standalone file.
+pub struct EntryReader<'a, R: ?Sized + Read + Seek> {
+    inner: &'a mut R,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a, R: ?Sized + Read + Seek> EntryReader<'a, R> {
+    pub(crate) fn new(inner: &'a mut R, entry: &IndexEntry) -> Result<Self, OdinTarError> {
+        inner.seek(SeekFrom::Start(entry.data_offset))?;
+        return Ok(EntryReader {
+            inner,
+            start: entry.data_offset,
+            len: entry.size,
+            pos: 0,
+        });
+    }
+
+    /// Size of the entry's contents.
+    pub fn len(&self) -> u64 {
+        return self.len;
+    }
+
+    /// Returns whether the entry is empty.
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+}
+
+impl<R: ?Sized + Read + Seek> Read for EntryReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        if remaining == 0 {
+            return Ok(0);
+        }
+        let max = std::cmp::min(buf.len() as u64, remaining) as usize;
+        let read = self.inner.read(&mut buf[..max])?;
+        self.pos += read as u64;
+        return Ok(read);
+    }
+}
+
+impl<R: ?Sized + Read + Seek> Seek for EntryReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos: i128 = match pos {
+            SeekFrom::Start(p) => p.into(),
+            SeekFrom::End(p) => i128::from(self.len) + i128::from(p),
+            SeekFrom::Current(p) => i128::from(self.pos) + i128::from(p),
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before start of archive entry",
+            ));
+        }
+        // Like for files, seeking beyond the end is allowed, reads there simply return nothing
+        let new_pos: u64 = new_pos
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Seek offset overflow"))?;
+        let inner_pos = self
+            .start
+            .checked_add(new_pos)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek offset overflow"))?;
+        self.inner.seek(SeekFrom::Start(inner_pos))?;
+        self.pos = new_pos;
+        return Ok(new_pos);
+    }
+}
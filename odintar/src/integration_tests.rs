@@ -1,11 +1,36 @@
 use std::fs::File;
-use std::io::Cursor;
+use std::io::{Cursor, SeekFrom, Write};
 
 use crate::*;
 
 const TEST_FILE: &str =
     "./testdata/BL_A405FNXXU4CVK1_CL25488227_QB58944467_REV00_user_low_ship.tar.md5";
 
+/// Build a plain tar archive holding the given files.
+fn build_tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, data) in files {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, *data).unwrap();
+    }
+    return builder.into_inner().unwrap();
+}
+
+/// Turn a plain tar archive into an Odin one by appending metadata the way Samsung's build system does.
+fn append_odin_trailer(mut tar: Vec<u8>, extra_lines: &str) -> Vec<u8> {
+    let orig_size = tar.len();
+    tar.extend_from_slice(
+        format!("Show the build information\nRBS BUILD_ID:1\n{extra_lines}original_tar_file_size:{orig_size}\n")
+            .as_bytes(),
+    );
+    let md5 = md5::compute(&tar);
+    tar.extend_from_slice(format!("{md5:x}  AP.tar\n").as_bytes());
+    return tar;
+}
+
 #[test]
 fn test_read_metadata() {
     let expected = Metadata {
@@ -15,6 +40,11 @@ fn test_read_metadata() {
         orig_file_name: String::from(
             "BL_A405FNXXU4CVK1_CL25488227_QB58944467_REV00_user_low_ship.tar",
         ),
+        info_lines: vec![
+            String::from("Show the build information"),
+            String::from("RBS BUILD_ID:58944467"),
+            String::from("original_tar_file_size:3368960"),
+        ],
     };
 
     let f = File::open(TEST_FILE).unwrap();
@@ -77,3 +107,643 @@ fn test_read_contents() {
     }
     assert_eq!(expected.len(), count);
 }
+
+#[test]
+fn test_plain_tar_is_unverified() {
+    let data = build_tar(&[("boot.img", b"boot"), ("system.img", b"system")]);
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+
+    assert!(!archive.has_metadata().unwrap());
+    match archive.validate() {
+        Err(OdinTarError::Unverified) => {}
+        v => panic!("Wrong result! Expected Unverified, got {v:?}"),
+    }
+
+    // Contents must still be accessible
+    let count = archive.archive().entries().unwrap().count();
+    assert_eq!(2, count);
+}
+
+#[test]
+fn test_long_trailer() {
+    // Trailers longer than a few hundred bytes used to be cut off
+    let extra_lines: String = (0..64).map(|i| format!("EXTRA_{i}:{i}\n")).collect();
+    let data = append_odin_trailer(build_tar(&[("boot.img", b"boot")]), &extra_lines);
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+
+    let metadata = archive.metadata().unwrap();
+    assert_eq!(64, metadata.extra_fields().len());
+    assert_eq!("AP.tar", metadata.orig_file_name);
+    assert!(archive.validate().is_ok());
+}
+
+#[test]
+fn test_oversized_trailer() {
+    // Anything this large after the archive isn't metadata, and isn't read into memory
+    let extra_lines = "EXTRA:0\n".repeat(10 * 1024);
+    let data = append_odin_trailer(build_tar(&[("boot.img", b"boot")]), &extra_lines);
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+    match archive.metadata() {
+        Err(OdinTarError::MetadataTooLarge(len)) => assert!(len > 64 * 1024),
+        other => panic!("Wrong result! Expected MetadataTooLarge, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_tiny_archive() {
+    // Archives shorter than the old fixed-size trailer search window used to fail
+    let data = append_odin_trailer(build_tar(&[]), "");
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+
+    assert!(archive.has_metadata().unwrap());
+    assert!(archive.validate().is_ok());
+}
+
+#[test]
+fn test_index() {
+    let f = File::open(TEST_FILE).unwrap();
+    let mut archive = OdinTar::from_reader(f);
+
+    let index = archive.index().unwrap();
+    let names: Vec<&str> = index.entries().iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(
+        vec![
+            "sboot.bin.lz4",
+            "param.bin.lz4",
+            "cm.bin.lz4",
+            "vbmeta.img.lz4"
+        ],
+        names
+    );
+    for entry in index.entries() {
+        assert_eq!(Compression::Lz4, entry.compression);
+        assert_eq!(entry.header_offset + 512, entry.data_offset);
+    }
+    assert_eq!(
+        "vbmeta.img",
+        index.get("vbmeta.img.lz4").unwrap().uncompressed_name()
+    );
+    assert!(index.get("vbmeta.img").is_none());
+}
+
+#[test]
+fn test_entry_reader() {
+    let data = build_tar(&[("boot.img", b"boot contents"), ("system.img", b"system")]);
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+    let index = archive.index().unwrap();
+
+    let entry = index.get("boot.img").unwrap().clone();
+    assert_eq!(Compression::None, entry.compression);
+    let mut rdr = archive.entry_reader(&entry).unwrap();
+    let mut contents = String::new();
+    rdr.read_to_string(&mut contents).unwrap();
+    assert_eq!("boot contents", contents);
+
+    // Seeks are relative to the entry, and reads don't leak into the next one
+    rdr.seek(SeekFrom::End(-8)).unwrap();
+    let mut contents = String::new();
+    rdr.read_to_string(&mut contents).unwrap();
+    assert_eq!("contents", contents);
+    assert!(rdr.seek(SeekFrom::Current(-100)).is_err());
+}
+
+#[test]
+fn test_rewrite() {
+    let data = append_odin_trailer(
+        build_tar(&[
+            ("boot.img.lz4", b"old boot"),
+            ("recovery.img", b"recovery"),
+            ("system.img", b"system"),
+        ]),
+        "",
+    );
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+    let new_boot: Vec<u8> = b"new boot ".repeat(1000);
+
+    let mut edit = ArchiveEdit::new();
+    edit.replace(
+        "boot.img.lz4",
+        NewContents::new(Cursor::new(&new_boot), true),
+    )
+    .remove("recovery.img")
+    .add(
+        "vbmeta.img",
+        NewContents::new(Cursor::new(b"vbmeta"), false),
+    );
+    let mut out: Vec<u8> = Vec::new();
+    let metadata = archive.rewrite(edit, &mut out).unwrap();
+    assert_eq!(1, metadata.build_id);
+    assert_eq!("AP.tar", metadata.orig_file_name);
+
+    let mut rewritten = OdinTar::from_reader(Cursor::new(out));
+    assert!(rewritten.validate().is_ok());
+    assert_eq!(metadata, rewritten.metadata().unwrap());
+
+    let index = rewritten.index().unwrap();
+    let names: Vec<&str> = index.entries().iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(vec!["boot.img.lz4", "system.img", "vbmeta.img"], names);
+    let tar_size = index.entries().last().unwrap().data_offset + 512 + 1024;
+    assert_eq!(tar_size, metadata.orig_size);
+
+    let entry = index.get("boot.img.lz4").unwrap().clone();
+    let mut decoder = lz4_flex::frame::FrameDecoder::new(rewritten.entry_reader(&entry).unwrap());
+    let mut boot: Vec<u8> = Vec::new();
+    decoder.read_to_end(&mut boot).unwrap();
+    assert_eq!(new_boot, boot);
+}
+
+#[test]
+fn test_rewrite_long_names() {
+    // Too long for a tar header, so they're stored in GNU long name headers in front of it
+    let kept = format!("{}system.img", "a".repeat(100));
+    let replaced = format!("{}boot.img", "b".repeat(100));
+    let data = append_odin_trailer(
+        build_tar(&[(&kept, b"system"), (&replaced, b"old boot")]),
+        "",
+    );
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+    let index = archive.index().unwrap();
+    assert_eq!(0, index.get(&kept).unwrap().header_offset);
+    for entry in index.entries() {
+        assert!(entry.header_offset + 512 < entry.data_offset);
+    }
+
+    let mut edit = ArchiveEdit::new();
+    edit.replace(&replaced, NewContents::new(Cursor::new(b"new boot"), false));
+    let mut out: Vec<u8> = Vec::new();
+    archive.rewrite(edit, &mut out).unwrap();
+
+    let mut rewritten = OdinTar::from_reader(Cursor::new(out));
+    assert!(rewritten.validate().is_ok());
+    let index = rewritten.index().unwrap();
+    let names: Vec<&str> = index.entries().iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(vec![kept.as_str(), replaced.as_str()], names);
+    for (name, expected) in [(&kept, "system"), (&replaced, "new boot")] {
+        let entry = index.get(name).unwrap().clone();
+        let mut contents = String::new();
+        rewritten
+            .entry_reader(&entry)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(expected, contents);
+    }
+}
+
+#[test]
+fn test_rewrite_invalid_edits() {
+    let data = build_tar(&[("boot.img", b"boot")]);
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+
+    let mut edit = ArchiveEdit::new();
+    edit.remove("system.img");
+    match archive.rewrite(edit, &mut Vec::new()) {
+        Err(OdinTarError::EntryNotFound(_)) => {}
+        v => panic!("Wrong result! Expected EntryNotFound, got {v:?}"),
+    }
+
+    let mut edit = ArchiveEdit::new();
+    edit.add("boot.img", NewContents::new(Cursor::new(b"boot"), false));
+    match archive.rewrite(edit, &mut Vec::new()) {
+        Err(OdinTarError::DuplicateEntry(_)) => {}
+        v => panic!("Wrong result! Expected DuplicateEntry, got {v:?}"),
+    }
+}
+
+/// Encrypt the given data the way Samsung's update server does.
+fn encrypt(mut data: Vec<u8>, key: [u8; 16]) -> Vec<u8> {
+    use aes::cipher::generic_array::GenericArray;
+    use aes::cipher::{BlockEncrypt, KeyInit};
+
+    let cipher = aes::Aes128::new(&GenericArray::from(key));
+    let padding = 16 - data.len() % 16;
+    data.extend(std::iter::repeat_n(padding as u8, padding));
+    for block in data.chunks_exact_mut(16) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    return data;
+}
+
+/// Pack the given files into a zip the way Samsung's update server does.
+fn build_package(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    for (name, data) in files {
+        zip.start_file(*name, options).unwrap();
+        zip.write_all(data).unwrap();
+    }
+    return zip.finish().unwrap().into_inner();
+}
+
+#[test]
+fn test_decrypt() {
+    let mut tar_md5: Vec<u8> = Vec::new();
+    File::open(TEST_FILE)
+        .unwrap()
+        .read_to_end(&mut tar_md5)
+        .unwrap();
+    let plain = build_package(&[
+        ("BL_A405FNXXU4CVK1.tar.md5", &tar_md5),
+        ("README.txt", b"not an archive"),
+    ]);
+    let key = enc2_key(
+        "SM-A405FN",
+        "EUX",
+        "A405FNXXU4CVK1/A405FNOXM4CVK1/A405FNXXU4CVK1/A405FNXXU4CVK1",
+    );
+    let encrypted = encrypt(plain.clone(), key);
+
+    // The decrypted package is read directly, and the archives in it open as such
+    let decryptor = Decryptor::new(Cursor::new(encrypted.clone()), key).unwrap();
+    assert_eq!(plain.len() as u64, decryptor.len());
+    let mut package = FirmwarePackage::new(decryptor).unwrap();
+    assert_eq!(
+        vec!["BL_A405FNXXU4CVK1.tar.md5"],
+        package.archive_names().unwrap()
+    );
+    let mut extracted: Vec<u8> = Vec::new();
+    package
+        .extract_archive("BL_A405FNXXU4CVK1.tar.md5", &mut extracted)
+        .unwrap();
+    let mut archive = OdinTar::from_reader(Cursor::new(extracted));
+    archive.validate().unwrap();
+    assert!(matches!(
+        package.extract_archive("AP_A405FNXXU4CVK1.tar.md5", &mut Vec::new()),
+        Err(OdinTarError::EntryNotFound(_))
+    ));
+
+    let dir = std::env::temp_dir().join(format!("odintar-package-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths = package.extract_archives(&dir).unwrap();
+    assert_eq!(vec![dir.join("BL_A405FNXXU4CVK1.tar.md5")], paths);
+    assert!(OdinTar::from_reader(File::open(&paths[0]).unwrap())
+        .validate()
+        .is_ok());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Unaligned reads across block boundaries
+    let mut decryptor = Decryptor::new(Cursor::new(encrypted.clone()), key).unwrap();
+    decryptor.seek(SeekFrom::Start(1000)).unwrap();
+    let mut buf = [0; 37];
+    decryptor.read_exact(&mut buf).unwrap();
+    assert_eq!(&plain[1000..1037], &buf);
+
+    let wrong_key = enc2_key(
+        "SM-A405FN",
+        "XEF",
+        "A405FNXXU4CVK1/A405FNOXM4CVK1/A405FNXXU4CVK1/A405FNXXU4CVK1",
+    );
+    assert!(matches!(
+        Decryptor::new(Cursor::new(encrypted), wrong_key),
+        Err(OdinTarError::DecryptionError)
+    ));
+}
+
+#[test]
+fn test_enc4_key() {
+    assert_eq!(
+        Some(EncVersion::Enc4),
+        EncVersion::from_file_name("SM-A405FN_1_20221208.zip.enc4")
+    );
+    assert_eq!(
+        Some(EncVersion::Enc2),
+        EncVersion::from_file_name("firmware.zip.ENC2")
+    );
+    assert_eq!(None, EncVersion::from_file_name("firmware.zip"));
+
+    // Characters of the logic value index into the version
+    let version = "0123456789abcdef";
+    assert_eq!(
+        md5::compute("fedc").0,
+        enc4_key(version, "\x0f\x0e\x0d\x0c").unwrap()
+    );
+    assert!(matches!(
+        enc4_key("short", "zzzz"),
+        Err(OdinTarError::InvalidKeyMaterial)
+    ));
+}
+
+/// Decode a hex string into bytes.
+fn from_hex(s: &str) -> Vec<u8> {
+    return (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect();
+}
+
+/// Keys and ciphertexts computed independently with md5sum and openssl, for the stock firmware in `TEST_FILE`.
+#[test]
+fn test_decrypt_known_answers() {
+    let version = "A405FNXXU4CVK1/A405FNOXM4CVK1/A405FNXXU4CVK1/A405FNXXU4CVK1";
+    let enc2 = enc2_key("SM-A405FN", "EUX", version);
+    assert_eq!(
+        "d3582cf2798c6d5565bb26a7ccb97e09",
+        format!("{:x}", md5::Digest(enc2))
+    );
+    // Selects "KUK/UXCU5F0XAX1F" from the version
+    let enc4 = enc4_key(version, "l8ln8wzxs4b6ogm4").unwrap();
+    assert_eq!(
+        "cd6abad2a2d49d68c9813f8420668ae5",
+        format!("{:x}", md5::Digest(enc4))
+    );
+
+    // The first block of the stock archive, followed by a block of padding
+    let mut plain = [0; 16];
+    File::open(TEST_FILE)
+        .unwrap()
+        .read_exact(&mut plain)
+        .unwrap();
+    for (key, ciphertext) in [
+        (
+            enc2,
+            "dfe70ec5879f79d89a7de0e04092c6b59b5b3ae4b1ea9a61da25d04b33112e31",
+        ),
+        (
+            enc4,
+            "c7c4a1380f95f19236790be6d3606f6274cc080c5a031b00502199579afc1fb2",
+        ),
+    ] {
+        let ciphertext = from_hex(ciphertext);
+        assert_eq!(ciphertext, encrypt(plain.to_vec(), key));
+        let mut decryptor = Decryptor::new(Cursor::new(ciphertext), key).unwrap();
+        let mut decrypted: Vec<u8> = Vec::new();
+        decryptor.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(&plain[..], decrypted);
+    }
+}
+
+/// Build a minimal super image holding the given logical partitions.
+///
+/// Every chunk of a partition becomes one extent, and extents are laid out back to front
+/// to make sure readers don't rely on them being in physical order.
+fn build_super_image(partitions: &[(&str, &[&[u8]])]) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+
+    fn name(s: &str) -> [u8; 36] {
+        let mut name = [0; 36];
+        name[..s.len()].copy_from_slice(s.as_bytes());
+        return name;
+    }
+
+    let first_sector: u64 = 2048;
+    let mut data: Vec<u8> = Vec::new();
+    let mut partition_table: Vec<u8> = Vec::new();
+    let mut extent_table: Vec<u8> = Vec::new();
+    let num_extents: usize = partitions.iter().map(|(_, e)| e.len()).sum();
+    let mut extent_sectors: Vec<u64> = Vec::new();
+    for (_, extents) in partitions.iter().rev() {
+        for extent in extents.iter().rev() {
+            extent_sectors.push(first_sector + data.len() as u64 / 512);
+            data.extend_from_slice(extent);
+        }
+    }
+    extent_sectors.reverse();
+
+    let mut extent_idx = 0;
+    for (partition_name, extents) in partitions {
+        partition_table.extend_from_slice(&name(partition_name));
+        partition_table.extend_from_slice(&1u32.to_le_bytes());
+        partition_table.extend_from_slice(&(extent_idx as u32).to_le_bytes());
+        partition_table.extend_from_slice(&(extents.len() as u32).to_le_bytes());
+        partition_table.extend_from_slice(&0u32.to_le_bytes());
+        for extent in *extents {
+            extent_table.extend_from_slice(&(extent.len() as u64 / 512).to_le_bytes());
+            extent_table.extend_from_slice(&0u32.to_le_bytes());
+            extent_table.extend_from_slice(&extent_sectors[extent_idx].to_le_bytes());
+            extent_table.extend_from_slice(&0u32.to_le_bytes());
+            extent_idx += 1;
+        }
+    }
+    assert_eq!(num_extents, extent_idx);
+    let mut group_table: Vec<u8> = name("default").to_vec();
+    group_table.extend_from_slice(&[0; 12]);
+    let total_size = first_sector * 512 + data.len() as u64;
+    let mut block_device_table: Vec<u8> = Vec::new();
+    block_device_table.extend_from_slice(&first_sector.to_le_bytes());
+    block_device_table.extend_from_slice(&[0; 8]);
+    block_device_table.extend_from_slice(&total_size.to_le_bytes());
+    block_device_table.extend_from_slice(&name("super"));
+    block_device_table.extend_from_slice(&0u32.to_le_bytes());
+
+    let tables_desc = [
+        (0, partitions.len(), 52),
+        (partition_table.len(), num_extents, 24),
+        (partition_table.len() + extent_table.len(), 1, 48),
+        (
+            partition_table.len() + extent_table.len() + group_table.len(),
+            1,
+            64,
+        ),
+    ];
+    let tables = [
+        partition_table,
+        extent_table,
+        group_table,
+        block_device_table,
+    ]
+    .concat();
+
+    let mut header: Vec<u8> = Vec::new();
+    header.extend_from_slice(&0x414c5030u32.to_le_bytes());
+    header.extend_from_slice(&10u16.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&128u32.to_le_bytes());
+    header.extend_from_slice(&[0; 32]);
+    header.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+    header.extend_from_slice(&Sha256::digest(&tables));
+    for (offset, num, size) in tables_desc {
+        header.extend_from_slice(&(offset as u32).to_le_bytes());
+        header.extend_from_slice(&(num as u32).to_le_bytes());
+        header.extend_from_slice(&(size as u32).to_le_bytes());
+    }
+    let checksum = Sha256::digest(&header);
+    header[12..44].copy_from_slice(&checksum);
+
+    let mut geometry: Vec<u8> = Vec::new();
+    geometry.extend_from_slice(&0x616c4467u32.to_le_bytes());
+    geometry.extend_from_slice(&52u32.to_le_bytes());
+    geometry.extend_from_slice(&[0; 32]);
+    geometry.extend_from_slice(&65536u32.to_le_bytes());
+    geometry.extend_from_slice(&2u32.to_le_bytes());
+    geometry.extend_from_slice(&4096u32.to_le_bytes());
+    let checksum = Sha256::digest(&geometry);
+    geometry[8..40].copy_from_slice(&checksum);
+
+    let mut image = vec![0; first_sector as usize * 512];
+    image[4096..4096 + geometry.len()].copy_from_slice(&geometry);
+    image[8192..8192 + geometry.len()].copy_from_slice(&geometry);
+    image[12288..12288 + header.len()].copy_from_slice(&header);
+    image[12288 + header.len()..12288 + header.len() + tables.len()].copy_from_slice(&tables);
+    image.extend_from_slice(&data);
+    return image;
+}
+
+/// Convert a raw image into a sparse one, using every chunk type.
+fn sparsify(image: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 4096;
+    let mut chunks: Vec<u8> = Vec::new();
+    let mut num_chunks: u32 = 0;
+    for block in image.chunks(BLOCK_SIZE) {
+        let mut block = block.to_vec();
+        block.resize(BLOCK_SIZE, 0);
+        let pattern = &block[..4];
+        if block.iter().all(|b| *b == 0) {
+            chunks.extend_from_slice(&[0xC3, 0xCA, 0, 0, 1, 0, 0, 0, 12, 0, 0, 0]);
+        } else if block.chunks(4).all(|c| c == pattern) {
+            chunks.extend_from_slice(&[0xC2, 0xCA, 0, 0, 1, 0, 0, 0, 16, 0, 0, 0]);
+            chunks.extend_from_slice(pattern);
+        } else {
+            chunks.extend_from_slice(&[0xC1, 0xCA, 0, 0, 1, 0, 0, 0]);
+            chunks.extend_from_slice(&(12 + BLOCK_SIZE as u32).to_le_bytes());
+            chunks.extend_from_slice(&block);
+        }
+        num_chunks += 1;
+    }
+    // Checksum chunk, which produces no output
+    chunks.extend_from_slice(&[0xC4, 0xCA, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0]);
+    num_chunks += 1;
+
+    let mut sparse: Vec<u8> = vec![0x3A, 0xFF, 0x26, 0xED, 1, 0, 0, 0, 28, 0, 12, 0];
+    sparse.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    sparse.extend_from_slice(&(image.len().div_ceil(BLOCK_SIZE) as u32).to_le_bytes());
+    sparse.extend_from_slice(&num_chunks.to_le_bytes());
+    sparse.extend_from_slice(&0u32.to_le_bytes());
+    sparse.extend_from_slice(&chunks);
+    return sparse;
+}
+
+#[test]
+fn test_sparse_reader() {
+    let mut image: Vec<u8> = (0..3 * 4096).map(|i| (i % 251) as u8).collect();
+    image.extend(std::iter::repeat_n(0, 4096));
+    image.extend([1, 2, 3, 4].repeat(1024));
+
+    let mut rdr = SparseReader::new(Cursor::new(sparsify(&image))).unwrap();
+    assert_eq!(image.len() as u64, rdr.len());
+    let mut unsparsed: Vec<u8> = Vec::new();
+    rdr.read_to_end(&mut unsparsed).unwrap();
+    assert_eq!(image, unsparsed);
+
+    assert!(matches!(
+        SparseReader::new(Cursor::new(image)),
+        Err(OdinTarError::InvalidSparseImage(_))
+    ));
+}
+
+#[test]
+fn test_super_image() {
+    let system_a: Vec<u8> = (0..8192).map(|i| (i % 13) as u8).collect();
+    let system_b: Vec<u8> = [7, 7, 7, 7].repeat(1024);
+    let vendor: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+    let image = build_super_image(&[("system", &[&system_a, &system_b]), ("vendor", &[&vendor])]);
+
+    // Raw image, read directly
+    let metadata = LpMetadata::read(&mut Cursor::new(&image)).unwrap();
+    assert_eq!((10, 0), metadata.version);
+    let names: Vec<&str> = metadata
+        .partitions
+        .iter()
+        .map(|p| p.name.as_str())
+        .collect();
+    assert_eq!(vec!["system", "vendor"], names);
+    assert_eq!(12288, metadata.partition("system").unwrap().size());
+    assert_eq!("default", metadata.partition("vendor").unwrap().group_name);
+    assert_eq!("super", metadata.block_devices[0].partition_name);
+
+    // Sparse and compressed inside an archive, read through a forward-only reader
+    let sparse = sparsify(&image);
+    let mut compressed: Vec<u8> = Vec::new();
+    lz4::compress(
+        &mut Cursor::new(&sparse),
+        sparse.len() as u64,
+        &mut compressed,
+    )
+    .unwrap();
+    let data = append_odin_trailer(build_tar(&[("super.img.lz4", &compressed)]), "");
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+    let index = archive.index().unwrap();
+    let mut rdr = archive
+        .image_reader(index.get("super.img.lz4").unwrap())
+        .unwrap();
+    assert_eq!(metadata, LpMetadata::read(&mut rdr).unwrap());
+
+    let mut extracted = Cursor::new(Vec::new());
+    let size = metadata
+        .extract_partition("system", &mut rdr, &mut extracted)
+        .unwrap();
+    assert_eq!(12288, size);
+    assert_eq!([system_a, system_b].concat(), extracted.into_inner());
+
+    assert!(matches!(
+        metadata.extract_partition("product", &mut rdr, &mut Cursor::new(Vec::new())),
+        Err(OdinTarError::LogicalPartitionNotFound(_))
+    ));
+
+    // Corrupted metadata is rejected
+    let mut corrupted = image.clone();
+    corrupted[12288 + 128] ^= 0xff;
+    assert!(matches!(
+        LpMetadata::read(&mut Cursor::new(&corrupted)),
+        Err(OdinTarError::InvalidLpMetadata(_))
+    ));
+}
+
+#[test]
+fn test_extract() {
+    let image: Vec<u8> = (0..3 * 4096).map(|i| (i % 251) as u8).collect();
+    let sparse = sparsify(&image);
+    let mut compressed: Vec<u8> = Vec::new();
+    lz4::compress(
+        &mut Cursor::new(&sparse),
+        sparse.len() as u64,
+        &mut compressed,
+    )
+    .unwrap();
+    let data = append_odin_trailer(
+        build_tar(&[("system.img.lz4", &compressed), ("param.bin", b"param")]),
+        "",
+    );
+
+    let dir = std::env::temp_dir().join(format!("odintar-extract-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+
+    // As stored
+    let manifest = archive.extract(&dir, ExtractOptions::default()).unwrap();
+    assert_eq!(1, manifest.metadata.as_ref().unwrap().build_id);
+    assert_eq!("system.img.lz4", manifest.files[0].file_name);
+    assert_eq!(
+        compressed,
+        std::fs::read(dir.join("system.img.lz4")).unwrap()
+    );
+
+    // Fully decoded
+    let options = ExtractOptions {
+        decompress: true,
+        unsparse: true,
+    };
+    let manifest = archive.extract(&dir, options).unwrap();
+    assert_eq!("system.img", manifest.files[0].file_name);
+    assert_eq!(image.len() as u64, manifest.files[0].size);
+    assert_eq!(image, std::fs::read(dir.join("system.img")).unwrap());
+    assert_eq!(
+        "ccaaac7c8b56412a1c396cf617a57930f8cda8da41aaf8382012ecd6380b16b2",
+        manifest.files[1].sha256
+    );
+    let written = std::fs::read_to_string(dir.join(MANIFEST_FILE_NAME)).unwrap();
+    assert_eq!(manifest.to_string(), written);
+    assert!(written.contains("BUILD_ID:1\n"));
+    assert!(written.contains("  param.bin  5  param.bin\n"));
+
+    // Entries that only differ in compression collide once decompressed
+    let data = build_tar(&[("boot.img", b"boot"), ("boot.img.lz4", &compressed)]);
+    let mut archive = OdinTar::from_reader(Cursor::new(data));
+    archive.extract(&dir, ExtractOptions::default()).unwrap();
+    match archive.extract(&dir, options) {
+        Err(OdinTarError::DuplicateEntry(name)) => assert_eq!("boot.img", name),
+        v => panic!("Wrong result! Expected DuplicateEntry, got {v:?}"),
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,21 +1,47 @@
 //! This crate implements support for the Odin .tar.md5 file format,
-//! in particular parsing it's metadata and performing hash validation.
+//! in particular parsing it's metadata, performing hash validation and editing archive contents.
+//! Sparse and dynamic partition (super.img) images inside archives can be inspected as well.
+//! It can also decrypt the encrypted firmware packages (.zip.enc2, .zip.enc4) Samsung distributes archives in,
+//! and extract the archives from them.
 
 #![allow(clippy::needless_return)]
 #![forbid(unsafe_code)]
 #![forbid(missing_docs)]
 
+mod edit;
+pub use edit::*;
+mod enc;
+pub use enc::*;
 mod error;
 pub use error::*;
+mod extract;
+pub use extract::*;
+mod image;
+pub use image::*;
+mod index;
+pub use index::*;
+mod lp;
+pub use lp::*;
+mod lz4;
+pub use lz4::{is_lz4_frame, lz4_content_size};
 mod metadata;
 pub use metadata::*;
+mod package;
+pub use package::*;
+mod sparse;
+pub use sparse::*;
 #[cfg(test)]
 mod integration_tests;
+mod tar_header;
 
 use std::io::{Read, Seek, SeekFrom};
 
 pub use tar;
 
+/// Upper bound for the size of the metadata trailer.
+/// Anything larger is assumed to be garbage rather than metadata.
+const MAX_TRAILER_LEN: u64 = 64 * 1024;
+
 /// An Odin tar archive.
 pub struct OdinTar<R: ?Sized + Read + Seek> {
     reader: R,
@@ -32,34 +58,33 @@ impl<R: Read + Seek> OdinTar<R> {
     /// Validate the checksum of archive contents based on the Odin metadata in it.
     ///
     /// Quite slow, as the entire archive contents have to be read once.
+    ///
+    /// Returns `OdinTarError::Unverified` for plain tar archives without an Odin metadata trailer.
+    /// Whether to accept those anyway is up to the caller.
     pub fn validate(&mut self) -> Result<(), OdinTarError> {
-        let expected = self.metadata()?.md5;
+        let (trailer_offset, trailer) = self.read_trailer()?.ok_or(OdinTarError::Unverified)?;
+        let expected = Metadata::from_file_trailer(&String::from_utf8(trailer.clone())?)?.md5;
+        // Only the last line of the trailer (the checksum itself) is excluded from the hash.
+        let hashed_len = trailer_offset + checksum_line_offset(&trailer);
         let mut ctx = md5::Context::new();
 
         // Hash the file, 1MiB at a time.
         let mut buf: Vec<u8> = vec![0; 1024 * 1024];
-        // Ignore the appended filename and hash.
-        let data_to_discard_pos = self.hashed_offset()?;
-        let mut read_total: u64 = 0;
+        let mut remaining: u64 = hashed_len;
+        self.reader.rewind()?;
 
-        loop {
-            let read: usize = self.reader.read(&mut buf)?;
+        while remaining > 0 {
+            let to_read: usize = std::cmp::min(remaining, buf.len() as u64).try_into()?;
+            let read: usize = self.reader.read(&mut buf[..to_read])?;
             if read == 0 {
-                // Assume reader is exhausted and all data has been processed
+                // Reader is exhausted early, the resulting checksum mismatch reports this
                 break;
             }
-            let read_u64: u64 = read.try_into()?;
-            read_total += read_u64;
-
-            // Ignore any metadata we may have read
-            let read_total_signed: i64 = read_total.try_into()?;
-            let data_to_discard_pos_signed: i64 = data_to_discard_pos.try_into()?;
-            let metadata_overlap: usize =
-                std::cmp::max(0, read_total_signed - data_to_discard_pos_signed).try_into()?;
-            let buf_used = &buf[0..read - metadata_overlap];
-
-            ctx.consume(buf_used);
+            ctx.consume(&buf[..read]);
+            remaining -= read as u64;
         }
+        self.reader.rewind()?;
+
         let got = ctx.compute();
         let got: String = format!("{got:x}");
         if got != expected {
@@ -69,69 +94,107 @@ impl<R: Read + Seek> OdinTar<R> {
         }
     }
 
-    /// Returns offset into the underlying `Reader` at which data which should not be hashed begins.
-    fn hashed_offset(&mut self) -> Result<u64, OdinTarError> {
-        // We simply search for the second occurrence of 0x0A (LF) in the file from the end.
-        // Doing this on a reader is a pain, so we read the end of the file into a buffer.
-        // NOTE: This assumes metadata no longer than 768 bytes.
-        self.reader.seek(SeekFrom::End(-768))?;
-        let mut buf: Vec<u8> = vec![0; 768];
-        self.reader.read_exact(&mut buf)?;
-        let reader_size = self.reader.seek(SeekFrom::End(0))?;
-        self.reader.rewind()?;
+    /// Returns whether the archive carries an Odin metadata trailer.
+    ///
+    /// Archives without one are plain tar files. They can still be read, but not validated.
+    pub fn has_metadata(&mut self) -> Result<bool, OdinTarError> {
+        return Ok(self.read_trailer()?.is_some());
+    }
 
-        let occurrence_indices = buf
-            .iter()
-            .enumerate()
-            .filter(|(_, x)| **x == 0x0A)
-            .map(|(i, _)| i + 1)
-            .collect::<Vec<usize>>();
-        if occurrence_indices.len() < 2 {
-            return Err(OdinTarError::MetadataError);
+    /// Returns the offset into the underlying `Reader` at which the tar archive's end-of-archive marker begins.
+    ///
+    /// This walks the tar headers rather than guessing based on runs of zeroes,
+    /// so it only has to seek once per entry.
+    fn tar_end_offset(&mut self) -> Result<Option<u64>, OdinTarError> {
+        self.reader.rewind()?;
+        let mut pos: u64 = 0;
+        loop {
+            let header = match tar_header::read_block(&mut self.reader)? {
+                Some(header) => header,
+                // Archive ends without an end-of-archive marker
+                None => return Ok(None),
+            };
+            if tar_header::is_zero_block(&header) {
+                return Ok(Some(pos));
+            }
+            let size = tar_header::entry_size(&header, pos)?;
+            pos += tar_header::BLOCK_SIZE + tar_header::padded_size(size);
+            self.reader.seek(SeekFrom::Start(pos))?;
         }
-
-        let offset = occurrence_indices[occurrence_indices.len() - 2] as u64 + reader_size - 768;
-
-        return Ok(offset);
     }
 
-    /// Returns offset into the underlying `Reader` at which metadata begins.
-    fn metadata_offset(&mut self) -> Result<u64, OdinTarError> {
-        // Assume that looking for the last sequence of zeroes is a sane substitute for actually parsing
-        // the tar to find terminating blocks.
-        // Assume that this is in the sequence (metadata longer than this probably doesn't exist)
-        // TODO: This approach isn't very robust. Probably better to find the start of the sequence and skip it then.
-        self.reader.seek(SeekFrom::End(-768))?;
-        // Find start of metadata
-        loop {
-            let mut sample: [u8; 4] = [0, 0, 0, 0];
-            self.reader.read_exact(&mut sample)?;
-            if sample != [0, 0, 0, 0] {
-                // We've gone one too far
-                self.reader.seek(SeekFrom::Current(-4))?;
-                break;
+    /// Read the raw Odin metadata trailer, if there is one.
+    ///
+    /// Returns the offset into the underlying `Reader` at which the trailer begins, and the trailer itself.
+    fn read_trailer(&mut self) -> Result<Option<(u64, Vec<u8>)>, OdinTarError> {
+        let mut pos = match self.tar_end_offset()? {
+            Some(pos) => pos,
+            None => {
+                self.reader.rewind()?;
+                return Ok(None);
             }
-            self.reader.seek(SeekFrom::Current(4))?;
+        };
+
+        // Skip the blocks terminating the archive and any padding to the record size
+        self.reader.seek(SeekFrom::Start(pos))?;
+        let trailer_offset: u64 = loop {
+            match tar_header::read_block(&mut self.reader)? {
+                None => {
+                    self.reader.rewind()?;
+                    return Ok(None);
+                }
+                Some(block) if tar_header::is_zero_block(&block) => pos += tar_header::BLOCK_SIZE,
+                Some(block) => {
+                    let leading_zeroes = block.iter().take_while(|b| **b == 0).count();
+                    break pos + leading_zeroes as u64;
+                }
+            }
+        };
+
+        // Guard against reading an entire disk image if this isn't actually a trailer
+        let trailer_len = self.reader.seek(SeekFrom::End(0))? - trailer_offset;
+        if trailer_len > MAX_TRAILER_LEN {
+            return Err(OdinTarError::MetadataTooLarge(trailer_len));
         }
-        // Hack to get the seek position
-        let pos = self.reader.stream_position()?;
+        self.reader.seek(SeekFrom::Start(trailer_offset))?;
+        let mut trailer: Vec<u8> = Vec::with_capacity(trailer_len.try_into()?);
+        self.reader.read_to_end(&mut trailer)?;
         self.reader.rewind()?;
-        return Ok(pos);
+
+        return Ok(Some((trailer_offset, trailer)));
     }
 
     /// Parse the Odin-specific metadata out of the archive.
+    ///
+    /// Returns `OdinTarError::Unverified` for plain tar archives without an Odin metadata trailer.
     pub fn metadata(&mut self) -> Result<Metadata, OdinTarError> {
-        let pos = self.metadata_offset()?;
-        self.reader.seek(SeekFrom::Start(pos))?;
+        let (_, trailer) = self.read_trailer()?.ok_or(OdinTarError::Unverified)?;
+        let metadata = Metadata::from_file_trailer(&String::from_utf8(trailer)?)?;
 
-        // Extract and parse it
-        let mut data: String = String::with_capacity(512);
-        self.reader.read_to_string(&mut data)?;
-        let metadata = Metadata::from_file_trailer(&data)?;
+        return Ok(metadata);
+    }
 
-        self.reader.rewind()?;
+    /// Build an index of the files in the archive.
+    ///
+    /// Only the tar headers are read, so this is cheap even for archives several GiB in size.
+    pub fn index(&mut self) -> Result<ArchiveIndex, OdinTarError> {
+        return ArchiveIndex::build(&mut self.reader);
+    }
 
-        return Ok(metadata);
+    /// Get a reader over the contents of the given entry, as found in this archive's index.
+    ///
+    /// Contents are returned as stored, i.e. without decompression.
+    pub fn entry_reader(&mut self, entry: &IndexEntry) -> Result<EntryReader<'_, R>, OdinTarError> {
+        return EntryReader::new(&mut self.reader, entry);
+    }
+
+    /// Get a reader over the decoded contents of the given entry.
+    ///
+    /// LZ4-compressed entries are decompressed and sparse images are unsparsed on the fly,
+    /// so the reader yields the raw image as it ends up on the device.
+    pub fn image_reader(&mut self, entry: &IndexEntry) -> Result<ImageReader<'_>, OdinTarError> {
+        let reader = EntryReader::new(&mut self.reader, entry)?;
+        return ImageReader::new(reader, entry.compression, true);
     }
 
     /// Return the underlying tar archive, consuming the instance.
@@ -141,3 +204,14 @@ impl<R: Read + Seek> OdinTar<R> {
         return tar::Archive::new(self.reader);
     }
 }
+
+/// Returns the offset into the trailer at which the checksum line begins.
+///
+/// Everything before it, including the other metadata lines, is covered by the checksum.
+fn checksum_line_offset(trailer: &[u8]) -> u64 {
+    let trimmed_len = trailer.len() - trailer.iter().rev().take_while(|b| **b == b'\n').count();
+    return match trailer[..trimmed_len].iter().rposition(|b| *b == b'\n') {
+        Some(idx) => (idx + 1) as u64,
+        None => 0,
+    };
+}
@@ -0,0 +1,394 @@
+//! Parser for the liblp metadata of Android dynamic partition images (super.img).
+//!
+//! A super image holds a number of logical partitions (system, vendor, product, ...),
+//! each made up of one or more extents on the underlying block device.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use sha2::{Digest, Sha256};
+
+use crate::OdinTarError;
+
+/// Sector size all LP offsets and sizes are given in.
+pub const LP_SECTOR_SIZE: u64 = 512;
+/// Space reserved at the start of the device before the geometry.
+const LP_PARTITION_RESERVED_BYTES: u64 = 4096;
+/// Size reserved for each geometry copy.
+const LP_METADATA_GEOMETRY_SIZE: u64 = 4096;
+const LP_METADATA_GEOMETRY_MAGIC: u32 = 0x616c4467;
+const LP_METADATA_HEADER_MAGIC: u32 = 0x414c5030;
+const LP_METADATA_MAJOR_VERSION: u16 = 10;
+/// Size of the metadata header in version 10.0. Version 10.2 extends it.
+const LP_METADATA_HEADER_V1_0_SIZE: usize = 128;
+/// Size of the fixed part of the geometry.
+const LP_GEOMETRY_SIZE: usize = 52;
+/// Size of names in partition, group and block device entries.
+const LP_NAME_LEN: usize = 36;
+
+const LP_PARTITION_ENTRY_SIZE: usize = 52;
+const LP_EXTENT_ENTRY_SIZE: usize = 24;
+const LP_GROUP_ENTRY_SIZE: usize = 48;
+const LP_BLOCK_DEVICE_ENTRY_SIZE: usize = 64;
+
+/// Attribute flag of read-only partitions.
+pub const LP_PARTITION_ATTR_READONLY: u32 = 0x1;
+/// Attribute flag of partitions with a slot suffix (A/B devices).
+pub const LP_PARTITION_ATTR_SLOT_SUFFIXED: u32 = 0x2;
+
+/// Layout of the metadata area.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LpGeometry {
+    /// Maximum size of a single metadata copy.
+    pub metadata_max_size: u32,
+    /// Number of metadata slots (one per A/B slot).
+    pub metadata_slot_count: u32,
+    /// Logical block size of the device.
+    pub logical_block_size: u32,
+}
+
+/// Where an extent's data comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LpExtentTarget {
+    /// Data is stored at the given sector of the block device with the given index.
+    Linear {
+        /// First physical sector of the extent.
+        sector: u64,
+        /// Index into the metadata's block devices.
+        block_device: u32,
+    },
+    /// Extent reads as zeros and has no backing storage.
+    Zero,
+}
+
+/// A contiguous part of a logical partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LpExtent {
+    /// Length of the extent in sectors.
+    pub num_sectors: u64,
+    /// Where the extent's data is stored.
+    pub target: LpExtentTarget,
+}
+
+/// A logical partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LpPartition {
+    /// Name of the partition, e.g. `system` or `vendor_a`.
+    pub name: String,
+    /// Attribute flags, see the `LP_PARTITION_ATTR_*` constants.
+    pub attributes: u32,
+    /// Name of the group the partition belongs to.
+    pub group_name: String,
+    /// Extents making up the partition, in logical order.
+    pub extents: Vec<LpExtent>,
+}
+
+impl LpPartition {
+    /// Size of the partition in bytes.
+    pub fn size(&self) -> u64 {
+        return self
+            .extents
+            .iter()
+            .map(|e| e.num_sectors * LP_SECTOR_SIZE)
+            .sum();
+    }
+}
+
+/// A group of partitions sharing a size limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LpGroup {
+    /// Name of the group.
+    pub name: String,
+    /// Group flags.
+    pub flags: u32,
+    /// Maximum combined size of the group's partitions in bytes. 0 means unlimited.
+    pub maximum_size: u64,
+}
+
+/// A physical block device backing the logical partitions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LpBlockDevice {
+    /// Name of the physical partition, usually `super`.
+    pub partition_name: String,
+    /// First sector usable for extents.
+    pub first_logical_sector: u64,
+    /// Size of the block device in bytes.
+    pub size: u64,
+    /// Block device flags.
+    pub flags: u32,
+}
+
+/// The metadata of a super image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LpMetadata {
+    /// Layout of the metadata area.
+    pub geometry: LpGeometry,
+    /// Version of the metadata format, as (major, minor).
+    pub version: (u16, u16),
+    /// Logical partitions, in the order they're stored.
+    pub partitions: Vec<LpPartition>,
+    /// Partition groups.
+    pub groups: Vec<LpGroup>,
+    /// Physical block devices.
+    pub block_devices: Vec<LpBlockDevice>,
+}
+
+/// Location of a table within the metadata.
+struct TableDescriptor {
+    offset: usize,
+    num_entries: usize,
+    entry_size: usize,
+}
+
+impl LpMetadata {
+    /// Read the metadata of slot 0 from the given unsparsed super image.
+    ///
+    /// The reader is only ever seeked forward, so it's fine to pass an `ImageReader`.
+    /// Both the geometry and metadata checksums are verified.
+    pub fn read<R: Read + Seek + ?Sized>(reader: &mut R) -> Result<LpMetadata, OdinTarError> {
+        reader.seek(SeekFrom::Start(LP_PARTITION_RESERVED_BYTES))?;
+        let mut geometry = vec![0; LP_METADATA_GEOMETRY_SIZE as usize];
+        reader.read_exact(&mut geometry)?;
+        let geometry = parse_geometry(&geometry)?;
+
+        // The primary metadata follows both geometry copies
+        reader.seek(SeekFrom::Start(
+            LP_PARTITION_RESERVED_BYTES + 2 * LP_METADATA_GEOMETRY_SIZE,
+        ))?;
+        let mut header = vec![0; LP_METADATA_HEADER_V1_0_SIZE];
+        reader.read_exact(&mut header)?;
+        if u32_at(&header, 0) != LP_METADATA_HEADER_MAGIC {
+            return Err(OdinTarError::InvalidLpMetadata("bad header magic"));
+        }
+        let version = (u16_at(&header, 4), u16_at(&header, 6));
+        if version.0 != LP_METADATA_MAJOR_VERSION {
+            return Err(OdinTarError::InvalidLpMetadata("unsupported version"));
+        }
+        let header_size = u32_at(&header, 8) as usize;
+        if header_size < LP_METADATA_HEADER_V1_0_SIZE
+            || header_size as u64 > u64::from(geometry.metadata_max_size)
+        {
+            return Err(OdinTarError::InvalidLpMetadata("bad header size"));
+        }
+        header.resize(header_size, 0);
+        reader.read_exact(&mut header[LP_METADATA_HEADER_V1_0_SIZE..])?;
+        verify_checksum(&header, 12, "header checksum mismatch")?;
+
+        let tables_size = u32_at(&header, 44) as usize;
+        if (header_size + tables_size) as u64 > u64::from(geometry.metadata_max_size) {
+            return Err(OdinTarError::InvalidLpMetadata("bad tables size"));
+        }
+        let mut tables = vec![0; tables_size];
+        reader.read_exact(&mut tables)?;
+        if Sha256::digest(&tables).as_slice() != &header[48..80] {
+            return Err(OdinTarError::InvalidLpMetadata("tables checksum mismatch"));
+        }
+
+        let partitions_desc = table_descriptor(&header, 80, LP_PARTITION_ENTRY_SIZE)?;
+        let extents_desc = table_descriptor(&header, 92, LP_EXTENT_ENTRY_SIZE)?;
+        let groups_desc = table_descriptor(&header, 104, LP_GROUP_ENTRY_SIZE)?;
+        let block_devices_desc = table_descriptor(&header, 116, LP_BLOCK_DEVICE_ENTRY_SIZE)?;
+
+        let mut extents: Vec<LpExtent> = Vec::new();
+        for entry in table_entries(&tables, &extents_desc)? {
+            let target = match u32_at(entry, 8) {
+                0 => LpExtentTarget::Linear {
+                    sector: u64_at(entry, 12),
+                    block_device: u32_at(entry, 20),
+                },
+                1 => LpExtentTarget::Zero,
+                _ => return Err(OdinTarError::InvalidLpMetadata("unknown extent type")),
+            };
+            extents.push(LpExtent {
+                num_sectors: u64_at(entry, 0),
+                target,
+            });
+        }
+
+        let mut groups: Vec<LpGroup> = Vec::new();
+        for entry in table_entries(&tables, &groups_desc)? {
+            groups.push(LpGroup {
+                name: name_at(entry, 0)?,
+                flags: u32_at(entry, 36),
+                maximum_size: u64_at(entry, 40),
+            });
+        }
+
+        let mut partitions: Vec<LpPartition> = Vec::new();
+        for entry in table_entries(&tables, &partitions_desc)? {
+            let first_extent = u32_at(entry, 40) as usize;
+            let num_extents = u32_at(entry, 44) as usize;
+            let group_index = u32_at(entry, 48) as usize;
+            let partition_extents = first_extent
+                .checked_add(num_extents)
+                .and_then(|end| extents.get(first_extent..end))
+                .ok_or(OdinTarError::InvalidLpMetadata("extent index out of range"))?;
+            let group = groups
+                .get(group_index)
+                .ok_or(OdinTarError::InvalidLpMetadata("group index out of range"))?;
+            partitions.push(LpPartition {
+                name: name_at(entry, 0)?,
+                attributes: u32_at(entry, 36),
+                group_name: group.name.clone(),
+                extents: partition_extents.to_vec(),
+            });
+        }
+
+        let mut block_devices: Vec<LpBlockDevice> = Vec::new();
+        for entry in table_entries(&tables, &block_devices_desc)? {
+            block_devices.push(LpBlockDevice {
+                first_logical_sector: u64_at(entry, 0),
+                size: u64_at(entry, 16),
+                partition_name: name_at(entry, 24)?,
+                flags: u32_at(entry, 60),
+            });
+        }
+
+        return Ok(LpMetadata {
+            geometry,
+            version,
+            partitions,
+            groups,
+            block_devices,
+        });
+    }
+
+    /// Look up a logical partition by it's name.
+    pub fn partition(&self, name: &str) -> Option<&LpPartition> {
+        return self.partitions.iter().find(|p| p.name == name);
+    }
+
+    /// Copy the contents of the given logical partition out of the super image into `dst`.
+    ///
+    /// Extents are read in the order they're stored in the image, so `reader` only needs to seek forward
+    /// and has to be positioned before the partition's data, e.g. right after reading the metadata.
+    /// Only single-device super images are supported.
+    pub fn extract_partition<R: Read + Seek + ?Sized, W: Write + Seek + ?Sized>(
+        &self,
+        name: &str,
+        reader: &mut R,
+        dst: &mut W,
+    ) -> Result<u64, OdinTarError> {
+        let partition = self
+            .partition(name)
+            .ok_or_else(|| OdinTarError::LogicalPartitionNotFound(name.to_string()))?;
+
+        // Pair each extent with it's offset in the logical partition
+        let mut extents: Vec<(u64, &LpExtent)> = Vec::with_capacity(partition.extents.len());
+        let mut logical_offset: u64 = 0;
+        for extent in &partition.extents {
+            extents.push((logical_offset, extent));
+            logical_offset += extent.num_sectors * LP_SECTOR_SIZE;
+        }
+        extents.sort_by_key(|(_, e)| match e.target {
+            LpExtentTarget::Linear { sector, .. } => sector,
+            LpExtentTarget::Zero => 0,
+        });
+
+        for (offset, extent) in extents {
+            let len = extent.num_sectors * LP_SECTOR_SIZE;
+            dst.seek(SeekFrom::Start(offset))?;
+            match extent.target {
+                LpExtentTarget::Linear {
+                    sector,
+                    block_device,
+                } => {
+                    if block_device != 0 {
+                        return Err(OdinTarError::InvalidLpMetadata(
+                            "extents on secondary block devices are not supported",
+                        ));
+                    }
+                    reader.seek(SeekFrom::Start(sector * LP_SECTOR_SIZE))?;
+                    let copied = io::copy(&mut reader.take(len), dst)?;
+                    if copied != len {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                }
+                LpExtentTarget::Zero => {
+                    io::copy(&mut io::repeat(0).take(len), dst)?;
+                }
+            }
+        }
+        return Ok(logical_offset);
+    }
+}
+
+fn parse_geometry(data: &[u8]) -> Result<LpGeometry, OdinTarError> {
+    if u32_at(data, 0) != LP_METADATA_GEOMETRY_MAGIC {
+        return Err(OdinTarError::InvalidLpMetadata("bad geometry magic"));
+    }
+    let struct_size = u32_at(data, 4) as usize;
+    if !(LP_GEOMETRY_SIZE..=data.len()).contains(&struct_size) {
+        return Err(OdinTarError::InvalidLpMetadata("bad geometry size"));
+    }
+    verify_checksum(&data[..struct_size], 8, "geometry checksum mismatch")?;
+    return Ok(LpGeometry {
+        metadata_max_size: u32_at(data, 40),
+        metadata_slot_count: u32_at(data, 44),
+        logical_block_size: u32_at(data, 48),
+    });
+}
+
+/// Verify a structure's SHA-256, which is computed with the checksum field itself zeroed.
+fn verify_checksum(
+    data: &[u8],
+    checksum_offset: usize,
+    error: &'static str,
+) -> Result<(), OdinTarError> {
+    let mut zeroed = data.to_vec();
+    zeroed[checksum_offset..checksum_offset + 32].fill(0);
+    if Sha256::digest(&zeroed).as_slice() != &data[checksum_offset..checksum_offset + 32] {
+        return Err(OdinTarError::InvalidLpMetadata(error));
+    }
+    return Ok(());
+}
+
+fn table_descriptor(
+    header: &[u8],
+    offset: usize,
+    min_entry_size: usize,
+) -> Result<TableDescriptor, OdinTarError> {
+    let desc = TableDescriptor {
+        offset: u32_at(header, offset) as usize,
+        num_entries: u32_at(header, offset + 4) as usize,
+        entry_size: u32_at(header, offset + 8) as usize,
+    };
+    if desc.num_entries > 0 && desc.entry_size < min_entry_size {
+        return Err(OdinTarError::InvalidLpMetadata("table entries too small"));
+    }
+    return Ok(desc);
+}
+
+fn table_entries<'a>(
+    tables: &'a [u8],
+    desc: &TableDescriptor,
+) -> Result<impl Iterator<Item = &'a [u8]>, OdinTarError> {
+    let table = desc
+        .num_entries
+        .checked_mul(desc.entry_size)
+        .and_then(|len| desc.offset.checked_add(len))
+        .and_then(|end| tables.get(desc.offset..end))
+        .ok_or(OdinTarError::InvalidLpMetadata("table out of bounds"))?;
+    // chunks_exact panics on a zero size, which is only possible for empty tables
+    return Ok(table.chunks_exact(desc.entry_size.max(1)));
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap());
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    return u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+}
+
+fn name_at(data: &[u8], offset: usize) -> Result<String, OdinTarError> {
+    let name: Vec<u8> = data[offset..offset + LP_NAME_LEN]
+        .iter()
+        .take_while(|b| **b != 0)
+        .copied()
+        .collect();
+    return Ok(String::from_utf8(name)?);
+}
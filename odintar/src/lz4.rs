@@ -0,0 +1,73 @@
+//! Helpers for the LZ4 frame format Odin uses for compressed archive entries.
+
+use std::io::{self, Read, Write};
+
+use lz4_flex::frame::{BlockSize, FrameEncoder, FrameInfo};
+
+/// LZ4 frame magic number, as it appears on disk.
+const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4D, 0x18];
+
+/// Returns whether the given data starts like an LZ4 frame.
+pub fn is_lz4_frame(data: &[u8]) -> bool {
+    return data.starts_with(&LZ4_MAGIC);
+}
+
+/// Flag in the frame descriptor signalling that the uncompressed size is recorded.
+const FLG_CONTENT_SIZE: u8 = 0x08;
+
+/// Read the uncompressed size out of an LZ4 frame header, if the frame records it.
+///
+/// `header` should be at least the first 14 bytes of the frame.
+pub fn lz4_content_size(header: &[u8]) -> Option<u64> {
+    if !is_lz4_frame(header) {
+        return None;
+    }
+    let flags = *header.get(4)?;
+    if flags & FLG_CONTENT_SIZE == 0 {
+        return None;
+    }
+    // Magic, FLG and BD bytes precede the size
+    let size: [u8; 8] = header.get(6..14)?.try_into().ok()?;
+    return Some(u64::from_le_bytes(size));
+}
+
+/// Compress `src` into a single LZ4 frame written to `dst`.
+///
+/// The frame parameters match the `lz4 -B6 --content-size` invocation used by Samsung's build system.
+/// `size` is the number of bytes `src` will yield, it's recorded in the frame header.
+pub(crate) fn compress(src: &mut dyn Read, size: u64, dst: &mut dyn Write) -> io::Result<()> {
+    let info = FrameInfo::new()
+        .block_size(BlockSize::Max1MB)
+        .content_size(Some(size))
+        .content_checksum(true);
+    let mut encoder = FrameEncoder::with_frame_info(info, dst);
+    io::copy(src, &mut encoder)?;
+    encoder.finish()?;
+    return Ok(());
+}
+
+#[test]
+fn test_lz4_content_size() {
+    let mut frame: Vec<u8> = Vec::new();
+    compress(&mut &b"content"[..], 7, &mut frame).unwrap();
+    assert!(is_lz4_frame(&frame));
+    assert_eq!(Some(7), lz4_content_size(&frame));
+    assert_eq!(None, lz4_content_size(b"content"));
+}
+
+#[test]
+fn test_compress_frame_descriptor() {
+    let mut frame: Vec<u8> = Vec::new();
+    compress(&mut &[0u8; 4096][..], 4096, &mut frame).unwrap();
+    // FLG: version 1, independent blocks, content size and checksum present
+    assert_eq!(0x6C, frame[4]);
+    // BD: 1 MiB maximum block size, as with `lz4 -B6`
+    assert_eq!(0x60, frame[5]);
+
+    // Same as the entries of stock firmware, such as sboot.bin.lz4 in the test archive
+    let stock = std::fs::read(
+        "testdata/BL_A405FNXXU4CVK1_CL25488227_QB58944467_REV00_user_low_ship.tar.md5",
+    )
+    .unwrap();
+    assert_eq!(frame[..6], stock[512..518]);
+}
@@ -11,39 +11,112 @@ pub struct Metadata {
     pub md5: String,
     /// Filename used to identify contents before packing.
     pub orig_file_name: String,
+    /// Lines of the trailer in front of the checksum line, in the order they appear in.
+    ///
+    /// Writing the metadata keeps them as they are, except for updating the build ID and original size.
+    /// If empty, the layout of Samsung's build system is used.
+    pub info_lines: Vec<String>,
 }
 
+const BUILD_ID_KEY: &str = "BUILD_ID";
+const ORIG_SIZE_KEY: &str = "original_tar_file_size";
+
 impl Metadata {
     /// Parse the metadata from the given Odin .tar.md5 file trailer
     /// (the part after the 2 empty blocks terminating the archive).
     pub fn from_file_trailer(data: &str) -> Result<Metadata, OdinTarError> {
+        // There's (sometimes?) a newline after the last entry, trim it so we don't see an empty last line
+        let mut lines: Vec<&str> = data.trim_end_matches('\n').lines().collect();
+
+        // This entry does not follow the key:value format of the rest, so assume it can only sanely ever be last.
+        // It's the output of md5sum, so the filename may be prefixed by '*' in binary mode.
+        let last_line = lines
+            .pop()
+            .filter(|l| !l.is_empty())
+            .ok_or(OdinTarError::MissingMetadataField("md5"))?;
+        let (hash, name) = last_line
+            .split_once(' ')
+            .ok_or_else(|| OdinTarError::MalformedMetadataLine(String::from(last_line)))?;
+        if hash.len() != 32 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(OdinTarError::MalformedMetadataLine(String::from(last_line)));
+        }
+        let name = name.trim_start_matches(' ').trim_start_matches('*');
+        let md5 = hash.to_ascii_lowercase();
+        let orig_file_name = String::from(name);
+
         let mut build_id: Option<u64> = None;
         let mut orig_size: Option<u64> = None;
-        for line in data.lines() {
-            if line.contains("BUILD_ID") {
-                let id = line.rsplit_once(':').unwrap().1;
-                let id: u64 = str::parse(id)?;
-                build_id = Some(id);
-            } else if line.contains("original_tar_file_size") {
-                let size = line.rsplit_once(':').unwrap().1;
-                let size: u64 = str::parse(size)?;
-                orig_size = Some(size);
+        for line in lines.iter().copied() {
+            // Lines that aren't key:value pairs are free-form commentary, such as "Show the build information"
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let parse = |value: &str| -> Result<u64, OdinTarError> {
+                return value
+                    .trim()
+                    .parse()
+                    .map_err(|_| OdinTarError::MalformedMetadataLine(String::from(line)));
+            };
+            // The build ID key is usually prefixed by the build system's name, e.g. "RBS BUILD_ID"
+            if key.contains(BUILD_ID_KEY) {
+                build_id = Some(parse(value)?);
+            } else if key.trim() == ORIG_SIZE_KEY {
+                orig_size = Some(parse(value)?);
             }
         }
-        // This entry does not follow the key:value format of the rest, so assume it can only sanely ever be last
-        // There's (sometimes?) a newline after the last entry, trim it so we don't confuse rsplit
-        let last_line = data.trim_end_matches('\n').rsplit_once('\n').unwrap().1;
-        let (hash, name) = last_line.split_once("  ").unwrap();
-        let md5 = String::from(hash);
-        let orig_file_name = String::from(name);
 
         return Ok(Metadata {
-            build_id: build_id.unwrap(),
-            orig_size: orig_size.unwrap(),
+            build_id: build_id.ok_or(OdinTarError::MissingMetadataField(BUILD_ID_KEY))?,
+            orig_size: orig_size.ok_or(OdinTarError::MissingMetadataField(ORIG_SIZE_KEY))?,
             md5,
             orig_file_name,
+            info_lines: lines.into_iter().map(String::from).collect(),
         });
     }
+
+    /// Any further key:value lines not known to this library, in the order they appear in.
+    pub fn extra_fields(&self) -> Vec<(&str, &str)> {
+        return self
+            .info_lines
+            .iter()
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(key, _)| !key.contains(BUILD_ID_KEY) && key.trim() != ORIG_SIZE_KEY)
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+    }
+
+    /// Serialize everything but the checksum line, in the format Samsung's build system uses.
+    ///
+    /// Unlike the checksum line, this part of the trailer is covered by the checksum.
+    pub(crate) fn build_info(&self) -> String {
+        let mut info = String::new();
+        let mut has_build_id = false;
+        let mut has_orig_size = false;
+        for line in &self.info_lines {
+            match line.split_once(':') {
+                Some((key, _)) if key.contains(BUILD_ID_KEY) => {
+                    info.push_str(&format!("{key}:{}\n", self.build_id));
+                    has_build_id = true;
+                }
+                Some((key, _)) if key.trim() == ORIG_SIZE_KEY => {
+                    info.push_str(&format!("{key}:{}\n", self.orig_size));
+                    has_orig_size = true;
+                }
+                _ => info.push_str(&format!("{line}\n")),
+            }
+        }
+
+        if self.info_lines.is_empty() {
+            info.push_str("Show the build information\n");
+        }
+        if !has_build_id {
+            info.push_str(&format!("RBS {BUILD_ID_KEY}:{}\n", self.build_id));
+        }
+        if !has_orig_size {
+            info.push_str(&format!("{ORIG_SIZE_KEY}:{}\n", self.orig_size));
+        }
+        return info;
+    }
 }
 
 #[test]
@@ -57,7 +130,58 @@ fn test_metadata_parse() {
         orig_file_name: String::from(
             "BL_A405FNXXU4CVK1_CL25488227_QB58944467_REV00_user_low_ship.tar",
         ),
+        info_lines: vec![
+            String::from("Show the build information"),
+            String::from("RBS BUILD_ID:58944467"),
+            String::from("original_tar_file_size:3368960"),
+        ],
     };
     let got = Metadata::from_file_trailer(input).unwrap();
     assert_eq!(expected, got);
 }
+
+#[test]
+fn test_metadata_parse_extra_fields() {
+    let input: &str = "RBS BUILD_ID:1\nSIGNER:release\noriginal_tar_file_size:2\n218789cf915d52335c8d699169b31b99  AP.tar";
+    let got = Metadata::from_file_trailer(input).unwrap();
+    assert_eq!(vec![("SIGNER", "release")], got.extra_fields());
+}
+
+#[test]
+fn test_metadata_build_info() {
+    // Lines this library doesn't know keep their place, known fields are updated in place
+    let info = "RBS BUILD_ID:1\nfree-form line\noriginal_tar_file_size:2\nSIGNER:release\n";
+    let mut metadata =
+        Metadata::from_file_trailer(&format!("{info}218789cf915d52335c8d699169b31b99  AP.tar"))
+            .unwrap();
+    assert_eq!(info, metadata.build_info());
+    metadata.orig_size = 3;
+    assert_eq!(
+        "RBS BUILD_ID:1\nfree-form line\noriginal_tar_file_size:3\nSIGNER:release\n",
+        metadata.build_info()
+    );
+
+    metadata.info_lines.clear();
+    assert_eq!(
+        "Show the build information\nRBS BUILD_ID:1\noriginal_tar_file_size:3\n",
+        metadata.build_info()
+    );
+}
+
+#[test]
+fn test_metadata_parse_missing_field() {
+    let input: &str = "RBS BUILD_ID:1\n218789cf915d52335c8d699169b31b99  AP.tar\n";
+    match Metadata::from_file_trailer(input) {
+        Err(OdinTarError::MissingMetadataField(ORIG_SIZE_KEY)) => {}
+        other => panic!("Wrong result! Expected MissingMetadataField, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_metadata_parse_missing_checksum() {
+    let input: &str = "RBS BUILD_ID:1\noriginal_tar_file_size:2\n";
+    match Metadata::from_file_trailer(input) {
+        Err(OdinTarError::MalformedMetadataLine(_)) => {}
+        other => panic!("Wrong result! Expected MalformedMetadataLine, got {other:?}"),
+    }
+}
@@ -0,0 +1,76 @@
+//! Samsung's firmware packages, the zip files the Odin archives of a firmware (AP, BL, CP, CSC, ...) are distributed in.
+//!
+//! Encrypted packages (.zip.enc2, .zip.enc4) are read through a `Decryptor`.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::OdinTarError;
+
+/// A firmware package holding Odin archives.
+pub struct FirmwarePackage<R: Read + Seek> {
+    zip: zip::ZipArchive<R>,
+}
+
+impl<R: Read + Seek> FirmwarePackage<R> {
+    /// Open the package from the given reader, e.g. a `Decryptor`.
+    pub fn new(reader: R) -> Result<FirmwarePackage<R>, OdinTarError> {
+        let zip = zip::ZipArchive::new(reader)?;
+        return Ok(FirmwarePackage { zip });
+    }
+
+    /// Names of the Odin archives (.tar.md5 and .tar) in the package, in the order they are stored in.
+    pub fn archive_names(&mut self) -> Result<Vec<String>, OdinTarError> {
+        let mut names: Vec<String> = Vec::new();
+        for i in 0..self.zip.len() {
+            let file = self.zip.by_index_raw(i)?;
+            let name = file.name().to_ascii_lowercase();
+            if file.is_file() && (name.ends_with(".tar.md5") || name.ends_with(".tar")) {
+                names.push(String::from(file.name()));
+            }
+        }
+        return Ok(names);
+    }
+
+    /// Write the contents of the archive with the given name to `out`, returning their size.
+    ///
+    /// The contents are streamed and checked against the package's checksum as they are written.
+    pub fn extract_archive(
+        &mut self,
+        name: &str,
+        out: &mut dyn Write,
+    ) -> Result<u64, OdinTarError> {
+        let mut file = match self.zip.by_name(name) {
+            Ok(file) => file,
+            Err(zip::result::ZipError::FileNotFound) => {
+                return Err(OdinTarError::EntryNotFound(String::from(name)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        return Ok(io::copy(&mut file, out)?);
+    }
+
+    /// Extract all Odin archives into the directory `dir`, which must exist, returning the paths written.
+    ///
+    /// Archives are written under their file name, without any directories the package stores them under.
+    /// Existing files are overwritten, but two archives extracting to the same file name are an error.
+    pub fn extract_archives(&mut self, dir: &Path) -> Result<Vec<PathBuf>, OdinTarError> {
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for name in self.archive_names()? {
+            let file_name = Path::new(&name)
+                .file_name()
+                .ok_or_else(|| OdinTarError::InvalidEntryName(name.clone()))?;
+            let path = dir.join(file_name);
+            if paths.contains(&path) {
+                return Err(OdinTarError::DuplicateEntry(name));
+            }
+
+            let mut out = BufWriter::new(File::create(&path)?);
+            self.extract_archive(&name, &mut out)?;
+            out.flush()?;
+            paths.push(path);
+        }
+        return Ok(paths);
+    }
+}
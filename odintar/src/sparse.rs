@@ -0,0 +1,202 @@
+//! Support for Android sparse images, as produced by `img2simg`.
+//!
+//! Large filesystem images (system, super, ...) are commonly stored this way inside Odin archives.
+
+use std::io::{self, Read};
+
+use crate::OdinTarError;
+
+/// Sparse image magic number, as it appears on disk.
+const SPARSE_MAGIC: [u8; 4] = [0x3A, 0xFF, 0x26, 0xED];
+/// Size of the file header in format version 1.0.
+const FILE_HEADER_SIZE: usize = 28;
+/// Size of a chunk header in format version 1.0.
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_TYPE_RAW: u16 = 0xCAC1;
+const CHUNK_TYPE_FILL: u16 = 0xCAC2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_TYPE_CRC32: u16 = 0xCAC4;
+
+/// Returns whether the given data starts like a sparse image.
+pub fn is_sparse_image(data: &[u8]) -> bool {
+    return data.starts_with(&SPARSE_MAGIC);
+}
+
+/// What the chunk currently being read expands to.
+#[derive(Debug, Clone, Copy)]
+enum Chunk {
+    /// Data is copied from the sparse image.
+    Raw,
+    /// A 4-byte pattern, repeated.
+    Fill([u8; 4]),
+    /// Nothing is stored, reads return zeros.
+    Zero,
+}
+
+/// Reader yielding the unsparsed contents of a sparse image.
+///
+/// The sparse image is read front to back, so this works on top of plain streams such as an LZ4 decoder.
+pub struct SparseReader<R: Read> {
+    inner: R,
+    block_size: u32,
+    total_blocks: u32,
+    chunks_left: u32,
+    /// Extra bytes after each chunk header, for headers larger than we know about.
+    chunk_header_padding: usize,
+    chunk: Chunk,
+    /// Bytes left to yield from the current chunk.
+    chunk_left: u64,
+    /// Bytes yielded so far.
+    pos: u64,
+}
+
+impl<R: Read> SparseReader<R> {
+    /// Wrap the given reader, which has to be positioned at the start of a sparse image.
+    pub fn new(mut inner: R) -> Result<SparseReader<R>, OdinTarError> {
+        let mut header = [0; FILE_HEADER_SIZE];
+        inner.read_exact(&mut header)?;
+        if !is_sparse_image(&header) {
+            return Err(OdinTarError::InvalidSparseImage("bad magic"));
+        }
+        let major_version = u16::from_le_bytes([header[4], header[5]]);
+        if major_version != 1 {
+            return Err(OdinTarError::InvalidSparseImage("unsupported version"));
+        }
+        let file_header_size = usize::from(u16::from_le_bytes([header[8], header[9]]));
+        let chunk_header_size = usize::from(u16::from_le_bytes([header[10], header[11]]));
+        let block_size = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        let total_blocks = u32::from_le_bytes(header[16..20].try_into().unwrap());
+        let total_chunks = u32::from_le_bytes(header[20..24].try_into().unwrap());
+        if file_header_size < FILE_HEADER_SIZE
+            || chunk_header_size < CHUNK_HEADER_SIZE
+            || block_size == 0
+            || block_size % 4 != 0
+        {
+            return Err(OdinTarError::InvalidSparseImage("malformed header"));
+        }
+        skip(&mut inner, (file_header_size - FILE_HEADER_SIZE) as u64)?;
+
+        return Ok(SparseReader {
+            inner,
+            block_size,
+            total_blocks,
+            chunks_left: total_chunks,
+            chunk_header_padding: chunk_header_size - CHUNK_HEADER_SIZE,
+            chunk: Chunk::Zero,
+            chunk_left: 0,
+            pos: 0,
+        });
+    }
+
+    /// Size of the unsparsed image.
+    pub fn len(&self) -> u64 {
+        return u64::from(self.block_size) * u64::from(self.total_blocks);
+    }
+
+    /// Whether the unsparsed image is empty.
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// Advance to the next chunk producing output.
+    ///
+    /// Returns `false` once all chunks are consumed.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        loop {
+            if self.chunks_left == 0 {
+                return Ok(false);
+            }
+            self.chunks_left -= 1;
+
+            let mut header = [0; CHUNK_HEADER_SIZE];
+            self.inner.read_exact(&mut header)?;
+            skip(&mut self.inner, self.chunk_header_padding as u64)?;
+            let chunk_type = u16::from_le_bytes([header[0], header[1]]);
+            let blocks = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            let out_len = u64::from(blocks) * u64::from(self.block_size);
+
+            match chunk_type {
+                CHUNK_TYPE_RAW => self.chunk = Chunk::Raw,
+                CHUNK_TYPE_FILL => {
+                    let mut pattern = [0; 4];
+                    self.inner.read_exact(&mut pattern)?;
+                    self.chunk = Chunk::Fill(pattern);
+                }
+                CHUNK_TYPE_DONT_CARE => self.chunk = Chunk::Zero,
+                CHUNK_TYPE_CRC32 => {
+                    // Checksums aren't verified, just skip over it
+                    skip(&mut self.inner, 4)?;
+                    continue;
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unknown sparse chunk type {chunk_type:#x}"),
+                    ))
+                }
+            }
+            if self.pos + out_len > self.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Sparse chunks exceed the image size",
+                ));
+            }
+            self.chunk_left = out_len;
+            if out_len > 0 {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.chunk_left == 0 && !self.next_chunk()? {
+            // Blocks not covered by any chunk read as zeros, like simg2img does
+            self.chunk = Chunk::Zero;
+            self.chunk_left = self.len() - self.pos;
+            if self.chunk_left == 0 {
+                return Ok(0);
+            }
+        }
+
+        let max = std::cmp::min(buf.len() as u64, self.chunk_left) as usize;
+        let buf = &mut buf[..max];
+        let n = match self.chunk {
+            Chunk::Raw => {
+                let n = self.inner.read(buf)?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                n
+            }
+            Chunk::Fill(pattern) => {
+                // Chunks are block aligned, so the pattern phase follows from the position
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = pattern[((self.pos + i as u64) % 4) as usize];
+                }
+                max
+            }
+            Chunk::Zero => {
+                buf.fill(0);
+                max
+            }
+        };
+        self.chunk_left -= n as u64;
+        self.pos += n as u64;
+        return Ok(n);
+    }
+}
+
+/// Discard `len` bytes from the given reader.
+fn skip<R: Read + ?Sized>(r: &mut R, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut r.take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    return Ok(());
+}
@@ -0,0 +1,77 @@
+//! Minimal helpers for walking raw tar headers without going through the `tar` crate.
+//! These are needed where the archive has to be inspected by offset, e.g. to find where the tar ends.
+
+use std::io::{self, Read};
+
+use crate::OdinTarError;
+
+/// Size of a tar block. Headers and data are always aligned to this.
+pub(crate) const BLOCK_SIZE: u64 = 512;
+
+const SIZE_FIELD_OFFSET: usize = 124;
+const SIZE_FIELD_LEN: usize = 12;
+
+/// Returns whether the given block consists only of zeroes, which marks the end of a tar archive.
+pub(crate) fn is_zero_block(block: &[u8]) -> bool {
+    return block.iter().all(|b| *b == 0);
+}
+
+/// Parse the entry data size out of a raw tar header.
+///
+/// Supports both the octal and the GNU base-256 encoding (used for entries larger than 8GiB).
+/// `offset` is only used for error reporting.
+pub(crate) fn entry_size(
+    header: &[u8; BLOCK_SIZE as usize],
+    offset: u64,
+) -> Result<u64, OdinTarError> {
+    let field = &header[SIZE_FIELD_OFFSET..SIZE_FIELD_OFFSET + SIZE_FIELD_LEN];
+
+    // GNU base-256 encoding, flagged by the highest bit of the first byte
+    if field[0] & 0x80 != 0 {
+        let mut size: u64 = 0;
+        for b in &field[4..] {
+            size = (size << 8) | u64::from(*b);
+        }
+        return Ok(size);
+    }
+
+    let text: String = field
+        .iter()
+        .take_while(|b| **b != 0)
+        .map(|b| *b as char)
+        .collect();
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(0);
+    }
+    return u64::from_str_radix(text, 8).map_err(|_| OdinTarError::MalformedTarHeader(offset));
+}
+
+/// Round the given data size up to the next block boundary.
+pub(crate) fn padded_size(size: u64) -> u64 {
+    return size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+}
+
+/// Read a full block, returning `Ok(None)` on a clean end of file.
+pub(crate) fn read_block(
+    r: &mut impl Read,
+) -> Result<Option<[u8; BLOCK_SIZE as usize]>, OdinTarError> {
+    let mut block = [0u8; BLOCK_SIZE as usize];
+    let mut filled: usize = 0;
+    while filled < block.len() {
+        match r.read(&mut block[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if filled == 0 {
+        return Ok(None);
+    }
+    if filled < block.len() {
+        // Trailing data that isn't a full block can only be the metadata trailer
+        block[filled..].fill(0);
+    }
+    return Ok(Some(block));
+}
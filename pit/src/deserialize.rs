@@ -104,12 +104,15 @@ fn read_u32_as_usize_and_advance(data: &[u8]) -> Result<(usize, &[u8]), PitError
     return Ok((int, data));
 }
 
+/// Read a little-endian `u32`, using all four bytes.
+/// Older versions dropped the most significant byte, truncating values of 2^24 and up,
+/// such as the block counts of large partitions.
 fn read_u32_and_advance(data: &[u8]) -> Result<(u32, &[u8]), PitError> {
     let mut int_raw: [u8; 4] = [0; 4];
     if data.len() < 4 {
         return Err(PitError::FieldTooShort(4, data.len()));
     }
-    for (i, b) in data[0..3].iter().enumerate() {
+    for (i, b) in data[0..4].iter().enumerate() {
         int_raw[i] = *b;
     }
 
@@ -1,6 +1,7 @@
 use std::{fs::File, io::Read, path::Path};
 
 use super::Pit;
+use either::Either;
 use test_case::test_case;
 
 const PIT_PATH: &str = "./testdata/";
@@ -99,3 +100,16 @@ fn deserialize(file: &str) {
 
     Pit::deserialize(&data).unwrap();
 }
+
+#[test]
+fn deserialize_full_u32() {
+    let mut data = std::fs::read(Path::new(PIT_PATH).join("A40_EUR_OPEN.pit")).unwrap();
+    // Block count of the first entry, which starts right after the 28 byte header
+    data[28 + 24..28 + 28].copy_from_slice(&[0x01, 0x02, 0x03, 0x04]);
+
+    let pit = Pit::deserialize(&data).unwrap();
+    match &pit.entries()[0] {
+        Either::Right(entry) => assert_eq!(0x04030201, entry.block_num),
+        Either::Left(_) => panic!("Expected a version 2 PIT"),
+    }
+}
@@ -1,3 +1,5 @@
+use core::fmt;
+
 /// Error type returned when PIT file (de)serialization fails.
 #[derive(Debug, Clone, PartialEq)]
 pub enum PitError {
@@ -18,3 +20,23 @@ pub enum PitError {
     /// PIT contained an unreasonable number of entries.
     TooManyEntries(usize),
 }
+
+impl fmt::Display for PitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PitError::MagicTooShort => write!(f, "PIT is too short to contain the magic bytes"),
+            PitError::InvalidPit(magic) => write!(f, "invalid PIT magic bytes {magic:02X?}"),
+            PitError::FieldTooShort(expected, actual) => write!(
+                f,
+                "PIT field needs {expected} bytes, but only {actual} are left"
+            ),
+            PitError::InvalidUTF8(data) => write!(f, "PIT string is not valid UTF-8: {data:02X?}"),
+            PitError::InvalidBinaryType(t) => write!(f, "invalid partition binary type {t}"),
+            PitError::InvalidDeviceType(t) => write!(f, "invalid partition device type {t}"),
+            PitError::NoBlockData => write!(f, "PIT contains no partition entries"),
+            PitError::TooManyEntries(n) => write!(f, "PIT claims an unreasonable {n} entries"),
+        }
+    }
+}
+
+impl std::error::Error for PitError {}
@@ -58,6 +58,14 @@ impl Pit {
         }
     }
 
+    /// Get all PIT entries, in the order they appear in the PIT.
+    pub fn entries(&self) -> Vec<Either<PitEntryV1, PitEntryV2>> {
+        match &self.0 {
+            Either::Left(s) => return s.entries.iter().cloned().map(Either::Left).collect(),
+            Either::Right(s) => return s.entries.iter().cloned().map(Either::Right).collect(),
+        }
+    }
+
     /// Look up the PIT device's gang name.
     pub fn gang_name(&self) -> String {
         match &self.0 {
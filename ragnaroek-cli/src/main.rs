@@ -8,6 +8,7 @@ use std::{
     fs::File,
     io::{stdin, Read, Write},
     path::Path,
+    sync::OnceLock,
 };
 
 use ragnaroek::{download_protocol::ActionAfter, *};
@@ -18,16 +19,13 @@ use is_terminal::IsTerminal;
 
 use pit::*;
 
-/// All the Odin .ini files I could find only ever mention this port
-const WIRELESS_PORT: u16 = 13579;
-/// All the targets implementing wireless mode seem to use this IP
-const WIRELESS_TARGET_IP: &str = "192.168.49.1";
-
 fn main() {
     env_logger::init();
 
     let args = define_cli();
+    check_device_arg(&args);
     match args.subcommand() {
+        Some(("list-devices", _)) => list_devices(),
         Some(("detect", sub_args)) => detect(sub_args),
         Some(("wait-for-device", sub_args)) => wait_for_device(sub_args),
         Some(("print-pit", sub_args)) => print_pit(sub_args),
@@ -38,24 +36,84 @@ fn main() {
         Some(("shell", sub_args)) => shell(sub_args),
         Some(("factory-reset", sub_args)) => factory_reset(sub_args),
         Some(("upload-mode", sub_args)) => upload_mode(sub_args),
+        Some(("odintar", sub_args)) => odintar(sub_args),
+        Some(("emulate", sub_args)) => emulate(sub_args),
+        Some(("dissect", sub_args)) => dissect(sub_args),
+        Some(("relay", sub_args)) => relay(sub_args),
         _ => panic!("Unexpected missing subcommand! This should've been caught by clap."),
     }
 }
 
+/// Transports offered by the download mode subcommands. The serial transport is only supported on Unix.
+#[cfg(unix)]
+const TRANSPORTS: [&str; 4] = ["net", "usb", "serial", "relay"];
+#[cfg(not(unix))]
+const TRANSPORTS: [&str; 3] = ["net", "usb", "relay"];
+
+/// Transports a relay can reach its locally attached target with.
+#[cfg(unix)]
+const RELAY_TRANSPORTS: [&str; 2] = ["usb", "serial"];
+#[cfg(not(unix))]
+const RELAY_TRANSPORTS: [&str; 1] = ["usb"];
+
 fn define_cli() -> ArgMatches {
     // Arguments common to all subcommands
     let transport = Arg::new("transport")
         .long("transport")
         .short('t')
-        .help("Choose how to communicate with the target. USB is even more experimental than everything else about ragnaroek.")
-        .value_parser(["net", "usb"])
+        .help("Choose how to communicate with the target. USB is even more experimental than everything else about ragnaroek. Relay talks to a target attached to another host running relay serve, see --target.")
+        .value_parser(TRANSPORTS)
         .default_value("net");
+    let device = Arg::new("device")
+        .long("device")
+        .help("Choose which USB device to use if several are connected, by serial number or port path (e.g. 1-4.2). Prefix it with serial: or path: if it's ambiguous. Only applies to the usb transport. See list-devices.")
+        .num_args(1);
+    let serial_port = Arg::new("serial-port")
+        .long("serial-port")
+        .help("Choose which tty to use with the serial transport.")
+        .num_args(1)
+        .default_value("/dev/ttyACM0");
+    let record = Arg::new("record")
+        .long("record")
+        .help("Record all traffic with the target to a transcript file, which --replay can serve back later.")
+        .num_args(1);
+    let capture = Arg::new("capture")
+        .long("capture")
+        .help("Capture all traffic with the target to a pcapng file for Wireshark, with Odin commands annotated.")
+        .value_name("FILE")
+        .num_args(1);
+    let replay = Arg::new("replay")
+        .long("replay")
+        .help("Replay a transcript recorded with --record instead of talking to a target. Overrides --transport.")
+        .num_args(1)
+        .conflicts_with("capture");
+    let bind = Arg::new("bind")
+        .long("bind")
+        .help("Choose which local address to listen on for network targets in download mode. Use :: for IPv6.")
+        .num_args(1)
+        .value_parser(clap::value_parser!(std::net::IpAddr))
+        .default_value("0.0.0.0");
+    let target = Arg::new("target")
+        .long("target")
+        .help("Choose which address to connect to for network targets in shell and upload mode, and for relays.")
+        .num_args(1)
+        .default_value(WIRELESS_TARGET_IP);
+    let port = Arg::new("port")
+        .long("port")
+        .help("Choose which TCP port to use for network targets and relays. Defaults to the port Odin uses, 13579, or 13580 for relays.")
+        .num_args(1)
+        .value_parser(clap::value_parser!(u16));
     let reboot = Arg::new("reboot")
         .long("reboot")
         .short('r')
         .help("Choose which mode to reboot target into at the end. Older bootloader versions don't support all of these.")
         .value_parser(["os", "odin", "shutdown", "none"])
         .default_value("none");
+    let metrics = Arg::new("metrics")
+        .long("metrics")
+        .help("Write transfer metrics, such as throughput over time, to a JSON file at the end.")
+        .value_name("FILE")
+        .num_args(1);
     let output_format = Arg::new("output-format")
         .long("output-format")
         .short('o')
@@ -66,14 +124,33 @@ fn define_cli() -> ArgMatches {
         .required(false);
 
     // Subcommands
+    let list_devices =
+        Command::new("list-devices").about("List all connected USB devices in download mode.");
+
     let detect = Command::new("detect")
         .about("Test whether a supported device is connected, returning failure if not. Use wait-for-device if this is not what you want.")
         .arg(transport.clone())
+        .arg(device.clone())
+        .arg(serial_port.clone())
+        .arg(record.clone())
+        .arg(capture.clone())
+        .arg(replay.clone())
+        .arg(bind.clone())
+        .arg(target.clone())
+        .arg(port.clone())
         .arg(reboot.clone());
 
     let print_pit = Command::new("print-pit")
         .about("Print the target's Partition Information Table (PIT).")
         .arg(transport.clone())
+        .arg(device.clone())
+        .arg(serial_port.clone())
+        .arg(record.clone())
+        .arg(capture.clone())
+        .arg(replay.clone())
+        .arg(bind.clone())
+        .arg(target.clone())
+        .arg(port.clone())
         .arg(reboot.clone())
         .arg(output_format.clone());
 
@@ -91,6 +168,14 @@ fn define_cli() -> ArgMatches {
     let save_pit = Command::new("save-pit")
         .about("Save the target's Partition Information Table (PIT).")
         .arg(transport.clone())
+        .arg(device.clone())
+        .arg(serial_port.clone())
+        .arg(record.clone())
+        .arg(capture.clone())
+        .arg(replay.clone())
+        .arg(bind.clone())
+        .arg(target.clone())
+        .arg(port.clone())
         .arg(reboot.clone())
         .arg(
             Arg::new("path")
@@ -103,7 +188,16 @@ fn define_cli() -> ArgMatches {
 
     let flash = Command::new("flash").about("Flash the given image to the given partition. Remember that flashing certain partitions incorrectly may brick your device!")
     .arg(transport.clone())
+        .arg(device.clone())
+        .arg(serial_port.clone())
+        .arg(record.clone())
+        .arg(capture.clone())
+        .arg(replay.clone())
+        .arg(bind.clone())
+        .arg(target.clone())
+        .arg(port.clone())
         .arg(reboot.clone())
+        .arg(metrics.clone())
     .arg(Arg::new("partition")
         .short('p')
         .long("partition")
@@ -127,7 +221,16 @@ fn define_cli() -> ArgMatches {
     );
     let flash_odintar = Command::new("flash-odintar").about("Flash the given multi-partition Odin archive (.tar.md5) to the device. Remember that flashing certain partitions incorrectly may brick your device!")
     .arg(transport.clone())
+        .arg(device.clone())
+        .arg(serial_port.clone())
+        .arg(record.clone())
+        .arg(capture.clone())
+        .arg(replay.clone())
+        .arg(bind.clone())
+        .arg(target.clone())
+        .arg(port.clone())
         .arg(reboot.clone())
+        .arg(metrics)
     .arg(Arg::new("filename")
         .short('f')
         .long("filename")
@@ -141,13 +244,36 @@ fn define_cli() -> ArgMatches {
         .value_parser(clap::value_parser!(bool))
         .default_value("false")
         .help("Whether to flash to the microSD card instead of the device itself.")
+    )
+    .arg(Arg::new("allow-unverified")
+        .long("allow-unverified")
+        .required(false)
+        .action(clap::ArgAction::SetTrue)
+        .help("Flash plain tar archives without Odin metadata, whose contents can't be verified.")
     );
 
     let wait_for_device = Command::new("wait-for-device")
         .about(
             "Wait until a supported device is connected. Then return with a successful exit code.",
         )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .num_args(1)
+                .value_parser(clap::value_parser!(u64))
+                .help(
+                    "Give up after this many seconds, returning failure. Waits forever by default.",
+                ),
+        )
         .arg(transport.clone())
+        .arg(device.clone())
+        .arg(serial_port.clone())
+        .arg(record.clone())
+        .arg(capture.clone())
+        .arg(replay.clone())
+        .arg(bind.clone())
+        .arg(target.clone())
+        .arg(port.clone())
         .arg(reboot);
 
     let shell = Command::new("shell")
@@ -155,11 +281,27 @@ fn define_cli() -> ArgMatches {
             "Enter an interactive shell session with the bootloader.
             See https://samsung-loki.github.io/samsung-docs/docs/Odin/Commands/ for details.",
         )
-        .arg(transport.clone());
+        .arg(transport.clone())
+        .arg(device.clone())
+        .arg(serial_port.clone())
+        .arg(record.clone())
+        .arg(capture.clone())
+        .arg(replay.clone())
+        .arg(bind.clone())
+        .arg(target.clone())
+        .arg(port.clone());
 
     let factory_reset = Command::new("factory-reset")
         .about("Performs a factory reset")
-        .arg(transport.clone());
+        .arg(transport.clone())
+        .arg(device.clone())
+        .arg(serial_port.clone())
+        .arg(record.clone())
+        .arg(capture.clone())
+        .arg(replay.clone())
+        .arg(bind.clone())
+        .arg(target.clone())
+        .arg(port.clone());
 
     // TODO: Add subcommands for displaying probe table etc.
     // TODO: Add support for specifying name of probe table memory range to dump
@@ -173,6 +315,14 @@ fn define_cli() -> ArgMatches {
         .subcommand(Command::new("dump")
             .about("Dump the given memory region to a file.")
             .arg(transport.clone())
+        .arg(device.clone())
+        .arg(serial_port.clone())
+        .arg(record.clone())
+        .arg(capture.clone())
+        .arg(replay.clone())
+        .arg(bind.clone())
+        .arg(target.clone())
+        .arg(port.clone())
             .arg(Arg::new("filename")
                 .short('f')
                 .long("filename")
@@ -185,25 +335,367 @@ fn define_cli() -> ArgMatches {
                 .long("start-address")
                 .required(true)
                 .num_args(1)
-                .help("Memory address the dump should start at (inclusive). Required.")
+                .value_parser(parse_address)
+                .help("Memory address the dump should start at (inclusive), decimal or hex with 0x prefix. Required.")
             )
             .arg(Arg::new("end-address")
                 .short('e')
                 .long("end-address")
                 .required(true)
                 .num_args(1)
-                .help("Memory address the dump should end at (inclusive). Required.")
+                .value_parser(parse_address)
+                .help("Memory address the dump should end at (inclusive), decimal or hex with 0x prefix. Required.")
             )
         )
         .subcommand(Command::new("probe")
             .about("Dump the probe table to stdout. This is a listing of memory regions and their properties.")
             .arg(transport)
+            .arg(device.clone())
+            .arg(serial_port.clone())
+            .arg(record.clone())
+            .arg(capture.clone())
+            .arg(replay.clone())
+        .arg(bind.clone())
+        .arg(target.clone())
+        .arg(port.clone())
+        );
+
+    let odintar = Command::new("odintar")
+        .about("Inspect and modify Odin archives (.tar.md5). These commands do not interact with a target in any way.")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("list")
+                .about("List the files in the given archive, without extracting them.")
+                .arg(
+                    Arg::new("filename")
+                        .short('f')
+                        .long("filename")
+                        .required(true)
+                        .num_args(1)
+                        .help("The filename of the archive to inspect. Required."),
+                ),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Check whether every file in the given archive has a partition in the given PIT, and fits into it.")
+                .arg(
+                    Arg::new("filename")
+                        .short('f')
+                        .long("filename")
+                        .required(true)
+                        .num_args(1)
+                        .help("The filename of the archive to check. Required."),
+                )
+                .arg(
+                    Arg::new("pit-path")
+                        .long("pit-path")
+                        .short('p')
+                        .required(true)
+                        .num_args(1)
+                        .help("The PIT file to check against. Required."),
+                ),
+        )
+        .subcommand(
+            Command::new("extract")
+                .about("Extract all files in the given archive to a directory, and write a manifest with their sizes and SHA-256 checksums.")
+                .arg(
+                    Arg::new("filename")
+                        .short('f')
+                        .long("filename")
+                        .required(true)
+                        .num_args(1)
+                        .help("The filename of the archive to extract. Required."),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .required(true)
+                        .num_args(1)
+                        .help("The directory to extract to. Created if it doesn't exist. Required."),
+                )
+                .arg(
+                    Arg::new("decompress")
+                        .long("decompress")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Decompress .lz4 files."),
+                )
+                .arg(
+                    Arg::new("unsparse")
+                        .long("unsparse")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Convert sparse images to raw ones. Compressed files are only unsparsed together with --decompress."),
+                ),
+        )
+        .subcommand(
+            Command::new("super")
+                .about("List the logical partitions in the dynamic partition image (super.img) of the given archive, or extract one of them.")
+                .arg(
+                    Arg::new("filename")
+                        .short('f')
+                        .long("filename")
+                        .required(true)
+                        .num_args(1)
+                        .help("The filename of the archive holding the super image. Required."),
+                )
+                .arg(
+                    Arg::new("entry")
+                        .long("entry")
+                        .num_args(1)
+                        .default_value("super.img")
+                        .help("The name of the super image in the archive, without compression extension."),
+                )
+                .arg(
+                    Arg::new("extract")
+                        .short('x')
+                        .long("extract")
+                        .num_args(1)
+                        .requires("output")
+                        .value_name("PARTITION")
+                        .help("The logical partition to extract."),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .num_args(1)
+                        .help("The filename to write the extracted partition to."),
+                ),
+        )
+        .subcommand(
+            Command::new("decrypt")
+                .about("Decrypt a firmware package downloaded from Samsung's update service (.zip.enc2, .zip.enc4). The package is a zip holding the firmware's Odin archives.")
+                .arg(
+                    Arg::new("filename")
+                        .short('f')
+                        .long("filename")
+                        .required(true)
+                        .num_args(1)
+                        .help("The filename of the encrypted package. The encryption scheme is determined by it's extension. Required."),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .required_unless_present("extract")
+                        .num_args(1)
+                        .help("The filename to write the decrypted package, a zip file, to. Required unless --extract is given."),
+                )
+                .arg(
+                    Arg::new("extract")
+                        .long("extract")
+                        .num_args(1)
+                        .help("Extract the Odin archives (.tar.md5) from the decrypted package into the given directory, which must exist."),
+                )
+                .arg(
+                    Arg::new("model")
+                        .long("model")
+                        .num_args(1)
+                        .help("The device model, e.g. SM-A405FN. Required for .enc2."),
+                )
+                .arg(
+                    Arg::new("region")
+                        .long("region")
+                        .num_args(1)
+                        .help("The region (CSC) code, e.g. EUX. Required for .enc2."),
+                )
+                .arg(
+                    Arg::new("fw-version")
+                        .long("fw-version")
+                        .required(true)
+                        .num_args(1)
+                        .help("The full firmware version, e.g. A405FNXXU4CVK1/A405FNOXM4CVK1/A405FNXXU4CVK1/A405FNXXU4CVK1. Required."),
+                )
+                .arg(
+                    Arg::new("logic-value")
+                        .long("logic-value")
+                        .num_args(1)
+                        .help("The LOGIC_VALUE_FACTORY the update service lists for the package. Required for .enc4, whose key can't be derived from the model, region and version alone."),
+                ),
+        )
+        .subcommand(
+            Command::new("edit")
+                .about("Copy the given archive while replacing, removing or adding files. Writes a new Odin checksum.")
+                .arg(
+                    Arg::new("filename")
+                        .short('f')
+                        .long("filename")
+                        .required(true)
+                        .num_args(1)
+                        .help("The filename of the archive to copy. Required."),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .required(true)
+                        .num_args(1)
+                        .help("The filename to write the new archive to. Required."),
+                )
+                .arg(
+                    Arg::new("replace")
+                        .long("replace")
+                        .action(clap::ArgAction::Append)
+                        .value_name("NAME=PATH")
+                        .help("Replace the archive file NAME with the file at PATH. Files are LZ4-compressed if NAME ends in .lz4 and they aren't already."),
+                )
+                .arg(
+                    Arg::new("remove")
+                        .long("remove")
+                        .action(clap::ArgAction::Append)
+                        .value_name("NAME")
+                        .help("Remove the archive file NAME."),
+                )
+                .arg(
+                    Arg::new("add")
+                        .long("add")
+                        .action(clap::ArgAction::Append)
+                        .value_name("PATH")
+                        .help("Add the file at PATH to the archive, named after it's basename."),
+                )
+                .arg(
+                    Arg::new("lz4")
+                        .long("lz4")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("LZ4-compress added files, appending .lz4 to their names."),
+                ),
+        );
+
+    let emulate = Command::new("emulate")
+        .about("Pretend to be a target, for testing flashers without a device. Connects to a flasher waiting for network targets.")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("download")
+                .about("Emulate a target in download mode. Flashed partitions are written into a disk image laid out by the PIT.")
+                .arg(
+                    Arg::new("pit-path")
+                        .long("pit-path")
+                        .short('p')
+                        .help("Specify which PIT file the target has.")
+                        .required(true)
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("disk")
+                        .long("disk")
+                        .short('d')
+                        .help("Disk image to write flashed partitions to. Created if it doesn't exist.")
+                        .required(true)
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("proto-version")
+                        .long("proto-version")
+                        .help("Protocol version the target speaks.")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(u32).range(1..=4))
+                        .default_value("4"),
+                )
+                .arg(
+                    Arg::new("compression")
+                        .long("compression")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Claim to support compressed transfers."),
+                )
+                .arg(
+                    Arg::new("host")
+                        .long("host")
+                        .help("Address of the flasher to connect to.")
+                        .num_args(1)
+                        .default_value("127.0.0.1"),
+                )
+                .arg(port.clone()),
+        )
+        .subcommand(
+            Command::new("upload")
+                .about("Emulate a target in upload mode, serving memory from image files. Listens for a connecting dumper.")
+                .arg(
+                    Arg::new("region")
+                        .long("region")
+                        .short('r')
+                        .action(clap::ArgAction::Append)
+                        .required(true)
+                        .value_name("NAME:ADDRESS:PATH")
+                        .value_parser(parse_memory_region)
+                        .help("Serve the file at PATH as memory region NAME, starting at ADDRESS. Addresses may be given in hex with a 0x prefix. Required."),
+                )
+                .arg(
+                    Arg::new("bits")
+                        .long("bits")
+                        .help("Address size the target reports.")
+                        .num_args(1)
+                        .value_parser(["32", "64"])
+                        .default_value("64"),
+                )
+                .arg(
+                    Arg::new("device-name")
+                        .long("device-name")
+                        .help("Device name the target reports.")
+                        .num_args(1)
+                        .default_value("EMULATED"),
+                )
+                .arg(bind.clone())
+                .arg(port.clone()),
+        );
+
+    let dissect = Command::new("dissect")
+        .about("Print an annotated transcript of a USB or network capture of a session. Supports usbmon text, pcap and pcapng files. This command does not interact with a target in any way.")
+        .arg(Arg::new("file")
+            .long("file")
+            .short('f')
+            .help("Specify which capture file to read.")
+            .value_parser(clap::value_parser!(String))
+            .required(true)
+        );
+
+    let relay_transport = Arg::new("transport")
+        .long("transport")
+        .short('t')
+        .help("Choose how to communicate with the locally attached target.")
+        .value_parser(RELAY_TRANSPORTS)
+        .default_value("usb");
+    let listen = Arg::new("listen")
+        .long("listen")
+        .help("Choose which local address to listen on for relay clients. Only this host can connect by default. The relay has no authentication, use e.g. 0.0.0.0:13580 to make the target available to everyone on the network.")
+        .num_args(1)
+        .value_parser(clap::value_parser!(std::net::SocketAddr))
+        .default_value("127.0.0.1:13580");
+    let relay = Command::new("relay")
+        .about("Make a locally attached target available to other hosts.")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("serve")
+                .about("Relay the target to ragnaroek instances using --transport relay, one at a time. Runs until killed.")
+                .arg(relay_transport.clone())
+                .arg(device.clone())
+                .arg(serial_port.clone())
+                .arg(record.clone())
+                .arg(capture.clone())
+                .arg(listen),
+        )
+        .subcommand(
+            Command::new("mitm")
+                .about("Connect the target to an Odin client waiting for network targets, logging all traffic in between. Use --capture or --record to keep it.")
+                .arg(
+                    Arg::new("odin")
+                        .long("odin")
+                        .help("Address of the Odin client to connect to.")
+                        .num_args(1)
+                        .required(true),
+                )
+                .arg(relay_transport)
+                .arg(device.clone())
+                .arg(serial_port.clone())
+                .arg(record.clone())
+                .arg(capture.clone())
+                .arg(port.clone()),
         );
 
     // Putting it all together
     return Command::new("ragnaroek")
         .arg_required_else_help(true)
         .subcommands([
+            list_devices,
             detect,
             wait_for_device,
             print_pit,
@@ -214,25 +706,166 @@ fn define_cli() -> ArgMatches {
             shell,
             upload_mode,
             factory_reset,
+            odintar,
+            emulate,
+            dissect,
+            relay,
         ])
         .get_matches();
 }
 
+/// Returns a `CancelHandle` that's cancelled when the user presses Ctrl-C.
+///
+/// The handler is installed on the first call, later calls return the same handle.
+/// Pressing Ctrl-C again exits immediately, in case the cancelled operation already finished.
+fn ctrl_c_handle() -> CancelHandle {
+    static CANCEL: OnceLock<CancelHandle> = OnceLock::new();
+    let cancel = CANCEL.get_or_init(|| {
+        let cancel = CancelHandle::new();
+        let on_interrupt = cancel.clone();
+        or_exit(ctrlc::set_handler(move || {
+            if on_interrupt.is_cancelled() {
+                std::process::exit(130);
+            }
+            on_interrupt.cancel();
+        }));
+        return cancel;
+    });
+    return cancel.clone();
+}
+
+/// Reject `--device` with transports other than USB, which would silently ignore it.
+fn check_device_arg(args: &ArgMatches) {
+    let device = args.try_get_one::<String>("device").ok().flatten();
+    let transport = args.try_get_one::<String>("transport").ok().flatten();
+    if let (Some(_), Some(transport)) = (device, transport) {
+        if transport != "usb" {
+            clap::Error::raw(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!("--device only applies to the usb transport, not {transport}\n"),
+            )
+            .exit();
+        }
+    }
+    if let Some((_, sub_args)) = args.subcommand() {
+        check_device_arg(sub_args);
+    }
+}
+
 fn get_download_communicator(args: &ArgMatches) -> Result<Box<dyn Communicator>> {
+    if let Some(replayer) = replay_communicator(args)? {
+        return Ok(replayer);
+    }
     let transport = args
         .get_one::<String>("transport")
         .expect("Transport must have been set! This is probably clap bug.");
-    match transport.as_str() {
-        "usb" => {
-            let conn = UsbConnection::establish()?;
-            return Ok(Box::new(conn));
-        }
-        "net" => {
-            let mut listener = NetBindListener::new(WIRELESS_PORT);
-            let conn = listener.accept()?;
-            return Ok(Box::new(conn));
-        }
+    let comm: Box<dyn Communicator> = match transport.as_str() {
+        "usb" => Box::new(usb_connection(args)?),
+        "net" => Box::new(net_listener(args)?.accept()?),
+        "serial" => serial_connection(args)?,
+        "relay" => Box::new(relay_connection(args)?),
         _ => panic!("Unexpected invalid transport! This should've been caught by clap."),
+    };
+    return record_communicator(args, comm, CaptureLinkType::NetBind);
+}
+
+fn usb_connection(args: &ArgMatches) -> Result<UsbConnection> {
+    let selector = args
+        .get_one::<String>("device")
+        .map(|s| UsbDeviceSelector::parse(s));
+    return Ok(UsbConnection::establish_with(selector.as_ref())?);
+}
+
+/// Returns a `Communicator` replaying a transcript, if one was requested.
+fn replay_communicator(args: &ArgMatches) -> Result<Option<Box<dyn Communicator>>> {
+    match args.get_one::<String>("replay") {
+        Some(path) => return Ok(Some(Box::new(TranscriptReplayer::load(path)?))),
+        None => return Ok(None),
+    }
+}
+
+/// Wraps the `Communicator` to capture traffic and record a transcript, if requested.
+/// `net_link` tells which side listens when the net transport is used.
+fn record_communicator(
+    args: &ArgMatches,
+    comm: Box<dyn Communicator>,
+    net_link: CaptureLinkType,
+) -> Result<Box<dyn Communicator>> {
+    let comm: Box<dyn Communicator> = match args.get_one::<String>("capture") {
+        Some(path) => {
+            let link = match args.get_one::<String>("transport").map(|t| t.as_str()) {
+                Some("net") => net_link,
+                // The serial transport is USB underneath, too
+                _ => CaptureLinkType::Usb,
+            };
+            Box::new(Capture::create(comm, path, link)?)
+        }
+        None => comm,
+    };
+    match args.get_one::<String>("record") {
+        Some(path) => return Ok(Box::new(TranscriptRecorder::create(comm, path)?)),
+        None => return Ok(comm),
+    }
+}
+
+#[cfg(unix)]
+fn serial_connection(args: &ArgMatches) -> Result<Box<dyn Communicator>> {
+    let path = args
+        .get_one::<String>("serial-port")
+        .expect("Argument with default value not set! This is probably a clap bug.");
+    return Ok(Box::new(SerialConnection::open(path)?));
+}
+
+/// Unreachable in practice, as the serial transport isn't offered on other platforms.
+#[cfg(not(unix))]
+fn serial_connection(_args: &ArgMatches) -> Result<Box<dyn Communicator>> {
+    return Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "The serial transport is only supported on Unix",
+    )
+    .into());
+}
+
+fn net_listener(args: &ArgMatches) -> Result<NetBindListener> {
+    let bind = args
+        .get_one::<std::net::IpAddr>("bind")
+        .expect("Argument with default value not set! This is probably a clap bug.");
+    let port = args.get_one::<u16>("port").unwrap_or(&WIRELESS_PORT);
+    return Ok(NetBindListener::bind((*bind, *port))?);
+}
+
+fn net_connection(args: &ArgMatches) -> Result<NetConnectConnection> {
+    let target = args
+        .get_one::<String>("target")
+        .expect("Argument with default value not set! This is probably a clap bug.");
+    let port = args.get_one::<u16>("port").unwrap_or(&WIRELESS_PORT);
+    return Ok(NetConnectConnection::connect(
+        (target.as_str(), *port),
+        &NetConnectOptions::default(),
+    )?);
+}
+
+fn list_devices() {
+    let devices = or_exit(list_usb_devices());
+    if devices.is_empty() {
+        println!("No devices found");
+        return;
+    }
+    for dev in devices {
+        let interface = match &dev.interface {
+            Some(i) => format!(
+                "interface {} (in {:#04x}, out {:#04x}, {} byte packets)",
+                i.number, i.in_endpoint, i.out_endpoint, i.in_max_packet_size
+            ),
+            None => String::from("no usable interface"),
+        };
+        println!(
+            "{:<12} PID {:04x}  serial {:<20} {:<24} {interface}",
+            dev.port_path,
+            dev.product_id,
+            dev.serial.as_deref().unwrap_or("<unreadable>"),
+            dev.product.as_deref().unwrap_or(""),
+        );
     }
 }
 
@@ -250,21 +883,81 @@ fn parse_reboot_option(args: &ArgMatches) -> ActionAfter {
 }
 
 fn detect(args: &ArgMatches) {
-    let comm: Box<dyn Communicator> = get_download_communicator(args).unwrap();
-    let sess = download_protocol::Session::begin(comm).unwrap();
+    let comm: Box<dyn Communicator> = or_exit(get_download_communicator(args));
+    detect_with(comm, args);
+}
+
+fn detect_with(comm: Box<dyn Communicator>, args: &ArgMatches) {
+    let sess = or_exit(download_protocol::Session::begin(comm));
     let reboot = parse_reboot_option(args);
-    sess.end(reboot).unwrap();
+    or_exit(sess.end(reboot));
+}
+
+fn relay_connection(args: &ArgMatches) -> Result<RelayConnection> {
+    let target = args
+        .get_one::<String>("target")
+        .expect("Argument with default value not set! This is probably a clap bug.");
+    let port = args.get_one::<u16>("port").unwrap_or(&RELAY_PORT);
+    return Ok(RelayConnection::connect(
+        (target.as_str(), *port),
+        &NetConnectOptions::default(),
+    )?);
 }
 
 fn wait_for_device(args: &ArgMatches) {
-    let _: Box<dyn Communicator>;
-    loop {
-        if get_download_communicator(args).is_ok() {
-            break;
+    let transport = args
+        .get_one::<String>("transport")
+        .expect("Transport must have been set! This is probably clap bug.");
+    let timeout = args
+        .get_one::<u64>("timeout")
+        .map(|secs| std::time::Duration::from_secs(*secs));
+    let comm: Result<Box<dyn Communicator>> = match transport.as_str() {
+        // Nothing to wait for
+        _ if args.contains_id("replay") => get_download_communicator(args),
+        // The relay waits for the target itself
+        "relay" => get_download_communicator(args),
+        "usb" => {
+            let selector = args
+                .get_one::<String>("device")
+                .map(|s| UsbDeviceSelector::parse(s));
+            wait_for_usb_device(selector.as_ref(), timeout, &ctrl_c_handle())
+                .map_err(Error::from)
+                .and_then(|_| get_download_communicator(args))
+        }
+        "net" => net_listener(args)
+            .and_then(|mut l| Ok(l.accept_timeout(timeout, &ctrl_c_handle())?))
+            .and_then(|c| record_communicator(args, Box::new(c), CaptureLinkType::NetBind)),
+        "serial" => {
+            // The tty only appears once the target has been connected
+            let path = args
+                .get_one::<String>("serial-port")
+                .expect("Argument with default value not set! This is probably a clap bug.");
+            let start = std::time::Instant::now();
+            let cancel = ctrl_c_handle();
+            while !Path::new(path).exists()
+                && timeout.is_none_or(|t| start.elapsed() < t)
+                && !cancel.is_cancelled()
+            {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            if cancel.is_cancelled() {
+                Err(Error::from(std::io::Error::new(
+                    std::io::ErrorKind::Interrupted,
+                    "Waiting for the serial port was cancelled",
+                )))
+            } else {
+                get_download_communicator(args)
+            }
+        }
+        _ => panic!("Unexpected invalid transport! This should've been caught by clap."),
+    };
+    match comm {
+        Ok(comm) => detect_with(comm, args),
+        Err(e) => {
+            eprintln!("No device found: {}", error_chain(&e));
+            std::process::exit(1);
         }
     }
-
-    detect(args);
 }
 
 fn pretty_print_pit(pit: pit::Pit) {
@@ -287,14 +980,14 @@ fn pretty_print_pit(pit: pit::Pit) {
 }
 
 fn print_pit(args: &ArgMatches) {
-    let comm: Box<dyn Communicator> = get_download_communicator(args).unwrap();
-    let mut sess = download_protocol::Session::begin(comm).unwrap();
-    let pit_data = sess.download_pit(sess.params).unwrap();
-    let pit = pit::Pit::deserialize(&pit_data).unwrap();
+    let comm: Box<dyn Communicator> = or_exit(get_download_communicator(args));
+    let mut sess = or_exit(download_protocol::Session::begin(comm));
+    let pit_data = or_exit(sess.download_pit(sess.params));
+    let pit = or_exit(pit::Pit::deserialize(&pit_data));
     pretty_print_pit(pit);
 
     let reboot = parse_reboot_option(args);
-    sess.end(reboot).unwrap();
+    or_exit(sess.end(reboot));
 }
 
 fn parse_pit(args: &ArgMatches) {
@@ -302,76 +995,89 @@ fn parse_pit(args: &ArgMatches) {
         .get_one::<String>("pit-path")
         .expect("Required argument not set! This is probably a clap bug.");
     let path = Path::new(&path);
-    let mut f = File::open(path).unwrap();
+    let mut f = or_exit(File::open(path));
 
     let mut pit_data: Vec<u8> = Vec::new();
-    f.read_to_end(&mut pit_data).unwrap();
+    or_exit(f.read_to_end(&mut pit_data));
 
-    let pit = pit::Pit::deserialize(&pit_data).unwrap();
+    let pit = or_exit(pit::Pit::deserialize(&pit_data));
 
     let output_format: &str = args
         .get_one::<String>("output-format")
         .expect("Required argument not set! This is probably a clap bug.");
     match output_format {
         "human" => pretty_print_pit(pit),
-        "json" => println!(
-            "{}",
-            serde_json::to_string(&pit).expect("Failed to serialize PIT! This is probably a bug.")
-        ),
+        "json" => println!("{}", or_exit(serde_json::to_string(&pit))),
         _ => panic!("Unexpected output format! This is probably a clap bug."),
     }
 }
 
+fn dissect(args: &ArgMatches) {
+    let path: &str = args
+        .get_one::<String>("file")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let dissected = or_exit(ragnaroek::dissect::dissect_file(path));
+    for (i, d) in dissected.iter().enumerate() {
+        println!("{i:>6} {d}");
+    }
+}
+
 fn save_pit(args: &ArgMatches) {
     let path: &str = args
         .get_one::<String>("path")
         .expect("Required argument not set! This is probably a clap bug.");
 
-    let comm: Box<dyn Communicator> = get_download_communicator(args).unwrap();
-    let mut sess = download_protocol::Session::begin(comm).unwrap();
-    let pit_data = sess.download_pit(sess.params).unwrap();
+    let comm: Box<dyn Communicator> = or_exit(get_download_communicator(args));
+    let mut sess = or_exit(download_protocol::Session::begin(comm));
+    let pit_data = or_exit(sess.download_pit(sess.params));
 
     let reboot = parse_reboot_option(args);
-    sess.end(reboot).unwrap();
+    or_exit(sess.end(reboot));
 
-    let mut f = File::create(Path::new(path)).unwrap();
-    f.write_all(&pit_data).unwrap();
+    let mut f = or_exit(File::create(Path::new(path)));
+    or_exit(f.write_all(&pit_data));
 }
 
 fn flash(args: &ArgMatches) {
-    let comm: Box<dyn Communicator> = get_download_communicator(args).unwrap();
-    let mut sess = download_protocol::Session::begin(comm).unwrap();
+    let comm: Box<dyn Communicator> = or_exit(get_download_communicator(args));
+    let mut sess = or_exit(download_protocol::Session::begin_with_metrics(comm));
+    let metrics = sess
+        .metrics()
+        .expect("Session without metrics! This is probably a bug.");
 
     let t_flash: bool = *args
         .get_one::<bool>("t-flash")
         .expect("Argument invalid! This is probably a clap bug.");
     if t_flash {
-        sess.enable_tflash().unwrap();
+        or_exit(sess.enable_tflash());
     }
 
     // Find the PIT entry matching the partition to flash
-    let pit_data = sess.download_pit(sess.params).unwrap();
-    let pit = pit::Pit::deserialize(&pit_data).unwrap();
-    let partition_name = args.get_one::<String>("partition").unwrap();
-    let pit_entry = pit
-        .get_entry_by_name(partition_name)
-        .expect("A partition by that name could not be found! Make sure it exists");
-
-    // TODO: Do this in a more efficient way than loading everything into RAM
+    let pit_data = or_exit(sess.download_pit(sess.params));
+    let pit = or_exit(pit::Pit::deserialize(&pit_data));
+    let partition_name = args
+        .get_one::<String>("partition")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let pit_entry = pit.get_entry_by_name(partition_name).unwrap_or_else(|| {
+        exit_with(&format!(
+            "No partition named {partition_name} in the target's PIT"
+        ))
+    });
+
+    // The file is streamed to the target, one file part at a time
     let path: &str = args
         .get_one::<String>("filename")
         .expect("Required argument not set! This is probably a clap bug.");
     let path = Path::new(&path);
-    let mut f = File::open(path).unwrap();
-    let mut data: Vec<u8> = Vec::new();
-    f.read_to_end(&mut data).unwrap();
+    let mut f = or_exit(File::open(path));
+    let len = or_exit(f.metadata()).len();
 
     // TODO: Make progress bar optional for reducing binary/dependency tree size
     if std::io::stdout().is_terminal() {
-        let pb = ProgressBar::new(data.len() as u64);
+        let pb = ProgressBar::new(len);
         pb.set_style(ProgressStyle::with_template("{prefix} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+        .expect("Invalid progress bar template! This is probably a bug.")
+        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| { let _ = write!(w, "{:.1}s", state.eta().as_secs_f64()); })
         .progress_chars("#>-"));
         pb.finish_with_message(format!("{partition_name}: OK"));
         pb.set_prefix(partition_name.clone());
@@ -380,107 +1086,366 @@ fn flash(args: &ArgMatches) {
             total += progress;
             pb.set_position(total);
         };
-        sess.flash(&data, pit_entry, &mut Some(&mut update_pb))
-            .unwrap();
+        or_exit(sess.flash_from_reader(&mut f, len, pit_entry, &mut Some(&mut update_pb)));
     } else {
-        sess.flash(&data, pit_entry, &mut None::<&mut fn(u64)>)
-            .unwrap();
+        or_exit(sess.flash_from_reader(&mut f, len, pit_entry, &mut None::<&mut fn(u64)>));
     }
 
     let reboot = parse_reboot_option(args);
-    sess.end(reboot).unwrap();
+    or_exit(sess.end(reboot));
+    report_metrics(args, &metrics.snapshot());
 }
 
 fn flash_odintar(args: &ArgMatches) {
-    let comm: Box<dyn Communicator> = get_download_communicator(args).unwrap();
-    let mut sess = download_protocol::Session::begin(comm).unwrap();
+    let comm: Box<dyn Communicator> = or_exit(get_download_communicator(args));
+    let mut sess = or_exit(download_protocol::Session::begin_with_metrics(comm));
+    let metrics = sess
+        .metrics()
+        .expect("Session without metrics! This is probably a bug.");
 
     let t_flash: bool = *args
         .get_one::<bool>("t-flash")
         .expect("Argument invalid! This is probably a clap bug.");
     if t_flash {
-        sess.enable_tflash().unwrap();
+        or_exit(sess.enable_tflash());
     }
 
-    // Find the PIT entry matching the partition to flash
-    let pit_data = sess.download_pit(sess.params).unwrap();
-    let pit = pit::Pit::deserialize(&pit_data).unwrap();
+    // Find the PIT entries matching the files to flash
+    let pit_data = or_exit(sess.download_pit(sess.params));
+    let pit = or_exit(pit::Pit::deserialize(&pit_data));
 
     let path: &str = args
         .get_one::<String>("filename")
         .expect("Required argument not set! This is probably a clap bug.");
     let path = Path::new(&path);
-    let mut f = File::open(path).unwrap();
+    let mut f = or_exit(File::open(path));
+    let allow_unverified: bool = args.get_flag("allow-unverified");
 
     // TODO: Progress bar
-    sess.flash_odintar(&mut f, pit, &mut None::<&mut fn(u64)>)
-        .unwrap();
+    // Nothing is sent if the archive doesn't fit the target, which is worth explaining in detail
+    let result = sess.flash_odintar(&mut f, pit, allow_unverified, &mut None::<&mut fn(u64)>);
+    if let Err(Error::PreflightFailed(report)) = result.as_ref().map_err(Error::without_context) {
+        print_preflight_report(report);
+        eprintln!("Archive doesn't fit the target's partitions, nothing was flashed.");
+        let reboot = parse_reboot_option(args);
+        or_exit(sess.end(reboot));
+        std::process::exit(1);
+    }
+    or_exit(result);
     let reboot = parse_reboot_option(args);
-    sess.end(reboot).unwrap();
+    or_exit(sess.end(reboot));
+    report_metrics(args, &metrics.snapshot());
+}
+
+/// Format an error followed by everything that caused it.
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut msg = e.to_string();
+    let mut source = e.source();
+    while let Some(s) = source {
+        msg += ": ";
+        msg += &s.to_string();
+        source = s.source();
+    }
+    return msg;
+}
+
+/// Unwrap the result, or print the error with everything that caused it and exit.
+fn or_exit<T, E: std::error::Error>(r: std::result::Result<T, E>) -> T {
+    match r {
+        Ok(v) => return v,
+        Err(e) => exit_with(&error_chain(&e)),
+    }
+}
+
+/// Print the error and exit, for errors found by the CLI itself.
+fn exit_with(msg: &str) -> ! {
+    eprintln!("Error: {msg}");
+    std::process::exit(1);
+}
+
+/// Print a summary of the transfer metrics, and save all of them if requested.
+fn report_metrics(args: &ArgMatches, m: &MetricsSnapshot) {
+    use indicatif::{HumanBytes, HumanDuration};
+
+    println!(
+        "Sent {} in {} transfers, received {} in {} transfers, in {}",
+        HumanBytes(m.sent.bytes),
+        m.sent.transfers,
+        HumanBytes(m.received.bytes),
+        m.received.transfers,
+        HumanDuration(m.elapsed)
+    );
+    println!(
+        "Throughput: {}/s overall, {}/s while sending",
+        HumanBytes(m.throughput() as u64),
+        HumanBytes(m.sent.throughput() as u64)
+    );
+    println!(
+        "Latency: {:?} mean, {:?} max per send; {:?} mean, {:?} max per receive",
+        m.sent.latency.mean(),
+        m.sent.latency.max,
+        m.received.latency.mean(),
+        m.received.latency.max
+    );
+    if m.timeouts() > 0 {
+        println!("Timeouts: {}", m.timeouts());
+    }
+
+    if let Some(path) = args.get_one::<String>("metrics") {
+        let f = or_exit(File::create(path));
+        or_exit(serde_json::to_writer_pretty(f, m));
+    }
 }
 
 // TODO: DRY
 fn get_shell_communicator(args: &ArgMatches) -> Result<Box<dyn Communicator>> {
+    if let Some(replayer) = replay_communicator(args)? {
+        return Ok(replayer);
+    }
     let transport = args
         .get_one::<String>("transport")
         .expect("Transport must have been set! This is probably clap bug.");
-    match transport.as_str() {
-        "usb" => {
-            let conn = UsbConnection::establish()?;
-            return Ok(Box::new(conn));
-        }
-        "net" => {
-            let conn = NetConnectConnection::new(WIRELESS_TARGET_IP, WIRELESS_PORT);
-            return Ok(Box::new(conn));
-        }
+    let comm: Box<dyn Communicator> = match transport.as_str() {
+        "usb" => Box::new(usb_connection(args)?),
+        "net" => Box::new(net_connection(args)?),
+        "serial" => serial_connection(args)?,
+        "relay" => Box::new(relay_connection(args)?),
         _ => panic!("Unexpected invalid transport! This should've been caught by clap."),
-    }
+    };
+    return record_communicator(args, comm, CaptureLinkType::NetConnect);
 }
 
 fn shell(args: &ArgMatches) {
     println!("Waiting for target...");
-    let mut conn: Box<dyn Communicator> = get_shell_communicator(args).unwrap();
+    let mut conn: Box<dyn Communicator> = or_exit(get_shell_communicator(args));
     println!("Target connected!");
     println!("Press Ctrl-C to quit");
     loop {
         print!(">");
-        std::io::stdout().flush().unwrap();
-
-        let cmd = stdin()
-            .lines()
-            .next()
-            .expect("Could not get next stdin line! This is a bug.")
-            .expect("Command was not valid UTF-8");
-        let resp = shell::exchange_cmd(&mut conn, &cmd).expect("Command execution failed!");
+        or_exit(std::io::stdout().flush());
+
+        // End of input quits, just like Ctrl-C
+        let Some(cmd) = stdin().lines().next() else {
+            return;
+        };
+        let cmd = or_exit(cmd);
+        let resp = or_exit(shell::exchange_cmd(&mut conn, &cmd));
         match resp {
             Some(resp) => print!("\n{resp}\n"),
             None => print!("\n<Target sent an empty reply>\n"),
         }
-        std::io::stdout().flush().unwrap();
+        or_exit(std::io::stdout().flush());
     }
 }
 
 fn factory_reset(args: &ArgMatches) {
-    let comm: Box<dyn Communicator> = get_download_communicator(args).unwrap();
-    let mut sess = download_protocol::Session::begin(comm).unwrap();
-    sess.factory_reset().unwrap();
+    let comm: Box<dyn Communicator> = or_exit(get_download_communicator(args));
+    let mut sess = or_exit(download_protocol::Session::begin(comm));
+    or_exit(sess.factory_reset());
 }
 
-fn get_upload_communicator(args: &ArgMatches) -> Result<Box<dyn Communicator>> {
+fn emulate(args: &ArgMatches) {
+    match args.subcommand() {
+        Some(("download", sub_args)) => emulate_download(sub_args),
+        Some(("upload", sub_args)) => emulate_upload(sub_args),
+        _ => panic!("Unexpected missing subcommand! This should've been caught by clap."),
+    }
+}
+
+fn emulate_download(args: &ArgMatches) {
+    use download_protocol::emulator::{Target, TargetOptions};
+
+    let pit_path = args
+        .get_one::<String>("pit-path")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let disk_path = args
+        .get_one::<String>("disk")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let options = TargetOptions {
+        proto_version: *args
+            .get_one::<u32>("proto-version")
+            .expect("Argument with default value not set! This is probably a clap bug."),
+        supports_compression: args.get_flag("compression"),
+    };
+    let disk = or_exit(
+        File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(disk_path),
+    );
+    let pit_data = or_exit(std::fs::read(pit_path));
+    let mut target = or_exit(Target::new(pit_data, disk, options));
+
+    let host = args
+        .get_one::<String>("host")
+        .expect("Argument with default value not set! This is probably a clap bug.");
+    let port = args.get_one::<u16>("port").unwrap_or(&WIRELESS_PORT);
+    let mut conn: Box<dyn Communicator> = Box::new(or_exit(NetConnectConnection::connect(
+        (host.as_str(), *port),
+        &NetConnectOptions::default(),
+    )));
+    or_exit(target.serve(&mut conn));
+
+    let log = target.log();
+    for f in &log.flashed {
+        println!("Flashed {} bytes to {}", f.size, f.partition_name);
+    }
+    for why in &log.rejected {
+        println!("Discarded a sequence: {why}");
+    }
+    if log.pit_flashed {
+        println!("PIT replaced");
+    }
+    if log.userdata_erased {
+        println!("Userdata erased");
+    }
+    if let Some(action) = log.end_action {
+        println!("Session ended with action {action}");
+    }
+}
+
+fn relay(args: &ArgMatches) {
+    match args.subcommand() {
+        Some(("serve", sub_args)) => relay_serve(sub_args),
+        Some(("mitm", sub_args)) => relay_mitm(sub_args),
+        _ => panic!("Unexpected missing subcommand! This should've been caught by clap."),
+    }
+}
+
+/// Open the locally attached target of the relay subcommands.
+fn relay_device(args: &ArgMatches) -> Result<Box<dyn Communicator>> {
     let transport = args
         .get_one::<String>("transport")
         .expect("Transport must have been set! This is probably clap bug.");
-    match transport.as_str() {
-        "usb" => {
-            let conn = UsbConnection::establish()?;
-            return Ok(Box::new(conn));
-        }
-        "net" => {
-            let conn = NetConnectConnection::new(WIRELESS_TARGET_IP, WIRELESS_PORT);
-            return Ok(Box::new(conn));
-        }
+    let comm: Box<dyn Communicator> = match transport.as_str() {
+        "usb" => Box::new(usb_connection(args)?),
+        "serial" => serial_connection(args)?,
         _ => panic!("Unexpected invalid transport! This should've been caught by clap."),
+    };
+    // The net transport isn't offered for relays
+    return record_communicator(args, comm, CaptureLinkType::Usb);
+}
+
+fn relay_serve(args: &ArgMatches) {
+    let listen = args
+        .get_one::<std::net::SocketAddr>("listen")
+        .expect("Argument with default value not set! This is probably a clap bug.");
+    let mut device = or_exit(relay_device(args));
+    let mut relay = or_exit(Relay::bind(listen));
+    println!("Relaying on {}", or_exit(relay.local_addr()));
+    loop {
+        if let Err(e) = relay.serve_client(&mut device) {
+            eprintln!("Lost relay client: {e}");
+        }
+    }
+}
+
+fn relay_mitm(args: &ArgMatches) {
+    let odin = args
+        .get_one::<String>("odin")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let port = args.get_one::<u16>("port").unwrap_or(&WIRELESS_PORT);
+    let mut device = or_exit(relay_device(args));
+    let mut client: Box<dyn Communicator> = Box::new(or_exit(NetConnectConnection::connect(
+        (odin.as_str(), *port),
+        &NetConnectOptions::default(),
+    )));
+    let (stats, e) = man_in_the_middle(&mut client, &mut device, &ctrl_c_handle());
+    println!("Stopped relaying: {e}");
+    println!(
+        "Client -> device: {} transfers, {} bytes",
+        stats.to_device, stats.to_device_bytes
+    );
+    println!(
+        "Device -> client: {} transfers, {} bytes",
+        stats.to_client, stats.to_client_bytes
+    );
+}
+
+/// Parse a `NAME:ADDRESS:PATH` memory region specification.
+fn parse_memory_region(s: &str) -> std::result::Result<(String, u64, String), String> {
+    let mut parts = s.splitn(3, ':');
+    let (name, addr, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(name), Some(addr), Some(path)) => (name, addr, path),
+        _ => return Err(String::from("Expected NAME:ADDRESS:PATH")),
+    };
+    let addr = parse_address(addr)?;
+    return Ok((name.to_owned(), addr, path.to_owned()));
+}
+
+/// Parse a memory address, decimal or hex with a `0x` prefix.
+fn parse_address(addr: &str) -> std::result::Result<u64, String> {
+    return match addr.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => addr.parse::<u64>(),
     }
+    .map_err(|e| format!("Invalid address {addr}: {e}"));
+}
+
+fn emulate_upload(args: &ArgMatches) {
+    use upload_protocol::emulator::{MemoryRegion, Target, TargetConfig};
+
+    let bitness = match args
+        .get_one::<String>("bits")
+        .expect("Argument with default value not set! This is probably a clap bug.")
+        .as_str()
+    {
+        "32" => upload_protocol::Bitness::ThirtyTwo,
+        "64" => upload_protocol::Bitness::SixtyFour,
+        _ => panic!("Unexpected invalid bitness! This should've been caught by clap."),
+    };
+    let regions: Vec<MemoryRegion> = args
+        .get_many::<(String, u64, String)>("region")
+        .expect("Required argument not set! This is probably a clap bug.")
+        .map(|(name, start_addr, path)| MemoryRegion {
+            name: name.clone(),
+            partition_type: 0,
+            start_addr: *start_addr,
+            image: Box::new(or_exit(File::open(path))),
+        })
+        .collect();
+    let config = TargetConfig {
+        device_name: args
+            .get_one::<String>("device-name")
+            .expect("Argument with default value not set! This is probably a clap bug.")
+            .clone(),
+        bitness,
+        regions,
+    };
+    let mut target = or_exit(Target::new(config));
+
+    let mut listener = or_exit(net_listener(args));
+    println!("Listening on {}", or_exit(listener.local_addr()));
+    let mut conn: Box<dyn Communicator> = Box::new(or_exit(listener.accept()));
+    or_exit(target.serve(&mut conn));
+
+    let log = target.log();
+    println!("Probe table sent {} times", log.probes);
+    for (start_addr, end_addr) in &log.transfers {
+        println!("Transferred 0x{start_addr:X}..0x{end_addr:X}");
+    }
+    if log.ended {
+        println!("Session ended");
+    }
+}
+
+fn get_upload_communicator(args: &ArgMatches) -> Result<Box<dyn Communicator>> {
+    if let Some(replayer) = replay_communicator(args)? {
+        return Ok(replayer);
+    }
+    let transport = args
+        .get_one::<String>("transport")
+        .expect("Transport must have been set! This is probably clap bug.");
+    let comm: Box<dyn Communicator> = match transport.as_str() {
+        "usb" => Box::new(usb_connection(args)?),
+        "net" => Box::new(net_connection(args)?),
+        "serial" => serial_connection(args)?,
+        "relay" => Box::new(relay_connection(args)?),
+        _ => panic!("Unexpected invalid transport! This should've been caught by clap."),
+    };
+    return record_communicator(args, comm, CaptureLinkType::NetConnect);
 }
 
 fn upload_mode(args: &ArgMatches) {
@@ -492,14 +1457,17 @@ fn upload_mode(args: &ArgMatches) {
 }
 
 fn upload_mode_dump(args: &ArgMatches) {
-    let mut conn: Box<dyn Communicator> = get_upload_communicator(args).unwrap();
-    println!("[DEBUG] Target connected!");
+    let mut conn: Box<dyn Communicator> = or_exit(get_upload_communicator(args));
 
-    upload_protocol::handshake(&mut conn).unwrap();
+    or_exit(upload_protocol::handshake(&mut conn));
 
-    let start_addr: u64 = *args.get_one::<u64>("start-address").unwrap();
-    let end_addr: u64 = *args.get_one::<u64>("end-address").unwrap();
-    let data = upload_protocol::dump(&mut conn, start_addr, end_addr).unwrap();
+    let start_addr: u64 = *args
+        .get_one::<u64>("start-address")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let end_addr: u64 = *args
+        .get_one::<u64>("end-address")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let data = or_exit(upload_protocol::dump(&mut conn, start_addr, end_addr));
 
     // Write to file
     // TODO: OS strings may be more appropriate here
@@ -507,19 +1475,273 @@ fn upload_mode_dump(args: &ArgMatches) {
         .get_one::<String>("filename")
         .expect("Required argument not set! This is probably a clap bug.");
     let path = Path::new(&path);
-    let mut f = File::create(path).unwrap();
-    f.write_all(&data).unwrap();
+    let mut f = or_exit(File::create(path));
+    or_exit(f.write_all(&data));
 
-    upload_protocol::end_session(&mut conn).unwrap();
+    or_exit(upload_protocol::end_session(&mut conn));
 }
 
 fn upload_mode_probe(args: &ArgMatches) {
-    let mut conn: Box<dyn Communicator> = get_upload_communicator(args).unwrap();
+    let mut conn: Box<dyn Communicator> = or_exit(get_upload_communicator(args));
 
-    upload_protocol::handshake(&mut conn).unwrap();
+    or_exit(upload_protocol::handshake(&mut conn));
 
-    let table = upload_protocol::probe(&mut conn).unwrap();
+    let table = or_exit(upload_protocol::probe(&mut conn));
     println!("{table:?}");
 
-    upload_protocol::end_session(&mut conn).unwrap();
+    or_exit(upload_protocol::end_session(&mut conn));
+}
+
+fn print_preflight_report(report: &download_protocol::PreflightReport) {
+    for m in &report.matches {
+        let capacity = match m.capacity {
+            Some(capacity) => format!("{capacity} bytes"),
+            None => String::from("unknown size"),
+        };
+        let status = if m.overfills() { "TOO LARGE" } else { "OK" };
+        println!(
+            "{:<32} -> {:<16} {:>12} bytes / {:<20} {status}",
+            m.entry.name,
+            m.partition_name(),
+            m.image_size,
+            capacity
+        );
+    }
+    for entry in &report.unmatched {
+        println!("{:<32} -> no matching partition", entry.name);
+    }
+}
+
+fn odintar(args: &ArgMatches) {
+    match args.subcommand() {
+        Some(("list", sub_args)) => odintar_list(sub_args),
+        Some(("edit", sub_args)) => odintar_edit(sub_args),
+        Some(("check", sub_args)) => odintar_check(sub_args),
+        Some(("decrypt", sub_args)) => odintar_decrypt(sub_args),
+        Some(("super", sub_args)) => odintar_super(sub_args),
+        Some(("extract", sub_args)) => odintar_extract(sub_args),
+        _ => panic!("Unexpected missing subcommand! This should've been caught by clap."),
+    }
+}
+
+fn odintar_list(args: &ArgMatches) {
+    let path: &str = args
+        .get_one::<String>("filename")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let f = or_exit(File::open(Path::new(path)));
+    let mut archive = odintar::OdinTar::from_reader(f);
+
+    match archive.metadata() {
+        Ok(metadata) => {
+            println!("Original file name: {}", metadata.orig_file_name);
+            println!("Build ID: {}", metadata.build_id);
+            println!("MD5: {}", metadata.md5);
+        }
+        Err(odintar::OdinTarError::Unverified) => {
+            println!("No Odin metadata, contents can't be verified");
+        }
+        Err(e) => exit_with(&format!(
+            "Failed to read archive metadata: {}",
+            error_chain(&e)
+        )),
+    }
+
+    let index = or_exit(archive.index());
+    println!("Entries:");
+    for entry in index.entries() {
+        println!(
+            "{:<32} {:>12} bytes  {:?}",
+            entry.name, entry.size, entry.compression
+        );
+    }
+}
+
+fn odintar_edit(args: &ArgMatches) {
+    let path: &str = args
+        .get_one::<String>("filename")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let output: &str = args
+        .get_one::<String>("output")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let compress_added: bool = args.get_flag("lz4");
+
+    let mut edit = odintar::ArchiveEdit::new();
+    for replacement in args.get_many::<String>("replace").unwrap_or_default() {
+        let (name, replacement_path) = replacement
+            .split_once('=')
+            .unwrap_or_else(|| exit_with("Replacements must be given as NAME=PATH"));
+        let mut f = or_exit(File::open(Path::new(replacement_path)));
+        // Don't compress twice if the replacement already is compressed
+        let mut magic: Vec<u8> = Vec::new();
+        or_exit((&mut f).take(4).read_to_end(&mut magic));
+        let compress = name.ends_with(".lz4") && !odintar::is_lz4_frame(&magic);
+        edit.replace(name, odintar::NewContents::new(f, compress));
+    }
+    for name in args.get_many::<String>("remove").unwrap_or_default() {
+        edit.remove(name);
+    }
+    for added_path in args.get_many::<String>("add").unwrap_or_default() {
+        let added_path = Path::new(added_path);
+        let mut name: String = added_path
+            .file_name()
+            .unwrap_or_else(|| exit_with("Added paths must point to a file"))
+            .to_string_lossy()
+            .into_owned();
+        if compress_added {
+            name.push_str(".lz4");
+        }
+        let f = or_exit(File::open(added_path));
+        edit.add(&name, odintar::NewContents::new(f, compress_added));
+    }
+
+    let f = or_exit(File::open(Path::new(path)));
+    let mut archive = odintar::OdinTar::from_reader(f);
+    let mut out = std::io::BufWriter::new(or_exit(File::create(Path::new(output))));
+    let metadata = or_exit(archive.rewrite(edit, &mut out));
+    println!("Wrote {output}, MD5: {}", metadata.md5);
+}
+
+fn odintar_check(args: &ArgMatches) {
+    let path: &str = args
+        .get_one::<String>("filename")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let pit_path: &str = args
+        .get_one::<String>("pit-path")
+        .expect("Required argument not set! This is probably a clap bug.");
+
+    let pit_data = or_exit(std::fs::read(Path::new(pit_path)));
+    let pit = or_exit(pit::Pit::deserialize(&pit_data));
+    let f = or_exit(File::open(Path::new(path)));
+    let report = or_exit(download_protocol::preflight(
+        &mut odintar::OdinTar::from_reader(f),
+        &pit,
+    ));
+
+    print_preflight_report(&report);
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+}
+
+fn odintar_decrypt(args: &ArgMatches) {
+    let path: &str = args
+        .get_one::<String>("filename")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let version: &str = args
+        .get_one::<String>("fw-version")
+        .expect("Required argument not set! This is probably a clap bug.");
+
+    let key = match odintar::EncVersion::from_file_name(path) {
+        Some(odintar::EncVersion::Enc2) => {
+            let model = args
+                .get_one::<String>("model")
+                .unwrap_or_else(|| exit_with("--model is required to decrypt .enc2 packages"));
+            let region = args
+                .get_one::<String>("region")
+                .unwrap_or_else(|| exit_with("--region is required to decrypt .enc2 packages"));
+            odintar::enc2_key(model, region, version)
+        }
+        Some(odintar::EncVersion::Enc4) => {
+            let logic_value = args.get_one::<String>("logic-value").unwrap_or_else(|| {
+                exit_with("--logic-value is required to decrypt .enc4 packages, use the LOGIC_VALUE_FACTORY the update service listed for it")
+            });
+            or_exit(odintar::enc4_key(version, logic_value))
+        }
+        None => exit_with("Unknown encryption scheme, expected a .enc2 or .enc4 file"),
+    };
+
+    let f = std::io::BufReader::new(or_exit(File::open(Path::new(path))));
+    let mut decryptor = or_exit(odintar::Decryptor::new(f, key));
+    if let Some(output) = args.get_one::<String>("output") {
+        let mut out = or_exit(File::create(Path::new(output)));
+        or_exit(std::io::copy(&mut decryptor, &mut out));
+        println!("Wrote {output}, {} bytes", decryptor.len());
+    }
+    if let Some(dir) = args.get_one::<String>("extract") {
+        let mut package = or_exit(odintar::FirmwarePackage::new(decryptor));
+        for path in or_exit(package.extract_archives(Path::new(dir))) {
+            println!("Extracted {}", path.display());
+        }
+    }
+}
+
+fn odintar_super(args: &ArgMatches) {
+    let path: &str = args
+        .get_one::<String>("filename")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let entry_name: &str = args
+        .get_one::<String>("entry")
+        .expect("Argument with default value not set! This is probably a clap bug.");
+
+    let f = std::io::BufReader::new(or_exit(File::open(Path::new(path))));
+    let mut archive = odintar::OdinTar::from_reader(f);
+    let index = or_exit(archive.index());
+    let entry = index
+        .entries()
+        .iter()
+        .find(|e| e.uncompressed_name() == entry_name)
+        .unwrap_or_else(|| exit_with(&format!("No {entry_name} found in the archive")))
+        .clone();
+    let mut image = or_exit(archive.image_reader(&entry));
+    let metadata = or_exit(odintar::LpMetadata::read(&mut image));
+
+    if let Some(partition) = args.get_one::<String>("extract") {
+        let output: &str = args
+            .get_one::<String>("output")
+            .expect("Required argument not set! This is probably a clap bug.");
+        let mut out = or_exit(File::create(Path::new(output)));
+        let size = or_exit(metadata.extract_partition(partition, &mut image, &mut out));
+        println!("Wrote {output}, {size} bytes");
+        return;
+    }
+
+    println!(
+        "LP metadata version {}.{}",
+        metadata.version.0, metadata.version.1
+    );
+    println!("Groups:");
+    for group in &metadata.groups {
+        println!("{:<32} max {:>12} bytes", group.name, group.maximum_size);
+    }
+    println!("Partitions:");
+    for partition in &metadata.partitions {
+        println!(
+            "{:<32} {:>12} bytes  group {}  {} extent(s)",
+            partition.name,
+            partition.size(),
+            partition.group_name,
+            partition.extents.len()
+        );
+    }
+}
+
+fn odintar_extract(args: &ArgMatches) {
+    let path: &str = args
+        .get_one::<String>("filename")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let output: &str = args
+        .get_one::<String>("output")
+        .expect("Required argument not set! This is probably a clap bug.");
+    let options = odintar::ExtractOptions {
+        decompress: args.get_flag("decompress"),
+        unsparse: args.get_flag("unsparse"),
+    };
+
+    let output = Path::new(output);
+    or_exit(std::fs::create_dir_all(output));
+    let f = std::io::BufReader::new(or_exit(File::open(Path::new(path))));
+    let mut archive = odintar::OdinTar::from_reader(f);
+    let manifest = or_exit(archive.extract(output, options));
+
+    match &manifest.metadata {
+        Some(metadata) => println!("Build ID: {}", metadata.build_id),
+        None => println!("No Odin metadata, contents can't be verified"),
+    }
+    for file in &manifest.files {
+        println!("{:<32} {:>12} bytes", file.file_name, file.size);
+    }
+    println!(
+        "Wrote manifest to {}",
+        output.join(odintar::MANIFEST_FILE_NAME).display()
+    );
 }
@@ -1,6 +1,7 @@
 //! Implements a shared asynchronous abstraction over a device connection,
 //! which can be used by all tabs without blocking or callback hell.
 
+use std::net::SocketAddr;
 use std::sync::{mpsc, Arc, Mutex, MutexGuard};
 
 use bus::Bus;
@@ -9,12 +10,7 @@ use lazy_static::lazy_static;
 use log;
 use pit::Pit;
 use ragnaroek::download_protocol::DownloadProtocolError;
-use ragnaroek::Result;
-
-/// All the Odin .ini files I could find only ever mention this port
-const WIRELESS_PORT: u16 = 13579;
-/// All the targets implementing wireless mode seem to use this IP
-const WIRELESS_TARGET_IP: &str = "192.168.49.1";
+use ragnaroek::{Communicator, Result};
 
 lazy_static! {
     // Connection singleton
@@ -27,8 +23,10 @@ lazy_static! {
 pub enum CommsMode {
     #[default]
     Usb,
-    NetBind,
-    NetConnect,
+    /// Wait for the target to connect to the given local address.
+    NetBind(SocketAddr),
+    /// Connect to the target at the given address.
+    NetConnect(SocketAddr),
 }
 
 /// A request to the connection thread.
@@ -49,6 +47,8 @@ pub enum ConnectionRequest {
 pub enum ConnectionEvent {
     /// A connection was established.
     Connected,
+    /// Connecting to the device failed.
+    ConnectFailed(ragnaroek::Error),
     /// A connection was lost.
     Disconnected,
     /// A PIT file was received.
@@ -83,30 +83,41 @@ impl SharedConnection {
     }
 }
 
+/// Connect to the device the given way.
+fn connect(m: CommsMode) -> Result<Box<dyn Communicator>> {
+    use CommsMode::*;
+    let c: Box<dyn Communicator> = match m {
+        Usb => Box::new(ragnaroek::UsbConnection::establish()?),
+        NetBind(addr) => Box::new(ragnaroek::NetBindListener::bind(addr)?.accept()?),
+        NetConnect(addr) => Box::new(ragnaroek::NetConnectConnection::connect(
+            addr,
+            &ragnaroek::NetConnectOptions::default(),
+        )?),
+    };
+    return Ok(c);
+}
+
 fn process_cmd(cmd: ConnectionRequest) -> bool {
     println!("Received command: {:?}", cmd);
     let mut sc = SHARED_CONNECTION.lock().unwrap();
     match cmd {
         ConnectionRequest::Connect(m) => {
             log::info!(target: "GUI", "Connecting to device in mode {:?}...", m);
-            use CommsMode::*;
-            sc.comm = match m {
-                Usb => {
-                    let c = ragnaroek::UsbConnection::establish().unwrap();
-                    Some(Box::new(c))
+            // Waiting for the device can take a while, don't hold the lock meanwhile
+            drop(sc);
+            let comm = connect(m);
+            let mut sc = SHARED_CONNECTION.lock().unwrap();
+            match comm {
+                Ok(comm) => {
+                    sc.comm = Some(comm);
+                    log::info!(target: "GUI", "Connected!");
+                    sc.event_bus.broadcast(ConnectionEvent::Connected);
                 }
-                NetBind => {
-                    let mut l = ragnaroek::NetBindListener::new(WIRELESS_PORT);
-                    let c = l.accept().unwrap();
-                    Some(Box::new(c))
+                Err(e) => {
+                    log::error!(target: "GUI", "Failed to connect: {e}");
+                    sc.event_bus.broadcast(ConnectionEvent::ConnectFailed(e));
                 }
-                NetConnect => {
-                    let c = ragnaroek::NetConnectConnection::new(WIRELESS_TARGET_IP, WIRELESS_PORT);
-                    Some(Box::new(c))
-                }
-            };
-            log::info!(target: "GUI", "Connected!");
-            sc.event_bus.broadcast(ConnectionEvent::Connected);
+            }
         }
         ConnectionRequest::BeginDLSession => {
             log::info!(target: "GUI", "Beginning download mode session...");
@@ -1,30 +1,86 @@
 use eframe::egui;
 
+use std::net::SocketAddr;
+
 use crate::shared_conn::*;
 
-pub struct ConnectTab {}
+use ragnaroek::{WIRELESS_PORT, WIRELESS_TARGET_IP};
+
+pub struct ConnectTab {
+    /// Local address to wait for the target on, as typed by the user
+    bind_addr: String,
+    /// Address of the target to connect to, as typed by the user
+    target_addr: String,
+    /// Why the last connection attempt failed, if it did
+    error: Option<String>,
+    /// Bus for receiving connection events
+    conn_bus: BusReader<ConnectionEvent>,
+}
 
 impl ConnectTab {
     pub fn new() -> ConnectTab {
-        return ConnectTab {};
+        return ConnectTab {
+            bind_addr: format!("0.0.0.0:{WIRELESS_PORT}"),
+            target_addr: format!("{WIRELESS_TARGET_IP}:{WIRELESS_PORT}"),
+            error: None,
+            conn_bus: get_event_bus(),
+        };
+    }
+
+    /// Ask the connection thread to connect to the given address, unless it doesn't parse.
+    fn connect_to(&mut self, addr: &str, mode: fn(SocketAddr) -> CommsMode) {
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) => {
+                self.error = None;
+                send_cmd(ConnectionRequest::Connect(mode(addr)));
+            }
+            Err(e) => self.error = Some(format!("Invalid address {addr:?}: {e}")),
+        }
     }
 
     /// Run it's logic and draw the connection tab's UI.
     pub fn ui(&mut self, ui: &mut egui::Ui) {
+        // Addresses for the network transports
+        ui.horizontal(|ui| {
+            ui.label("Listen on:");
+            ui.text_edit_singleline(&mut self.bind_addr);
+            ui.label("Target address:");
+            ui.text_edit_singleline(&mut self.target_addr);
+        });
+
         // Button bar for connecting to device
         ui.horizontal(|ui| {
             if ui.button("Connect (USB)").clicked() {
+                self.error = None;
                 send_cmd(ConnectionRequest::Connect(CommsMode::Usb));
             }
             if ui.button("Connect (Net Bind)").clicked() {
-                send_cmd(ConnectionRequest::Connect(CommsMode::NetBind));
+                let addr = self.bind_addr.clone();
+                self.connect_to(&addr, CommsMode::NetBind);
             }
             if ui.button("Connect (Net Connect)").clicked() {
-                send_cmd(ConnectionRequest::Connect(CommsMode::NetConnect));
+                let addr = self.target_addr.clone();
+                self.connect_to(&addr, CommsMode::NetConnect);
             }
             if ui.button("Disconnect").clicked() {
                 send_cmd(ConnectionRequest::Disconnect);
             }
         });
+
+        // Has a connection attempt finished?
+        while let Ok(event) = self.conn_bus.try_recv() {
+            match event {
+                ConnectionEvent::Connected => self.error = None,
+                ConnectionEvent::ConnectFailed(e) => self.error = Some(e.to_string()),
+                _ => {}
+            }
+        }
+        if let Some(error) = &self.error {
+            ui.add_space(20.0);
+            ui.horizontal(|ui| {
+                ui.label("Failed to connect: ");
+                ui.monospace(error.as_str());
+            });
+        }
     }
 }
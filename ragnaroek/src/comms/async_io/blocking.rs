@@ -0,0 +1,77 @@
+use super::pool::spawn_blocking;
+use super::*;
+use crate::Communicator;
+
+use std::sync::{Arc, Mutex};
+
+/// Adapts any blocking `Communicator` by running its calls on the shared worker pool.
+///
+/// Calls of a cancelled future still run to completion in the background, following calls wait for them.
+pub struct Blocking {
+    inner: Arc<Mutex<Box<dyn Communicator>>>,
+    /// Timeout to apply before the next call
+    timeout: Option<Duration>,
+}
+
+impl Blocking {
+    /// Wrap the given `Communicator`.
+    pub fn new(inner: Box<dyn Communicator>) -> Blocking {
+        return Blocking {
+            inner: Arc::new(Mutex::new(inner)),
+            timeout: None,
+        };
+    }
+
+    /// Run the given call on the pool, with exclusive access to the `Communicator`.
+    fn run<T, F>(&mut self, f: F) -> BoxFuture<'static, IOResult<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Box<dyn Communicator>) -> IOResult<T> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        let timeout = self.timeout.take();
+        return Box::pin(spawn_blocking(move || {
+            let mut c = inner.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(timeout) = timeout {
+                c.set_timeout(timeout);
+            }
+            return f(&mut c);
+        }));
+    }
+}
+
+impl AsyncCommunicator for Blocking {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, IOResult<()>> {
+        let data = data.to_vec();
+        return self.run(move |c| c.send(&data));
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> BoxFuture<'_, IOResult<Vec<u8>>> {
+        return self.run(move |c| c.recv_exact(how_much));
+    }
+
+    fn recv(&mut self) -> BoxFuture<'_, IOResult<Vec<u8>>> {
+        return self.run(|c| c.recv());
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    fn send_before<'a>(
+        &'a mut self,
+        data: &'a [u8],
+        deadline: Instant,
+    ) -> BoxFuture<'a, IOResult<()>> {
+        let data = data.to_vec();
+        return self.run(move |c| c.send_before(&data, deadline));
+    }
+
+    fn recv_exact_before(
+        &mut self,
+        how_much: usize,
+        deadline: Instant,
+    ) -> BoxFuture<'_, IOResult<Vec<u8>>> {
+        return self.run(move |c| c.recv_exact_before(how_much, deadline));
+    }
+}
@@ -0,0 +1,186 @@
+//! Asynchronous counterpart to `Communicator`, usable with any async runtime.
+//!
+//! Network connections are driven by a single shared reactor thread, so any number of them can be
+//! served from one task or thread. USB transfers run on a shared pool of blocking worker threads,
+//! which only exist while transfers are in flight.
+//!
+//! Operations are cancelled by dropping their future. The protocol state is undefined afterwards,
+//! so the only sensible thing to do with a `Session` whose operation was cancelled is to drop it.
+
+mod blocking;
+pub mod net;
+mod pool;
+mod reactor;
+pub mod usb;
+
+pub use blocking::Blocking;
+
+use std::future::Future;
+use std::io::Result as IOResult;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+/// A boxed future, as returned by `AsyncCommunicator`'s methods.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Asynchronous variant of `Communicator`, see there for the semantics of each method.
+///
+/// Methods return boxed futures, so that the trait can be used as `dyn AsyncCommunicator`.
+pub trait AsyncCommunicator: Send {
+    /// Send the entire buffer to the device.
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, IOResult<()>>;
+    /// Receive exactly the specified amount of data from the device.
+    fn recv_exact(&mut self, how_much: usize) -> BoxFuture<'_, IOResult<Vec<u8>>>;
+    /// Receive however much data is waiting to be read. Returned data may be empty.
+    fn recv(&mut self) -> BoxFuture<'_, IOResult<Vec<u8>>>;
+    /// Set a timeout for each following operation. Default is 30 seconds.
+    fn set_timeout(&mut self, timeout: Duration);
+
+    /// Like `send`, but fails with `ErrorKind::TimedOut` unless all data was sent before `deadline`.
+    ///
+    /// The default implementation applies the time left with `set_timeout`, which stays in effect afterwards.
+    /// All transports in this crate override it, leaving the timeout set by the caller alone.
+    fn send_before<'a>(
+        &'a mut self,
+        data: &'a [u8],
+        deadline: Instant,
+    ) -> BoxFuture<'a, IOResult<()>> {
+        match super::time_left(deadline) {
+            Ok(left) => self.set_timeout(left),
+            Err(e) => return Box::pin(async move { Err(e) }),
+        }
+        return self.send(data);
+    }
+
+    /// Like `recv_exact`, but fails with `ErrorKind::TimedOut` unless all data arrived before `deadline`.
+    ///
+    /// The default implementation applies the time left with `set_timeout`, which stays in effect afterwards.
+    /// All transports in this crate override it, leaving the timeout set by the caller alone.
+    fn recv_exact_before(
+        &mut self,
+        how_much: usize,
+        deadline: Instant,
+    ) -> BoxFuture<'_, IOResult<Vec<u8>>> {
+        match super::time_left(deadline) {
+            Ok(left) => self.set_timeout(left),
+            Err(e) => return Box::pin(async move { Err(e) }),
+        }
+        return self.recv_exact(how_much);
+    }
+}
+
+/// Minimal executor for tests, running the given futures concurrently on the current thread.
+#[cfg(test)]
+pub(crate) fn block_on_all<T>(mut futures: Vec<BoxFuture<'_, T>>) -> Vec<T> {
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::Thread;
+
+    struct ThreadWaker(Thread);
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut results: Vec<Option<T>> = futures.iter().map(|_| None).collect();
+    while results.iter().any(|r| r.is_none()) {
+        for (f, r) in futures.iter_mut().zip(results.iter_mut()) {
+            if r.is_none() {
+                if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+                    *r = Some(v);
+                }
+            }
+        }
+        if results.iter().any(|r| r.is_none()) {
+            std::thread::park();
+        }
+    }
+    return results.into_iter().map(|r| r.unwrap()).collect();
+}
+
+/// Run the given future to completion on the current thread.
+#[cfg(test)]
+pub(crate) fn block_on<T>(future: BoxFuture<'_, T>) -> T {
+    return block_on_all(vec![future]).pop().unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::pipe::Connection as Pipe;
+    use crate::Communicator;
+    use std::io::ErrorKind;
+    use std::task::{Context, Waker};
+    use std::time::Instant;
+
+    #[test]
+    fn test_net_timeout_and_cancel() {
+        let mut listener = net::Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // The connection is established by the OS even before it's accepted
+        let mut client =
+            block_on(Box::pin(net::Connection::connect(addr, Default::default()))).unwrap();
+        let mut server = block_on(Box::pin(listener.accept())).unwrap();
+
+        // Nothing was sent yet
+        assert!(block_on(server.recv()).unwrap().is_empty());
+        server.set_timeout(Duration::from_millis(50));
+        let start = Instant::now();
+        let err = block_on(server.recv_exact(4)).err().unwrap();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // A dropped receive doesn't disturb the next one
+        {
+            let mut pending = server.recv_exact(4);
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(pending.as_mut().poll(&mut cx).is_pending());
+        }
+        server.set_timeout(Duration::from_secs(5));
+        // A deadline overrides the timeout for its call only
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let err = block_on(server.recv_exact_before(4, deadline))
+            .err()
+            .unwrap();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        block_on(client.send(b"ODIN")).unwrap();
+        assert_eq!(b"ODIN".to_vec(), block_on(server.recv_exact(4)).unwrap());
+
+        // Large transfers need several rounds through the reactor
+        let data: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let len = data.len();
+        let received = block_on_all(vec![
+            Box::pin(async {
+                client.send(&data).await.unwrap();
+                return Vec::new();
+            }),
+            Box::pin(async { return server.recv_exact(len).await.unwrap() }),
+        ]);
+        assert_eq!(data, received[1]);
+    }
+
+    #[test]
+    fn test_blocking() {
+        let (host, mut target) = Pipe::pair();
+        let mut c = Blocking::new(Box::new(host));
+        block_on(c.send(b"ODIN")).unwrap();
+        assert_eq!(b"ODIN".to_vec(), target.recv_exact(4).unwrap());
+        target.send(b"LOKE").unwrap();
+        assert_eq!(b"LOKE".to_vec(), block_on(c.recv_exact(4)).unwrap());
+
+        c.set_timeout(Duration::from_millis(50));
+        let err = block_on(c.recv_exact(1)).err().unwrap();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+
+        // A deadline doesn't outlive its call
+        c.set_timeout(Duration::from_secs(5));
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let err = block_on(c.recv_exact_before(1, deadline)).err().unwrap();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        target.send(b"L").unwrap();
+        assert_eq!(b"L".to_vec(), block_on(c.recv_exact(1)).unwrap());
+    }
+}
@@ -0,0 +1,225 @@
+use super::reactor::{self, Interest, Registered};
+use super::*;
+use crate::comms::net_connect::{ConnectOptions, Connection as BlockingConnection};
+use crate::comms::{format_data_buf, DEFAULT_TIMEOUT};
+
+use std::future::poll_fn;
+use std::io::{Error as IOError, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Upper bound for the amount of data `recv` returns at once
+const RECV_BUF_SIZE: usize = 64 * 1024;
+
+/// Try a non-blocking socket operation, registering with the reactor if it would block.
+fn poll_io<T>(
+    registered: &mut Registered,
+    source: impl FnOnce() -> IOResult<reactor::Source>,
+    interest: Interest,
+    deadline: Option<Instant>,
+    cx: &mut Context<'_>,
+    mut op: impl FnMut() -> IOResult<T>,
+) -> Poll<IOResult<T>> {
+    loop {
+        match op() {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if deadline.is_some_and(|d| Instant::now() >= d) {
+                    return Poll::Ready(Err(IOError::new(
+                        ErrorKind::TimedOut,
+                        "Network operation timed out",
+                    )));
+                }
+                registered.register(source()?, interest, deadline, cx.waker());
+                return Poll::Pending;
+            }
+            result => return Poll::Ready(result),
+        }
+    }
+}
+
+/// Asynchronous counterpart to `NetBindListener`, accepting wireless ODIN mode connections.
+pub struct Listener {
+    l: TcpListener,
+    /// Timeout applied to accepted connections.
+    timeout: Duration,
+}
+
+impl Listener {
+    /// Create a new listener listening on the given port, on all IPv4 interfaces.
+    pub fn new(port: u16) -> IOResult<Listener> {
+        return Listener::bind((Ipv4Addr::UNSPECIFIED, port));
+    }
+
+    /// Create a new listener listening on the given address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> IOResult<Listener> {
+        let l = TcpListener::bind(addr)?;
+        l.set_nonblocking(true)?;
+        log::debug!(target: "NET", "Listening on {}", l.local_addr()?);
+        return Ok(Listener {
+            l,
+            timeout: DEFAULT_TIMEOUT,
+        });
+    }
+
+    /// The address the listener is bound to. Useful to find out the port when binding to port 0.
+    pub fn local_addr(&self) -> IOResult<SocketAddr> {
+        return self.l.local_addr();
+    }
+
+    /// Set the timeout of connections accepted from now on. Default is 30 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Wait until a device is connected.
+    ///
+    /// There's no timeout, drop the future to stop waiting.
+    pub async fn accept(&mut self) -> IOResult<Connection> {
+        let l = &self.l;
+        let mut registered = Registered::default();
+        let (stream, peer) = poll_fn(|cx| {
+            poll_io(
+                &mut registered,
+                || reactor::source(l),
+                Interest::Read,
+                None,
+                cx,
+                || l.accept(),
+            )
+        })
+        .await?;
+        log::debug!(target: "NET", "Accepted {peer}");
+        return Connection::from_stream(stream, self.timeout);
+    }
+}
+
+/// Asynchronous wireless ODIN mode connection.
+pub struct Connection {
+    s: TcpStream,
+    timeout: Duration,
+}
+
+impl Connection {
+    /// Establish a new connection to the target at the given address, see `NetConnectConnection::connect`.
+    ///
+    /// Connecting happens on the shared worker pool, as the standard library can only connect blocking.
+    pub async fn connect<A>(addr: A, options: ConnectOptions) -> IOResult<Connection>
+    where
+        A: ToSocketAddrs + Send + 'static,
+    {
+        let timeout = options.timeout;
+        let c = pool::spawn_blocking(move || BlockingConnection::connect(addr, &options)).await?;
+        return Connection::from_stream(c.into_stream(), timeout);
+    }
+
+    fn from_stream(s: TcpStream, timeout: Duration) -> IOResult<Connection> {
+        s.set_nonblocking(true)?;
+        return Ok(Connection { s, timeout });
+    }
+
+    fn deadline(&self) -> Instant {
+        return Instant::now() + self.timeout;
+    }
+}
+
+impl AsyncCommunicator for Connection {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, IOResult<()>> {
+        let deadline = self.deadline();
+        return self.send_before(data, deadline);
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> BoxFuture<'_, IOResult<Vec<u8>>> {
+        let deadline = self.deadline();
+        return self.recv_exact_before(how_much, deadline);
+    }
+
+    fn send_before<'a>(
+        &'a mut self,
+        data: &'a [u8],
+        deadline: Instant,
+    ) -> BoxFuture<'a, IOResult<()>> {
+        return Box::pin(async move {
+            log::trace!(target: "NET", "Send: {}", format_data_buf(data));
+            let mut sent: usize = 0;
+            let mut registered = Registered::default();
+            while sent < data.len() {
+                let s = &self.s;
+                let n = poll_fn(|cx| {
+                    return poll_io(
+                        &mut registered,
+                        || reactor::source(s),
+                        Interest::Write,
+                        Some(deadline),
+                        cx,
+                        || {
+                            let mut s = s;
+                            s.write(&data[sent..])
+                        },
+                    );
+                })
+                .await?;
+                if n == 0 {
+                    return Err(IOError::from(ErrorKind::WriteZero));
+                }
+                sent += n;
+            }
+            return Ok(());
+        });
+    }
+
+    fn recv_exact_before(
+        &mut self,
+        how_much: usize,
+        deadline: Instant,
+    ) -> BoxFuture<'_, IOResult<Vec<u8>>> {
+        return Box::pin(async move {
+            let mut buf = vec![0; how_much];
+            let mut received: usize = 0;
+            let mut registered = Registered::default();
+            while received < how_much {
+                let s = &self.s;
+                let n = poll_fn(|cx| {
+                    return poll_io(
+                        &mut registered,
+                        || reactor::source(s),
+                        Interest::Read,
+                        Some(deadline),
+                        cx,
+                        || {
+                            let mut s = s;
+                            s.read(&mut buf[received..])
+                        },
+                    );
+                })
+                .await?;
+                if n == 0 {
+                    return Err(IOError::from(ErrorKind::UnexpectedEof));
+                }
+                received += n;
+            }
+            log::trace!(target: "NET", "Recv exact: {}", format_data_buf(&buf));
+            return Ok(buf);
+        });
+    }
+
+    fn recv(&mut self) -> BoxFuture<'_, IOResult<Vec<u8>>> {
+        return Box::pin(async move {
+            let mut buf = vec![0; RECV_BUF_SIZE];
+            match (&self.s).read(&mut buf) {
+                Ok(0) => return Err(IOError::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => buf.truncate(n),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => buf.clear(),
+                Err(e) => return Err(e),
+            }
+            log::trace!(target: "NET", "Recv nonblocking: {}", format_data_buf(&buf));
+            return Ok(buf);
+        });
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        log::debug!(target: "NET", "Setting timeout: {timeout:?}");
+        self.timeout = timeout;
+    }
+}
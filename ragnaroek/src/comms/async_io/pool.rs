@@ -0,0 +1,116 @@
+//! Shared pool of threads for running blocking calls from futures.
+//!
+//! Threads are only kept around while there's work to do, so idle connections don't occupy one.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// How long an idle worker waits for new jobs before exiting
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    /// Number of workers waiting for jobs
+    idle: usize,
+}
+
+#[derive(Default)]
+struct Pool {
+    queue: Mutex<Queue>,
+    available: Condvar,
+}
+
+fn pool() -> &'static Pool {
+    static POOL: OnceLock<Pool> = OnceLock::new();
+    return POOL.get_or_init(Pool::default);
+}
+
+fn worker(pool: &'static Pool) {
+    let mut queue = pool.queue.lock().unwrap();
+    loop {
+        if let Some(job) = queue.jobs.pop_front() {
+            drop(queue);
+            job();
+            queue = pool.queue.lock().unwrap();
+            continue;
+        }
+        queue.idle += 1;
+        let (q, timeout) = pool
+            .available
+            .wait_timeout(queue, WORKER_IDLE_TIMEOUT)
+            .unwrap();
+        queue = q;
+        queue.idle -= 1;
+        if timeout.timed_out() && queue.jobs.is_empty() {
+            return;
+        }
+    }
+}
+
+struct TaskState<T> {
+    result: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// Future resolving to the result of a call running on the pool.
+///
+/// Dropping it doesn't stop the call, it just discards the result.
+pub(crate) struct BlockingTask<T> {
+    state: Arc<Mutex<TaskState<T>>>,
+}
+
+impl<T> Future for BlockingTask<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => return Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+/// Run the given blocking call on the pool.
+pub(crate) fn spawn_blocking<T, F>(f: F) -> BlockingTask<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let state = Arc::new(Mutex::new(TaskState {
+        result: None,
+        waker: None,
+    }));
+    let task_state = state.clone();
+    let job: Job = Box::new(move || {
+        let result = f();
+        let mut state = task_state.lock().unwrap();
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    });
+
+    let pool = pool();
+    let mut queue = pool.queue.lock().unwrap();
+    queue.jobs.push_back(job);
+    if queue.idle >= queue.jobs.len() {
+        pool.available.notify_one();
+    } else {
+        std::thread::Builder::new()
+            .name(String::from("ragnaroek-blocking"))
+            .spawn(move || worker(pool))
+            .expect("Failed to spawn worker thread");
+    }
+    return BlockingTask { state };
+}
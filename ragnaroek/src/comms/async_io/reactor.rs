@@ -0,0 +1,284 @@
+//! A single background thread waking futures whose sockets became ready or whose deadline passed.
+//!
+//! Futures try their operation first, and only register with the reactor if it would block.
+//! Registrations are one-shot, a future registers again every time it's pending.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+#[cfg(unix)]
+use std::io::Result as IOResult;
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::fd::{AsFd, OwnedFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::sync::Arc;
+
+/// How often registrations are woken to retry without readiness information, on platforms lacking `poll`
+#[cfg(not(unix))]
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Something the reactor can wait on.
+///
+/// This is a duplicate of the socket's descriptor, so it stays valid even if the socket is closed while registered.
+#[cfg(unix)]
+pub(crate) type Source = Arc<OwnedFd>;
+/// Something the reactor can wait on.
+#[cfg(not(unix))]
+pub(crate) type Source = ();
+
+/// Get the reactor's handle for the given socket.
+#[cfg(unix)]
+pub(crate) fn source<S: AsFd>(s: &S) -> IOResult<Source> {
+    return Ok(Arc::new(s.as_fd().try_clone_to_owned()?));
+}
+
+/// Get the reactor's handle for the given socket.
+#[cfg(not(unix))]
+pub(crate) fn source<S>(_s: &S) -> std::io::Result<Source> {
+    return Ok(());
+}
+
+/// What a registration waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Interest {
+    Read,
+    Write,
+}
+
+struct Registration {
+    id: u64,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    source: Source,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    interest: Interest,
+    deadline: Option<Instant>,
+    waker: Waker,
+}
+
+struct Reactor {
+    registrations: Mutex<Vec<Registration>>,
+    /// Written to for interrupting `poll` when registrations are added
+    #[cfg(unix)]
+    notify: UnixStream,
+}
+
+fn reactor() -> &'static Reactor {
+    static REACTOR: OnceLock<Reactor> = OnceLock::new();
+    return REACTOR.get_or_init(|| {
+        #[cfg(unix)]
+        {
+            let (notify, notified) = UnixStream::pair().expect("Failed to create reactor pipe");
+            notify.set_nonblocking(true).unwrap();
+            notified.set_nonblocking(true).unwrap();
+            spawn(move || run(notified));
+            return Reactor {
+                registrations: Mutex::new(Vec::new()),
+                notify,
+            };
+        }
+        #[cfg(not(unix))]
+        {
+            spawn(run);
+            return Reactor {
+                registrations: Mutex::new(Vec::new()),
+            };
+        }
+    });
+}
+
+fn spawn<F: FnOnce() + Send + 'static>(f: F) {
+    std::thread::Builder::new()
+        .name(String::from("ragnaroek-reactor"))
+        .spawn(f)
+        .expect("Failed to spawn reactor thread");
+}
+
+/// A future's registration with the reactor, withdrawn when dropped.
+///
+/// Futures keep one across polls, so dropping them while pending doesn't leave their registration,
+/// and the duplicated descriptor it holds, behind.
+#[derive(Default)]
+pub(crate) struct Registered {
+    id: Option<u64>,
+}
+
+impl Registered {
+    /// Wake the given waker once the source is ready for the given interest, or the deadline passed.
+    ///
+    /// Replaces the earlier registration, if it's still pending.
+    pub(crate) fn register(
+        &mut self,
+        source: Source,
+        interest: Interest,
+        deadline: Option<Instant>,
+        waker: &Waker,
+    ) {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        self.deregister();
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        reactor().registrations.lock().unwrap().push(Registration {
+            id,
+            source,
+            interest,
+            deadline,
+            waker: waker.clone(),
+        });
+        self.id = Some(id);
+        notify();
+    }
+
+    fn deregister(&mut self) {
+        let Some(id) = self.id.take() else {
+            return;
+        };
+        let mut regs = reactor().registrations.lock().unwrap();
+        let len = regs.len();
+        regs.retain(|reg| reg.id != id);
+        if regs.len() != len {
+            drop(regs);
+            // Let the reactor stop polling the source
+            notify();
+        }
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.deregister();
+    }
+}
+
+/// Interrupt the reactor's wait, so it picks up changed registrations.
+fn notify() {
+    #[cfg(unix)]
+    {
+        // A full pipe already guarantees a wakeup
+        let _ = (&reactor().notify).write(&[0]);
+    }
+}
+
+/// Time until the earliest deadline among the registrations, if there is any.
+fn next_timeout(registrations: &[Registration]) -> Option<Duration> {
+    let now = Instant::now();
+    return registrations
+        .iter()
+        .filter_map(|r| r.deadline)
+        .min()
+        .map(|d| d.saturating_duration_since(now));
+}
+
+#[cfg(unix)]
+fn run(notified: UnixStream) {
+    let r = reactor();
+    loop {
+        let (polled, timeout): (Vec<(u64, Source, Interest)>, Option<Duration>) = {
+            let regs = r.registrations.lock().unwrap();
+            (
+                regs.iter()
+                    .map(|reg| (reg.id, reg.source.clone(), reg.interest))
+                    .collect(),
+                next_timeout(&regs),
+            )
+        };
+
+        let mut poll_fds: Vec<PollFd> = vec![PollFd::new(notified.as_fd(), PollFlags::POLLIN)];
+        poll_fds.extend(polled.iter().map(|(_, fd, interest)| {
+            let flags = match interest {
+                Interest::Read => PollFlags::POLLIN,
+                Interest::Write => PollFlags::POLLOUT,
+            };
+            PollFd::new(fd.as_fd(), flags)
+        }));
+        let timeout = match timeout {
+            Some(t) => PollTimeout::try_from(t).unwrap_or(PollTimeout::MAX),
+            None => PollTimeout::NONE,
+        };
+        if let Err(e) = poll(&mut poll_fds, timeout) {
+            log::warn!(target: "ASYNC", "Polling failed: {e}");
+        }
+        let ready: Vec<u64> = poll_fds[1..]
+            .iter()
+            .zip(polled.iter())
+            .filter(|(p, _)| p.revents().is_some_and(|r| !r.is_empty()))
+            .map(|(_, (id, _, _))| *id)
+            .collect();
+        drop(poll_fds);
+        drop(polled);
+
+        let mut buf = [0; 64];
+        while matches!((&notified).read(&mut buf), Ok(n) if n > 0) {}
+
+        // Registrations may have been withdrawn or added meanwhile, so match them by ID
+        let now = Instant::now();
+        let mut regs = r.registrations.lock().unwrap();
+        regs.retain(|reg| {
+            let due = ready.contains(&reg.id) || reg.deadline.is_some_and(|d| d <= now);
+            if due {
+                reg.waker.wake_by_ref();
+            }
+            return !due;
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn run() {
+    let r = reactor();
+    loop {
+        let timeout = next_timeout(&r.registrations.lock().unwrap());
+        std::thread::sleep(timeout.map_or(RETRY_INTERVAL, |t| t.min(RETRY_INTERVAL)));
+        for reg in r.registrations.lock().unwrap().drain(..) {
+            reg.waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn is_registered(id: u64) -> bool {
+        return reactor()
+            .registrations
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|reg| reg.id == id);
+    }
+
+    #[test]
+    fn test_deregister_on_drop() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut registered = Registered::default();
+        registered.register(
+            source(&socket).unwrap(),
+            Interest::Read,
+            None,
+            Waker::noop(),
+        );
+        let first = registered.id.unwrap();
+        assert!(is_registered(first));
+
+        // Registering again replaces the earlier registration
+        registered.register(
+            source(&socket).unwrap(),
+            Interest::Read,
+            None,
+            Waker::noop(),
+        );
+        let second = registered.id.unwrap();
+        assert!(!is_registered(first));
+        assert!(is_registered(second));
+
+        drop(registered);
+        assert!(!is_registered(second));
+    }
+}
@@ -0,0 +1,62 @@
+use super::*;
+use crate::comms::usb::{Connection as BlockingConnection, UsbDeviceSelector};
+
+/// Asynchronous USB ODIN mode connection.
+///
+/// rusb doesn't expose libusb's asynchronous transfers, so transfers run on the shared worker pool.
+pub struct Connection {
+    inner: Blocking,
+}
+
+impl Connection {
+    /// Establish a new connection to the first viable USB device.
+    /// Returns an error if no suitable device could be found.
+    pub async fn establish() -> IOResult<Connection> {
+        return Connection::establish_with(None).await;
+    }
+
+    /// Establish a new connection to the viable USB device chosen by `selector`,
+    /// or the first viable one if no selector is given.
+    /// Returns an error if no suitable device could be found.
+    pub async fn establish_with(selector: Option<UsbDeviceSelector>) -> IOResult<Connection> {
+        let c = pool::spawn_blocking(move || BlockingConnection::establish_with(selector.as_ref()))
+            .await?;
+        return Ok(Connection {
+            inner: Blocking::new(Box::new(c)),
+        });
+    }
+}
+
+impl AsyncCommunicator for Connection {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, IOResult<()>> {
+        return self.inner.send(data);
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> BoxFuture<'_, IOResult<Vec<u8>>> {
+        return self.inner.recv_exact(how_much);
+    }
+
+    fn recv(&mut self) -> BoxFuture<'_, IOResult<Vec<u8>>> {
+        return self.inner.recv();
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.set_timeout(timeout);
+    }
+
+    fn send_before<'a>(
+        &'a mut self,
+        data: &'a [u8],
+        deadline: Instant,
+    ) -> BoxFuture<'a, IOResult<()>> {
+        return self.inner.send_before(data, deadline);
+    }
+
+    fn recv_exact_before(
+        &mut self,
+        how_much: usize,
+        deadline: Instant,
+    ) -> BoxFuture<'_, IOResult<Vec<u8>>> {
+        return self.inner.recv_exact_before(how_much, deadline);
+    }
+}
@@ -0,0 +1,506 @@
+use super::*;
+use crate::download_protocol::{
+    OdinCmd, OdinCmdPacket, OdinCmdReply, CMD_PACKET_LEN, CMD_REPLY_LEN, PING, PONG,
+};
+
+use std::fs::File;
+use std::io::{BufWriter, Result as IOResult, Write};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_SHB_USERAPPL: u16 = 4;
+
+/// Linux usbmon header with the 64-byte layout, as produced by the mmap interface.
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+/// Raw IPv4 packets without a link layer header.
+const LINKTYPE_IPV4: u16 = 228;
+
+/// Wireshark refuses records larger than this, so larger transfers are split up.
+/// It's also small enough for an IPv4 packet.
+const MAX_RECORD_DATA: usize = 65000;
+
+const USB_HEADER_LEN: usize = 64;
+const USB_TRANSFER_BULK: u8 = 3;
+const USB_BUS: u16 = 1;
+const USB_DEVICE: u8 = 1;
+const USB_ENDPOINT_OUT: u8 = 0x01;
+const USB_ENDPOINT_IN: u8 = 0x81;
+
+const IPV4_HEADER_LEN: usize = 20;
+const TCP_HEADER_LEN: usize = 20;
+const IP_PROTO_TCP: u8 = 6;
+const TCP_FLAGS_PSH_ACK: u8 = 0x18;
+/// Addresses are made up, except for the target's, which is what wireless targets use.
+const HOST_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 49, 100);
+/// Port of whichever side connects, which would be picked by its OS.
+const EPHEMERAL_PORT: u16 = 50000;
+const TARGET_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 49, 1);
+
+/// How transfers are framed in a capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureLinkType {
+    /// USB bulk transfers with usbmon pseudo-headers, one record per transfer.
+    Usb,
+    /// TCP segments in IPv4 packets, as if captured on the wireless target's network,
+    /// with the host listening on the Odin port for the target to connect, as in download mode.
+    NetBind,
+    /// Like `NetBind`, but with the target listening on the Odin port for the host to connect.
+    NetConnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ToTarget,
+    FromTarget,
+}
+
+/// Describe the given transfer, if it looks like part of the Odin protocol.
+///
+/// Transfers aren't tracked, so file data that happens to look like a command is annotated as well.
+fn annotate(dir: Direction, data: &[u8]) -> Option<String> {
+    match (dir, data.len()) {
+        (Direction::ToTarget, _) if data == PING => {
+            return Some(String::from("Odin handshake"));
+        }
+        (Direction::FromTarget, _) if data == PONG => {
+            return Some(String::from("Odin handshake reply"));
+        }
+        (Direction::ToTarget, CMD_PACKET_LEN) => {
+            let p = OdinCmdPacket::from_wire(data).ok()?;
+            if p.cmd() == OdinCmd::ChunkTransferOk {
+                return None;
+            }
+            let args: Vec<String> = p.args().iter().map(|a| format!("0x{a:X}")).collect();
+            return Some(format!("Odin command {:?}: {}", p.cmd(), args.join(", ")));
+        }
+        (Direction::FromTarget, CMD_REPLY_LEN) => {
+            let r = OdinCmdReply::from_wire(data.try_into().ok()?).ok()?;
+            return Some(format!("Odin reply {:?}: 0x{:X}", r.cmd, r.arg));
+        }
+        _ => return None,
+    }
+}
+
+/// Append a pcapng option, padded to 32 bits.
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+/// Write a pcapng block with the given type and body.
+fn write_block<W: Write>(out: &mut W, block_type: u32, mut body: Vec<u8>) -> IOResult<()> {
+    body.resize(body.len().next_multiple_of(4), 0);
+    let len = (body.len() + 12) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(&body)?;
+    out.write_all(&len.to_le_bytes())?;
+    return Ok(());
+}
+
+/// Internet checksum over the given header.
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|w| u32::from(u16::from_be_bytes([w[0], w[1]])))
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    return !(sum as u16);
+}
+
+/// `Capture` wraps another `Communicator`, writing all transfers to a pcapng file Wireshark can read.
+///
+/// Transfers that look like Odin commands and replies get a comment describing them.
+/// Failed calls aren't captured. Each record is flushed right away, so the capture survives a crash.
+/// Failing to write the capture fails the call, after it was made on the inner `Communicator`.
+pub struct Capture<W: Write + Send> {
+    inner: Box<dyn Communicator>,
+    out: W,
+    link: CaptureLinkType,
+    /// Identifier of the next USB request block
+    urb_id: u64,
+    /// TCP sequence numbers of both directions
+    host_seq: u32,
+    target_seq: u32,
+}
+
+impl<W: Write + Send> Capture<W> {
+    /// Start capturing transfers of `inner` into `out`.
+    pub fn new(inner: Box<dyn Communicator>, mut out: W, link: CaptureLinkType) -> IOResult<Self> {
+        let mut shb: Vec<u8> = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is unknown
+        shb.extend_from_slice(&u64::MAX.to_le_bytes());
+        push_option(&mut shb, OPT_SHB_USERAPPL, b"ragnaroek");
+        push_option(&mut shb, OPT_END, &[]);
+        write_block(&mut out, BLOCK_SECTION_HEADER, shb)?;
+
+        let (link_type, name): (u16, &[u8]) = match link {
+            CaptureLinkType::Usb => (LINKTYPE_USB_LINUX_MMAPPED, b"usbmon1"),
+            CaptureLinkType::NetBind | CaptureLinkType::NetConnect => (LINKTYPE_IPV4, b"wlan"),
+        };
+        let mut idb: Vec<u8> = Vec::new();
+        idb.extend_from_slice(&link_type.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        push_option(&mut idb, OPT_IF_NAME, name);
+        push_option(&mut idb, OPT_END, &[]);
+        write_block(&mut out, BLOCK_INTERFACE_DESCRIPTION, idb)?;
+        out.flush()?;
+
+        return Ok(Capture {
+            inner,
+            out,
+            link,
+            urb_id: 1,
+            host_seq: 1,
+            target_seq: 1,
+        });
+    }
+
+    /// Stop capturing, returning the inner `Communicator` and the capture's writer.
+    pub fn into_inner(self) -> (Box<dyn Communicator>, W) {
+        return (self.inner, self.out);
+    }
+
+    /// Capture a transfer, split up into as many records as needed.
+    fn capture(&mut self, dir: Direction, data: &[u8]) -> IOResult<()> {
+        let comment = annotate(dir, data);
+        // Zero-length packets exist on USB only
+        if data.is_empty() && self.link == CaptureLinkType::Usb {
+            self.write_record(dir, data, None)?;
+        }
+        for (i, chunk) in data.chunks(MAX_RECORD_DATA).enumerate() {
+            let comment = if i == 0 { comment.as_deref() } else { None };
+            self.write_record(dir, chunk, comment)?;
+        }
+        return self.out.flush();
+    }
+
+    fn write_record(&mut self, dir: Direction, data: &[u8], comment: Option<&str>) -> IOResult<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let packet = match self.link {
+            CaptureLinkType::Usb => self.usb_packet(dir, data, now),
+            CaptureLinkType::NetBind | CaptureLinkType::NetConnect => self.tcp_packet(dir, data),
+        };
+
+        let micros = now.as_micros() as u64;
+        let mut epb: Vec<u8> = Vec::new();
+        // Interface ID
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(micros as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&packet);
+        epb.resize(epb.len().next_multiple_of(4), 0);
+        if let Some(comment) = comment {
+            push_option(&mut epb, OPT_COMMENT, comment.as_bytes());
+            push_option(&mut epb, OPT_END, &[]);
+        }
+        return write_block(&mut self.out, BLOCK_ENHANCED_PACKET, epb);
+    }
+
+    /// Build a usbmon record. Outgoing data is captured on submission, incoming data on completion.
+    fn usb_packet(&mut self, dir: Direction, data: &[u8], now: Duration) -> Vec<u8> {
+        let (event, endpoint) = match dir {
+            Direction::ToTarget => (b'S', USB_ENDPOINT_OUT),
+            Direction::FromTarget => (b'C', USB_ENDPOINT_IN),
+        };
+        let mut p: Vec<u8> = Vec::with_capacity(USB_HEADER_LEN + data.len());
+        p.extend_from_slice(&self.urb_id.to_le_bytes());
+        p.push(event);
+        p.push(USB_TRANSFER_BULK);
+        p.push(endpoint);
+        p.push(USB_DEVICE);
+        p.extend_from_slice(&USB_BUS.to_le_bytes());
+        // No setup packet, data present
+        p.push(b'-');
+        p.push(0);
+        p.extend_from_slice(&(now.as_secs() as i64).to_le_bytes());
+        p.extend_from_slice(&(now.subsec_micros() as i32).to_le_bytes());
+        // Status
+        p.extend_from_slice(&0i32.to_le_bytes());
+        // URB and captured length
+        p.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        p.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        // Setup packet, interval, start frame, transfer flags, ISO descriptor count
+        p.resize(USB_HEADER_LEN, 0);
+        p.extend_from_slice(data);
+
+        self.urb_id += 1;
+        return p;
+    }
+
+    /// Build an IPv4 packet carrying a TCP segment.
+    fn tcp_packet(&mut self, dir: Direction, data: &[u8]) -> Vec<u8> {
+        let (host_port, target_port) = match self.link {
+            CaptureLinkType::NetBind => (WIRELESS_PORT, EPHEMERAL_PORT),
+            _ => (EPHEMERAL_PORT, WIRELESS_PORT),
+        };
+        let (src, dst, sport, dport, seq, ack) = match dir {
+            Direction::ToTarget => (
+                HOST_ADDR,
+                TARGET_ADDR,
+                host_port,
+                target_port,
+                self.host_seq,
+                self.target_seq,
+            ),
+            Direction::FromTarget => (
+                TARGET_ADDR,
+                HOST_ADDR,
+                target_port,
+                host_port,
+                self.target_seq,
+                self.host_seq,
+            ),
+        };
+        let total_len = (IPV4_HEADER_LEN + TCP_HEADER_LEN + data.len()) as u16;
+        let mut p: Vec<u8> = Vec::with_capacity(total_len as usize);
+        // Version 4, no options
+        p.push(0x45);
+        p.push(0);
+        p.extend_from_slice(&total_len.to_be_bytes());
+        p.extend_from_slice(&(self.urb_id as u16).to_be_bytes());
+        // Don't fragment
+        p.extend_from_slice(&0x4000u16.to_be_bytes());
+        p.push(64);
+        p.push(IP_PROTO_TCP);
+        p.extend_from_slice(&[0, 0]);
+        p.extend_from_slice(&src.octets());
+        p.extend_from_slice(&dst.octets());
+        let checksum = ipv4_checksum(&p);
+        p[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        p.extend_from_slice(&sport.to_be_bytes());
+        p.extend_from_slice(&dport.to_be_bytes());
+        p.extend_from_slice(&seq.to_be_bytes());
+        p.extend_from_slice(&ack.to_be_bytes());
+        // Header length in 32-bit words, no options
+        p.push(((TCP_HEADER_LEN / 4) as u8) << 4);
+        p.push(TCP_FLAGS_PSH_ACK);
+        p.extend_from_slice(&u16::MAX.to_be_bytes());
+        // Checksum is left out, Wireshark doesn't verify it by default. No urgent data.
+        p.extend_from_slice(&[0, 0, 0, 0]);
+        p.extend_from_slice(data);
+
+        let seq = match dir {
+            Direction::ToTarget => &mut self.host_seq,
+            Direction::FromTarget => &mut self.target_seq,
+        };
+        *seq = seq.wrapping_add(data.len() as u32);
+        self.urb_id += 1;
+        return p;
+    }
+}
+
+impl Capture<BufWriter<File>> {
+    /// Start capturing transfers of `inner` into a new file at the given path, overwriting it if it exists.
+    pub fn create<P: AsRef<Path>>(
+        inner: Box<dyn Communicator>,
+        path: P,
+        link: CaptureLinkType,
+    ) -> IOResult<Self> {
+        log::info!(target: "CAP", "Capturing traffic to {}", path.as_ref().display());
+        return Capture::new(inner, BufWriter::new(File::create(path)?), link);
+    }
+}
+
+impl<W: Write + Send> Communicator for Capture<W> {
+    fn send(&mut self, data: &[u8]) -> IOResult<()> {
+        self.inner.send(data)?;
+        self.capture(Direction::ToTarget, data)?;
+        return Ok(());
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
+        let data = self.inner.recv_exact(how_much)?;
+        self.capture(Direction::FromTarget, &data)?;
+        return Ok(data);
+    }
+
+    fn recv(&mut self) -> IOResult<Vec<u8>> {
+        let data = self.inner.recv()?;
+        if !data.is_empty() {
+            self.capture(Direction::FromTarget, &data)?;
+        }
+        return Ok(data);
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.set_timeout(timeout);
+    }
+
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        self.inner.send_before(data, deadline)?;
+        self.capture(Direction::ToTarget, data)?;
+        return Ok(());
+    }
+
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        let data = self.inner.recv_exact_before(how_much, deadline)?;
+        self.capture(Direction::FromTarget, &data)?;
+        return Ok(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::pipe::Connection as Pipe;
+    use crate::download_protocol::emulator::{spawn_target, TargetOptions};
+    use crate::download_protocol::{ActionAfter, Session};
+
+    const PIT: &str = "../pit/testdata/A40_EUR_OPEN.pit";
+
+    /// A captured record: link type, packet data and comment.
+    type Record = (u16, Vec<u8>, Option<String>);
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        return u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    }
+
+    /// Split a pcapng file into its records.
+    fn read_records(data: &[u8]) -> Vec<Record> {
+        assert_eq!(BLOCK_SECTION_HEADER, u32_at(data, 0));
+        assert_eq!(BYTE_ORDER_MAGIC, u32_at(data, 8));
+        let mut link_type: u16 = 0;
+        let mut records: Vec<Record> = Vec::new();
+        let mut pos: usize = 0;
+        while pos < data.len() {
+            let block_type = u32_at(data, pos);
+            let len = u32_at(data, pos + 4) as usize;
+            assert_eq!(len, u32_at(data, pos + len - 4) as usize);
+            let body = &data[pos + 8..pos + len - 4];
+            match block_type {
+                BLOCK_INTERFACE_DESCRIPTION => {
+                    link_type = u16::from_le_bytes([body[0], body[1]]);
+                }
+                BLOCK_ENHANCED_PACKET => {
+                    let caplen = u32_at(body, 12) as usize;
+                    let packet = body[20..20 + caplen].to_vec();
+                    let options = &body[(20 + caplen).next_multiple_of(4)..];
+                    let comment = (options.len() >= 4
+                        && u16::from_le_bytes([options[0], options[1]]) == OPT_COMMENT)
+                        .then(|| {
+                            let len = u16::from_le_bytes([options[2], options[3]]) as usize;
+                            String::from_utf8(options[4..4 + len].to_vec()).unwrap()
+                        });
+                    records.push((link_type, packet, comment));
+                }
+                _ => {}
+            }
+            pos += len;
+        }
+        return records;
+    }
+
+    /// Capture a session with an emulated target: handshake, PIT download and end.
+    fn capture_session(link: CaptureLinkType) -> Vec<Record> {
+        let path = std::env::temp_dir().join(format!(
+            "ragnaroek-capture-{}-{link:?}.pcapng",
+            std::process::id()
+        ));
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+
+        let capture = Capture::create(Box::new(host), &path, link).unwrap();
+        let mut sess = Session::begin(Box::new(capture)).unwrap();
+        sess.download_pit(sess.params).unwrap();
+        sess.end(ActionAfter::Nothing).unwrap();
+        target.join().unwrap().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        return read_records(&data);
+    }
+
+    #[test]
+    fn test_usb_capture() {
+        let records = capture_session(CaptureLinkType::Usb);
+        assert!(records
+            .iter()
+            .all(|r| r.0 == LINKTYPE_USB_LINUX_MMAPPED && r.1.len() >= USB_HEADER_LEN));
+
+        let (_, handshake, comment) = &records[0];
+        assert_eq!(b'S', handshake[8]);
+        assert_eq!(USB_ENDPOINT_OUT, handshake[10]);
+        assert_eq!(4, u32_at(handshake, 36));
+        assert_eq!(b"ODIN", &handshake[USB_HEADER_LEN..]);
+        assert_eq!(Some("Odin handshake"), comment.as_deref());
+
+        let (_, reply, comment) = &records[1];
+        assert_eq!(b'C', reply[8]);
+        assert_eq!(USB_ENDPOINT_IN, reply[10]);
+        assert_eq!(b"LOKE", &reply[USB_HEADER_LEN..]);
+        assert_eq!(Some("Odin handshake reply"), comment.as_deref());
+
+        let comments: Vec<&str> = records.iter().filter_map(|r| r.2.as_deref()).collect();
+        assert_eq!("Odin command SessionStart: 0x0, 0x4", comments[2]);
+        assert!(comments.contains(&"Odin command TransferPIT: 0x1"));
+        assert!(comments
+            .iter()
+            .any(|c| c.starts_with("Odin reply SessionEnd")));
+    }
+
+    #[test]
+    fn test_net_capture() {
+        for (link, target_port) in [
+            (CaptureLinkType::NetBind, EPHEMERAL_PORT),
+            (CaptureLinkType::NetConnect, WIRELESS_PORT),
+        ] {
+            check_net_capture(link, target_port);
+        }
+    }
+
+    fn check_net_capture(link: CaptureLinkType, target_port: u16) {
+        let records = capture_session(link);
+        let mut seqs: [u32; 2] = [1, 1];
+        for (link_type, packet, _) in &records {
+            assert_eq!(LINKTYPE_IPV4, *link_type);
+            assert_eq!(0x45, packet[0]);
+            assert_eq!(
+                packet.len(),
+                u16::from_be_bytes([packet[2], packet[3]]) as usize
+            );
+            assert_eq!(0, ipv4_checksum(&packet[..IPV4_HEADER_LEN]));
+
+            // Sequence numbers follow the data sent in each direction
+            let tcp = &packet[IPV4_HEADER_LEN..];
+            let from_target = packet[12..16] == TARGET_ADDR.octets();
+            let (sport, dport) = (
+                u16::from_be_bytes([tcp[0], tcp[1]]),
+                u16::from_be_bytes([tcp[2], tcp[3]]),
+            );
+            assert_eq!(target_port, if from_target { sport } else { dport });
+            assert_ne!(sport, dport);
+            let seq = u32::from_be_bytes(tcp[4..8].try_into().unwrap());
+            assert_eq!(seqs[from_target as usize], seq);
+            seqs[from_target as usize] += (packet.len() - IPV4_HEADER_LEN - TCP_HEADER_LEN) as u32;
+        }
+        let pit_len = std::fs::read(PIT).unwrap().len() as u32;
+        assert!(seqs[1] > pit_len);
+        assert!(records
+            .iter()
+            .any(|r| r.2.as_deref() == Some("Odin command SessionEnd: 0x0")));
+    }
+}
@@ -0,0 +1,750 @@
+use super::*;
+
+use crate::download_protocol::CMD_REPLY_LEN;
+use std::fs;
+use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+use std::path::Path;
+use std::time::Instant;
+
+/// A fault `Injector` can inject into a single call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Consume the data a read asked for, then report a timeout as if it never arrived.
+    Drop,
+    /// Return only the first `len` bytes of a read, violating the `recv_exact` contract
+    /// the way a misbehaving transport would. The rest of the data is lost.
+    Truncate {
+        /// Number of bytes to keep
+        len: usize,
+    },
+    /// XOR the byte at `offset` of a read with `mask`. Offsets past the end wrap around.
+    Corrupt {
+        /// Which byte to corrupt
+        offset: usize,
+        /// Bits to flip
+        mask: u8,
+    },
+    /// Stall for the given time before performing the call.
+    /// Stalls reaching the call's deadline fail with `ErrorKind::TimedOut` once it passed, without performing the call.
+    Delay(Duration),
+    /// An extra zero-length transfer. Sends are preceded by an empty one,
+    /// reads return an empty transfer and leave the data they asked for in place.
+    ZeroLength,
+    /// Transfer the first `after` bytes of the call, then disconnect for good.
+    /// The wrapped `Communicator` is dropped, so the other end notices as well.
+    Disconnect {
+        /// Number of bytes to transfer before disconnecting
+        after: usize,
+    },
+}
+
+impl Fault {
+    /// Whether this fault can be injected into the given call.
+    fn applies_to(&self, call: Call) -> bool {
+        match (self, call) {
+            (Fault::Delay(_) | Fault::ZeroLength | Fault::Disconnect { .. }, _) => true,
+            (Fault::Drop, Call::RecvExact(_) | Call::Recv) => true,
+            (Fault::Truncate { len }, Call::RecvExact(n)) => *len < n,
+            (Fault::Corrupt { .. }, Call::RecvExact(n)) => n > 0,
+            _ => false,
+        }
+    }
+}
+
+/// Which call a scheduled fault is injected into. Counting starts at 0 and includes calls faults were injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// The n-th call of any kind, except `set_timeout`.
+    Call(usize),
+    /// The n-th `send`.
+    Send(usize),
+    /// The n-th `recv_exact` or `recv`.
+    Recv(usize),
+    /// The n-th 8-byte `recv_exact`, the size of `OdinCmdReply` frames.
+    Reply(usize),
+}
+
+/// Faults injected at random, at a given rate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RandomFaults {
+    /// Seed for the random number generator. The same seed and calls result in the same faults.
+    pub seed: u64,
+    /// A fault is injected into one in this many calls, on average.
+    pub one_in: u32,
+    /// Faults to choose from. Faults that don't apply to the chosen call are skipped.
+    /// `Corrupt` faults are only injected into 8-byte reads, where they hit `OdinCmdReply` frames.
+    pub faults: Vec<Fault>,
+}
+
+/// The faults an `Injector` injects.
+///
+/// Plans can be written as text, one instruction per line. Lines starting with `#` are comments.
+/// ```text
+/// # Inject a fault at the given call
+/// call 3 delay 500
+/// reply 1 corrupt 0 ff
+/// send 2 disconnect 10
+/// recv 0 truncate 4
+/// # Inject random faults into one in 20 calls
+/// seed 1234
+/// one-in 20
+/// random drop
+/// random zero-length
+/// # Never wait longer than a second
+/// max-timeout 1000
+/// ```
+/// Delays and timeouts are in milliseconds, corruption masks in hex.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FaultPlan {
+    /// Faults injected at a specific call.
+    pub scheduled: Vec<(Trigger, Fault)>,
+    /// Faults injected at random, in calls no scheduled fault was injected into.
+    pub random: Option<RandomFaults>,
+    /// Upper bound for timeouts set through the `Injector`, so stalls surface quickly.
+    /// Sessions set their own timeouts for each call, which are usually much longer.
+    pub max_timeout: Option<Duration>,
+}
+
+fn parse_fault(words: &[&str]) -> Option<Fault> {
+    let fault = match words {
+        ["drop"] => Fault::Drop,
+        ["truncate", len] => Fault::Truncate {
+            len: len.parse().ok()?,
+        },
+        ["corrupt", offset, mask] => Fault::Corrupt {
+            offset: offset.parse().ok()?,
+            mask: u8::from_str_radix(mask, 16).ok()?,
+        },
+        ["delay", ms] => Fault::Delay(Duration::from_millis(ms.parse().ok()?)),
+        ["zero-length"] => Fault::ZeroLength,
+        ["disconnect", after] => Fault::Disconnect {
+            after: after.parse().ok()?,
+        },
+        _ => return None,
+    };
+    return Some(fault);
+}
+
+impl FaultPlan {
+    /// Parse a plan.
+    pub fn parse(s: &str) -> IOResult<FaultPlan> {
+        let mut plan = FaultPlan::default();
+        let mut random = RandomFaults {
+            seed: 0,
+            one_in: 10,
+            faults: Vec::new(),
+        };
+        for (i, line) in s.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() || words[0].starts_with('#') {
+                continue;
+            }
+            let ok = match words[..] {
+                ["seed", seed] => seed.parse().map(|seed| random.seed = seed).is_ok(),
+                ["one-in", n] => n
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .map(|n| random.one_in = n)
+                    .is_some(),
+                ["max-timeout", ms] => ms
+                    .parse()
+                    .map(|ms| plan.max_timeout = Some(Duration::from_millis(ms)))
+                    .is_ok(),
+                ["random", ref fault @ ..] => parse_fault(fault)
+                    .map(|fault| random.faults.push(fault))
+                    .is_some(),
+                [trigger, n, ref fault @ ..] => {
+                    let trigger = match (trigger, n.parse()) {
+                        ("call", Ok(n)) => Some(Trigger::Call(n)),
+                        ("send", Ok(n)) => Some(Trigger::Send(n)),
+                        ("recv", Ok(n)) => Some(Trigger::Recv(n)),
+                        ("reply", Ok(n)) => Some(Trigger::Reply(n)),
+                        _ => None,
+                    };
+                    match (trigger, parse_fault(fault)) {
+                        (Some(trigger), Some(fault)) => {
+                            plan.scheduled.push((trigger, fault));
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+                _ => false,
+            };
+            if !ok {
+                return Err(IOError::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid fault plan instruction on line {}", i + 1),
+                ));
+            }
+        }
+        if !random.faults.is_empty() {
+            plan.random = Some(random);
+        }
+        return Ok(plan);
+    }
+
+    /// Read a plan from the file at the given path.
+    pub fn load<P: AsRef<Path>>(path: P) -> IOResult<FaultPlan> {
+        return FaultPlan::parse(&fs::read_to_string(path)?);
+    }
+}
+
+/// A fault `Injector` injected, for reproducing failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InjectedFault {
+    /// Index of the call, counted like `Trigger::Call`.
+    pub call: usize,
+    /// The fault injected.
+    pub fault: Fault,
+}
+
+/// Kinds of calls, with the length asked for where it matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Call {
+    Send,
+    RecvExact(usize),
+    Recv,
+}
+
+/// `Injector` wraps another `Communicator`, injecting faults into its calls according to a `FaultPlan`.
+///
+/// Paired with an emulated target, this exercises error handling that's hard to trigger with real devices.
+pub struct Injector {
+    /// `None` once disconnected
+    inner: Option<Box<dyn Communicator>>,
+    plan: FaultPlan,
+    /// State of the random number generator
+    rng: u64,
+    timeout: Duration,
+    calls: usize,
+    sends: usize,
+    recvs: usize,
+    replies: usize,
+    injected: Vec<InjectedFault>,
+}
+
+impl Injector {
+    /// Wrap the given `Communicator`.
+    pub fn new(inner: Box<dyn Communicator>, plan: FaultPlan) -> Injector {
+        let rng = plan.random.as_ref().map(|r| r.seed).unwrap_or(0);
+        let mut injector = Injector {
+            inner: Some(inner),
+            plan,
+            rng,
+            timeout: super::DEFAULT_TIMEOUT,
+            calls: 0,
+            sends: 0,
+            recvs: 0,
+            replies: 0,
+            injected: Vec::new(),
+        };
+        if injector.plan.max_timeout.is_some() {
+            injector.set_timeout(super::DEFAULT_TIMEOUT);
+        }
+        return injector;
+    }
+
+    /// Faults injected so far.
+    pub fn injected(&self) -> &[InjectedFault] {
+        return &self.injected;
+    }
+
+    /// Whether a `Disconnect` fault was injected.
+    pub fn is_disconnected(&self) -> bool {
+        return self.inner.is_none();
+    }
+
+    /// Next number from the random number generator, using SplitMix64.
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        return z ^ (z >> 31);
+    }
+
+    /// Decide which fault to inject into the given call, if any.
+    fn next_fault(&mut self, call: Call) -> Option<Fault> {
+        let is_reply = call == Call::RecvExact(CMD_REPLY_LEN);
+        let scheduled = self
+            .plan
+            .scheduled
+            .iter()
+            .find(|(trigger, fault)| {
+                let hit = match *trigger {
+                    Trigger::Call(n) => n == self.calls,
+                    Trigger::Send(n) => call == Call::Send && n == self.sends,
+                    Trigger::Recv(n) => call != Call::Send && n == self.recvs,
+                    Trigger::Reply(n) => is_reply && n == self.replies,
+                };
+                return hit && fault.applies_to(call);
+            })
+            .map(|(_, fault)| *fault);
+
+        let fault = match (scheduled, self.plan.random.clone()) {
+            (Some(fault), _) => Some(fault),
+            (None, Some(random)) => {
+                let hit = self.next_random().is_multiple_of(u64::from(random.one_in));
+                let pick = self.next_random() as usize;
+                match random.faults.get(pick % random.faults.len().max(1)) {
+                    Some(Fault::Corrupt { .. }) if !is_reply => None,
+                    Some(fault) if hit && fault.applies_to(call) => Some(*fault),
+                    _ => None,
+                }
+            }
+            (None, None) => None,
+        };
+
+        if let Some(fault) = fault {
+            log::debug!(target: "FAULT", "Injecting {fault:?} into call {} ({call:?})", self.calls);
+            self.injected.push(InjectedFault {
+                call: self.calls,
+                fault,
+            });
+        }
+        self.calls += 1;
+        match call {
+            Call::Send => self.sends += 1,
+            Call::RecvExact(_) | Call::Recv => self.recvs += 1,
+        }
+        if is_reply {
+            self.replies += 1;
+        }
+        return fault;
+    }
+
+    fn inner(&mut self) -> IOResult<&mut Box<dyn Communicator>> {
+        return self.inner.as_mut().ok_or(IOError::new(
+            ErrorKind::NotConnected,
+            "Disconnected by injected fault",
+        ));
+    }
+
+    /// Stall for the given time, or until the deadline passes. Returns whether the deadline passed.
+    fn delay(&self, delay: Duration, deadline: Instant) -> bool {
+        let left = deadline.saturating_duration_since(Instant::now());
+        std::thread::sleep(delay.min(left));
+        return delay >= left;
+    }
+
+    /// Bring the deadline forward to the plan's `max_timeout`, if it has one.
+    fn cap_deadline(&self, deadline: Instant) -> Instant {
+        return match self.plan.max_timeout {
+            Some(max) => deadline.min(Instant::now() + max),
+            None => deadline,
+        };
+    }
+
+    fn disconnect(&mut self) {
+        log::debug!(target: "FAULT", "Disconnecting");
+        self.inner = None;
+    }
+}
+
+impl Communicator for Injector {
+    fn send(&mut self, data: &[u8]) -> IOResult<()> {
+        return self.send_before(data, Instant::now() + self.timeout);
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
+        return self.recv_exact_before(how_much, Instant::now() + self.timeout);
+    }
+
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        self.inner()?;
+        let deadline = self.cap_deadline(deadline);
+        match self.next_fault(Call::Send) {
+            // Shorter stalls just delay the call
+            Some(Fault::Delay(d)) if self.delay(d, deadline) => {
+                return Err(IOError::new(ErrorKind::TimedOut, "Injected send timeout"));
+            }
+            Some(Fault::ZeroLength) => self.inner()?.send_before(&[], deadline)?,
+            Some(Fault::Disconnect { after }) => {
+                // Whether the partial send succeeds doesn't matter, the connection is gone either way
+                let _ = self
+                    .inner()?
+                    .send_before(&data[..after.min(data.len())], deadline);
+                self.disconnect();
+                return Err(IOError::new(
+                    ErrorKind::BrokenPipe,
+                    "Injected disconnect during send",
+                ));
+            }
+            _ => {}
+        }
+        return self.inner()?.send_before(data, deadline);
+    }
+
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        self.inner()?;
+        let deadline = self.cap_deadline(deadline);
+        match self.next_fault(Call::RecvExact(how_much)) {
+            Some(Fault::Drop) => {
+                self.inner()?.recv_exact_before(how_much, deadline)?;
+                return Err(IOError::new(ErrorKind::TimedOut, "Injected dropped read"));
+            }
+            Some(Fault::Truncate { len }) => {
+                let mut buf = self.inner()?.recv_exact_before(how_much, deadline)?;
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            Some(Fault::Corrupt { offset, mask }) => {
+                let mut buf = self.inner()?.recv_exact_before(how_much, deadline)?;
+                buf[offset % how_much] ^= mask;
+                return Ok(buf);
+            }
+            // Shorter stalls just delay the call
+            Some(Fault::Delay(d)) if self.delay(d, deadline) => {
+                return Err(IOError::new(ErrorKind::TimedOut, "Injected read timeout"));
+            }
+            Some(Fault::ZeroLength) => return Ok(Vec::new()),
+            Some(Fault::Disconnect { after }) => {
+                let _ = self
+                    .inner()?
+                    .recv_exact_before(after.min(how_much), deadline);
+                self.disconnect();
+                return Err(IOError::new(
+                    ErrorKind::UnexpectedEof,
+                    "Injected disconnect during read",
+                ));
+            }
+            _ => {}
+        }
+        return self.inner()?.recv_exact_before(how_much, deadline);
+    }
+
+    fn recv(&mut self) -> IOResult<Vec<u8>> {
+        self.inner()?;
+        match self.next_fault(Call::Recv) {
+            Some(Fault::Drop) => {
+                self.inner()?.recv()?;
+                return Ok(Vec::new());
+            }
+            // Shorter stalls just delay the call
+            Some(Fault::Delay(d)) if self.delay(d, Instant::now() + self.timeout) => {
+                return Err(IOError::new(ErrorKind::TimedOut, "Injected read timeout"));
+            }
+            Some(Fault::ZeroLength) => return Ok(Vec::new()),
+            Some(Fault::Disconnect { .. }) => {
+                self.disconnect();
+                return Err(IOError::new(
+                    ErrorKind::UnexpectedEof,
+                    "Injected disconnect during read",
+                ));
+            }
+            _ => {}
+        }
+        return self.inner()?.recv();
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        let timeout = match self.plan.max_timeout {
+            Some(max) => timeout.min(max),
+            None => timeout,
+        };
+        self.timeout = timeout;
+        if let Some(inner) = self.inner.as_mut() {
+            inner.set_timeout(timeout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::pipe::Connection as Pipe;
+    use crate::download_protocol::emulator::{spawn_target, Target, TargetOptions};
+    use crate::download_protocol::{ActionAfter, Session};
+    use crate::upload_protocol;
+
+    use pit::Pit;
+    use std::fs::File;
+    use std::thread::JoinHandle;
+
+    const PIT: &str = "../pit/testdata/A40_EUR_OPEN.pit";
+
+    /// Start an emulated download mode target, returning an `Injector` connected to it.
+    /// The target is expected to fail when the host does.
+    /// Timeouts are capped at 200 milliseconds unless the plan has its own cap.
+    fn faulty_target(
+        plan: FaultPlan,
+    ) -> (
+        Box<dyn Communicator>,
+        JoinHandle<crate::Result<Target<File>>>,
+    ) {
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+        let plan = FaultPlan {
+            max_timeout: plan.max_timeout.or(Some(Duration::from_millis(200))),
+            ..plan
+        };
+        let c: Box<dyn Communicator> = Box::new(Injector::new(Box::new(host), plan));
+        return (c, target);
+    }
+
+    /// Begin a session, download the PIT, flash a file and end the session.
+    fn run_session(c: Box<dyn Communicator>) -> crate::Result<()> {
+        let pit = Pit::deserialize(&std::fs::read(PIT).unwrap()).unwrap();
+        let image: Vec<u8> = vec![0x5A; 100_000];
+        let mut sess = Session::begin(c)?;
+        sess.download_pit(sess.params)?;
+        sess.flash(
+            &image,
+            pit.get_entry_by_name("CM").unwrap(),
+            &mut None::<&mut fn(u64)>,
+        )?;
+        sess.end(ActionAfter::Nothing)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn test_plan_format() {
+        let plan = FaultPlan::parse(
+            "# comment\n\
+             call 3 delay 500\n\
+             reply 1 corrupt 0 ff\n\
+             send 2 disconnect 10\n\
+             recv 0 truncate 4\n\
+             seed 1234\n\
+             one-in 20\n\
+             random drop\n\
+             random zero-length\n\
+             max-timeout 1000\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                (Trigger::Call(3), Fault::Delay(Duration::from_millis(500))),
+                (
+                    Trigger::Reply(1),
+                    Fault::Corrupt {
+                        offset: 0,
+                        mask: 0xFF
+                    }
+                ),
+                (Trigger::Send(2), Fault::Disconnect { after: 10 }),
+                (Trigger::Recv(0), Fault::Truncate { len: 4 }),
+            ],
+            plan.scheduled
+        );
+        assert_eq!(
+            Some(RandomFaults {
+                seed: 1234,
+                one_in: 20,
+                faults: vec![Fault::Drop, Fault::ZeroLength]
+            }),
+            plan.random
+        );
+        assert_eq!(Some(Duration::from_millis(1000)), plan.max_timeout);
+        assert!(FaultPlan::parse("reply x drop").is_err());
+        assert!(FaultPlan::parse("one-in 0").is_err());
+    }
+
+    #[test]
+    fn test_scheduled_faults() {
+        // Without faults, everything works
+        let (c, target) = faulty_target(FaultPlan::default());
+        run_session(c).unwrap();
+        target.join().unwrap().unwrap();
+
+        let faults = [
+            (Trigger::Reply(0), Fault::Drop),
+            (Trigger::Reply(2), Fault::Truncate { len: 3 }),
+            (
+                Trigger::Reply(4),
+                Fault::Corrupt {
+                    offset: 0,
+                    mask: 0x80,
+                },
+            ),
+            (
+                Trigger::Reply(1),
+                Fault::Corrupt {
+                    offset: 4,
+                    mask: 0x01,
+                },
+            ),
+            (Trigger::Recv(0), Fault::Delay(Duration::from_secs(60))),
+            (Trigger::Recv(3), Fault::ZeroLength),
+            (Trigger::Send(5), Fault::Disconnect { after: 100 }),
+            (Trigger::Recv(6), Fault::Disconnect { after: 0 }),
+        ];
+        for (trigger, fault) in faults {
+            let (c, target) = faulty_target(FaultPlan {
+                scheduled: vec![(trigger, fault)],
+                ..Default::default()
+            });
+            let start = Instant::now();
+            assert!(
+                run_session(c).is_err(),
+                "{fault:?} at {trigger:?} went unnoticed"
+            );
+            assert!(start.elapsed() < Duration::from_secs(5));
+            let _ = target.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_timeout_policy() {
+        use crate::download_protocol::TimeoutPolicy;
+
+        let short = Duration::from_millis(100);
+        let timeouts = TimeoutPolicy {
+            handshake: short,
+            command: short,
+            pit: short,
+            part_ack: short,
+            sequence_end: short,
+            erase: Duration::from_secs(2),
+        };
+        let slow_reply = |reply| FaultPlan {
+            scheduled: vec![(
+                Trigger::Reply(reply),
+                Fault::Delay(Duration::from_millis(300)),
+            )],
+            max_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+
+        // Replies: begin session, packet size, erase, end session.
+        // A slow erase is fine, as it has its own timeout
+        let (c, target) = faulty_target(slow_reply(2));
+        let mut sess = Session::begin_with_timeouts(c, timeouts).unwrap();
+        assert_eq!(timeouts, sess.params.timeouts);
+        sess.factory_reset().unwrap();
+        sess.end(ActionAfter::Nothing).unwrap();
+        target.join().unwrap().unwrap();
+
+        // Other commands still time out quickly
+        let (c, target) = faulty_target(slow_reply(3));
+        let mut sess = Session::begin_with_timeouts(c, timeouts).unwrap();
+        sess.factory_reset().unwrap();
+        match sess
+            .end(ActionAfter::Nothing)
+            .as_ref()
+            .map_err(crate::Error::without_context)
+        {
+            Err(crate::Error::TransferError(crate::error::TransferError::Io(e))) => {
+                assert_eq!(ErrorKind::TimedOut, e.kind())
+            }
+            other => panic!("Expected a timeout, got {other:?}"),
+        }
+        let _ = target.join().unwrap();
+
+        // Without overrides, timeouts depend on the protocol version
+        let (c, target) = faulty_target(FaultPlan::default());
+        let sess = Session::begin(c).unwrap();
+        assert_eq!(
+            TimeoutPolicy::for_version(sess.params.proto_version),
+            sess.params.timeouts
+        );
+        sess.end(ActionAfter::Nothing).unwrap();
+        target.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_random_faults() {
+        let faults = vec![
+            Fault::Drop,
+            Fault::Truncate { len: 1 },
+            Fault::Corrupt {
+                offset: 0,
+                mask: 0xFF,
+            },
+            Fault::Delay(Duration::from_secs(60)),
+            Fault::ZeroLength,
+            Fault::Disconnect { after: 3 },
+        ];
+        for seed in 0..20 {
+            let (c, target) = faulty_target(FaultPlan {
+                scheduled: Vec::new(),
+                random: Some(RandomFaults {
+                    seed,
+                    one_in: 8,
+                    faults: faults.clone(),
+                }),
+                ..Default::default()
+            });
+            // Some faults go unnoticed, such as extra empty transfers where one is expected anyway.
+            // What matters is that no fault causes a panic or a hang.
+            let start = Instant::now();
+            let _ = run_session(c);
+            assert!(start.elapsed() < Duration::from_secs(5), "Seed {seed} hung");
+            let _ = target.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_upload_mode_faults() {
+        use upload_protocol::emulator::{MemoryRegion, TargetConfig};
+
+        let faults = [
+            (Trigger::Recv(0), Fault::Drop),
+            (Trigger::Recv(0), Fault::Truncate { len: 5 }),
+            (
+                Trigger::Recv(0),
+                Fault::Corrupt {
+                    offset: 2,
+                    mask: 0x20,
+                },
+            ),
+            (Trigger::Send(0), Fault::Disconnect { after: 4 }),
+            (Trigger::Recv(0), Fault::ZeroLength),
+            // Probing, with the table's header, the DRAM entry and the terminating entry as reads 1 to 3
+            (Trigger::Send(1), Fault::Disconnect { after: 0 }),
+            (Trigger::Recv(1), Fault::ZeroLength),
+            (Trigger::Recv(1), Fault::Truncate { len: 5 }),
+            (Trigger::Recv(2), Fault::Truncate { len: 20 }),
+            (Trigger::Recv(2), Fault::ZeroLength),
+            // Name no longer valid UTF-8
+            (
+                Trigger::Recv(2),
+                Fault::Corrupt {
+                    offset: 4,
+                    mask: 0x80,
+                },
+            ),
+            // End address before the start address
+            (
+                Trigger::Recv(2),
+                Fault::Corrupt {
+                    offset: 35,
+                    mask: 0x80,
+                },
+            ),
+            (Trigger::Recv(3), Fault::Drop),
+        ];
+        for (trigger, fault) in faults {
+            let (host, target) = Pipe::pair();
+            let mut emulator = upload_protocol::emulator::Target::new(TargetConfig {
+                device_name: String::from("EMULATED"),
+                bitness: upload_protocol::Bitness::SixtyFour,
+                regions: vec![MemoryRegion {
+                    name: String::from("DRAM"),
+                    partition_type: 0,
+                    start_addr: 0x8000_0000,
+                    image: Box::new(std::io::Cursor::new(vec![0; 0x1000])),
+                }],
+            })
+            .unwrap();
+            let target = std::thread::spawn(move || {
+                let mut c: Box<dyn Communicator> = Box::new(target);
+                let _ = emulator.serve(&mut c);
+            });
+
+            let plan = FaultPlan {
+                scheduled: vec![(trigger, fault)],
+                ..Default::default()
+            };
+            let mut c: Box<dyn Communicator> = Box::new(Injector::new(Box::new(host), plan));
+            c.set_timeout(Duration::from_millis(200));
+            let result =
+                upload_protocol::handshake(&mut c).and_then(|()| upload_protocol::probe(&mut c));
+            assert!(result.is_err(), "{fault:?} on {trigger:?} went unnoticed");
+            drop(c);
+            target.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use super::*;
+
+use std::io::{ErrorKind, Result as IOResult};
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer};
+
+/// Default length of the intervals throughput is sampled over
+const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Durations are serialized as fractional seconds, which is easier to work with than serde's default.
+#[cfg(feature = "serde")]
+fn serialize_secs<S: Serializer>(d: &Duration, s: S) -> std::result::Result<S::Ok, S::Error> {
+    return s.serialize_f64(d.as_secs_f64());
+}
+
+/// Latency of the calls in one direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct LatencyStats {
+    /// Number of calls, including failed ones.
+    pub calls: u64,
+    /// Time spent in all calls together.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_secs"))]
+    pub total: Duration,
+    /// Latency of the fastest call.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_secs"))]
+    pub min: Duration,
+    /// Latency of the slowest call.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_secs"))]
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    fn record(&mut self, latency: Duration) {
+        self.min = if self.calls == 0 {
+            latency
+        } else {
+            self.min.min(latency)
+        };
+        self.max = self.max.max(latency);
+        self.total += latency;
+        self.calls += 1;
+    }
+
+    /// Average latency of a call. Zero if there were none.
+    pub fn mean(&self) -> Duration {
+        if self.calls == 0 {
+            return Duration::ZERO;
+        }
+        return self.total.div_f64(self.calls as f64);
+    }
+}
+
+/// Traffic in one direction.
+///
+/// Polling reads that returned no data aren't counted at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DirectionStats {
+    /// Successful transfers.
+    pub transfers: u64,
+    /// Successful transfers without any data.
+    pub zero_length: u64,
+    /// Bytes transferred successfully.
+    pub bytes: u64,
+    /// Calls that failed because they timed out.
+    pub timeouts: u64,
+    /// Calls that failed for any other reason.
+    pub errors: u64,
+    /// Latency of all calls.
+    pub latency: LatencyStats,
+}
+
+impl DirectionStats {
+    /// Bytes per second while transferring, not counting the time spent between calls.
+    pub fn throughput(&self) -> f64 {
+        if self.latency.total.is_zero() {
+            return 0.0;
+        }
+        return self.bytes as f64 / self.latency.total.as_secs_f64();
+    }
+}
+
+/// Bytes transferred during one sampling interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ThroughputSample {
+    /// When the interval started, relative to the start of the measurement.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_secs"))]
+    pub start: Duration,
+    /// Bytes sent to the target during the interval.
+    pub sent: u64,
+    /// Bytes received from the target during the interval.
+    pub received: u64,
+}
+
+/// Everything `Metrics` measured, as of the time the snapshot was taken.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct MetricsSnapshot {
+    /// Time since the measurement started.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_secs"))]
+    pub elapsed: Duration,
+    /// Length of the intervals in `samples`.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_secs"))]
+    pub sample_interval: Duration,
+    /// Traffic to the target.
+    pub sent: DirectionStats,
+    /// Traffic from the target.
+    pub received: DirectionStats,
+    /// Throughput over time, one sample per interval since the measurement started.
+    pub samples: Vec<ThroughputSample>,
+}
+
+impl MetricsSnapshot {
+    /// Bytes per second in both directions together, over the whole measurement.
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        return (self.sent.bytes + self.received.bytes) as f64 / self.elapsed.as_secs_f64();
+    }
+
+    /// Calls that timed out, in both directions.
+    pub fn timeouts(&self) -> u64 {
+        return self.sent.timeouts + self.received.timeouts;
+    }
+}
+
+/// State shared between `Metrics` and its handles.
+struct State {
+    start: Instant,
+    snapshot: MetricsSnapshot,
+}
+
+impl State {
+    fn new(sample_interval: Duration) -> State {
+        return State {
+            start: Instant::now(),
+            snapshot: MetricsSnapshot {
+                sample_interval,
+                ..Default::default()
+            },
+        };
+    }
+
+    /// Record a call, which either transferred the given number of bytes or failed with the given kind of error.
+    fn record(
+        &mut self,
+        sent: bool,
+        began: Instant,
+        result: std::result::Result<usize, ErrorKind>,
+    ) {
+        let now = Instant::now();
+        let stats = if sent {
+            &mut self.snapshot.sent
+        } else {
+            &mut self.snapshot.received
+        };
+        stats.latency.record(now - began);
+        let len = match result {
+            Ok(len) => len,
+            // Sockets report their read timeouts as `WouldBlock` on some platforms
+            Err(ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                stats.timeouts += 1;
+                return;
+            }
+            Err(_) => {
+                stats.errors += 1;
+                return;
+            }
+        };
+        stats.transfers += 1;
+        stats.bytes += len as u64;
+        if len == 0 {
+            stats.zero_length += 1;
+        }
+
+        let interval = self.snapshot.sample_interval;
+        let index = ((now - self.start).as_nanos() / interval.as_nanos()) as usize;
+        let samples = &mut self.snapshot.samples;
+        while samples.len() <= index {
+            samples.push(ThroughputSample {
+                start: interval * samples.len() as u32,
+                ..Default::default()
+            });
+        }
+        if sent {
+            samples[index].sent += len as u64;
+        } else {
+            samples[index].received += len as u64;
+        }
+    }
+}
+
+/// Handle for querying and resetting the measurements of a `Metrics`, from anywhere.
+///
+/// Clones share the same measurements, which stay available after the `Metrics` is dropped.
+#[derive(Clone)]
+pub struct MetricsHandle {
+    state: Arc<Mutex<State>>,
+}
+
+impl MetricsHandle {
+    /// Everything measured so far.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let state = self.state.lock().unwrap();
+        let mut snapshot = state.snapshot.clone();
+        snapshot.elapsed = state.start.elapsed();
+        return snapshot;
+    }
+
+    /// Throw away all measurements and start over.
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = State::new(state.snapshot.sample_interval);
+    }
+}
+
+/// `Metrics` wraps another `Communicator`, measuring the traffic passing through it.
+///
+/// Use `handle` to get at the measurements, since the `Metrics` itself usually ends up inside a `Session`.
+pub struct Metrics {
+    inner: Box<dyn Communicator>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Metrics {
+    /// Wrap the given `Communicator`, sampling throughput every second.
+    pub fn new(inner: Box<dyn Communicator>) -> Metrics {
+        return Metrics::with_sample_interval(inner, DEFAULT_SAMPLE_INTERVAL);
+    }
+
+    /// Wrap the given `Communicator`, sampling throughput over intervals of the given length.
+    ///
+    /// # Panics
+    /// Panics if the interval is zero.
+    pub fn with_sample_interval(inner: Box<dyn Communicator>, interval: Duration) -> Metrics {
+        assert!(!interval.is_zero(), "Sample interval must not be zero");
+        return Metrics {
+            inner,
+            state: Arc::new(Mutex::new(State::new(interval))),
+        };
+    }
+
+    /// A handle to the measurements.
+    pub fn handle(&self) -> MetricsHandle {
+        return MetricsHandle {
+            state: self.state.clone(),
+        };
+    }
+
+    fn record(&self, sent: bool, began: Instant, result: std::result::Result<usize, ErrorKind>) {
+        self.state.lock().unwrap().record(sent, began, result);
+    }
+}
+
+impl Communicator for Metrics {
+    fn send(&mut self, data: &[u8]) -> IOResult<()> {
+        let began = Instant::now();
+        let result = self.inner.send(data);
+        self.record(
+            true,
+            began,
+            result.as_ref().map(|()| data.len()).map_err(|e| e.kind()),
+        );
+        return result;
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
+        let began = Instant::now();
+        let result = self.inner.recv_exact(how_much);
+        self.record(
+            false,
+            began,
+            result.as_ref().map(|d| d.len()).map_err(|e| e.kind()),
+        );
+        return result;
+    }
+
+    fn recv(&mut self) -> IOResult<Vec<u8>> {
+        let began = Instant::now();
+        let result = self.inner.recv();
+        match &result {
+            Ok(data) if data.is_empty() => {}
+            Ok(data) => self.record(false, began, Ok(data.len())),
+            Err(e) => self.record(false, began, Err(e.kind())),
+        }
+        return result;
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.set_timeout(timeout);
+    }
+
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        let began = Instant::now();
+        let result = self.inner.send_before(data, deadline);
+        self.record(
+            true,
+            began,
+            result.as_ref().map(|()| data.len()).map_err(|e| e.kind()),
+        );
+        return result;
+    }
+
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        let began = Instant::now();
+        let result = self.inner.recv_exact_before(how_much, deadline);
+        self.record(
+            false,
+            began,
+            result.as_ref().map(|d| d.len()).map_err(|e| e.kind()),
+        );
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::pipe::Connection as Pipe;
+    use crate::download_protocol::emulator::{spawn_target, TargetOptions};
+    use crate::download_protocol::{ActionAfter, Session};
+
+    #[test]
+    fn test_session_metrics() {
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+
+        let mut sess = Session::begin_with_metrics(Box::new(host)).unwrap();
+        let handle = sess.metrics().unwrap();
+        let pit = sess.download_pit(sess.params).unwrap();
+        sess.end(ActionAfter::Nothing).unwrap();
+        target.join().unwrap().unwrap();
+
+        let m = handle.snapshot();
+        assert!(m.received.bytes > pit.len() as u64);
+        assert!(m.sent.transfers > 0);
+        assert_eq!(0, m.timeouts());
+        assert_eq!(0, m.sent.errors + m.received.errors);
+        assert_eq!(
+            m.sent.transfers + m.sent.timeouts + m.sent.errors,
+            m.sent.latency.calls
+        );
+        assert!(m.sent.latency.min <= m.sent.latency.mean());
+        assert!(m.sent.latency.mean() <= m.sent.latency.max);
+        assert_eq!(m.sent.bytes, m.samples.iter().map(|s| s.sent).sum::<u64>());
+        assert_eq!(
+            m.received.bytes,
+            m.samples.iter().map(|s| s.received).sum::<u64>()
+        );
+
+        handle.reset();
+        assert_eq!(MetricsSnapshot::default().sent, handle.snapshot().sent);
+    }
+
+    #[test]
+    fn test_timeouts_and_samples() {
+        let (host, mut target) = Pipe::pair();
+        let mut m = Metrics::with_sample_interval(Box::new(host), Duration::from_millis(20));
+        let handle = m.handle();
+        m.set_timeout(Duration::from_millis(1));
+
+        m.send(&[1, 2, 3]).unwrap();
+        assert_eq!(ErrorKind::TimedOut, m.recv_exact(4).unwrap_err().kind());
+        assert!(m.recv().unwrap().is_empty());
+        std::thread::sleep(Duration::from_millis(50));
+        target.send(&[4, 5]).unwrap();
+        assert_eq!(vec![4, 5], m.recv_exact(2).unwrap());
+        m.send(&[]).unwrap();
+
+        let s = handle.snapshot();
+        assert_eq!(2, s.sent.transfers);
+        assert_eq!(1, s.sent.zero_length);
+        assert_eq!(3, s.sent.bytes);
+        assert_eq!(1, s.received.transfers);
+        assert_eq!(2, s.received.bytes);
+        assert_eq!(1, s.received.timeouts);
+        assert_eq!(2, s.received.latency.calls);
+        assert!(s.samples.len() >= 3);
+        assert_eq!(3, s.samples[0].sent);
+        assert_eq!(2, s.samples.last().unwrap().received);
+        assert_eq!(Duration::from_millis(40), s.samples[2].start);
+    }
+}
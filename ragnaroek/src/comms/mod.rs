@@ -1,15 +1,32 @@
 /// This module implements low-level communication with the target device.
 /// It does not actually understand protocol details, but only provides dumb bidirectional pipes.
+#[cfg(feature = "async")]
+pub mod async_io;
+pub mod capture;
+pub mod fault;
+pub mod metrics;
 pub mod net_bind;
 pub mod net_connect;
+pub mod pipe;
+pub mod relay;
+#[cfg(unix)]
+pub mod serial;
+pub mod transcript;
 pub mod usb;
 
 pub use std::io::Result;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Default timeout in seconds
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// All the Odin .ini files I could find only ever mention this port
+pub const WIRELESS_PORT: u16 = 13579;
+/// All the targets implementing wireless mode seem to use this IP
+pub const WIRELESS_TARGET_IP: &str = "192.168.49.1";
+
 /// This trait implements an interface that allows for decoupling
 /// the transport of bytes to and from the target from the actual Odin protocol implementation.
 pub trait Communicator: Send {
@@ -27,6 +44,121 @@ pub trait Communicator: Send {
     fn recv(&mut self) -> Result<Vec<u8>>;
     /// Set a timeout for this `Communicator`. Default is 30 seconds.
     fn set_timeout(&mut self, timeout: Duration);
+
+    /// Like `send`, but fails with `ErrorKind::TimedOut` unless all data was sent before `deadline`.
+    ///
+    /// The default implementation applies the time left with `set_timeout`, which stays in effect afterwards.
+    /// All transports in this crate override it, leaving the timeout set by the caller alone.
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> Result<()> {
+        self.set_timeout(time_left(deadline)?);
+        return self.send(data);
+    }
+
+    /// Like `recv_exact`, but fails with `ErrorKind::TimedOut` unless all data arrived before `deadline`.
+    ///
+    /// The default implementation applies the time left with `set_timeout`, which stays in effect afterwards.
+    /// All transports in this crate override it, leaving the timeout set by the caller alone.
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> Result<Vec<u8>> {
+        self.set_timeout(time_left(deadline)?);
+        return self.recv_exact(how_much);
+    }
+}
+
+/// Time left until the given deadline, or a timeout error if it has passed.
+fn time_left(deadline: Instant) -> Result<Duration> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "Deadline passed",
+        ));
+    }
+    return Ok(left);
+}
+
+/// Handle for cancelling a long-running blocking operation, such as waiting for a device, from another thread.
+///
+/// Clones share the same state, so cancelling one cancels all of them.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// Create a new handle that's not cancelled.
+    pub fn new() -> CancelHandle {
+        return CancelHandle::default();
+    }
+
+    /// Request cancellation. Operations notice this within a short polling interval.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether cancellation was requested.
+    pub fn is_cancelled(&self) -> bool {
+        return self.cancelled.load(Ordering::SeqCst);
+    }
+}
+
+/// Apply the given timeout to both directions of a TCP stream.
+///
+/// A zero timeout can't be represented by sockets, it's rounded up to the smallest one that can.
+fn set_stream_timeout(s: &std::net::TcpStream, timeout: Duration) {
+    let timeout = timeout.max(Duration::from_millis(1));
+    if let Err(e) = s
+        .set_read_timeout(Some(timeout))
+        .and_then(|()| s.set_write_timeout(Some(timeout)))
+    {
+        log::warn!(target: "NET", "Failed to set timeout: {e}");
+    }
+}
+
+/// Run `f` on a TCP stream with the time left until `deadline` as its timeout.
+/// The stream's previous timeouts are restored afterwards.
+fn with_stream_deadline<T>(
+    s: &mut std::net::TcpStream,
+    deadline: Instant,
+    f: impl FnOnce(&mut std::net::TcpStream) -> Result<T>,
+) -> Result<T> {
+    let read_timeout = s.read_timeout()?;
+    let write_timeout = s.write_timeout()?;
+    set_stream_timeout(s, time_left(deadline)?);
+    let ret = f(s);
+    if let Err(e) = s
+        .set_read_timeout(read_timeout)
+        .and_then(|()| s.set_write_timeout(write_timeout))
+    {
+        log::warn!(target: "NET", "Failed to restore timeout: {e}");
+    }
+    return ret;
+}
+
+/// Read whatever data is waiting on a TCP stream, without blocking.
+///
+/// Returns an empty buffer if nothing is waiting, and an `UnexpectedEof` error if the other end closed the connection.
+fn recv_stream_nonblocking(s: &mut std::net::TcpStream) -> Result<Vec<u8>> {
+    use std::io::{ErrorKind, Read};
+
+    /// Upper bound for the amount of data returned at once
+    const RECV_BUF_SIZE: usize = 64 * 1024;
+
+    let mut buf = vec![0; RECV_BUF_SIZE];
+    s.set_nonblocking(true)?;
+    let result = s.read(&mut buf);
+    s.set_nonblocking(false)?;
+    match result {
+        Ok(0) => {
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection was closed",
+            ))
+        }
+        Ok(n) => buf.truncate(n),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => buf.clear(),
+        Err(e) => return Err(e),
+    }
+    return Ok(buf);
 }
 
 /// Helper feature for debug logging
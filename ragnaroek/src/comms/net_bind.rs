@@ -1,37 +1,93 @@
 use super::*;
 
-use std::io::{Read, Result as IOResult, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io::{ErrorKind, Read, Result as IOResult, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Instant;
+
+/// How often to check for incoming connections while accepting with a timeout
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Listener accepts new wireless AP ODIN mode connections.
 pub struct Listener {
     l: TcpListener,
+    /// Read and write timeout applied to accepted connections.
+    timeout: Duration,
 }
 
 impl Listener {
-    /// Create a new listener listening on the given port, on all interfaces.
-    /// IPv4 only.
-    pub fn new(port: u16) -> Listener {
+    /// Create a new listener listening on the given port, on all IPv4 interfaces.
+    pub fn new(port: u16) -> IOResult<Listener> {
         // All currently known devices do not use IPv6
-        let l = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
+        return Listener::bind((Ipv4Addr::UNSPECIFIED, port));
+    }
+
+    /// Create a new listener listening on the given address.
+    /// Pass an IPv6 address such as `[::]:13579` to accept IPv6 connections.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> IOResult<Listener> {
+        let l = TcpListener::bind(addr)?;
+
+        log::debug!(target: "NET", "Listening on {}", l.local_addr()?);
+        return Ok(Listener {
+            l,
+            timeout: super::DEFAULT_TIMEOUT,
+        });
+    }
+
+    /// The address the listener is bound to. Useful to find out the port when binding to port 0.
+    pub fn local_addr(&self) -> IOResult<SocketAddr> {
+        return self.l.local_addr();
+    }
 
-        log::debug!(target: "NET", "Listening");
-        return Listener { l };
+    /// Set the read and write timeout of connections accepted from now on. Default is 30 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
     }
 
     /// Blocks until a device is connected.
     /// Returns a `Connection` once this happens.
     pub fn accept(&mut self) -> IOResult<Connection> {
-        let (stream, _) = self.l.accept()?;
-
-        log::debug!(target: "NET", "Accepted");
-        stream
-            .set_read_timeout(Some(super::DEFAULT_TIMEOUT))
-            .unwrap();
-        stream
-            .set_write_timeout(Some(super::DEFAULT_TIMEOUT))
-            .unwrap();
-        return Ok(Connection { s: stream });
+        let (stream, peer) = self.l.accept()?;
+
+        log::debug!(target: "NET", "Accepted {peer}");
+        return Connection::from_stream(stream, self.timeout);
+    }
+
+    /// Blocks until a device is connected, the timeout passes or `cancel` is cancelled.
+    ///
+    /// Fails with `ErrorKind::TimedOut` or `ErrorKind::Interrupted` respectively.
+    pub fn accept_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+        cancel: &CancelHandle,
+    ) -> IOResult<Connection> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        self.l.set_nonblocking(true)?;
+        let result = loop {
+            match self.l.accept() {
+                Ok((stream, peer)) => {
+                    log::debug!(target: "NET", "Accepted {peer}");
+                    break stream.set_nonblocking(false).map(|()| stream);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => break Err(e),
+            }
+            if cancel.is_cancelled() {
+                break Err(std::io::Error::new(
+                    ErrorKind::Interrupted,
+                    "Accepting a connection was cancelled",
+                ));
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                break Err(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    "Timed out waiting for a connection",
+                ));
+            }
+            std::thread::sleep(ACCEPT_POLL_INTERVAL);
+        };
+        self.l.set_nonblocking(false)?;
+
+        return Connection::from_stream(result?, self.timeout);
     }
 }
 
@@ -41,6 +97,13 @@ pub struct Connection {
     s: TcpStream,
 }
 
+impl Connection {
+    fn from_stream(s: TcpStream, timeout: Duration) -> IOResult<Connection> {
+        set_stream_timeout(&s, timeout);
+        return Ok(Connection { s });
+    }
+}
+
 impl Communicator for Connection {
     /// Sends the given data to the device.
     /// Blocks until all data could be sent or an error occurs.
@@ -57,28 +120,27 @@ impl Communicator for Connection {
         return Ok(buf);
     }
 
-    #[allow(clippy::read_zero_byte_vec)] // Handled, but clippy is too stupid to notice
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        log::trace!(target: "NET", "Send: {}", format_data_buf(data));
+        return with_stream_deadline(&mut self.s, deadline, |s| s.write_all(data));
+    }
+
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        let mut buf = vec![0; how_much];
+        with_stream_deadline(&mut self.s, deadline, |s| s.read_exact(&mut buf))?;
+
+        log::trace!(target: "NET", "Recv exact: {}", format_data_buf(&buf));
+        return Ok(buf);
+    }
+
     fn recv(&mut self) -> Result<Vec<u8>> {
-        let mut buf = vec![];
-        let bytes_read = self.s.read(&mut buf)?;
+        let buf = recv_stream_nonblocking(&mut self.s)?;
         log::trace!(target: "NET", "Recv nonblocking: {}", format_data_buf(&buf));
-        // Should probably never happen
-        if bytes_read == 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Read zero bytes",
-            ));
-        }
         return Ok(buf);
     }
 
     fn set_timeout(&mut self, timeout: Duration) {
-        log::info!(target: "NET", "Setting timeout: {timeout:?}");
-        self.s
-            .set_read_timeout(Some(super::DEFAULT_TIMEOUT))
-            .unwrap();
-        self.s
-            .set_write_timeout(Some(super::DEFAULT_TIMEOUT))
-            .unwrap();
+        log::debug!(target: "NET", "Setting timeout: {timeout:?}");
+        set_stream_timeout(&self.s, timeout);
     }
 }
@@ -1,7 +1,32 @@
 use super::*;
 
-use std::io::{Read, Result as IOResult, Write};
-use std::net::TcpStream;
+use std::io::{ErrorKind, Read, Result as IOResult, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Instant;
+
+/// How to establish a connection to the target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectOptions {
+    /// Timeout of a single connection attempt.
+    pub connect_timeout: Duration,
+    /// How often to try connecting before giving up. At least one attempt is always made.
+    pub attempts: u32,
+    /// Delay before the second attempt. It doubles with every further attempt.
+    pub backoff: Duration,
+    /// Read and write timeout of the established connection.
+    pub timeout: Duration,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        return ConnectOptions {
+            connect_timeout: Duration::from_secs(5),
+            attempts: 3,
+            backoff: Duration::from_millis(500),
+            timeout: super::DEFAULT_TIMEOUT,
+        };
+    }
+}
 
 /// Connector manages the TCP connection to the target.
 pub struct Connection {
@@ -9,14 +34,48 @@ pub struct Connection {
 }
 
 impl Connection {
-    /// Establishes a new connection to the target.
-    pub fn new(target_ip: &str, port: u16) -> Connection {
-        let s = TcpStream::connect(format!("{}:{}", target_ip, port)).unwrap();
+    /// Establishes a new connection to the target, using the default `ConnectOptions`.
+    pub fn new(target_ip: &str, port: u16) -> IOResult<Connection> {
+        return Connection::connect((target_ip, port), &ConnectOptions::default());
+    }
+
+    /// Establishes a new connection to the target at the given address, IPv4 or IPv6.
+    ///
+    /// If the address resolves to several socket addresses, each is tried in turn on every attempt.
+    /// Returns the error of the last attempt if all of them fail.
+    pub fn connect<A: ToSocketAddrs>(addr: A, options: &ConnectOptions) -> IOResult<Connection> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let mut last_err =
+            std::io::Error::new(ErrorKind::InvalidInput, "Address resolved to nothing");
+        let mut backoff = options.backoff;
+
+        for attempt in 1..=options.attempts.max(1) {
+            for addr in &addrs {
+                match TcpStream::connect_timeout(addr, options.connect_timeout) {
+                    Ok(s) => {
+                        log::debug!(target: "NET", "Connected to {addr}");
+                        set_stream_timeout(&s, options.timeout);
+                        return Ok(Connection { s });
+                    }
+                    Err(e) => {
+                        log::debug!(target: "NET", "Connection attempt {attempt} to {addr} failed: {e}");
+                        last_err = e;
+                    }
+                }
+            }
+            if attempt < options.attempts {
+                std::thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+            }
+        }
+        return Err(last_err);
+    }
+}
 
-        log::debug!(target: "NET", "Connected");
-        s.set_read_timeout(Some(super::DEFAULT_TIMEOUT)).unwrap();
-        s.set_write_timeout(Some(super::DEFAULT_TIMEOUT)).unwrap();
-        return Connection { s };
+impl Connection {
+    /// Give up the `Connection`, returning the underlying stream.
+    pub(crate) fn into_stream(self) -> TcpStream {
+        return self.s;
     }
 }
 
@@ -36,28 +95,125 @@ impl Communicator for Connection {
         return Ok(buf);
     }
 
-    #[allow(clippy::read_zero_byte_vec)] // Handled, but clippy is too stupid to notice
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        log::trace!(target: "NET", "Send: {}", format_data_buf(data));
+        return with_stream_deadline(&mut self.s, deadline, |s| s.write_all(data));
+    }
+
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        let mut buf = vec![0; how_much];
+        with_stream_deadline(&mut self.s, deadline, |s| s.read_exact(&mut buf))?;
+
+        log::trace!(target: "NET", "Recv exact: {}", format_data_buf(&buf));
+        return Ok(buf);
+    }
+
     fn recv(&mut self) -> IOResult<Vec<u8>> {
-        let mut buf = vec![];
-        let bytes_read = self.s.read(&mut buf)?;
-        // Should probably never happen
-        if bytes_read == 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Read zero bytes",
-            ));
-        }
+        let buf = recv_stream_nonblocking(&mut self.s)?;
         log::trace!(target: "NET", "Recv nonblocking: {}", format_data_buf(&buf));
         return Ok(buf);
     }
 
     fn set_timeout(&mut self, timeout: Duration) {
-        log::info!(target: "NET", "Setting timeout: {timeout:?}");
-        self.s
-            .set_read_timeout(Some(super::DEFAULT_TIMEOUT))
+        log::debug!(target: "NET", "Setting timeout: {timeout:?}");
+        set_stream_timeout(&self.s, timeout);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::net_bind::Listener;
+
+    #[test]
+    fn test_connect_and_timeouts() {
+        let mut listener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancelHandle::new();
+
+        // Nobody connects
+        let err = listener
+            .accept_timeout(Some(Duration::from_millis(50)), &cancel)
+            .err()
+            .unwrap();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+
+        let mut client = Connection::connect(addr, &ConnectOptions::default()).unwrap();
+        let mut server = listener.accept_timeout(None, &cancel).unwrap();
+        client.send(b"ODIN").unwrap();
+        assert_eq!(b"ODIN".to_vec(), server.recv_exact(4).unwrap());
+
+        // The timeout passed in is the one applied
+        server.set_timeout(Duration::from_millis(50));
+        let err = server.recv_exact(1).err().unwrap();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+
+        // Deadlines apply to a single call, the timeout set before stays in effect
+        server.set_timeout(Duration::from_secs(5));
+        let start = Instant::now();
+        let err = server
+            .recv_exact_before(1, start + Duration::from_millis(50))
+            .err()
             .unwrap();
-        self.s
-            .set_write_timeout(Some(super::DEFAULT_TIMEOUT))
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            client.send(b"!").unwrap();
+        });
+        assert_eq!(b"!".to_vec(), server.recv_exact(1).unwrap());
+        sender.join().unwrap();
+
+        cancel.cancel();
+        let err = listener.accept_timeout(None, &cancel).err().unwrap();
+        assert_eq!(ErrorKind::Interrupted, err.kind());
+    }
+
+    #[test]
+    fn test_recv_nonblocking() {
+        let mut listener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = Connection::connect(addr, &ConnectOptions::default()).unwrap();
+        let mut server = listener.accept_timeout(None, &CancelHandle::new()).unwrap();
+
+        // Nothing waiting yet
+        assert_eq!(Vec::<u8>::new(), client.recv().unwrap());
+        assert_eq!(Vec::<u8>::new(), server.recv().unwrap());
+
+        server.send(b"LOKE").unwrap();
+        let mut received = Vec::new();
+        while received.len() < 4 {
+            received.extend(client.recv().unwrap());
+        }
+        assert_eq!(b"LOKE".to_vec(), received);
+
+        // A closed connection is reported instead of looking like silence
+        drop(server);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(ErrorKind::UnexpectedEof, client.recv().unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_connect_retries() {
+        // Grab a free port, then close it again so connections are refused
+        let port = Listener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let options = ConnectOptions {
+            attempts: 2,
+            backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let err = Connection::connect(("127.0.0.1", port), &options)
+            .err()
             .unwrap();
+        assert_eq!(ErrorKind::ConnectionRefused, err.kind());
     }
 }
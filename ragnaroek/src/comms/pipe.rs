@@ -0,0 +1,117 @@
+use super::*;
+
+use std::collections::VecDeque;
+use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+/// One direction of a pipe.
+#[derive(Default)]
+struct Channel {
+    data: Mutex<ChannelState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct ChannelState {
+    buf: VecDeque<u8>,
+    /// Set once either end is dropped
+    closed: bool,
+}
+
+impl Channel {
+    fn close(&self) {
+        self.data.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// `Connection` is one end of an in-process pipe, connecting a host to an emulated target without any I/O.
+///
+/// Like TCP, pipes are byte streams: empty sends do nothing and `recv_exact(0)` returns immediately.
+pub struct Connection {
+    tx: Arc<Channel>,
+    rx: Arc<Channel>,
+    timeout: Duration,
+}
+
+impl Connection {
+    /// Create both ends of a new pipe.
+    pub fn pair() -> (Connection, Connection) {
+        let a = Arc::new(Channel::default());
+        let b = Arc::new(Channel::default());
+        return (
+            Connection {
+                tx: a.clone(),
+                rx: b.clone(),
+                timeout: super::DEFAULT_TIMEOUT,
+            },
+            Connection {
+                tx: b,
+                rx: a,
+                timeout: super::DEFAULT_TIMEOUT,
+            },
+        );
+    }
+}
+
+impl Communicator for Connection {
+    fn send(&mut self, data: &[u8]) -> IOResult<()> {
+        log::trace!(target: "PIPE", "Send: {}", format_data_buf(data));
+        let mut state = self.tx.data.lock().unwrap();
+        if state.closed {
+            return Err(IOError::new(ErrorKind::BrokenPipe, "Other end was closed"));
+        }
+        state.buf.extend(data);
+        self.tx.ready.notify_all();
+        return Ok(());
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
+        return self.recv_exact_before(how_much, Instant::now() + self.timeout);
+    }
+
+    /// Sending never blocks, so there's no deadline to miss.
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        return self.send(data);
+    }
+
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        let mut state = self.rx.data.lock().unwrap();
+        while state.buf.len() < how_much {
+            if state.closed {
+                return Err(IOError::new(
+                    ErrorKind::UnexpectedEof,
+                    "Other end was closed",
+                ));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(IOError::new(ErrorKind::TimedOut, "Receive timed out"));
+            }
+            state = self.rx.ready.wait_timeout(state, left).unwrap().0;
+        }
+        let buf: Vec<u8> = state.buf.drain(..how_much).collect();
+
+        log::trace!(target: "PIPE", "Recv exact: {}", format_data_buf(&buf));
+        return Ok(buf);
+    }
+
+    fn recv(&mut self) -> IOResult<Vec<u8>> {
+        let buf: Vec<u8> = self.rx.data.lock().unwrap().buf.drain(..).collect();
+        log::trace!(target: "PIPE", "Recv nonblocking: {}", format_data_buf(&buf));
+        return Ok(buf);
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        log::debug!(target: "PIPE", "Setting timeout: {timeout:?}");
+        self.timeout = timeout;
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.tx.close();
+        self.rx.close();
+    }
+}
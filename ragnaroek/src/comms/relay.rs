@@ -0,0 +1,607 @@
+//! Relays a local `Communicator`, usually a USB connection, to remote hosts over TCP.
+//!
+//! The remote host sends each `Communicator` call as a request frame, which the relay performs on the device
+//! and answers with a response frame. Every transfer is a frame of its own, so transfer boundaries and
+//! zero-length packets survive the trip.
+//!
+//! Frames are a type byte, a little-endian `u32` payload length and the payload.
+
+use super::net_connect::{ConnectOptions, Connection as NetConnection};
+use super::*;
+
+use std::io::{Error as IOError, ErrorKind, Read, Result as IOResult, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+/// Default TCP port of the relay, next to the one Odin uses.
+pub const RELAY_PORT: u16 = 13580;
+
+/// Sent by the relay to every client, followed by the protocol version
+const GREETING: &[u8] = b"RAGNAROEK-RELAY";
+const VERSION: u8 = 1;
+/// Upper bound for frame payloads, so a confused peer can't make us allocate all memory
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+/// Extra time the client waits for the relay, on top of the device's timeout.
+/// This lets timeouts of the device, which are reported by the relay, arrive before the client gives up.
+const TIMEOUT_MARGIN: Duration = Duration::from_secs(5);
+/// How long the man-in-the-middle pump sleeps if neither side had any data
+const PUMP_IDLE_INTERVAL: Duration = Duration::from_millis(1);
+
+const OP_SEND: u8 = 0x01;
+const OP_RECV_EXACT: u8 = 0x02;
+const OP_RECV: u8 = 0x03;
+const OP_SET_TIMEOUT: u8 = 0x04;
+/// Like `OP_SEND`, with the time left until the deadline in front of the data
+const OP_SEND_BEFORE: u8 = 0x05;
+/// Like `OP_RECV_EXACT`, followed by the time left until the deadline
+const OP_RECV_EXACT_BEFORE: u8 = 0x06;
+
+const STATUS_OK: u8 = 0x00;
+const STATUS_ERR: u8 = 0x01;
+
+/// Error kinds that are preserved across the relay, all others arrive as `ErrorKind::Other`.
+const ERROR_KINDS: [ErrorKind; 9] = [
+    ErrorKind::Other,
+    ErrorKind::TimedOut,
+    ErrorKind::UnexpectedEof,
+    ErrorKind::NotConnected,
+    ErrorKind::BrokenPipe,
+    ErrorKind::PermissionDenied,
+    ErrorKind::InvalidData,
+    ErrorKind::Interrupted,
+    ErrorKind::NotFound,
+];
+
+fn write_frame(s: &mut TcpStream, kind: u8, payload: &[u8]) -> IOResult<()> {
+    let len: u32 = payload
+        .len()
+        .try_into()
+        .map_err(|_| IOError::new(ErrorKind::InvalidInput, "Frame too large"))?;
+    let mut frame: Vec<u8> = Vec::with_capacity(5 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(payload);
+    return s.write_all(&frame);
+}
+
+fn read_frame(s: &mut TcpStream) -> IOResult<(u8, Vec<u8>)> {
+    let mut header = [0; 5];
+    s.read_exact(&mut header)?;
+    let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            format!("Relay frame of {len} bytes is too large"),
+        ));
+    }
+    let mut payload = vec![0; len];
+    s.read_exact(&mut payload)?;
+    return Ok((header[0], payload));
+}
+
+fn encode_error(e: &IOError) -> Vec<u8> {
+    let kind = ERROR_KINDS.iter().position(|k| *k == e.kind()).unwrap_or(0) as u8;
+    let mut payload = vec![kind];
+    payload.extend_from_slice(e.to_string().as_bytes());
+    return payload;
+}
+
+fn decode_error(payload: &[u8]) -> IOError {
+    let kind = payload
+        .first()
+        .and_then(|k| ERROR_KINDS.get(*k as usize))
+        .copied()
+        .unwrap_or(ErrorKind::Other);
+    let msg = String::from_utf8_lossy(payload.get(1..).unwrap_or_default());
+    return IOError::new(kind, format!("Relay: {msg}"));
+}
+
+fn u64_payload(payload: &[u8]) -> IOResult<u64> {
+    let bytes: [u8; 8] = payload
+        .try_into()
+        .map_err(|_| IOError::new(ErrorKind::InvalidData, "Malformed relay request"))?;
+    return Ok(u64::from_le_bytes(bytes));
+}
+
+/// Length of an exact receive, which has to fit in the frame that answers it.
+fn recv_len_payload(payload: &[u8]) -> IOResult<usize> {
+    let len = u64_payload(payload)?;
+    if len > MAX_FRAME_LEN as u64 {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            format!("Relay receive of {len} bytes is too large"),
+        ));
+    }
+    return Ok(len as usize);
+}
+
+/// Split the time left until a deadline off the front of a payload.
+fn deadline_payload(payload: &[u8]) -> IOResult<(Instant, &[u8])> {
+    if payload.len() < 8 {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "Malformed relay request",
+        ));
+    }
+    let (left, rest) = payload.split_at(8);
+    let deadline = Instant::now() + Duration::from_micros(u64_payload(left)?);
+    return Ok((deadline, rest));
+}
+
+/// Listens for remote hosts and relays their calls to a local device.
+pub struct Relay {
+    l: TcpListener,
+}
+
+impl Relay {
+    /// Listen for remote hosts on the given address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> IOResult<Relay> {
+        let l = TcpListener::bind(addr)?;
+        log::info!(target: "RELAY", "Listening on {}", l.local_addr()?);
+        return Ok(Relay { l });
+    }
+
+    /// The address the relay is bound to. Useful to find out the port when binding to port 0.
+    pub fn local_addr(&self) -> IOResult<SocketAddr> {
+        return self.l.local_addr();
+    }
+
+    /// Wait for a remote host and relay its calls to `device` until it disconnects.
+    ///
+    /// Errors of the device are passed on to the remote host, only errors talking to the remote host are returned.
+    pub fn serve_client(&mut self, device: &mut Box<dyn Communicator>) -> IOResult<()> {
+        let (mut s, peer) = self.l.accept()?;
+        log::info!(target: "RELAY", "Client {peer} connected");
+        s.set_nodelay(true)?;
+        let mut greeting = GREETING.to_vec();
+        greeting.push(VERSION);
+        s.write_all(&greeting)?;
+
+        loop {
+            let (op, payload) = match read_frame(&mut s) {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    log::info!(target: "RELAY", "Client {peer} disconnected");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            let result: IOResult<Vec<u8>> = match op {
+                OP_SEND => device.send(&payload).map(|()| Vec::new()),
+                OP_RECV_EXACT => device.recv_exact(recv_len_payload(&payload)?),
+                OP_RECV => device.recv(),
+                OP_SET_TIMEOUT => {
+                    device.set_timeout(Duration::from_millis(u64_payload(&payload)?));
+                    Ok(Vec::new())
+                }
+                OP_SEND_BEFORE => {
+                    let (deadline, data) = deadline_payload(&payload)?;
+                    device.send_before(data, deadline).map(|()| Vec::new())
+                }
+                OP_RECV_EXACT_BEFORE => {
+                    let (deadline, how_much) = deadline_payload(&payload)?;
+                    device.recv_exact_before(recv_len_payload(how_much)?, deadline)
+                }
+                _ => {
+                    return Err(IOError::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown relay request {op:#04x}"),
+                    ))
+                }
+            };
+            match result {
+                Ok(data) => write_frame(&mut s, STATUS_OK, &data)?,
+                Err(e) => {
+                    log::debug!(target: "RELAY", "Device error: {e}");
+                    write_frame(&mut s, STATUS_ERR, &encode_error(&e))?;
+                }
+            }
+        }
+    }
+}
+
+/// `Connection` talks to a device attached to a remote `Relay`.
+pub struct Connection {
+    s: TcpStream,
+    /// Timeout of the device, the socket's is a bit longer
+    timeout: Duration,
+    /// How much longer than the device the socket waits, `TIMEOUT_MARGIN` outside of tests
+    margin: Duration,
+    /// Set once a request failed without its response being read completely.
+    /// A late response would be taken for the answer to the next request, so no more requests are sent.
+    broken: bool,
+}
+
+impl Connection {
+    /// Connect to the relay at the given address.
+    ///
+    /// The timeout in `options` is applied to the device, see `set_timeout`.
+    pub fn connect<A: ToSocketAddrs>(addr: A, options: &ConnectOptions) -> IOResult<Connection> {
+        let mut s = NetConnection::connect(addr, options)?.into_stream();
+        s.set_nodelay(true)?;
+        set_stream_timeout(&s, options.timeout + TIMEOUT_MARGIN);
+
+        let mut greeting = vec![0; GREETING.len() + 1];
+        s.read_exact(&mut greeting)?;
+        if !greeting.starts_with(GREETING) {
+            return Err(IOError::new(ErrorKind::InvalidData, "Peer is not a relay"));
+        }
+        if greeting[GREETING.len()] != VERSION {
+            return Err(IOError::new(
+                ErrorKind::Unsupported,
+                format!("Unsupported relay version {}", greeting[GREETING.len()]),
+            ));
+        }
+
+        let mut c = Connection {
+            s,
+            timeout: options.timeout,
+            margin: TIMEOUT_MARGIN,
+            broken: false,
+        };
+        c.request(
+            OP_SET_TIMEOUT,
+            &(options.timeout.as_millis() as u64).to_le_bytes(),
+        )?;
+        return Ok(c);
+    }
+
+    /// Send a request to the relay and wait for its response.
+    ///
+    /// If that fails halfway, such as when the response takes too long, the connection is broken for good.
+    fn request(&mut self, op: u8, payload: &[u8]) -> IOResult<Vec<u8>> {
+        if self.broken {
+            return Err(IOError::new(
+                ErrorKind::NotConnected,
+                "Relay connection is out of sync after an earlier failure",
+            ));
+        }
+        let response = write_frame(&mut self.s, op, payload).and_then(|()| read_frame(&mut self.s));
+        let (status, payload) = match response {
+            Ok(response) => response,
+            Err(e) => {
+                log::warn!(target: "RELAY", "Request failed, giving up on the connection: {e}");
+                self.broken = true;
+                return Err(e);
+            }
+        };
+        match status {
+            STATUS_OK => return Ok(payload),
+            STATUS_ERR => return Err(decode_error(&payload)),
+            _ => {
+                self.broken = true;
+                return Err(IOError::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown relay response {status:#04x}"),
+                ));
+            }
+        }
+    }
+
+    /// Send a request carrying a deadline, waiting for the response only as long as the deadline allows.
+    fn request_before(&mut self, op: u8, payload: &[u8], deadline: Instant) -> IOResult<Vec<u8>> {
+        let left = time_left(deadline)?;
+        let mut request = (left.as_micros() as u64).to_le_bytes().to_vec();
+        request.extend_from_slice(payload);
+        set_stream_timeout(&self.s, left + self.margin);
+        let ret = self.request(op, &request);
+        set_stream_timeout(&self.s, self.timeout + self.margin);
+        return ret;
+    }
+}
+
+impl Communicator for Connection {
+    fn send(&mut self, data: &[u8]) -> IOResult<()> {
+        log::trace!(target: "RELAY", "Send: {}", format_data_buf(data));
+        self.request(OP_SEND, data)?;
+        return Ok(());
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
+        let buf = self.request(OP_RECV_EXACT, &(how_much as u64).to_le_bytes())?;
+        if buf.len() != how_much {
+            return Err(IOError::new(
+                ErrorKind::InvalidData,
+                format!("Relay returned {} instead of {how_much} bytes", buf.len()),
+            ));
+        }
+        log::trace!(target: "RELAY", "Recv exact: {}", format_data_buf(&buf));
+        return Ok(buf);
+    }
+
+    fn recv(&mut self) -> IOResult<Vec<u8>> {
+        let buf = self.request(OP_RECV, &[])?;
+        log::trace!(target: "RELAY", "Recv nonblocking: {}", format_data_buf(&buf));
+        return Ok(buf);
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        log::debug!(target: "RELAY", "Setting timeout: {timeout:?}");
+        self.timeout = timeout;
+        set_stream_timeout(&self.s, timeout + self.margin);
+        let millis = timeout.as_millis() as u64;
+        if let Err(e) = self.request(OP_SET_TIMEOUT, &millis.to_le_bytes()) {
+            log::warn!(target: "RELAY", "Failed to set timeout: {e}");
+        }
+    }
+
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        log::trace!(target: "RELAY", "Send: {}", format_data_buf(data));
+        self.request_before(OP_SEND_BEFORE, data, deadline)?;
+        return Ok(());
+    }
+
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        let payload = (how_much as u64).to_le_bytes();
+        let buf = self.request_before(OP_RECV_EXACT_BEFORE, &payload, deadline)?;
+        if buf.len() != how_much {
+            return Err(IOError::new(
+                ErrorKind::InvalidData,
+                format!("Relay returned {} instead of {how_much} bytes", buf.len()),
+            ));
+        }
+        log::trace!(target: "RELAY", "Recv exact: {}", format_data_buf(&buf));
+        return Ok(buf);
+    }
+}
+
+/// Traffic passed on by `man_in_the_middle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MitmStats {
+    /// Transfers from the client to the device.
+    pub to_device: u64,
+    /// Bytes from the client to the device.
+    pub to_device_bytes: u64,
+    /// Transfers from the device to the client.
+    pub to_client: u64,
+    /// Bytes from the device to the client.
+    pub to_client_bytes: u64,
+}
+
+/// Pass traffic between a real Odin client and a device, logging every transfer.
+///
+/// Both sides are polled with `Communicator::recv`, so transfers are passed on as they're read.
+/// Zero-length packets can't be told apart from no data this way, and are lost.
+/// Wrap `device` in a `Capture` or `Recorder` to keep the traffic.
+///
+/// Returns once either side fails or disconnects, or `cancel` is cancelled.
+pub fn man_in_the_middle(
+    client: &mut Box<dyn Communicator>,
+    device: &mut Box<dyn Communicator>,
+    cancel: &CancelHandle,
+) -> (MitmStats, IOError) {
+    let mut stats = MitmStats::default();
+    loop {
+        if cancel.is_cancelled() {
+            return (
+                stats,
+                IOError::new(ErrorKind::Interrupted, "Relaying was cancelled"),
+            );
+        }
+        let to_device = match client.recv() {
+            Ok(data) => data,
+            Err(e) => return (stats, e),
+        };
+        if !to_device.is_empty() {
+            log::info!(target: "RELAY", "Client -> device, {} bytes: {}", to_device.len(), format_data_buf(&to_device));
+            if let Err(e) = device.send(&to_device) {
+                return (stats, e);
+            }
+            stats.to_device += 1;
+            stats.to_device_bytes += to_device.len() as u64;
+        }
+
+        let to_client = match device.recv() {
+            Ok(data) => data,
+            Err(e) => return (stats, e),
+        };
+        if !to_client.is_empty() {
+            log::info!(target: "RELAY", "Device -> client, {} bytes: {}", to_client.len(), format_data_buf(&to_client));
+            if let Err(e) = client.send(&to_client) {
+                return (stats, e);
+            }
+            stats.to_client += 1;
+            stats.to_client_bytes += to_client.len() as u64;
+        }
+
+        if to_device.is_empty() && to_client.is_empty() {
+            std::thread::sleep(PUMP_IDLE_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::pipe::Connection as Pipe;
+    use crate::download_protocol::emulator::{spawn_target, TargetOptions};
+    use crate::download_protocol::{ActionAfter, Session};
+
+    use std::sync::Mutex;
+
+    const PIT: &str = "../pit/testdata/A40_EUR_OPEN.pit";
+
+    /// A device that records what it is sent, and fails to receive anything exact.
+    struct Sink {
+        sent: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Communicator for Sink {
+        fn send(&mut self, data: &[u8]) -> IOResult<()> {
+            self.sent.lock().unwrap().push(data.to_vec());
+            return Ok(());
+        }
+
+        fn recv_exact(&mut self, _how_much: usize) -> IOResult<Vec<u8>> {
+            return Err(IOError::new(ErrorKind::TimedOut, "Nothing to receive"));
+        }
+
+        fn recv(&mut self) -> IOResult<Vec<u8>> {
+            return Ok(vec![1, 2, 3]);
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) {}
+    }
+
+    /// A device that takes its time to answer, ignoring deadlines.
+    struct Slow {
+        delay: Duration,
+    }
+
+    impl Communicator for Slow {
+        fn send(&mut self, _data: &[u8]) -> IOResult<()> {
+            return Ok(());
+        }
+
+        fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
+            std::thread::sleep(self.delay);
+            return Ok(vec![0x55; how_much]);
+        }
+
+        fn recv(&mut self) -> IOResult<Vec<u8>> {
+            return Ok(vec![0xAA]);
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) {}
+    }
+
+    #[test]
+    fn test_session_over_relay() {
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+        let mut relay = Relay::bind("127.0.0.1:0").unwrap();
+        let addr = relay.local_addr().unwrap();
+        let relay = std::thread::spawn(move || {
+            let mut device: Box<dyn Communicator> = Box::new(host);
+            relay.serve_client(&mut device).unwrap();
+        });
+
+        let c = Connection::connect(addr, &ConnectOptions::default()).unwrap();
+        let mut sess = Session::begin(Box::new(c)).unwrap();
+        let pit = sess.download_pit(sess.params).unwrap();
+        assert_eq!(std::fs::read(PIT).unwrap(), pit);
+        sess.end(ActionAfter::Nothing).unwrap();
+        target.join().unwrap().unwrap();
+        relay.join().unwrap();
+    }
+
+    #[test]
+    fn test_boundaries_and_errors() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut relay = Relay::bind("127.0.0.1:0").unwrap();
+        let addr = relay.local_addr().unwrap();
+        let device_sent = sent.clone();
+        let relay = std::thread::spawn(move || {
+            let mut device: Box<dyn Communicator> = Box::new(Sink { sent: device_sent });
+            relay.serve_client(&mut device).unwrap();
+        });
+
+        let mut c = Connection::connect(addr, &ConnectOptions::default()).unwrap();
+        c.send(&[0xAA, 0xBB]).unwrap();
+        c.send(&[]).unwrap();
+        c.send(&[0xCC]).unwrap();
+        let e = c.recv_exact(4).unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, e.kind());
+        assert!(e.to_string().contains("Nothing to receive"));
+        assert_eq!(vec![1, 2, 3], c.recv().unwrap());
+        drop(c);
+        relay.join().unwrap();
+
+        assert_eq!(
+            vec![vec![0xAA, 0xBB], vec![], vec![0xCC]],
+            *sent.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_receive_too_large() {
+        let mut relay = Relay::bind("127.0.0.1:0").unwrap();
+        let addr = relay.local_addr().unwrap();
+        let relay = std::thread::spawn(move || {
+            let mut device: Box<dyn Communicator> = Box::new(Sink {
+                sent: Arc::new(Mutex::new(Vec::new())),
+            });
+            return relay.serve_client(&mut device);
+        });
+
+        let mut s = TcpStream::connect(addr).unwrap();
+        let mut greeting = [0; GREETING.len() + 1];
+        s.read_exact(&mut greeting).unwrap();
+        let how_much = (MAX_FRAME_LEN as u64 + 1).to_le_bytes();
+        write_frame(&mut s, OP_RECV_EXACT, &how_much).unwrap();
+        // Errors of the device would be passed on, this one never reaches it
+        let e = relay.join().unwrap().unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.kind());
+        assert!(e.to_string().contains("too large"));
+    }
+
+    #[test]
+    fn test_late_response() {
+        let mut relay = Relay::bind("127.0.0.1:0").unwrap();
+        let addr = relay.local_addr().unwrap();
+        let relay = std::thread::spawn(move || {
+            let mut device: Box<dyn Communicator> = Box::new(Slow {
+                delay: Duration::from_millis(300),
+            });
+            // The client goes away while its request is still being answered
+            let _ = relay.serve_client(&mut device);
+        });
+
+        let mut c = Connection::connect(addr, &ConnectOptions::default()).unwrap();
+        c.margin = Duration::from_millis(20);
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let e = c.recv_exact_before(4, deadline).unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+        // The late response must not be taken for the answer to the next request
+        std::thread::sleep(Duration::from_millis(400));
+        let e = c.recv().unwrap_err();
+        assert_eq!(ErrorKind::NotConnected, e.kind());
+        let e = c.send(&[0x01]).unwrap_err();
+        assert_eq!(ErrorKind::NotConnected, e.kind());
+        drop(c);
+        relay.join().unwrap();
+    }
+
+    #[test]
+    fn test_not_a_relay() {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = l.local_addr().unwrap();
+        let peer = std::thread::spawn(move || {
+            let (mut s, _) = l.accept().unwrap();
+            s.write_all(b"LOKE and some more bytes").unwrap();
+        });
+        let e = Connection::connect(addr, &ConnectOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(ErrorKind::InvalidData, e.kind());
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_man_in_the_middle() {
+        let (device, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+        let (odin, client) = Pipe::pair();
+        let cancel = CancelHandle::new();
+        let mitm_cancel = cancel.clone();
+        let mitm = std::thread::spawn(move || {
+            let mut client: Box<dyn Communicator> = Box::new(client);
+            let mut device: Box<dyn Communicator> = Box::new(device);
+            return man_in_the_middle(&mut client, &mut device, &mitm_cancel);
+        });
+
+        let mut sess = Session::begin(Box::new(odin)).unwrap();
+        let pit = sess.download_pit(sess.params).unwrap();
+        assert_eq!(std::fs::read(PIT).unwrap(), pit);
+        sess.end(ActionAfter::Nothing).unwrap();
+        target.join().unwrap().unwrap();
+
+        cancel.cancel();
+        let (stats, e) = mitm.join().unwrap();
+        assert_eq!(ErrorKind::Interrupted, e.kind());
+        assert!(stats.to_device > 0);
+        assert!(stats.to_client_bytes > pit.len() as u64);
+    }
+}
@@ -0,0 +1,228 @@
+use super::*;
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error as IOError, ErrorKind, Read, Result as IOResult, Write};
+use std::os::fd::AsFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Instant;
+
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::termios::{self, ControlFlags, FlushArg, SetArg, SpecialCharacterIndices};
+
+/// Upper bound for the amount of data `recv` returns at once
+const RECV_BUF_SIZE: usize = 1024 * 1024;
+
+/// `Connection` implements an ODIN mode connection over a serial tty,
+/// such as the `/dev/ttyACM*` device Linux' `cdc_acm` driver creates for targets in download mode.
+///
+/// The tty is put into raw mode, so data passes through unaltered.
+/// Zero-length USB packets are not visible through a tty, so `recv_exact(0)` returns immediately.
+pub struct Connection {
+    f: File,
+    timeout: Duration,
+}
+
+impl Connection {
+    /// Open the tty at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> IOResult<Connection> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            // Don't let the target become our controlling terminal
+            .custom_flags(OFlag::O_NOCTTY.bits())
+            .open(path.as_ref())?;
+
+        log::debug!(target: "SERIAL", "Opened {}", path.as_ref().display());
+        return Connection::from_file(f);
+    }
+
+    /// Wrap an already opened tty.
+    pub fn from_file(f: File) -> IOResult<Connection> {
+        let mut attrs = termios::tcgetattr(f.as_fd())?;
+        termios::cfmakeraw(&mut attrs);
+        // Ignore modem control lines, and enable the receiver
+        attrs.control_flags |= ControlFlags::CLOCAL | ControlFlags::CREAD;
+        // Reads return whatever is available, timeouts are handled by polling instead
+        attrs.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+        attrs.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        termios::tcsetattr(f.as_fd(), SetArg::TCSANOW, &attrs)?;
+        // Discard anything left over from earlier sessions
+        termios::tcflush(f.as_fd(), FlushArg::TCIFLUSH)?;
+
+        return Ok(Connection {
+            f,
+            timeout: super::DEFAULT_TIMEOUT,
+        });
+    }
+
+    /// Wait until the tty is ready for the given kind of I/O, or the deadline passes.
+    ///
+    /// Returns whether it's ready. A hung up tty is only reported once there's nothing left to read.
+    fn wait_ready(&self, flags: PollFlags, deadline: Instant) -> IOResult<bool> {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let timeout = PollTimeout::try_from(left).unwrap_or(PollTimeout::MAX);
+        let mut fds = [PollFd::new(self.f.as_fd(), flags)];
+        let ready = poll(&mut fds, timeout)?;
+        if ready > 0 {
+            let revents = fds[0].revents().unwrap_or(PollFlags::empty());
+            if revents.intersects(PollFlags::POLLERR | PollFlags::POLLNVAL) {
+                return Err(IOError::new(ErrorKind::BrokenPipe, "tty failed"));
+            }
+            if revents.contains(PollFlags::POLLHUP) && !revents.contains(flags) {
+                return Err(IOError::new(ErrorKind::BrokenPipe, "tty hung up"));
+            }
+        }
+        return Ok(ready > 0);
+    }
+}
+
+impl Communicator for Connection {
+    /// Sends the given data to the device.
+    /// Blocks until all data could be sent or an error occurs.
+    fn send(&mut self, data: &[u8]) -> IOResult<()> {
+        return self.send_before(data, Instant::now() + self.timeout);
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
+        return self.recv_exact_before(how_much, Instant::now() + self.timeout);
+    }
+
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        log::trace!(target: "SERIAL", "Send: {}", format_data_buf(data));
+        let mut sent: usize = 0;
+        while sent < data.len() {
+            if !self.wait_ready(PollFlags::POLLOUT, deadline)? {
+                return Err(IOError::new(ErrorKind::TimedOut, "Send timed out"));
+            }
+            match self.f.write(&data[sent..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(written) => sent += written,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        return Ok(());
+    }
+
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        let mut buf = vec![0; how_much];
+        let mut received: usize = 0;
+        while received < how_much {
+            if !self.wait_ready(PollFlags::POLLIN, deadline)? {
+                log::debug!(target: "SERIAL", "Recv timed out with {received} of {how_much} bytes received");
+                return Err(IOError::new(ErrorKind::TimedOut, "Receive timed out"));
+            }
+            match self.f.read(&mut buf[received..]) {
+                // The tty was readable, so nothing to read means it hung up
+                Ok(0) => {
+                    return Err(IOError::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("tty hung up with {received} of {how_much} bytes received"),
+                    ))
+                }
+                Ok(read) => received += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        log::trace!(target: "SERIAL", "Recv blocking: {}", format_data_buf(&buf));
+        return Ok(buf);
+    }
+
+    fn recv(&mut self) -> IOResult<Vec<u8>> {
+        let mut buf = vec![0; RECV_BUF_SIZE];
+        // VMIN and VTIME are 0, so this doesn't block
+        let read = match self.f.read(&mut buf) {
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e),
+        };
+        buf.truncate(read);
+
+        log::trace!(target: "SERIAL", "Recv nonblocking: {}", format_data_buf(&buf));
+        return Ok(buf);
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        log::debug!(target: "SERIAL", "Setting timeout: {timeout:?}");
+        self.timeout = timeout;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nix::pty::openpty;
+
+    /// Open a pseudo-terminal pair, returning the fake target's end and a `Connection` to it.
+    fn pty_pair() -> (File, Connection) {
+        let pty = openpty(None, None).unwrap();
+        let conn = Connection::from_file(File::from(pty.slave)).unwrap();
+        return (File::from(pty.master), conn);
+    }
+
+    #[test]
+    fn test_scripted_target() {
+        let (mut target, mut conn) = pty_pair();
+        let target = std::thread::spawn(move || {
+            // Answer the handshake, then send a large response in small pieces
+            let mut handshake = [0; 4];
+            target.read_exact(&mut handshake).unwrap();
+            assert_eq!(b"ODIN", &handshake);
+            target.write_all(b"LOKE").unwrap();
+            for chunk in [[0x55; 1000]; 4] {
+                target.write_all(&chunk).unwrap();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            // Keep the pty open until the other side is done
+            let mut done = [0; 1];
+            target.read_exact(&mut done).unwrap();
+        });
+
+        conn.send(b"ODIN").unwrap();
+        assert_eq!(b"LOKE".to_vec(), conn.recv_exact(4).unwrap());
+        // Data passes through unaltered, even bytes a tty would usually interpret
+        assert_eq!(vec![0x55; 4000], conn.recv_exact(4000).unwrap());
+        assert_eq!(Vec::<u8>::new(), conn.recv_exact(0).unwrap());
+
+        conn.set_timeout(Duration::from_millis(50));
+        let err = conn.recv_exact(1).err().unwrap();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        assert!(conn.recv().unwrap().is_empty());
+
+        conn.send(&[0]).unwrap();
+        target.join().unwrap();
+    }
+
+    #[test]
+    fn test_hangup() {
+        let (mut target, mut conn) = pty_pair();
+        target.write_all(b"LO").unwrap();
+        drop(target);
+
+        // A hangup fails the receive right away, instead of when the timeout runs out
+        conn.set_timeout(Duration::from_secs(10));
+        let start = Instant::now();
+        let err = conn.recv_exact(4).err().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_ne!(ErrorKind::TimedOut, err.kind());
+    }
+
+    #[test]
+    fn test_raw_mode() {
+        let (mut target, mut conn) = pty_pair();
+        // Control characters, CR and NL must not be translated or swallowed
+        let data: Vec<u8> = (0..=255).collect();
+        target.write_all(&data).unwrap();
+        assert_eq!(data, conn.recv_exact(256).unwrap());
+
+        conn.send(&data).unwrap();
+        let mut echoed = vec![0; 256];
+        target.read_exact(&mut echoed).unwrap();
+        assert_eq!(data, echoed);
+    }
+}
@@ -0,0 +1,700 @@
+use super::*;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{
+    BufRead, BufReader, BufWriter, Error as IOError, ErrorKind, Result as IOResult, Write,
+};
+use std::path::Path;
+use std::time::Instant;
+
+/// First line of every transcript file.
+const TRANSCRIPT_HEADER: &str = "# ragnaroek transcript 1";
+
+/// `ErrorKind`s that survive a round trip through a transcript. Anything else is replayed as `Other`.
+const KNOWN_ERROR_KINDS: [ErrorKind; 17] = [
+    ErrorKind::NotFound,
+    ErrorKind::PermissionDenied,
+    ErrorKind::ConnectionRefused,
+    ErrorKind::ConnectionReset,
+    ErrorKind::ConnectionAborted,
+    ErrorKind::NotConnected,
+    ErrorKind::BrokenPipe,
+    ErrorKind::WouldBlock,
+    ErrorKind::InvalidInput,
+    ErrorKind::InvalidData,
+    ErrorKind::TimedOut,
+    ErrorKind::WriteZero,
+    ErrorKind::Interrupted,
+    ErrorKind::Unsupported,
+    ErrorKind::UnexpectedEof,
+    ErrorKind::OutOfMemory,
+    ErrorKind::Other,
+];
+
+/// A failed operation, as recorded in a transcript.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptError {
+    /// Kind of the I/O error.
+    pub kind: ErrorKind,
+    /// Description of the I/O error.
+    pub message: String,
+}
+
+impl From<&IOError> for TranscriptError {
+    fn from(e: &IOError) -> Self {
+        return TranscriptError {
+            kind: e.kind(),
+            message: e.to_string(),
+        };
+    }
+}
+
+impl From<&TranscriptError> for IOError {
+    fn from(e: &TranscriptError) -> Self {
+        return IOError::new(e.kind, e.message.clone());
+    }
+}
+
+/// A single call to a `Communicator` and its outcome.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranscriptEvent {
+    /// Data sent to the target, and the error if sending failed.
+    Send {
+        /// Data passed to `send`.
+        data: Vec<u8>,
+        /// Error `send` returned, if any.
+        error: Option<TranscriptError>,
+    },
+    /// Data received from the target with `recv_exact`.
+    RecvExact {
+        /// Amount of data requested.
+        len: usize,
+        /// The received data, or the error `recv_exact` returned.
+        result: std::result::Result<Vec<u8>, TranscriptError>,
+    },
+    /// Data received from the target with `recv`.
+    Recv {
+        /// The received data, or the error `recv` returned.
+        result: std::result::Result<Vec<u8>, TranscriptError>,
+    },
+    /// Timeout change.
+    SetTimeout(Duration),
+}
+
+impl TranscriptEvent {
+    /// Short description of the call, without any data.
+    fn describe(&self) -> String {
+        match self {
+            TranscriptEvent::Send { data, .. } => return format!("send of {} bytes", data.len()),
+            TranscriptEvent::RecvExact { len, .. } => return format!("recv_exact of {len} bytes"),
+            TranscriptEvent::Recv { .. } => return String::from("recv"),
+            TranscriptEvent::SetTimeout(t) => return format!("set_timeout to {t:?}"),
+        }
+    }
+}
+
+/// A `TranscriptEvent` and when it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptRecord {
+    /// Time since recording started, taken when the call returned.
+    pub elapsed: Duration,
+    /// The call.
+    pub event: TranscriptEvent,
+}
+
+impl fmt::Display for TranscriptRecord {
+    /// Formats the record as a single transcript line.
+    ///
+    /// Lines start with the elapsed time in microseconds and the operation, followed by its arguments and the data as hex.
+    /// Empty data is written as `-`, errors as `!`, their kind and message.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.elapsed.as_micros())?;
+        match &self.event {
+            TranscriptEvent::Send { data, error } => {
+                write!(f, "send {}", to_hex(data))?;
+                if let Some(e) = error {
+                    write!(f, " {}", format_error(e))?;
+                }
+            }
+            TranscriptEvent::RecvExact { len, result } => {
+                write!(f, "recv_exact {len} {}", format_result(result))?;
+            }
+            TranscriptEvent::Recv { result } => write!(f, "recv {}", format_result(result))?,
+            TranscriptEvent::SetTimeout(t) => write!(f, "timeout {}", t.as_micros())?,
+        }
+        return Ok(());
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    if data.is_empty() {
+        return String::from("-");
+    }
+    return data.iter().map(|b| format!("{b:02x}")).collect();
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s == "-" {
+        return Some(Vec::new());
+    }
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect();
+}
+
+fn format_error(e: &TranscriptError) -> String {
+    // Newlines would break the line-based format
+    let message = e.message.replace(['\r', '\n'], " ");
+    return format!("! {:?} {message}", e.kind);
+}
+
+fn format_result(result: &std::result::Result<Vec<u8>, TranscriptError>) -> String {
+    match result {
+        Ok(data) => return to_hex(data),
+        Err(e) => return format_error(e),
+    }
+}
+
+/// Parses the `! <kind> <message>` part of a line, with the `!` already consumed.
+fn parse_error(rest: &str) -> Option<TranscriptError> {
+    let (kind, message) = rest.split_once(' ').unwrap_or((rest, ""));
+    let kind = KNOWN_ERROR_KINDS
+        .iter()
+        .find(|k| format!("{k:?}") == kind)
+        .copied()
+        .unwrap_or(ErrorKind::Other);
+    return Some(TranscriptError {
+        kind,
+        message: message.to_string(),
+    });
+}
+
+/// Parses data or an error, whichever the rest of the line contains.
+fn parse_result(rest: &str) -> Option<std::result::Result<Vec<u8>, TranscriptError>> {
+    match rest.strip_prefix("! ") {
+        Some(e) => return Some(Err(parse_error(e)?)),
+        None => return Some(Ok(from_hex(rest)?)),
+    }
+}
+
+fn parse_record(line: &str) -> Option<TranscriptRecord> {
+    let (elapsed, line) = line.split_once(' ')?;
+    let elapsed = Duration::from_micros(elapsed.parse().ok()?);
+    let (op, rest) = line.split_once(' ')?;
+    let event = match op {
+        "send" => {
+            let (data, error) = match rest.split_once(" ! ") {
+                Some((data, e)) => (data, Some(parse_error(e)?)),
+                None => (rest, None),
+            };
+            TranscriptEvent::Send {
+                data: from_hex(data)?,
+                error,
+            }
+        }
+        "recv_exact" => {
+            let (len, rest) = rest.split_once(' ')?;
+            TranscriptEvent::RecvExact {
+                len: len.parse().ok()?,
+                result: parse_result(rest)?,
+            }
+        }
+        "recv" => TranscriptEvent::Recv {
+            result: parse_result(rest)?,
+        },
+        "timeout" => TranscriptEvent::SetTimeout(Duration::from_micros(rest.parse().ok()?)),
+        _ => return None,
+    };
+    return Some(TranscriptRecord { elapsed, event });
+}
+
+/// Record of all traffic of a session, as written by `Recorder` and served back by `Replayer`.
+///
+/// Transcripts are stored as text, one record per line. Lines starting with `#` are comments.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Transcript {
+    /// All records, in the order the calls were made.
+    pub records: Vec<TranscriptRecord>,
+}
+
+impl Transcript {
+    /// Parse a transcript.
+    pub fn read<R: BufRead>(r: R) -> IOResult<Transcript> {
+        let mut records: Vec<TranscriptRecord> = Vec::new();
+        for (i, line) in r.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_record(&line) {
+                Some(record) => records.push(record),
+                None => {
+                    return Err(IOError::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid transcript record on line {}", i + 1),
+                    ))
+                }
+            }
+        }
+        return Ok(Transcript { records });
+    }
+
+    /// Write the transcript in the format `read` understands.
+    pub fn write<W: Write>(&self, mut w: W) -> IOResult<()> {
+        writeln!(w, "{TRANSCRIPT_HEADER}")?;
+        for record in &self.records {
+            writeln!(w, "{record}")?;
+        }
+        return w.flush();
+    }
+
+    /// Read a transcript from the file at the given path.
+    pub fn load<P: AsRef<Path>>(path: P) -> IOResult<Transcript> {
+        return Transcript::read(BufReader::new(File::open(path)?));
+    }
+
+    /// Write the transcript to a file at the given path, overwriting it if it exists.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> IOResult<()> {
+        return self.write(BufWriter::new(File::create(path)?));
+    }
+}
+
+/// `Recorder` wraps another `Communicator`, writing a transcript of all calls and their outcomes as they happen.
+///
+/// Each record is flushed right away, so the transcript survives a crash.
+/// Failing to write the transcript fails the call, after it was made on the inner `Communicator`.
+pub struct Recorder<W: Write + Send> {
+    inner: Box<dyn Communicator>,
+    out: W,
+    start: Instant,
+}
+
+impl<W: Write + Send> Recorder<W> {
+    /// Start recording calls to `inner` into `out`.
+    pub fn new(inner: Box<dyn Communicator>, mut out: W) -> IOResult<Recorder<W>> {
+        writeln!(out, "{TRANSCRIPT_HEADER}")?;
+        out.flush()?;
+        return Ok(Recorder {
+            inner,
+            out,
+            start: Instant::now(),
+        });
+    }
+
+    /// Stop recording, returning the inner `Communicator` and the transcript's writer.
+    pub fn into_inner(self) -> (Box<dyn Communicator>, W) {
+        return (self.inner, self.out);
+    }
+
+    fn record(&mut self, event: TranscriptEvent) -> IOResult<()> {
+        let record = TranscriptRecord {
+            elapsed: self.start.elapsed(),
+            event,
+        };
+        writeln!(self.out, "{record}")?;
+        return self.out.flush();
+    }
+}
+
+impl Recorder<BufWriter<File>> {
+    /// Start recording calls to `inner` into a new file at the given path, overwriting it if it exists.
+    pub fn create<P: AsRef<Path>>(inner: Box<dyn Communicator>, path: P) -> IOResult<Self> {
+        log::info!(target: "REC", "Recording transcript to {}", path.as_ref().display());
+        return Recorder::new(inner, BufWriter::new(File::create(path)?));
+    }
+}
+
+impl<W: Write + Send> Recorder<W> {
+    fn record_send(&mut self, data: &[u8], ret: IOResult<()>) -> IOResult<()> {
+        self.record(TranscriptEvent::Send {
+            data: data.to_vec(),
+            error: ret.as_ref().err().map(TranscriptError::from),
+        })?;
+        return ret;
+    }
+
+    fn record_recv_exact(&mut self, how_much: usize, ret: IOResult<Vec<u8>>) -> IOResult<Vec<u8>> {
+        self.record(TranscriptEvent::RecvExact {
+            len: how_much,
+            result: ret
+                .as_ref()
+                .map(|d| d.clone())
+                .map_err(TranscriptError::from),
+        })?;
+        return ret;
+    }
+}
+
+impl<W: Write + Send> Communicator for Recorder<W> {
+    fn send(&mut self, data: &[u8]) -> IOResult<()> {
+        let ret = self.inner.send(data);
+        return self.record_send(data, ret);
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
+        let ret = self.inner.recv_exact(how_much);
+        return self.record_recv_exact(how_much, ret);
+    }
+
+    fn recv(&mut self) -> IOResult<Vec<u8>> {
+        let ret = self.inner.recv();
+        self.record(TranscriptEvent::Recv {
+            result: ret
+                .as_ref()
+                .map(|d| d.clone())
+                .map_err(TranscriptError::from),
+        })?;
+        return ret;
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.set_timeout(timeout);
+        if let Err(e) = self.record(TranscriptEvent::SetTimeout(timeout)) {
+            log::error!(target: "REC", "Failed to record timeout change: {e}");
+        }
+    }
+
+    /// Recorded like `send`, deadlines are timing and aren't part of transcripts.
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        let ret = self.inner.send_before(data, deadline);
+        return self.record_send(data, ret);
+    }
+
+    /// Recorded like `recv_exact`, deadlines are timing and aren't part of transcripts.
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        let ret = self.inner.recv_exact_before(how_much, deadline);
+        return self.record_recv_exact(how_much, ret);
+    }
+}
+
+/// `Replayer` serves a recorded `Transcript` back, without any target attached.
+///
+/// Every call must match the next record exactly, or it fails with `ErrorKind::InvalidData`.
+/// After the first mismatch, all further calls fail as well.
+/// Recorded errors are returned like the original ones, and timing is not reproduced.
+pub struct Replayer {
+    records: VecDeque<TranscriptRecord>,
+    /// Index of the next record in the transcript
+    pos: usize,
+    /// Description of the first mismatch, if any
+    mismatch: Option<String>,
+}
+
+impl Replayer {
+    /// Prepare replaying the given transcript.
+    pub fn new(transcript: Transcript) -> Replayer {
+        return Replayer {
+            records: transcript.records.into(),
+            pos: 0,
+            mismatch: None,
+        };
+    }
+
+    /// Prepare replaying the transcript in the file at the given path.
+    pub fn load<P: AsRef<Path>>(path: P) -> IOResult<Replayer> {
+        return Ok(Replayer::new(Transcript::load(path)?));
+    }
+
+    /// Number of records not replayed yet.
+    pub fn remaining(&self) -> usize {
+        return self.records.len();
+    }
+
+    /// Check that the whole transcript was replayed without any mismatch.
+    pub fn finish(&self) -> IOResult<()> {
+        if let Some(m) = &self.mismatch {
+            return Err(IOError::new(ErrorKind::InvalidData, m.clone()));
+        }
+        if !self.records.is_empty() {
+            return Err(IOError::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Transcript has {} records left, next is a {}",
+                    self.records.len(),
+                    self.records[0].event.describe()
+                ),
+            ));
+        }
+        return Ok(());
+    }
+
+    /// Take the next record, if it's the given call.
+    fn next(&mut self, call: &TranscriptEvent) -> IOResult<TranscriptEvent> {
+        if let Some(m) = &self.mismatch {
+            return Err(IOError::new(ErrorKind::InvalidData, m.clone()));
+        }
+        let expected = self.records.pop_front().map(|r| r.event);
+        let matches = match (&expected, call) {
+            (Some(TranscriptEvent::Send { data, .. }), TranscriptEvent::Send { data: got, .. }) => {
+                data == got
+            }
+            (
+                Some(TranscriptEvent::RecvExact { len, .. }),
+                TranscriptEvent::RecvExact { len: got, .. },
+            ) => len == got,
+            (Some(TranscriptEvent::Recv { .. }), TranscriptEvent::Recv { .. }) => true,
+            (Some(TranscriptEvent::SetTimeout(t)), TranscriptEvent::SetTimeout(got)) => t == got,
+            _ => false,
+        };
+
+        let pos = self.pos;
+        self.pos += 1;
+        match expected {
+            Some(expected) if matches => return Ok(expected),
+            Some(expected) => {
+                let mut m = format!(
+                    "Transcript mismatch at record {pos}: expected {}, got {}",
+                    expected.describe(),
+                    call.describe()
+                );
+                if let (
+                    TranscriptEvent::Send { data, .. },
+                    TranscriptEvent::Send { data: got, .. },
+                ) = (&expected, call)
+                {
+                    let offset = data.iter().zip(got).take_while(|(a, b)| a == b).count();
+                    m.push_str(&format!(", data differs at offset {offset}"));
+                }
+                log::error!(target: "REPLAY", "{m}");
+                self.mismatch = Some(m.clone());
+                return Err(IOError::new(ErrorKind::InvalidData, m));
+            }
+            None => {
+                let m = format!("Transcript ended, but got {}", call.describe());
+                log::error!(target: "REPLAY", "{m}");
+                self.mismatch = Some(m.clone());
+                return Err(IOError::new(ErrorKind::InvalidData, m));
+            }
+        }
+    }
+}
+
+impl Communicator for Replayer {
+    fn send(&mut self, data: &[u8]) -> IOResult<()> {
+        log::trace!(target: "REPLAY", "Send: {}", format_data_buf(data));
+        let call = TranscriptEvent::Send {
+            data: data.to_vec(),
+            error: None,
+        };
+        match self.next(&call)? {
+            TranscriptEvent::Send { error: Some(e), .. } => return Err((&e).into()),
+            _ => return Ok(()),
+        }
+    }
+
+    fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
+        let call = TranscriptEvent::RecvExact {
+            len: how_much,
+            result: Ok(Vec::new()),
+        };
+        match self.next(&call)? {
+            TranscriptEvent::RecvExact { result, .. } => return result.map_err(|e| (&e).into()),
+            _ => unreachable!("Replayer returned a record not matching the call"),
+        }
+    }
+
+    fn recv(&mut self) -> IOResult<Vec<u8>> {
+        let call = TranscriptEvent::Recv {
+            result: Ok(Vec::new()),
+        };
+        match self.next(&call)? {
+            TranscriptEvent::Recv { result } => return result.map_err(|e| (&e).into()),
+            _ => unreachable!("Replayer returned a record not matching the call"),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        // A mismatch is remembered and reported by the next call
+        let _ = self.next(&TranscriptEvent::SetTimeout(timeout));
+    }
+
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        return self.send(data);
+    }
+
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        return self.recv_exact(how_much);
+    }
+}
+
+impl Drop for Replayer {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::warn!(target: "REPLAY", "Replay incomplete: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::download_protocol::{ActionAfter, Session};
+
+    /// Fake target answering every `recv_exact` with the next scripted reply.
+    struct ScriptedTarget {
+        replies: VecDeque<Vec<u8>>,
+    }
+
+    impl Communicator for ScriptedTarget {
+        fn send(&mut self, data: &[u8]) -> IOResult<()> {
+            return Ok(());
+        }
+
+        fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
+            match self.replies.pop_front() {
+                Some(reply) if reply.len() == how_much => return Ok(reply),
+                _ => return Err(ErrorKind::TimedOut.into()),
+            }
+        }
+
+        fn recv(&mut self) -> IOResult<Vec<u8>> {
+            return Ok(Vec::new());
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) {}
+    }
+
+    fn reply(cmd: u32, arg: u32) -> Vec<u8> {
+        return [cmd.to_le_bytes(), arg.to_le_bytes()].concat();
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path =
+            std::env::temp_dir().join(format!("ragnaroek-transcript-{}.txt", std::process::id()));
+        let target = ScriptedTarget {
+            replies: VecDeque::from([b"LOKE".to_vec(), reply(0x64, 0), reply(0x67, 0)]),
+        };
+
+        // Protocol version 1 target, which skips packet size negotiation
+        let recorder = Recorder::create(Box::new(target), &path).unwrap();
+        let sess = Session::begin(Box::new(recorder)).unwrap();
+        sess.end(ActionAfter::RebootOS).unwrap();
+
+        let transcript = Transcript::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let events: Vec<&TranscriptEvent> = transcript.records.iter().map(|r| &r.event).collect();
+        assert_eq!(6, events.len());
+        assert_eq!(
+            &TranscriptEvent::Send {
+                data: b"ODIN".to_vec(),
+                error: None
+            },
+            events[0]
+        );
+        assert_eq!(
+            &TranscriptEvent::RecvExact {
+                len: 8,
+                result: Ok(reply(0x64, 0))
+            },
+            events[3]
+        );
+        // Per-call deadlines don't leave a timeout behind
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, TranscriptEvent::SetTimeout(_))));
+        assert!(transcript
+            .records
+            .windows(2)
+            .all(|w| w[0].elapsed <= w[1].elapsed));
+
+        // The same session replays without a target
+        let sess = Session::begin(Box::new(Replayer::new(transcript.clone()))).unwrap();
+        sess.end(ActionAfter::RebootOS).unwrap();
+
+        // A different session doesn't
+        let sess = Session::begin(Box::new(Replayer::new(transcript.clone()))).unwrap();
+        sess.end(ActionAfter::Shutdown).err().unwrap();
+
+        // Sessions ending early are caught by finish()
+        let mut replayer = Replayer::new(transcript);
+        replayer.send(b"ODIN").unwrap();
+        assert_eq!(b"LOKE".to_vec(), replayer.recv_exact(4).unwrap());
+        assert_eq!(4, replayer.remaining());
+        replayer.finish().err().unwrap();
+    }
+
+    #[test]
+    fn test_transcript_format() {
+        let timed_out = TranscriptError {
+            kind: ErrorKind::TimedOut,
+            message: String::from("Receive timed out"),
+        };
+        let transcript = Transcript {
+            records: vec![
+                TranscriptRecord {
+                    elapsed: Duration::from_micros(1),
+                    event: TranscriptEvent::Send {
+                        data: vec![0x00, 0xff],
+                        error: None,
+                    },
+                },
+                TranscriptRecord {
+                    elapsed: Duration::from_micros(2),
+                    event: TranscriptEvent::Send {
+                        data: Vec::new(),
+                        error: Some(timed_out.clone()),
+                    },
+                },
+                TranscriptRecord {
+                    elapsed: Duration::from_micros(3),
+                    event: TranscriptEvent::RecvExact {
+                        len: 0,
+                        result: Ok(Vec::new()),
+                    },
+                },
+                TranscriptRecord {
+                    elapsed: Duration::from_micros(4),
+                    event: TranscriptEvent::RecvExact {
+                        len: 8,
+                        result: Err(timed_out.clone()),
+                    },
+                },
+                TranscriptRecord {
+                    elapsed: Duration::from_micros(5),
+                    event: TranscriptEvent::Recv {
+                        result: Ok(b"OK".to_vec()),
+                    },
+                },
+                TranscriptRecord {
+                    elapsed: Duration::from_micros(6),
+                    event: TranscriptEvent::SetTimeout(Duration::from_secs(30)),
+                },
+            ],
+        };
+
+        let mut buf: Vec<u8> = Vec::new();
+        transcript.write(&mut buf).unwrap();
+        let text = String::from_utf8(buf.clone()).unwrap();
+        assert!(text.contains("\n1 send 00ff\n"));
+        assert!(text.contains("\n4 recv_exact 8 ! TimedOut Receive timed out\n"));
+        assert_eq!(transcript, Transcript::read(buf.as_slice()).unwrap());
+
+        let err = Transcript::read("1 send 0".as_bytes()).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        // Recorded errors are replayed
+        let mut replayer = Replayer::new(transcript);
+        replayer.send(&[0x00, 0xff]).unwrap();
+        assert_eq!(
+            ErrorKind::TimedOut,
+            replayer.send(&[]).err().unwrap().kind()
+        );
+        assert_eq!(Vec::<u8>::new(), replayer.recv_exact(0).unwrap());
+        let err = replayer.recv_exact(8).err().unwrap();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        assert_eq!("Receive timed out", err.to_string());
+        assert_eq!(2, replayer.remaining());
+        replayer.finish().err().unwrap();
+        assert_eq!(b"OK".to_vec(), replayer.recv().unwrap());
+        replayer.finish().err().unwrap();
+        replayer.set_timeout(Duration::from_secs(30));
+        replayer.finish().unwrap();
+    }
+}
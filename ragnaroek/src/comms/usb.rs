@@ -1,155 +1,566 @@
 use super::*;
 use rusb::*;
 
-use std::io::Result as IOResult;
-use std::time::Duration;
+use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// These are taken from Heimdall, may not be exhaustive
 const SAMSUNG_VID: u16 = 0x04E8;
 const VALID_PIDS: [u16; 3] = [0x6601, 0x685D, 0x68C3];
 /// USB class that the desired configuration has (USB communications device)
 const USB_CLASS_CDC_DATA: u8 = 0x0A;
+/// Upper bound for the amount of data `recv` returns at once
+const RECV_BUF_SIZE: usize = 1024 * 1024;
+/// How often to check for cancellation while waiting for a device
+const WAIT_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+/// How often to re-enumerate devices while waiting for one, if hotplug events are available.
+/// A device may not be accessible yet when it's arrival is reported, e.g. while udev is still setting permissions.
+const WAIT_HOTPLUG_RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to re-enumerate devices while waiting for one, if hotplug events are unavailable
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 /// `Connection` implements a USB ODIN mode connection.
 pub struct Connection {
-    handle: DeviceHandle<GlobalContext>,
+    handle: DeviceHandle<Context>,
     send_endpoint: u8,
     recv_endpoint: u8,
+    /// Max packet size of the receiving endpoint. Reads are always a multiple of this,
+    /// so that the device can never send more than fits into the buffer.
+    recv_packet_size: usize,
+    /// Data that was received, but not yet returned to the caller.
+    pending: Vec<u8>,
     timeout: Duration,
 }
 
+/// Details of the interface used for communicating with a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbInterfaceInfo {
+    /// Interface number.
+    pub number: u8,
+    /// Address of the endpoint receiving data from the device.
+    pub in_endpoint: u8,
+    /// Address of the endpoint sending data to the device.
+    pub out_endpoint: u8,
+    /// Max packet size of the receiving endpoint.
+    pub in_max_packet_size: usize,
+}
+
+/// A connected USB device that looks like a Samsung device in download mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbDeviceInfo {
+    /// Number of the bus the device is connected to.
+    pub bus: u8,
+    /// Address of the device on it's bus.
+    pub address: u8,
+    /// Physical location of the device, as bus number and port chain, e.g. `1-4.2`.
+    /// Unlike the address, this stays the same across reconnects to the same port.
+    pub port_path: String,
+    /// USB product ID.
+    pub product_id: u16,
+    /// Serial number string. `None` if the device couldn't be opened to read it, e.g. due to missing permissions.
+    pub serial: Option<String>,
+    /// Product name string, if it could be read.
+    pub product: Option<String>,
+    /// The interface that would be used for communication. `None` if the device doesn't have a suitable one.
+    pub interface: Option<UsbInterfaceInfo>,
+}
+
+/// Selects one device among several connected ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsbDeviceSelector {
+    /// Match the device's serial number string.
+    Serial(String),
+    /// Match the device's port path, e.g. `1-4.2`.
+    PortPath(String),
+}
+
+impl UsbDeviceSelector {
+    /// Parse a selector given by the user.
+    ///
+    /// The prefixes `serial:` and `path:` select by serial number or port path explicitly.
+    /// Without a prefix, strings of the form `<bus>-<port>[.<port>...]` select by port path, anything else by serial number.
+    pub fn parse(s: &str) -> UsbDeviceSelector {
+        if let Some(serial) = s.strip_prefix("serial:") {
+            return UsbDeviceSelector::Serial(serial.to_string());
+        }
+        if let Some(path) = s.strip_prefix("path:") {
+            return UsbDeviceSelector::PortPath(path.to_string());
+        }
+        let is_port_path = match s.split_once('-') {
+            Some((bus, ports)) => {
+                !bus.is_empty()
+                    && bus.chars().all(|c| c.is_ascii_digit())
+                    && !ports.is_empty()
+                    && ports
+                        .split('.')
+                        .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+            }
+            None => false,
+        };
+        if is_port_path {
+            return UsbDeviceSelector::PortPath(s.to_string());
+        } else {
+            return UsbDeviceSelector::Serial(s.to_string());
+        }
+    }
+
+    /// Returns whether the given device is selected.
+    pub fn matches(&self, dev: &UsbDeviceInfo) -> bool {
+        match self {
+            UsbDeviceSelector::Serial(serial) => return dev.serial.as_ref() == Some(serial),
+            UsbDeviceSelector::PortPath(path) => return dev.port_path == *path,
+        }
+    }
+}
+
+/// Turn a libusb error into an I/O error of the closest matching kind.
+pub(crate) fn usb_error(e: rusb::Error) -> IOError {
+    let kind = match e {
+        rusb::Error::Io => ErrorKind::Other,
+        rusb::Error::InvalidParam => ErrorKind::InvalidInput,
+        rusb::Error::Access => ErrorKind::PermissionDenied,
+        rusb::Error::NoDevice => ErrorKind::NotConnected,
+        rusb::Error::NotFound => ErrorKind::NotFound,
+        rusb::Error::Busy => ErrorKind::ResourceBusy,
+        rusb::Error::Timeout => ErrorKind::TimedOut,
+        rusb::Error::Overflow => ErrorKind::InvalidData,
+        rusb::Error::Pipe => ErrorKind::BrokenPipe,
+        rusb::Error::Interrupted => ErrorKind::Interrupted,
+        rusb::Error::NoMem => ErrorKind::OutOfMemory,
+        rusb::Error::NotSupported => ErrorKind::Unsupported,
+        rusb::Error::BadDescriptor => ErrorKind::InvalidData,
+        rusb::Error::Other => ErrorKind::Other,
+    };
+    return IOError::new(kind, e);
+}
+
 impl Connection {
     /// Establish a new connection to the first viable USB device.
     /// Returns an error if no suitable device could be found.
     pub fn establish() -> IOResult<Connection> {
-        // FIXME: Error handling
-        // Find the device
-        // TODO: Handle multi-device
-        let dev: Option<Device<GlobalContext>> = rusb::devices().unwrap().iter().find(|dev| {
-            let desc = dev.device_descriptor().unwrap();
-            desc.vendor_id() == SAMSUNG_VID && VALID_PIDS.contains(&desc.product_id())
+        return Connection::establish_with(None);
+    }
+
+    /// Establish a new connection to the viable USB device chosen by `selector`,
+    /// or the first viable one if no selector is given.
+    /// Returns an error if no suitable device could be found.
+    pub fn establish_with(selector: Option<&UsbDeviceSelector>) -> IOResult<Connection> {
+        let mut candidates = candidate_devices()?.into_iter().filter(|(_, info)| {
+            info.interface.is_some() && selector.is_none_or(|s| s.matches(info))
         });
-        let dev = dev.expect("Failed to find supported USB device!");
+        let (dev, info) = candidates.next().ok_or_else(|| {
+            IOError::new(ErrorKind::NotFound, "Failed to find supported USB device")
+        })?;
+        if selector.is_none() && candidates.next().is_some() {
+            log::warn!(target: "USB", "Multiple devices found, using the one at {}", info.port_path);
+        }
+        let endpoints = info
+            .interface
+            .expect("Candidates without interface must have been filtered out");
 
-        let mut handle = dev.open().unwrap();
+        let handle = dev.open().map_err(usb_error)?;
         // Not supported on macOS, ignore the error for now.
         let _ = handle.set_auto_detach_kernel_driver(true);
-        // Find endpoint suitable for sending data to the device
-        let (input, output, interface_num) = find_endpoints(&dev)
-            .expect("Could not find suitable endpoint for sending data to the USB device");
-        // Find endpoint suitable for receiving data from the device
-        handle.claim_interface(interface_num).unwrap();
+        handle
+            .claim_interface(endpoints.number)
+            .map_err(usb_error)?;
 
-        log::debug!(target: "USB", "Connected");
+        log::debug!(target: "USB", "Connected to {}", info.port_path);
         return Ok(Connection {
             handle,
-            recv_endpoint: input,
-            send_endpoint: output,
+            recv_endpoint: endpoints.in_endpoint,
+            send_endpoint: endpoints.out_endpoint,
+            recv_packet_size: endpoints.in_max_packet_size,
+            pending: Vec::new(),
             timeout: super::DEFAULT_TIMEOUT,
         });
     }
+
+    /// Perform a single bulk read of up to `len` bytes, rounded up to whole packets.
+    ///
+    /// Returns the received data, which may be empty if the device sent a zero-length packet.
+    fn read_packets(&mut self, len: usize, timeout: Duration) -> rusb::Result<Vec<u8>> {
+        let len = std::cmp::max(len, 1).next_multiple_of(self.recv_packet_size);
+        let mut buf = vec![0; len];
+        let read = self
+            .handle
+            .read_bulk(self.recv_endpoint, &mut buf, timeout)?;
+        buf.truncate(read);
+        return Ok(buf);
+    }
 }
 
-/// Walk the device's descriptors and find the correct endpoints.
-/// Returns Some((input_endpoint, output_endpoint, interface_number)) on success.
-fn find_endpoints(dev: &Device<GlobalContext>) -> Option<(u8, u8, u8)> {
-    // Walk in the order configuration descriptor -> interface descriptor -> endpoint descripto
-    // Get configuration descriptors
-    let conf = dev.active_config_descriptor().unwrap();
-    let ifaces = conf.interfaces();
+/// List all connected USB devices that look like Samsung devices in download mode.
+pub fn list_devices() -> IOResult<Vec<UsbDeviceInfo>> {
+    return Ok(candidate_devices()?
+        .into_iter()
+        .map(|(_, info)| info)
+        .collect());
+}
 
-    // Get interface descriptors of the correct class and correct number of endpoints
-    let mut iface_descriptor: Option<InterfaceDescriptor> = None;
-    let mut iface_num: Option<u8> = None;
-    for iface in ifaces {
-        for descr in iface.descriptors() {
-            if (descr.class_code() == USB_CLASS_CDC_DATA) && (descr.num_endpoints() == 2) {
-                iface_num = Some(descr.interface_number());
-                iface_descriptor = Some(descr);
-                break;
+/// Hotplug callback noting that some Samsung device arrived.
+///
+/// Devices mustn't be opened from within the callback, so the actual matching happens in `wait_for_device`.
+struct ArrivalFlag(Arc<AtomicBool>);
+
+impl Hotplug<Context> for ArrivalFlag {
+    fn device_arrived(&mut self, _device: Device<Context>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn device_left(&mut self, _device: Device<Context>) {}
+}
+
+/// Wait until a usable download mode device chosen by `selector` (or any, if `None`) is connected.
+///
+/// Uses libusb hotplug events where the platform supports them, and polling otherwise.
+/// Returns immediately if a matching device is already connected.
+/// Fails with `ErrorKind::TimedOut` once `timeout` passes, and `ErrorKind::Interrupted` if cancelled via `cancel`.
+pub fn wait_for_device(
+    selector: Option<&UsbDeviceSelector>,
+    timeout: Option<Duration>,
+    cancel: &CancelHandle,
+) -> IOResult<UsbDeviceInfo> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let ctx = Context::new().map_err(usb_error)?;
+
+    let arrived = Arc::new(AtomicBool::new(false));
+    let registration = if rusb::has_hotplug() {
+        let registration = HotplugBuilder::new()
+            .vendor_id(SAMSUNG_VID)
+            .register(&ctx, Box::new(ArrivalFlag(arrived.clone())));
+        match registration {
+            Ok(registration) => Some(registration),
+            Err(e) => {
+                log::debug!(target: "USB", "Failed to register for hotplug events, polling instead: {e}");
+                None
+            }
+        }
+    } else {
+        log::debug!(target: "USB", "Hotplug events unsupported, polling instead");
+        None
+    };
+    let rescan_interval = match registration {
+        Some(_) => WAIT_HOTPLUG_RESCAN_INTERVAL,
+        None => WAIT_POLL_INTERVAL,
+    };
+
+    let mut next_scan = Instant::now();
+    loop {
+        if arrived.swap(false, Ordering::SeqCst) || Instant::now() >= next_scan {
+            let found = candidate_devices()?.into_iter().find(|(_, info)| {
+                info.interface.is_some() && selector.is_none_or(|s| s.matches(info))
+            });
+            if let Some((_, info)) = found {
+                log::info!(target: "USB", "Found device at {}", info.port_path);
+                return Ok(info);
             }
+            next_scan = Instant::now() + rescan_interval;
         }
-        if iface_descriptor.is_some() {
-            break;
+
+        if cancel.is_cancelled() {
+            return Err(IOError::new(
+                ErrorKind::Interrupted,
+                "Waiting for device was cancelled",
+            ));
+        }
+        let mut wait = WAIT_EVENT_INTERVAL;
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(IOError::new(
+                    ErrorKind::TimedOut,
+                    "Timed out waiting for device",
+                ));
+            }
+            wait = wait.min(left);
+        }
+        match registration {
+            Some(_) => ctx.handle_events(Some(wait)).map_err(usb_error)?,
+            None => std::thread::sleep(wait),
         }
     }
+}
+
+/// Find all devices with a Samsung download mode VID/PID, along with their details.
+fn candidate_devices() -> IOResult<Vec<(Device<Context>, UsbDeviceInfo)>> {
+    // Unlike the global context, this reports failure to initialize libusb instead of panicking
+    let ctx = Context::new().map_err(usb_error)?;
+    let mut candidates = Vec::new();
+    for dev in ctx.devices().map_err(usb_error)?.iter() {
+        // Devices we can't even get the descriptor of are certainly not ours
+        let Ok(desc) = dev.device_descriptor() else {
+            continue;
+        };
+        if desc.vendor_id() != SAMSUNG_VID || !VALID_PIDS.contains(&desc.product_id()) {
+            continue;
+        }
+
+        let ports: Vec<String> = dev
+            .port_numbers()
+            .unwrap_or_default()
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        // Reading strings requires opening the device, which may not be permitted
+        let (serial, product) = match dev.open() {
+            Ok(handle) => (
+                handle.read_serial_number_string_ascii(&desc).ok(),
+                handle.read_product_string_ascii(&desc).ok(),
+            ),
+            Err(e) => {
+                log::debug!(target: "USB", "Failed to open device to read it's strings: {e}");
+                (None, None)
+            }
+        };
+        let interface = match find_endpoints(&dev) {
+            Ok(interface) => Some(interface),
+            Err(e) => {
+                log::debug!(target: "USB", "No suitable interface: {e}");
+                None
+            }
+        };
+
+        let info = UsbDeviceInfo {
+            bus: dev.bus_number(),
+            address: dev.address(),
+            port_path: format!("{}-{}", dev.bus_number(), ports.join(".")),
+            product_id: desc.product_id(),
+            serial,
+            product,
+            interface,
+        };
+        candidates.push((dev, info));
+    }
+    return Ok(candidates);
+}
+
+/// Walk the device's descriptors and find the correct endpoints.
+fn find_endpoints(dev: &Device<Context>) -> IOResult<UsbInterfaceInfo> {
+    // Walk in the order configuration descriptor -> interface descriptor -> endpoint descriptor
+    // Get configuration descriptors
+    let conf = dev.active_config_descriptor().map_err(usb_error)?;
+    let ifaces = conf.interfaces();
+
+    // Get interface descriptors of the correct class and correct number of endpoints
+    let iface_descriptor: Option<InterfaceDescriptor> = ifaces
+        .flat_map(|iface| iface.descriptors())
+        .find(|descr| descr.class_code() == USB_CLASS_CDC_DATA && descr.num_endpoints() == 2);
+    let iface_descriptor = iface_descriptor.ok_or_else(|| {
+        IOError::new(
+            ErrorKind::NotFound,
+            "Failed to find matching interface descriptor",
+        )
+    })?;
 
     // Of these endpoints, find the correct input and output ones
-    let iface_descriptor = iface_descriptor.expect("Failed to find matching interface descriptor");
-    let endpoint_descriptors = iface_descriptor.endpoint_descriptors();
-    let mut input: Option<u8> = None;
+    let mut input: Option<(u8, usize)> = None;
     let mut output: Option<u8> = None;
-    for descr in endpoint_descriptors {
+    for descr in iface_descriptor.endpoint_descriptors() {
         match descr.direction() {
-            Direction::In => input = Some(descr.address()),
+            Direction::In => input = Some((descr.address(), descr.max_packet_size().into())),
             Direction::Out => output = Some(descr.address()),
         }
     }
-    if input.is_none() {
-        panic!("Failed to find input descriptor");
-    }
-    if output.is_none() {
-        panic!("Failed to find output descriptor");
+    let (in_endpoint, in_max_packet_size) =
+        input.ok_or_else(|| IOError::new(ErrorKind::NotFound, "Failed to find input endpoint"))?;
+    let out_endpoint = output
+        .ok_or_else(|| IOError::new(ErrorKind::NotFound, "Failed to find output endpoint"))?;
+    if in_max_packet_size == 0 {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "Input endpoint has a max packet size of 0",
+        ));
     }
 
-    return Some((input.unwrap(), output.unwrap(), iface_num.unwrap()));
+    return Ok(UsbInterfaceInfo {
+        number: iface_descriptor.interface_number(),
+        in_endpoint,
+        out_endpoint,
+        in_max_packet_size,
+    });
 }
 
 impl Communicator for Connection {
     /// Sends the given data to the device.
     /// Blocks until all data could be sent or an error occurs.
+    ///
+    /// Empty data is sent as a zero-length packet.
+    /// No zero-length packet is added after data filling its last packet, the protocol sends those itself where the target expects them.
     fn send(&mut self, data: &[u8]) -> IOResult<()> {
-        log::trace!(target: "USB", "Send: {}", format_data_buf(data));
-        self.handle
-            .write_bulk(self.send_endpoint, data, self.timeout)
-            .unwrap();
-
-        return Ok(());
+        return self.send_before(data, Instant::now() + self.timeout);
     }
 
+    /// Receive exactly `how_much` bytes, possibly spanning multiple transfers.
+    ///
+    /// `how_much == 0` receives a single zero-length packet, which some protocol versions send to end a transfer.
+    /// If the timeout passes, data received so far is discarded and a `TimedOut` error is returned.
+    /// Data arriving after that belongs to a reply the caller gave up on, so keeping it would only desync later reads.
     fn recv_exact(&mut self, how_much: usize) -> IOResult<Vec<u8>> {
-        let mut buf = vec![0; how_much];
-        match self
-            .handle
-            .read_bulk(self.recv_endpoint, &mut buf, self.timeout)
-        {
-            // FIXME: Should read repeatedly until desired data volume is reached instead
-            Ok(read) => buf.resize(read, 0),
-            Err(e) => panic!("{}", e),
+        return self.recv_exact_before(how_much, Instant::now() + self.timeout);
+    }
+
+    fn send_before(&mut self, data: &[u8], deadline: Instant) -> IOResult<()> {
+        log::trace!(target: "USB", "Send: {}", format_data_buf(data));
+        let mut sent: usize = 0;
+        loop {
+            let written = self
+                .handle
+                .write_bulk(self.send_endpoint, &data[sent..], time_left(deadline)?)
+                .map_err(usb_error)?;
+            sent += written;
+            if sent >= data.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn recv_exact_before(&mut self, how_much: usize, deadline: Instant) -> IOResult<Vec<u8>> {
+        if how_much == 0 {
+            let packet = self
+                .read_packets(0, time_left(deadline)?)
+                .map_err(usb_error)?;
+            if !packet.is_empty() {
+                log::warn!(target: "USB", "Expected zero-length packet, got {} bytes", packet.len());
+                self.pending.extend_from_slice(&packet);
+            }
+            log::trace!(target: "USB", "Recv blocking: []");
+            return Ok(Vec::new());
         }
 
+        let mut pending = std::mem::take(&mut self.pending);
+        let result = take_exact(&mut pending, how_much, |wanted| {
+            let timeout = time_left(deadline)?;
+            return self.read_packets(wanted, timeout).map_err(usb_error);
+        });
+        self.pending = pending;
+        let buf = result?;
         log::trace!(target: "USB", "Recv blocking: {}", format_data_buf(&buf));
         return Ok(buf);
     }
 
     fn recv(&mut self) -> IOResult<Vec<u8>> {
-        // TODO: Figure out max size properly
-        let mut buf = vec![0; 1024 * 1024];
-        match self
-            .handle
-            .read_bulk(self.recv_endpoint, &mut buf, Duration::from_millis(1))
-        {
-            Ok(read) => buf.resize(read, 0),
-            // Timeout is used as a hack to not block if there's no data to read
-            Err(rusb::Error::Timeout) => buf.clear(),
-            // FIXME: Turn into an I/O error somehow
-            Err(e) => panic!("{}", e),
+        if !self.pending.is_empty() {
+            let buf = std::mem::take(&mut self.pending);
+            log::trace!(target: "USB", "Recv nonblocking: {}", format_data_buf(&buf));
+            return Ok(buf);
         }
 
+        let buf = match self.read_packets(RECV_BUF_SIZE, Duration::from_millis(1)) {
+            Ok(buf) => buf,
+            // Timeout is used as a hack to not block if there's no data to read
+            Err(rusb::Error::Timeout) => Vec::new(),
+            Err(e) => return Err(usb_error(e)),
+        };
+
         log::trace!(target: "USB", "Recv nonblocking: {}", format_data_buf(&buf));
         return Ok(buf);
     }
 
     fn set_timeout(&mut self, timeout: Duration) {
-        log::info!(target: "USB", "Setting timeout: {timeout:?}");
+        log::debug!(target: "USB", "Setting timeout: {timeout:?}");
         self.timeout = timeout;
     }
 }
 
+/// Take exactly `how_much` bytes off the front of `pending`, calling `read` for more data until there is enough.
+///
+/// `read` is passed the number of bytes still missing, and may return fewer or more than that.
+/// Zero-length packets only terminate the device's transfer, so reading continues after them.
+/// If `read` fails, everything pending is discarded.
+fn take_exact(
+    pending: &mut Vec<u8>,
+    how_much: usize,
+    mut read: impl FnMut(usize) -> IOResult<Vec<u8>>,
+) -> IOResult<Vec<u8>> {
+    while pending.len() < how_much {
+        match read(how_much - pending.len()) {
+            Ok(packets) => pending.extend_from_slice(&packets),
+            Err(e) => {
+                log::debug!(target: "USB", "Recv failed with {} of {how_much} bytes received, discarding them: {e}", pending.len());
+                pending.clear();
+                return Err(e);
+            }
+        }
+    }
+
+    let rest = pending.split_off(how_much);
+    return Ok(std::mem::replace(pending, rest));
+}
+
 impl Drop for Connection {
     fn drop(&mut self) {
         log::info!(target: "USB", "Dropping Connection, resetting device");
-        self.handle.reset().unwrap();
-        log::info!(target: "USB", "Device reset OK");
+        // The device may well be gone already, e.g. after a reboot
+        match self.handle.reset() {
+            Ok(()) => log::info!(target: "USB", "Device reset OK"),
+            Err(e) => log::warn!(target: "USB", "Device reset failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// `read` callback returning the given results in order.
+    fn scripted(mut results: Vec<IOResult<Vec<u8>>>) -> impl FnMut(usize) -> IOResult<Vec<u8>> {
+        results.reverse();
+        return move |_| results.pop().expect("Unexpected read");
+    }
+
+    #[test]
+    fn test_take_exact() {
+        // Transfers are reassembled, including zero-length packets in between
+        let mut pending = Vec::new();
+        let read = scripted(vec![Ok(vec![1, 2]), Ok(Vec::new()), Ok(vec![3, 4, 5, 6])]);
+        assert_eq!(vec![1, 2, 3, 4], take_exact(&mut pending, 4, read).unwrap());
+        // Surplus data is kept for the next call, which doesn't need to read
+        assert_eq!(vec![5, 6], pending);
+        assert_eq!(
+            vec![5],
+            take_exact(&mut pending, 1, scripted(Vec::new())).unwrap()
+        );
+        assert_eq!(vec![6], pending);
+
+        // A timeout discards what was received of the reply
+        let read = scripted(vec![
+            Ok(vec![7, 8]),
+            Err(IOError::new(ErrorKind::TimedOut, "Timeout")),
+        ]);
+        let e = take_exact(&mut pending, 8, read).unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, e.kind());
+        assert!(pending.is_empty());
+        // The next reply is read from scratch
+        let read = scripted(vec![Ok(vec![9, 10])]);
+        assert_eq!(vec![9, 10], take_exact(&mut pending, 2, read).unwrap());
+    }
+
+    #[test]
+    fn test_parse_selector() {
+        assert_eq!(
+            UsbDeviceSelector::PortPath(String::from("1-4.2")),
+            UsbDeviceSelector::parse("1-4.2")
+        );
+        assert_eq!(
+            UsbDeviceSelector::PortPath(String::from("3-1")),
+            UsbDeviceSelector::parse("3-1")
+        );
+        for serial in ["R58M123ABCD", "1-", "1-4.", "-4", "a-1"] {
+            assert_eq!(
+                UsbDeviceSelector::Serial(String::from(serial)),
+                UsbDeviceSelector::parse(serial)
+            );
+        }
+        assert_eq!(
+            UsbDeviceSelector::Serial(String::from("1-4")),
+            UsbDeviceSelector::parse("serial:1-4")
+        );
+        assert_eq!(
+            UsbDeviceSelector::PortPath(String::from("2-1.3")),
+            UsbDeviceSelector::parse("path:2-1.3")
+        );
     }
 }
@@ -0,0 +1,642 @@
+//! This module decodes captured traffic, such as of official Odin or Heimdall sessions,
+//! into download mode, upload mode and shell messages.
+//!
+//! Captures are first read into a list of `Transfer`s, which `dissect` then decodes.
+//! The decoder tracks the session, so data transfers like PIT chunks and file parts are recognized as such.
+
+mod reader;
+
+pub use reader::*;
+
+use crate::download_protocol::{
+    OdinCmd, OdinCmdPacket, OdinCmdReply, BEGIN_SESSION, CMD_PACKET_LEN, CMD_REPLY_LEN,
+    ERASE_USERDATA, FLASH_CMD_BEGIN_FLASH, FLASH_CMD_SEQUENCE_BEGIN, FLASH_CMD_SEQUENCE_END, PING,
+    PIT_FLAG_CHUNK, PIT_FLAG_DUMP, PIT_FLAG_END, PIT_FLAG_FLASH, PONG, SET_PACKET_SIZE,
+    SET_TOTAL_SIZE,
+};
+use crate::shell::SHELL_PREFIX;
+use crate::upload_protocol::{
+    Bitness, ACKNOWLEDGMENT, DATAXFER, DEVICE_NAME_LEN, POSTAMBLE, PREAMBLE, PROBE,
+};
+use crate::{Error, Result};
+
+use core::fmt;
+use std::time::Duration;
+
+/// Which way a transfer went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the host, such as Odin, to the target.
+    ToTarget,
+    /// From the target to the host.
+    FromTarget,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::ToTarget => write!(f, "H->T"),
+            Direction::FromTarget => write!(f, "T->H"),
+        }
+    }
+}
+
+/// A single transfer read from a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    /// Which way the transfer went.
+    pub direction: Direction,
+    /// When the transfer happened, if the capture says.
+    pub timestamp: Option<Duration>,
+    /// Length of the transfer. Can be larger than `data`, if the capture only kept part of it.
+    pub len: usize,
+    /// Data of the transfer, as far as it was captured.
+    pub data: Vec<u8>,
+}
+
+/// Magic strings of the upload mode protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadMagic {
+    /// `PrEaMbLe`, the host starting a session.
+    Preamble,
+    /// `AcKnOwLeDgMeNt`, the target's reply to the preamble.
+    Acknowledgment,
+    /// `PrObE`, the host requesting the probe table.
+    Probe,
+    /// `DaTaXfEr`, the host requesting a memory transfer.
+    DataXfer,
+    /// `PoStAmBlE`, the host ending the session.
+    Postamble,
+}
+
+impl UploadMagic {
+    const ALL: [(UploadMagic, &'static [u8]); 5] = [
+        (UploadMagic::Preamble, PREAMBLE),
+        (UploadMagic::Acknowledgment, ACKNOWLEDGMENT),
+        (UploadMagic::Probe, PROBE),
+        (UploadMagic::DataXfer, DATAXFER),
+        (UploadMagic::Postamble, POSTAMBLE),
+    ];
+
+    fn detect(data: &[u8]) -> Option<UploadMagic> {
+        return UploadMagic::ALL
+            .iter()
+            .find(|(_, magic)| data.starts_with(magic))
+            .map(|(m, _)| *m);
+    }
+}
+
+/// A decoded transfer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// The host's `ODIN` handshake.
+    Handshake,
+    /// The target's `LOKE` reply to the handshake.
+    HandshakeReply,
+    /// Download mode command packet.
+    Command(OdinCmdPacket),
+    /// The target's reply to a command, or acknowledgment of a file part.
+    Reply(OdinCmdReply),
+    /// Chunk of the PIT the target sends, with its index.
+    PitChunk(u32),
+    /// PIT the host sends to replace the target's.
+    PitData,
+    /// Part of a file sequence the host flashes, with its index in the sequence.
+    FilePart(u32),
+    /// Empty transfer, which some protocol steps require on USB.
+    Empty,
+    /// Command for the bootloader's shell.
+    ShellCommand(String),
+    /// Response of the bootloader's shell.
+    ShellResponse(String),
+    /// Upload mode magic string.
+    UploadMagic(UploadMagic),
+    /// Memory address sent ahead of an upload mode transfer.
+    UploadAddress(u64),
+    /// The target's upload mode probe table, with its device name and bitness.
+    ProbeTable(String, Bitness),
+    /// Memory the target sends in upload mode.
+    UploadData,
+    /// Data that doesn't fit the protocol as far as it's known.
+    Unknown,
+}
+
+/// What a command does, for the commands that are known.
+fn describe_command(p: &OdinCmdPacket) -> Option<&'static str> {
+    let arg1 = p.args()[0].inner;
+    let description = match (p.cmd(), arg1) {
+        (OdinCmd::SessionStart, BEGIN_SESSION) => "begin session",
+        (OdinCmd::SessionStart, SET_TOTAL_SIZE) => "set total size",
+        (OdinCmd::SessionStart, SET_PACKET_SIZE) => "set file part size",
+        (OdinCmd::SessionStart, ERASE_USERDATA) => "erase userdata",
+        (OdinCmd::TransferPIT, PIT_FLAG_FLASH) => "begin PIT flash",
+        (OdinCmd::TransferPIT, PIT_FLAG_DUMP) => "begin PIT dump",
+        (OdinCmd::TransferPIT, PIT_FLAG_CHUNK) => "PIT chunk or size",
+        (OdinCmd::TransferPIT, PIT_FLAG_END) => "end PIT transfer",
+        (OdinCmd::Flash, FLASH_CMD_BEGIN_FLASH) => "begin flash",
+        (OdinCmd::Flash, FLASH_CMD_SEQUENCE_BEGIN) => "begin file sequence",
+        (OdinCmd::Flash, FLASH_CMD_SEQUENCE_END) => "end file sequence",
+        (OdinCmd::SessionEnd, _) => "end session",
+        _ => return None,
+    };
+    return Some(description);
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Handshake => write!(f, "Handshake"),
+            Message::HandshakeReply => write!(f, "Handshake reply"),
+            Message::Command(p) => match describe_command(p) {
+                Some(d) => write!(f, "{p} ({d})"),
+                None => write!(f, "{p}"),
+            },
+            Message::Reply(r) => write!(f, "{r}"),
+            Message::PitChunk(i) => write!(f, "PIT chunk {i}"),
+            Message::PitData => write!(f, "PIT data"),
+            Message::FilePart(i) => write!(f, "File part {i}"),
+            Message::Empty => write!(f, "Empty transfer"),
+            Message::ShellCommand(s) => write!(f, "Shell command: {s:?}"),
+            Message::ShellResponse(s) => write!(f, "Shell response: {s:?}"),
+            Message::UploadMagic(m) => write!(f, "Upload mode {m:?}"),
+            Message::UploadAddress(a) => write!(f, "Upload mode address 0x{a:X}"),
+            Message::ProbeTable(name, bitness) => {
+                write!(f, "Probe table of {name:?} ({bitness:?})")
+            }
+            Message::UploadData => write!(f, "Memory data"),
+            Message::Unknown => write!(f, "Unknown"),
+        }
+    }
+}
+
+/// A transfer and what it was decoded as.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dissected {
+    /// The transfer as captured.
+    pub transfer: Transfer,
+    /// What the transfer was decoded as.
+    pub message: Message,
+}
+
+impl fmt::Display for Dissected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let t = &self.transfer;
+        match t.timestamp {
+            Some(ts) => write!(f, "{:>17.6} ", ts.as_secs_f64())?,
+            None => write!(f, "{:>17} ", "-")?,
+        }
+        write!(f, "{} {:>8} {}", t.direction, t.len, self.message)
+    }
+}
+
+/// Data the decoder expects next, based on earlier messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    Nothing,
+    /// The target sending the PIT chunk with the given index
+    PitChunk(u32),
+    /// The host sending a PIT
+    PitData,
+    /// The host sending file parts, currently the one with the given index, until the given amount of data was sent
+    FileParts(u32, u64),
+    /// The target sending its probe table
+    ProbeTable,
+    /// The target sending memory
+    UploadData,
+}
+
+/// Decoder state carried from one transfer to the next.
+struct Decoder {
+    expect: Expect,
+    /// Whether a PIT transfer dumps the target's PIT, as opposed to flashing it
+    pit_dump: bool,
+    upload_mode: bool,
+    shell: bool,
+}
+
+impl Decoder {
+    fn decode(&mut self, t: &Transfer) -> Message {
+        if t.len == 0 {
+            return Message::Empty;
+        }
+        match t.direction {
+            Direction::ToTarget => return self.decode_host(t),
+            Direction::FromTarget => return self.decode_target(t),
+        }
+    }
+
+    fn decode_host(&mut self, t: &Transfer) -> Message {
+        let data = &t.data[..];
+        if data == PING {
+            return Message::Handshake;
+        }
+        if let Some(cmd) = data.strip_prefix(SHELL_PREFIX.as_bytes()) {
+            self.shell = true;
+            return Message::ShellCommand(String::from_utf8_lossy(cmd).trim().to_string());
+        }
+        if let Some(magic) = UploadMagic::detect(data) {
+            self.upload_mode = true;
+            self.expect = match magic {
+                UploadMagic::Probe => Expect::ProbeTable,
+                UploadMagic::DataXfer => Expect::UploadData,
+                _ => Expect::Nothing,
+            };
+            return Message::UploadMagic(magic);
+        }
+
+        match self.expect {
+            Expect::PitData => {
+                self.expect = Expect::Nothing;
+                return Message::PitData;
+            }
+            // A part can span several transfers, it ends with the target's acknowledgment
+            Expect::FileParts(index, remaining) => {
+                let remaining = remaining.saturating_sub(t.len as u64);
+                self.expect = Expect::FileParts(index, remaining);
+                return Message::FilePart(index);
+            }
+            _ => self.expect = Expect::Nothing,
+        }
+
+        if self.upload_mode {
+            // Addresses are padded with zeros, so 32-bit ones read the same
+            let mut addr: [u8; 8] = [0; 8];
+            for (a, d) in addr.iter_mut().zip(data) {
+                *a = *d;
+            }
+            return Message::UploadAddress(u64::from_le_bytes(addr));
+        }
+
+        if t.len == CMD_PACKET_LEN && data.len() >= 8 {
+            if let Ok(p) = OdinCmdPacket::from_wire(data) {
+                let args: Vec<u32> = p.args().iter().map(|a| a.inner).collect();
+                let arg2 = args.get(1).copied().unwrap_or(0);
+                match (p.cmd(), args[0]) {
+                    (OdinCmd::TransferPIT, PIT_FLAG_FLASH) => self.pit_dump = false,
+                    (OdinCmd::TransferPIT, PIT_FLAG_DUMP) => self.pit_dump = true,
+                    (OdinCmd::TransferPIT, PIT_FLAG_CHUNK) if self.pit_dump => {
+                        self.expect = Expect::PitChunk(arg2);
+                    }
+                    (OdinCmd::TransferPIT, PIT_FLAG_CHUNK) => self.expect = Expect::PitData,
+                    (OdinCmd::Flash, FLASH_CMD_SEQUENCE_BEGIN) => {
+                        self.expect = Expect::FileParts(0, u64::from(arg2));
+                    }
+                    _ => {}
+                }
+                return Message::Command(p);
+            }
+        }
+        return Message::Unknown;
+    }
+
+    fn decode_target(&mut self, t: &Transfer) -> Message {
+        let data = &t.data[..];
+        if data == PONG {
+            return Message::HandshakeReply;
+        }
+        if let Some(magic) = UploadMagic::detect(data) {
+            self.upload_mode = true;
+            return Message::UploadMagic(magic);
+        }
+
+        match self.expect {
+            Expect::PitChunk(index) => {
+                self.expect = Expect::Nothing;
+                return Message::PitChunk(index);
+            }
+            Expect::ProbeTable => {
+                self.expect = Expect::Nothing;
+                let bitness = match data.first() {
+                    Some(b'+') => Bitness::SixtyFour,
+                    _ => Bitness::ThirtyTwo,
+                };
+                let name: Vec<u8> = data
+                    .iter()
+                    .take(DEVICE_NAME_LEN)
+                    .skip_while(|b| **b == b'+')
+                    .take_while(|b| **b != 0)
+                    .copied()
+                    .collect();
+                return Message::ProbeTable(String::from_utf8_lossy(&name).to_string(), bitness);
+            }
+            // Memory may arrive in several transfers
+            Expect::UploadData => return Message::UploadData,
+            _ => {}
+        }
+
+        if let Ok(buf) = <[u8; CMD_REPLY_LEN]>::try_from(data) {
+            if let Ok(r) = OdinCmdReply::from_wire(buf) {
+                if let Expect::FileParts(index, remaining) = self.expect {
+                    if r.cmd == OdinCmd::ChunkTransferOk {
+                        self.expect = match remaining {
+                            0 => Expect::Nothing,
+                            _ => Expect::FileParts(index + 1, remaining),
+                        };
+                    }
+                }
+                return Message::Reply(r);
+            }
+        }
+        if self.shell {
+            return Message::ShellResponse(String::from_utf8_lossy(data).to_string());
+        }
+        return Message::Unknown;
+    }
+}
+
+/// Decode the given transfers, in the order they happened.
+pub fn dissect(transfers: Vec<Transfer>) -> Vec<Dissected> {
+    let mut decoder = Decoder {
+        expect: Expect::Nothing,
+        pit_dump: false,
+        upload_mode: false,
+        shell: false,
+    };
+    return transfers
+        .into_iter()
+        .map(|transfer| Dissected {
+            message: decoder.decode(&transfer),
+            transfer,
+        })
+        .collect();
+}
+
+/// Read and decode the capture in the given file. See `read_capture` for supported formats.
+pub fn dissect_file<P: AsRef<std::path::Path>>(path: P) -> Result<Vec<Dissected>> {
+    let data = std::fs::read(path).map_err(Error::from)?;
+    return Ok(dissect(read_capture(&data)?));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::pipe::Connection as Pipe;
+    use crate::download_protocol::emulator::{spawn_target, TargetOptions};
+    use crate::download_protocol::{ActionAfter, Session};
+    use crate::upload_protocol::emulator::{MemoryRegion, Target as UploadTarget, TargetConfig};
+    use crate::upload_protocol::{end_session, handshake, send_packet};
+    use crate::{Capture, CaptureLinkType, Communicator};
+    use pit::Pit;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    const PIT: &str = "../pit/testdata/A40_EUR_OPEN.pit";
+
+    fn temp_path(name: &str) -> PathBuf {
+        return std::env::temp_dir()
+            .join(format!("ragnaroek-dissect-{}-{name}", std::process::id()));
+    }
+
+    /// Read a finished capture back and remove it.
+    fn read_back(path: &PathBuf) -> Vec<Dissected> {
+        let dissected = dissect_file(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        return dissected;
+    }
+
+    /// Capture a download mode session with an emulated target: PIT download, flash, PIT flash and end.
+    fn capture_download_session(link: CaptureLinkType, image: &[u8]) -> Vec<Dissected> {
+        let path = temp_path(&format!("{link:?}.pcapng"));
+        let pit_data = std::fs::read(PIT).unwrap();
+        let pit = Pit::deserialize(&pit_data).unwrap();
+
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+
+        let capture = Capture::create(Box::new(host), &path, link).unwrap();
+        let mut sess = Session::begin(Box::new(capture)).unwrap();
+        sess.download_pit(sess.params).unwrap();
+        sess.flash(
+            image,
+            pit.get_entry_by_name("CM").unwrap(),
+            &mut None::<&mut fn(u64)>,
+        )
+        .unwrap();
+        sess.flash_pit(&pit_data).unwrap();
+        sess.end(ActionAfter::Nothing).unwrap();
+        target.join().unwrap().unwrap();
+
+        return read_back(&path);
+    }
+
+    fn is_command(m: &Message, cmd: OdinCmd, arg1: u32) -> bool {
+        return matches!(m, Message::Command(p) if p.cmd() == cmd && p.args()[0].inner == arg1);
+    }
+
+    #[test]
+    fn test_download_session() {
+        let pit_len = std::fs::read(PIT).unwrap().len();
+        // Two file parts, the second one split into several records by the capture
+        let image: Vec<u8> = (0..1_300_000).map(|i| (i % 251) as u8).collect();
+
+        for link in [
+            CaptureLinkType::Usb,
+            CaptureLinkType::NetBind,
+            CaptureLinkType::NetConnect,
+        ] {
+            let dissected = capture_download_session(link, &image);
+            let messages: Vec<&Message> = dissected.iter().map(|d| &d.message).collect();
+
+            assert_eq!(&Message::Handshake, messages[0]);
+            assert_eq!(&Message::HandshakeReply, messages[1]);
+            assert!(is_command(
+                messages[2],
+                OdinCmd::SessionStart,
+                BEGIN_SESSION
+            ));
+            assert!(matches!(messages[3], Message::Reply(r) if r.cmd == OdinCmd::SessionStart));
+            assert!(!messages.contains(&&Message::Unknown), "{messages:?}");
+
+            let chunks: Vec<u32> = messages
+                .iter()
+                .filter_map(|m| match m {
+                    Message::PitChunk(i) => Some(*i),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                (0..pit_len.div_ceil(500) as u32).collect::<Vec<u32>>(),
+                chunks
+            );
+
+            let part_bytes = |index: u32| -> usize {
+                return dissected
+                    .iter()
+                    .filter(|d| d.message == Message::FilePart(index))
+                    .map(|d| d.transfer.len)
+                    .sum();
+            };
+            assert_eq!(1024 * 1024, part_bytes(0));
+            assert_eq!(1024 * 1024, part_bytes(1));
+            assert_eq!(0, part_bytes(2));
+            assert!(messages.iter().any(|m| matches!(m,
+                Message::Reply(r) if r.cmd == OdinCmd::ChunkTransferOk && r.arg.inner == 1)));
+
+            assert_eq!(
+                1,
+                messages.iter().filter(|m| ***m == Message::PitData).count()
+            );
+            assert!(is_command(
+                messages[messages.len() - 2],
+                OdinCmd::SessionEnd,
+                ActionAfter::Nothing as u32
+            ));
+            assert!(format!("{}", dissected[2]).contains("(begin session)"));
+        }
+    }
+
+    #[test]
+    fn test_upload_session() {
+        let path = temp_path("upload.pcapng");
+        let (host, target) = Pipe::pair();
+        let mut t = UploadTarget::new(TargetConfig {
+            device_name: String::from("EMULATED"),
+            bitness: Bitness::SixtyFour,
+            regions: vec![MemoryRegion {
+                name: String::from("DRAM"),
+                partition_type: 2,
+                start_addr: 0x8000_0000,
+                image: Box::new(Cursor::new(vec![0x55; 0x1000])),
+            }],
+        })
+        .unwrap();
+        let target = std::thread::spawn(move || {
+            let mut c: Box<dyn Communicator> = Box::new(target);
+            t.serve(&mut c).unwrap();
+        });
+
+        let capture = Capture::create(Box::new(host), &path, CaptureLinkType::Usb).unwrap();
+        let mut c: Box<dyn Communicator> = Box::new(capture);
+        handshake(&mut c).unwrap();
+        send_packet(&mut c, PROBE).unwrap();
+        // Device name, one region and the terminating entry, in one transfer as on USB
+        c.recv_exact(DEVICE_NAME_LEN + 2 * 40).unwrap();
+        send_packet(&mut c, &0x8000_0000u64.to_le_bytes()).unwrap();
+        send_packet(&mut c, &0x8000_0100u64.to_le_bytes()).unwrap();
+        send_packet(&mut c, DATAXFER).unwrap();
+        c.recv_exact(0x100).unwrap();
+        end_session(&mut c).unwrap();
+        drop(c);
+        target.join().unwrap();
+
+        let messages: Vec<Message> = read_back(&path).into_iter().map(|d| d.message).collect();
+        assert_eq!(
+            vec![
+                Message::UploadMagic(UploadMagic::Preamble),
+                Message::UploadMagic(UploadMagic::Acknowledgment),
+                Message::UploadMagic(UploadMagic::Probe),
+                Message::ProbeTable(String::from("EMULATED"), Bitness::SixtyFour),
+                Message::UploadAddress(0x8000_0000),
+                Message::UploadAddress(0x8000_0100),
+                Message::UploadMagic(UploadMagic::DataXfer),
+                Message::UploadData,
+                Message::UploadMagic(UploadMagic::Postamble),
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_usbmon_text() {
+        let text = "\
+ffff9c1d 1000000 S Bo:1:005:1 -115 4 = 4f44494e
+ffff9c1d 1000100 C Bo:1:005:1 0 4 >
+ffff9c1d 1000200 S Bi:1:005:2 -115 1024 <
+ffff9c1d 1000300 C Bi:1:005:2 0 4 = 4c4f4b45
+ffff9c1d 1000400 S Ii:1:005:3 -115 8 <
+ffff9c1d 1000500 S Bo:1:005:1 -115 1024 = 64000000 00000000 04000000 00000000 00000000 00000000 00000000 00000000
+ffff9c1d 1000600 C Bi:1:005:2 0 8 = 64000000 00000400
+ffff9c1d 1000700 S Bo:1:005:1 -115 0
+";
+        let dissected = dissect(read_capture(text.as_bytes()).unwrap());
+        let messages: Vec<&Message> = dissected.iter().map(|d| &d.message).collect();
+        assert_eq!(5, messages.len());
+        assert_eq!(&Message::Handshake, messages[0]);
+        assert_eq!(&Message::HandshakeReply, messages[1]);
+        match messages[2] {
+            Message::Command(p) => {
+                assert_eq!(OdinCmd::SessionStart, p.cmd());
+                assert_eq!(
+                    vec![0, 4],
+                    p.args().iter().map(|a| a.inner).collect::<Vec<u32>>()
+                );
+            }
+            m => panic!("Expected command, got {m:?}"),
+        }
+        match messages[3] {
+            Message::Reply(r) => {
+                assert_eq!(OdinCmd::SessionStart, r.cmd);
+                assert_eq!(0x40000, r.arg.inner);
+            }
+            m => panic!("Expected reply, got {m:?}"),
+        }
+        assert_eq!(&Message::Empty, messages[4]);
+        assert_eq!(1024, dissected[2].transfer.len);
+        assert_eq!(
+            Some(Duration::from_micros(1000500)),
+            dissected[2].transfer.timestamp
+        );
+
+        assert!(read_capture(b"ffff9c1d 1000000 S Bo:1:005:1 -115 x").is_err());
+    }
+
+    /// A USBPcap record, as captured by Wireshark on Windows.
+    fn usbpcap_record(from_device: bool, endpoint: u8, data: &[u8]) -> Vec<u8> {
+        let mut r: Vec<u8> = Vec::new();
+        r.extend_from_slice(&27u16.to_le_bytes());
+        r.extend_from_slice(&[0; 14]);
+        r.push(from_device as u8);
+        r.extend_from_slice(&[0; 4]);
+        r.push(endpoint);
+        r.push(3);
+        r.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        r.extend_from_slice(data);
+        return r;
+    }
+
+    #[test]
+    fn test_pcap() {
+        let mut cmd = vec![0; 1024];
+        cmd[0] = 0x67;
+        cmd[4] = 0x03;
+        let records = [
+            usbpcap_record(false, 0x01, b"ODIN"),
+            usbpcap_record(false, 0x81, &[]),
+            usbpcap_record(true, 0x81, b"LOKE"),
+            usbpcap_record(false, 0x01, &cmd),
+            usbpcap_record(true, 0x81, &[0x67, 0, 0, 0, 0, 0, 0, 0]),
+        ];
+
+        for big_endian in [false, true] {
+            let u32_bytes = |v: u32| -> [u8; 4] {
+                return match big_endian {
+                    true => v.to_be_bytes(),
+                    false => v.to_le_bytes(),
+                };
+            };
+            let mut pcap: Vec<u8> = Vec::new();
+            pcap.extend_from_slice(&u32_bytes(0xA1B2C3D4));
+            pcap.extend_from_slice(&[0; 16]);
+            pcap.extend_from_slice(&u32_bytes(249));
+            for (i, r) in records.iter().enumerate() {
+                pcap.extend_from_slice(&u32_bytes(10));
+                pcap.extend_from_slice(&u32_bytes(i as u32));
+                pcap.extend_from_slice(&u32_bytes(r.len() as u32));
+                pcap.extend_from_slice(&u32_bytes(r.len() as u32));
+                pcap.extend_from_slice(r);
+            }
+
+            let dissected = dissect(read_capture(&pcap).unwrap());
+            let messages: Vec<String> = dissected.iter().map(|d| d.message.to_string()).collect();
+            assert_eq!(4, messages.len(), "{messages:?}");
+            assert_eq!("Handshake", messages[0]);
+            assert_eq!("Handshake reply", messages[1]);
+            assert!(messages[2].contains("(end session)"), "{}", messages[2]);
+            assert_eq!(
+                Some(Duration::new(10, 3000)),
+                dissected[2].transfer.timestamp
+            );
+            assert_eq!(Direction::FromTarget, dissected[3].transfer.direction);
+        }
+    }
+}
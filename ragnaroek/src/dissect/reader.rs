@@ -0,0 +1,394 @@
+use super::*;
+
+use std::io::{Error as IOError, ErrorKind};
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const PCAPNG_SIMPLE_PACKET: u32 = 0x00000003;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x00000006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+const PCAPNG_OPT_END: u16 = 0;
+const PCAPNG_OPT_IF_TSRESOL: u16 = 9;
+
+const PCAP_MAGIC_MICROS: u32 = 0xA1B2C3D4;
+const PCAP_MAGIC_NANOS: u32 = 0xA1B23C4D;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_USB_LINUX: u32 = 189;
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_USBPCAP: u32 = 249;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const IP_PROTO_TCP: u8 = 6;
+const USB_TRANSFER_BULK: u8 = 3;
+const USB_DIR_IN: u8 = 0x80;
+
+fn invalid(msg: &str) -> Error {
+    return IOError::new(ErrorKind::InvalidData, msg.to_string()).into();
+}
+
+/// Little helper for reading integers of either byte order, failing instead of panicking on short data.
+#[derive(Clone, Copy)]
+struct Bytes<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Bytes<'a> {
+    fn slice(&self, offset: usize, len: usize) -> Result<&'a [u8]> {
+        return self
+            .data
+            .get(offset..offset.checked_add(len).ok_or(invalid("Length overflow"))?)
+            .ok_or(invalid("Capture is truncated"));
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        let b: [u8; 2] = self.slice(offset, 2)?.try_into().unwrap_or_default();
+        return Ok(if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        });
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        let b: [u8; 4] = self.slice(offset, 4)?.try_into().unwrap_or_default();
+        return Ok(if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        });
+    }
+}
+
+/// Read a capture, detecting its format.
+///
+/// Supported are pcap and pcapng files of USB traffic captured on Linux (usbmon) or Windows (USBPcap),
+/// pcap and pcapng files of IPv4 traffic, with or without Ethernet headers, and usbmon's text format.
+/// Only bulk transfers and TCP payloads are kept.
+pub fn read_capture(data: &[u8]) -> Result<Vec<Transfer>> {
+    let le = Bytes {
+        data,
+        big_endian: false,
+    };
+    match le.u32(0) {
+        Ok(PCAPNG_SECTION_HEADER) => return read_pcapng(data),
+        Ok(magic) if [PCAP_MAGIC_MICROS, PCAP_MAGIC_NANOS].contains(&magic) => {
+            return read_pcap(data)
+        }
+        // Big endian pcap
+        Ok(magic) if [PCAP_MAGIC_MICROS, PCAP_MAGIC_NANOS].contains(&magic.swap_bytes()) => {
+            return read_pcap(data)
+        }
+        _ => {}
+    }
+    match std::str::from_utf8(data) {
+        Ok(text) => return read_usbmon_text(text),
+        Err(_) => return Err(invalid("Unknown capture format")),
+    }
+}
+
+/// Read a capture in usbmon's text format, as found in `/sys/kernel/debug/usb/usbmon/*u`.
+///
+/// usbmon only shows the first 32 bytes of each transfer by default, so data may be truncated.
+pub fn read_usbmon_text(text: &str) -> Result<Vec<Transfer>> {
+    let mut transfers: Vec<Transfer> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let parse = || -> Option<Option<Transfer>> {
+            let timestamp = Duration::from_micros(words.get(1)?.parse().ok()?);
+            let event = *words.get(2)?;
+            let address = *words.get(3)?;
+            // Only bulk transfers carry protocol data
+            let direction = match address.get(..2)? {
+                "Bo" => Direction::ToTarget,
+                "Bi" => Direction::FromTarget,
+                _ => return Some(None),
+            };
+            // Outgoing data is shown on submission, incoming data on completion
+            match (direction, event) {
+                (Direction::ToTarget, "S") | (Direction::FromTarget, "C") => {}
+                _ => return Some(None),
+            }
+            let len: usize = words.get(5)?.parse().ok()?;
+            let mut data: Vec<u8> = Vec::new();
+            if words.get(6) == Some(&"=") {
+                for word in &words[7..] {
+                    for pair in word.as_bytes().chunks(2) {
+                        let pair = std::str::from_utf8(pair).ok()?;
+                        data.push(u8::from_str_radix(pair, 16).ok()?);
+                    }
+                }
+            }
+            return Some(Some(Transfer {
+                direction,
+                timestamp: Some(timestamp),
+                len,
+                data,
+            }));
+        };
+        match parse() {
+            Some(Some(t)) => transfers.push(t),
+            Some(None) => {}
+            None => {
+                return Err(IOError::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid usbmon record on line {}", i + 1),
+                )
+                .into())
+            }
+        }
+    }
+    return Ok(transfers);
+}
+
+/// Read a pcap file.
+pub fn read_pcap(data: &[u8]) -> Result<Vec<Transfer>> {
+    let mut b = Bytes {
+        data,
+        big_endian: false,
+    };
+    let magic = b.u32(0)?;
+    if magic != PCAP_MAGIC_MICROS && magic != PCAP_MAGIC_NANOS {
+        b.big_endian = true;
+    }
+    let nanos = b.u32(0)? == PCAP_MAGIC_NANOS;
+    let link_type = b.u32(20)? & 0xFFFF;
+
+    let mut packets = PacketReader::default();
+    let mut pos: usize = 24;
+    while pos < data.len() {
+        let secs = u64::from(b.u32(pos)?);
+        let frac = u64::from(b.u32(pos + 4)?);
+        let caplen = b.u32(pos + 8)? as usize;
+        let timestamp = match nanos {
+            true => Duration::from_secs(secs) + Duration::from_nanos(frac),
+            false => Duration::from_secs(secs) + Duration::from_micros(frac),
+        };
+        packets.push(link_type, timestamp, b.slice(pos + 16, caplen)?)?;
+        pos += 16 + caplen;
+    }
+    return Ok(packets.finish());
+}
+
+/// Read a pcapng file.
+pub fn read_pcapng(data: &[u8]) -> Result<Vec<Transfer>> {
+    let mut b = Bytes {
+        data,
+        big_endian: false,
+    };
+    // Link type and timestamp resolution of each interface in the current section
+    let mut interfaces: Vec<(u32, u64)> = Vec::new();
+    let mut packets = PacketReader::default();
+    let mut pos: usize = 0;
+    while pos < data.len() {
+        let block_type = b.u32(pos)?;
+        if block_type == PCAPNG_SECTION_HEADER {
+            // Each section can have a different byte order
+            b.big_endian = false;
+            if b.u32(pos + 8)? != PCAPNG_BYTE_ORDER_MAGIC {
+                b.big_endian = true;
+            }
+            interfaces.clear();
+        }
+        let len = b.u32(pos + 4)? as usize;
+        if len < 12 {
+            return Err(invalid("Invalid pcapng block length"));
+        }
+        let body = Bytes {
+            data: b.slice(pos + 8, len - 12)?,
+            big_endian: b.big_endian,
+        };
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type = u32::from(body.u16(0)?);
+                // Microseconds, unless the options say otherwise
+                let mut units_per_sec: u64 = 1_000_000;
+                let mut opt: usize = 8;
+                while opt + 4 <= body.data.len() {
+                    let code = body.u16(opt)?;
+                    let opt_len = body.u16(opt + 2)? as usize;
+                    if code == PCAPNG_OPT_END {
+                        break;
+                    }
+                    if code == PCAPNG_OPT_IF_TSRESOL {
+                        let resol = body.slice(opt + 4, 1)?[0];
+                        let (base, exp) = match resol & 0x80 {
+                            0 => (10u64, u32::from(resol)),
+                            _ => (2u64, u32::from(resol & 0x7F)),
+                        };
+                        units_per_sec = base.checked_pow(exp).unwrap_or(u64::MAX);
+                    }
+                    opt += 4 + opt_len.next_multiple_of(4);
+                }
+                interfaces.push((link_type, units_per_sec));
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                let (link_type, units_per_sec) = *interfaces
+                    .get(body.u32(0)? as usize)
+                    .ok_or(invalid("Packet refers to an unknown interface"))?;
+                let ts = (u64::from(body.u32(4)?) << 32) | u64::from(body.u32(8)?);
+                let timestamp = Duration::from_secs(ts / units_per_sec)
+                    + Duration::from_nanos(
+                        ((ts % units_per_sec) as u128 * 1_000_000_000 / units_per_sec as u128)
+                            as u64,
+                    );
+                let caplen = body.u32(12)? as usize;
+                packets.push(link_type, timestamp, body.slice(20, caplen)?)?;
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                let (link_type, _) = *interfaces
+                    .first()
+                    .ok_or(invalid("Packet refers to an unknown interface"))?;
+                let caplen = body.data.len().saturating_sub(4);
+                packets.push(link_type, Duration::ZERO, body.slice(4, caplen)?)?;
+            }
+            _ => {}
+        }
+        pos += len;
+    }
+    return Ok(packets.finish());
+}
+
+/// Turns packets of any supported link type into transfers.
+#[derive(Default)]
+struct PacketReader {
+    transfers: Vec<Transfer>,
+    /// TCP endpoint which sent the first payload, taken to be the host
+    host: Option<([u8; 4], u16)>,
+    /// Whether the last transfer came from TCP, so further segments in the same direction belong to it
+    in_tcp_stream: bool,
+}
+
+impl PacketReader {
+    fn push(&mut self, link_type: u32, timestamp: Duration, packet: &[u8]) -> Result<()> {
+        let p = Bytes {
+            data: packet,
+            big_endian: false,
+        };
+        match link_type {
+            LINKTYPE_USB_LINUX | LINKTYPE_USB_LINUX_MMAPPED => {
+                let header_len = if link_type == LINKTYPE_USB_LINUX {
+                    48
+                } else {
+                    64
+                };
+                let event = p.slice(8, 1)?[0];
+                let transfer_type = p.slice(9, 1)?[0];
+                let endpoint = p.slice(10, 1)?[0];
+                let urb_len = p.u32(32)? as usize;
+                if transfer_type != USB_TRANSFER_BULK {
+                    return Ok(());
+                }
+                let direction = match endpoint & USB_DIR_IN {
+                    0 => Direction::ToTarget,
+                    _ => Direction::FromTarget,
+                };
+                // Outgoing data is captured on submission, incoming data on completion
+                match (direction, event) {
+                    (Direction::ToTarget, b'S') | (Direction::FromTarget, b'C') => {}
+                    _ => return Ok(()),
+                }
+                let data = packet.get(header_len..).unwrap_or_default();
+                self.push_usb(direction, timestamp, urb_len, data);
+            }
+            LINKTYPE_USBPCAP => {
+                let header_len = p.u16(0)? as usize;
+                let from_device = p.slice(16, 1)?[0] & 1 == 1;
+                let endpoint = p.slice(21, 1)?[0];
+                let transfer_type = p.slice(22, 1)?[0];
+                let data_len = p.u32(23)? as usize;
+                if transfer_type != USB_TRANSFER_BULK {
+                    return Ok(());
+                }
+                let direction = match endpoint & USB_DIR_IN {
+                    0 => Direction::ToTarget,
+                    _ => Direction::FromTarget,
+                };
+                let data = packet.get(header_len..).unwrap_or_default();
+                // Outgoing data is captured on submission, incoming data on completion
+                match (direction, from_device) {
+                    (Direction::ToTarget, false) | (Direction::FromTarget, true) => {
+                        self.push_usb(direction, timestamp, data_len, data)
+                    }
+                    _ => {}
+                }
+            }
+            LINKTYPE_ETHERNET => {
+                if p.data.len() >= 14
+                    && u16::from_be_bytes([packet[12], packet[13]]) == ETHERTYPE_IPV4
+                {
+                    self.push_ipv4(timestamp, &packet[14..])?;
+                }
+            }
+            LINKTYPE_IPV4 => self.push_ipv4(timestamp, packet)?,
+            _ => return Err(invalid("Unsupported link type")),
+        }
+        return Ok(());
+    }
+
+    fn push_usb(&mut self, direction: Direction, timestamp: Duration, len: usize, data: &[u8]) {
+        self.in_tcp_stream = false;
+        self.transfers.push(Transfer {
+            direction,
+            timestamp: Some(timestamp),
+            len,
+            data: data.to_vec(),
+        });
+    }
+
+    fn push_ipv4(&mut self, timestamp: Duration, packet: &[u8]) -> Result<()> {
+        let p = Bytes {
+            data: packet,
+            big_endian: true,
+        };
+        let ip_header_len = ((p.slice(0, 1)?[0] & 0x0F) as usize) * 4;
+        let total_len = p.u16(2)? as usize;
+        if p.slice(9, 1)?[0] != IP_PROTO_TCP {
+            return Ok(());
+        }
+        let src: [u8; 4] = p.slice(12, 4)?.try_into().unwrap_or_default();
+        // Ethernet frames may carry padding past the end of the IP packet
+        let end = total_len.min(packet.len());
+        let tcp = Bytes {
+            data: p.slice(ip_header_len, end.saturating_sub(ip_header_len))?,
+            big_endian: true,
+        };
+        let sport = tcp.u16(0)?;
+        let tcp_header_len = ((tcp.slice(12, 1)?[0] >> 4) as usize) * 4;
+        let payload = tcp.data.get(tcp_header_len..).unwrap_or_default();
+        if payload.is_empty() {
+            return Ok(());
+        }
+
+        // Hosts always speak first
+        let host = *self.host.get_or_insert((src, sport));
+        let direction = match host == (src, sport) {
+            true => Direction::ToTarget,
+            false => Direction::FromTarget,
+        };
+        // TCP is a stream, consecutive segments in the same direction make up one transfer
+        match self.transfers.last_mut() {
+            Some(last) if self.in_tcp_stream && last.direction == direction => {
+                last.len += payload.len();
+                last.data.extend_from_slice(payload);
+            }
+            _ => self.transfers.push(Transfer {
+                direction,
+                timestamp: Some(timestamp),
+                len: payload.len(),
+                data: payload.to_vec(),
+            }),
+        }
+        self.in_tcp_stream = true;
+        return Ok(());
+    }
+
+    fn finish(self) -> Vec<Transfer> {
+        return self.transfers;
+    }
+}
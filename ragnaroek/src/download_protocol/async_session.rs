@@ -0,0 +1,525 @@
+//! Asynchronous counterpart to `Session`, speaking the same protocol over an `AsyncCommunicator`.
+
+use either::Either;
+use pit::*;
+
+use super::begin_session::*;
+use super::download_pit::{pit_chunk_packet, pit_dump_packet, pit_end_packet, pit_len};
+use super::end_session::{end_session_packet, ActionAfter};
+use super::flash::{
+    begin_flash_packet, end_packet, file_part_size_packet, open_odintar, sequence_begin_packet,
+    total_size_packet, SeekableReader,
+};
+use super::flash_pit::{pit_flash_packet, pit_size_packet};
+use crate::comms::async_io::AsyncCommunicator;
+use crate::download_protocol::*;
+use crate::error::ResultExt;
+use crate::{ErrorContext, Result};
+
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+/// Send a command and read the target's reply before the deadline, which has to be for the same command.
+async fn command(
+    c: &mut Box<dyn AsyncCommunicator>,
+    p: OdinCmdPacket,
+    deadline: Instant,
+) -> Result<OdinCmdReply> {
+    log::trace!(target: "CMD", "{}", p);
+    c.send_before(&p.to_wire(), deadline).await?;
+    return read_reply(c, p.cmd(), deadline).await;
+}
+
+/// Read the target's reply to the given command before the deadline.
+async fn read_reply(
+    c: &mut Box<dyn AsyncCommunicator>,
+    cmd: OdinCmd,
+    deadline: Instant,
+) -> Result<OdinCmdReply> {
+    let reply = OdinCmdReply::from_received(c.recv_exact_before(CMD_REPLY_LEN, deadline).await?)?;
+    log::trace!(target: "CMD", "{}", reply);
+    if reply.cmd != cmd {
+        return Err(DownloadProtocolError::UnexpectedOdinCmd(cmd, reply.cmd).into());
+    }
+    return Ok(reply);
+}
+
+/// Check the argument of a reply.
+fn expect_arg(reply: OdinCmdReply, arg: u32) -> Result<()> {
+    if reply.arg != OdinInt::from(arg) {
+        return Err(
+            DownloadProtocolError::UnexpectedOdinCmdArg(OdinInt::from(arg), reply.arg).into(),
+        );
+    }
+    return Ok(());
+}
+
+/// Asynchronous variant of `Session`.
+///
+/// Many sessions can make progress on a single thread, and operations are cancelled by dropping their future.
+/// After a cancelled operation, the target is in an unknown state and the session should be dropped.
+///
+/// Timeouts work like `Session`'s: each exchange with the target has to finish within the timeout of its phase.
+pub struct AsyncSession {
+    c: Box<dyn AsyncCommunicator>,
+    /// Session parameters, such as sizes of various transfers and the protocol version.
+    pub params: SessionParams,
+}
+
+impl AsyncSession {
+    /// Create a new `AsyncSession` and negotiate connection parameters with the target.
+    /// Consumes the `AsyncCommunicator` to enforce exclusive access.
+    pub async fn begin(c: Box<dyn AsyncCommunicator>) -> Result<Self> {
+        return AsyncSession::begin_inner(c, None).await;
+    }
+
+    /// Like `begin`, but with the given timeouts instead of the defaults for the target's protocol version.
+    pub async fn begin_with_timeouts(
+        c: Box<dyn AsyncCommunicator>,
+        timeouts: TimeoutPolicy,
+    ) -> Result<Self> {
+        return AsyncSession::begin_inner(c, Some(timeouts)).await;
+    }
+
+    async fn begin_inner(
+        mut c: Box<dyn AsyncCommunicator>,
+        timeouts: Option<TimeoutPolicy>,
+    ) -> Result<Self> {
+        let handshake = timeouts.map_or(HANDSHAKE_TIMEOUT, |t| t.handshake);
+        log::debug!(target: "DL", "Handshaking");
+        let deadline = Instant::now() + handshake;
+        c.send_before(&PING, deadline).await?;
+        let resp = c.recv_exact_before(PONG.len(), deadline).await?;
+        if resp != PONG {
+            return Err(DownloadProtocolError::InvalidMagicHandshake(resp).into());
+        }
+
+        log::debug!(target: "SESS", "Beginning session");
+        let deadline = Instant::now() + handshake;
+        let (proto_version, supports_compression) = version_and_compression_from_reply(
+            command(&mut c, begin_session_packet(), deadline).await?,
+        )?;
+
+        let max_file_part_size = if proto_version == ProtoVersion::V1 {
+            V1_MAX_FILE_PART_SIZE
+        } else {
+            let deadline = Instant::now() + handshake;
+            expect_arg(
+                command(&mut c, packet_size_packet(), deadline).await?,
+                REPLY_OK,
+            )?;
+            V2PLUS_MAX_FILE_PART_SIZE
+        };
+        let timeouts = timeouts.unwrap_or(TimeoutPolicy::for_version(proto_version));
+        let params = session_params(
+            proto_version,
+            supports_compression,
+            OdinInt::from(max_file_part_size),
+            timeouts,
+        )?;
+        log::debug!(target: "SESS", "Negotiated session params: {:?}", params);
+
+        return Ok(AsyncSession { c, params });
+    }
+
+    /// Send a command and read the target's reply, within the given timeout.
+    async fn command(&mut self, p: OdinCmdPacket, timeout: Duration) -> Result<OdinCmdReply> {
+        return command(&mut self.c, p, Instant::now() + timeout).await;
+    }
+
+    /// Enter T-Flash download mode (write to microSD card).
+    /// Call this after `begin` and before `end`.
+    pub async fn enable_tflash(&mut self) -> Result<()> {
+        log::debug!(target: "SESS", "Enabling T-Flash mode");
+        let resp = self
+            .command(tflash_packet(), self.params.timeouts.command)
+            .await?;
+        // Anything other than 0x00 indicates failure.
+        expect_arg(resp, REPLY_OK)?;
+        log::debug!(target: "SESS", "T-Flash mode enabled");
+        return Ok(());
+    }
+
+    /// End the `AsyncSession` and do cleanup.
+    pub async fn end(mut self, after: ActionAfter) -> Result<()> {
+        log::debug!(target: "SESS", "Ending session with action {:?}", after);
+        self.command(end_session_packet(after), self.params.timeouts.command)
+            .await?;
+        log::debug!(target: "SESS", "Ending session OK");
+        return Ok(());
+    }
+
+    /// Whether the protocol version requires empty transfers around PIT transfers.
+    fn is_proto_v3plus(&self) -> bool {
+        return self.params.proto_version == ProtoVersion::V3
+            || self.params.proto_version == ProtoVersion::V4;
+    }
+
+    /// Exchange empty transfers, if the protocol version requires them, and end the PIT transfer.
+    async fn end_pit_transfer(&mut self) -> Result<()> {
+        let deadline = Instant::now() + self.params.timeouts.pit;
+        if self.is_proto_v3plus() {
+            self.c.recv_exact_before(0, deadline).await?;
+            self.c.send_before(&[], deadline).await?;
+        }
+        let resp = command(&mut self.c, pit_end_packet(), deadline).await?;
+        return expect_arg(resp, REPLY_OK);
+    }
+
+    /// Download partitioning data from the target. Does not parse or validate the data.
+    pub async fn download_pit(&mut self) -> Result<Vec<u8>> {
+        log::info!(target: "PIT", "Start PIT download");
+        let timeout = self.params.timeouts.pit;
+        let total_len = pit_len(self.command(pit_dump_packet(), timeout).await?.arg)?;
+        let mut data: Vec<u8> = Vec::with_capacity(total_len);
+
+        let mut chunk_idx: u32 = 0;
+        while data.len() < total_len {
+            log::debug!(target: "PIT", "[Chunk {}] Fetching part of remaining {} bytes", chunk_idx, total_len - data.len());
+            let deadline = Instant::now() + timeout;
+            let p = pit_chunk_packet(OdinInt::from(chunk_idx));
+            self.c.send_before(&p.to_wire(), deadline).await?;
+            let left = core::cmp::min(total_len - data.len(), PIT_CHUNK_SIZE);
+            let chunk = self.c.recv_exact_before(left, deadline).await?;
+            if chunk.len() != left {
+                return Err(DownloadProtocolError::ShortRead(left, chunk).into());
+            }
+            data.extend_from_slice(&chunk);
+            chunk_idx += 1;
+        }
+
+        self.end_pit_transfer().await?;
+        log::info!(target: "PIT", "PIT download OK");
+        return Ok(data);
+    }
+
+    /// Upload partitioning data to the target, replacing its PIT. Does not parse or validate the data.
+    pub async fn flash_pit(&mut self, pit: &[u8]) -> Result<()> {
+        log::info!(target: "PIT", "Start PIT flash");
+        let timeout = self.params.timeouts.pit;
+        let total_len: u32 = pit.len().try_into()?;
+        expect_arg(self.command(pit_flash_packet(), timeout).await?, REPLY_OK)?;
+        let p = pit_size_packet(OdinInt::from(total_len));
+        expect_arg(self.command(p, timeout).await?, REPLY_OK)?;
+
+        let deadline = Instant::now() + timeout;
+        self.c.send_before(pit, deadline).await?;
+        let resp = read_reply(&mut self.c, OdinCmd::TransferPIT, deadline).await?;
+        expect_arg(resp, PIT_RECEIVED)?;
+
+        self.end_pit_transfer().await?;
+        log::info!(target: "PIT", "PIT flash OK");
+        return Ok(());
+    }
+
+    /// Flash a file to the target.
+    ///
+    /// `cb` is an optional callback, called after each file part is transferred with the number of bytes transferred since the last call.
+    pub async fn flash(
+        &mut self,
+        data: &[u8],
+        pit_entry: Either<PitEntryV1, PitEntryV2>,
+        cb: &mut Option<&mut (impl FnMut(u64) + Send)>,
+    ) -> Result<()> {
+        return self
+            .flash_from_reader(&mut &data[..], data.len() as u64, pit_entry, cb)
+            .await;
+    }
+
+    /// Flash a file of `len` bytes, read from `data`, to the target.
+    ///
+    /// Only one file part is held in memory at a time, so files larger than the host's memory can be flashed.
+    /// Reads from `data` block, so it should be something quick to read from, like a local file.
+    ///
+    /// `cb` is an optional callback, called after each file part is transferred with the number of bytes transferred since the last call.
+    pub async fn flash_from_reader(
+        &mut self,
+        data: &mut (dyn Read + Send),
+        len: u64,
+        pit_entry: Either<PitEntryV1, PitEntryV2>,
+        cb: &mut Option<&mut (impl FnMut(u64) + Send)>,
+    ) -> Result<()> {
+        log::info!(target: "FLASH", "Starting flash of {} bytes total", len);
+        let sp = self.params;
+        let supports_64bit_size: bool = sp.proto_version == ProtoVersion::V4;
+        let is_proto_v3plus: bool = sp.proto_version == ProtoVersion::V4;
+        let timeouts = sp.timeouts;
+        self.command(
+            total_size_packet(len, supports_64bit_size)?,
+            timeouts.command,
+        )
+        .await?;
+        let p = file_part_size_packet(sp.max_file_part_size);
+        expect_arg(self.command(p, timeouts.command).await?, REPLY_OK)?;
+        self.command(begin_flash_packet(), timeouts.command).await?;
+
+        let partition_name = match &pit_entry {
+            Either::Left(e) => &e.partition_name,
+            Either::Right(e) => &e.partition_name,
+        };
+        let max_seq_size = u64::from(sp.max_seq_size_bytes);
+        let total_seqs: usize = len.div_ceil(max_seq_size).try_into()?;
+        // The only buffer for file data, reused for every part
+        let mut part: Vec<u8> = vec![0; sp.max_file_part_size as usize];
+        let mut bytes_flashed: u64 = 0;
+        for i in 0..total_seqs {
+            let sequence_len: u32 = (len - bytes_flashed).min(max_seq_size).try_into()?;
+            let sequence_ctx = || ErrorContext {
+                partition: Some(partition_name.clone()),
+                sequence: Some((i + 1, total_seqs)),
+                ..Default::default()
+            };
+            log::debug!(target: "FLASH", "[Sequence {}] Starting transfer of {} bytes", i + 1, sequence_len);
+            let deadline = Instant::now() + timeouts.command;
+            command(&mut self.c, sequence_begin_packet(sequence_len), deadline)
+                .await
+                .context(|| ErrorContext {
+                    command: Some("beginning the sequence"),
+                    ..sequence_ctx()
+                })?;
+            // For USB, an empty bulk transfer is expected before the first packet
+            self.c.send_before(&[], deadline).await?;
+
+            let total_parts: u32 = (sequence_len as usize).div_ceil(part.len()).try_into()?;
+            let mut left = sequence_len as usize;
+            for part_idx in 0..total_parts {
+                let part_ctx = || ErrorContext {
+                    part: Some(part_idx),
+                    command: Some("sending the file part"),
+                    ..sequence_ctx()
+                };
+                let part_len = left.min(part.len());
+                data.read_exact(&mut part[..part_len])
+                    .context(|| ErrorContext {
+                        command: Some("reading the file part"),
+                        ..part_ctx()
+                    })?;
+                // Last part might have to be padded for this to work
+                part[part_len..].fill(0);
+                self.send_part(&part, part_idx, timeouts.part_ack)
+                    .await
+                    .context(part_ctx)?;
+                left -= part_len;
+                if let Some(cb) = cb {
+                    cb(part_len as u64);
+                }
+            }
+
+            bytes_flashed += u64::from(sequence_len);
+            let is_last_sequence = bytes_flashed >= len;
+            let deadline = Instant::now() + timeouts.sequence_end;
+            // For USB, older bootloaders expect empty transfers around the end of the sequence
+            if !is_proto_v3plus {
+                self.c.send_before(&[], deadline).await?;
+            }
+            let p = end_packet(&pit_entry, OdinInt::from(sequence_len), is_last_sequence);
+            command(&mut self.c, p, deadline)
+                .await
+                .context(|| ErrorContext {
+                    command: Some("ending the sequence"),
+                    ..sequence_ctx()
+                })?;
+            if !is_proto_v3plus {
+                self.c.send_before(&[], deadline).await?;
+            }
+            log::debug!(target: "FLASH", "[Sequence {}] OK", i + 1);
+        }
+        log::info!(target: "FLASH", "Flash OK");
+        return Ok(());
+    }
+
+    /// Flash all files of an Odin TAR archive, like `Session::flash_odintar`.
+    ///
+    /// The archive is checked against the PIT first, and rejected as a whole if it doesn't fit.
+    /// Each file is read one file part at a time while flashing.
+    /// Plain tar archives without Odin metadata are rejected unless `allow_unverified` is set.
+    ///
+    /// `cb` is a callback for e.g. displaying a progress bar. It's called for each file in the archive.
+    pub async fn flash_odintar(
+        &mut self,
+        rdr: &mut (dyn SeekableReader + Send),
+        pit: Pit,
+        allow_unverified: bool,
+        cb: &mut Option<&mut (impl FnMut(u64) + Send)>,
+    ) -> Result<()> {
+        log::info!(target: "FLASH", "Flashing ODIN archive");
+
+        let (mut archive, report) = open_odintar(rdr, &pit, allow_unverified)?;
+        let total = report.matches.len();
+        for (i, m) in report.matches.into_iter().enumerate() {
+            log::info!(target: "FLASH", "[File {}/{}] Flashing file {} to partition {}", i + 1, total, m.entry.name, m.partition_name());
+            let file = || ErrorContext {
+                file: Some(m.entry.name.clone()),
+                ..Default::default()
+            };
+            let mut rdr = archive.entry_reader(&m.entry).context(|| ErrorContext {
+                command: Some("reading the file"),
+                ..file()
+            })?;
+            self.flash_from_reader(&mut rdr, m.entry.size, m.pit_entry, cb)
+                .await
+                .context(file)?;
+            log::info!(target: "FLASH", "[File {}/{}] OK", i + 1, total);
+        }
+
+        log::info!(target: "FLASH", "Archive flash OK");
+        return Ok(());
+    }
+
+    /// Send a file part and check the target acknowledges it within the timeout.
+    async fn send_part(&mut self, part: &[u8], part_idx: u32, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        self.c.send_before(part, deadline).await?;
+        let resp = read_reply(&mut self.c, OdinCmd::ChunkTransferOk, deadline).await?;
+        if resp.arg != OdinInt::from(part_idx) {
+            return Err(DownloadProtocolError::UnexpectedFlashPacket(
+                OdinInt::from(part_idx),
+                resp.arg,
+            )
+            .into());
+        }
+        return Ok(());
+    }
+
+    /// Factory reset user data on the target.
+    pub async fn factory_reset(&mut self) -> Result<()> {
+        log::info!(target: "SESSION", "Erasing userdata");
+        let resp = self
+            .command(erase_userdata_packet(), self.params.timeouts.erase)
+            .await?;
+        log::info!(target: "SESSION", "Erased userdata OK, erase function status {}", resp.arg);
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::async_io::{block_on, block_on_all, net, Blocking, BoxFuture};
+    use crate::comms::pipe::Connection as Pipe;
+    use crate::download_protocol::emulator::{spawn_target, FlashedFile, Target, TargetOptions};
+    use crate::{NetConnectConnection, NetConnectOptions};
+    use std::fs::File;
+    use std::thread::JoinHandle;
+
+    const PIT: &str = "../pit/testdata/A40_EUR_OPEN.pit";
+
+    /// Run a whole session: PIT download, flash, PIT flash, factory reset and end.
+    async fn session(c: Box<dyn AsyncCommunicator>, image: &[u8]) -> Result<Vec<u8>> {
+        let pit_data = std::fs::read(PIT).unwrap();
+        let pit = Pit::deserialize(&pit_data).unwrap();
+        let mut sess = AsyncSession::begin(c).await?;
+        let downloaded = sess.download_pit().await?;
+        let mut flashed: u64 = 0;
+        let mut cb = |n: u64| flashed += n;
+        sess.flash(
+            image,
+            pit.get_entry_by_name("CM").unwrap(),
+            &mut Some(&mut cb),
+        )
+        .await?;
+        assert_eq!(image.len() as u64, flashed);
+        sess.flash_pit(&pit_data).await?;
+        sess.factory_reset().await?;
+        sess.end(ActionAfter::RebootOS).await?;
+        return Ok(downloaded);
+    }
+
+    fn check_target(target: JoinHandle<Result<Target<File>>>, image: &[u8]) {
+        let target = target.join().unwrap().unwrap();
+        let log = target.log();
+        assert_eq!(
+            vec![FlashedFile {
+                partition_name: String::from("CM"),
+                size: image.len() as u64
+            }],
+            log.flashed
+        );
+        assert!(log.pit_flashed && log.userdata_erased);
+        assert_eq!(Some(ActionAfter::RebootOS as u32), log.end_action);
+    }
+
+    #[test]
+    fn test_sessions_over_tcp() {
+        let pit_data = std::fs::read(PIT).unwrap();
+        let image: Vec<u8> = (0..1_500_000).map(|i| (i % 251) as u8).collect();
+        let mut listener = net::Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Targets in wireless mode connect to the host
+        let targets: Vec<JoinHandle<Result<Target<File>>>> = (1..=2)
+            .map(|proto_version| {
+                let options = TargetOptions {
+                    proto_version,
+                    supports_compression: false,
+                };
+                return spawn_target(
+                    move || {
+                        return NetConnectConnection::connect(addr, &NetConnectOptions::default())
+                            .unwrap();
+                    },
+                    options,
+                );
+            })
+            .collect();
+        let mut connections: Vec<Box<dyn AsyncCommunicator>> = Vec::new();
+        for _ in &targets {
+            connections.push(Box::new(block_on(Box::pin(listener.accept())).unwrap()));
+        }
+
+        // Both sessions make progress on this thread
+        let results = block_on_all(
+            connections
+                .into_iter()
+                .map(|c| -> BoxFuture<'_, Result<Vec<u8>>> { Box::pin(session(c, &image)) })
+                .collect(),
+        );
+        for (result, target) in results.into_iter().zip(targets) {
+            assert_eq!(pit_data, result.unwrap());
+            check_target(target, &image);
+        }
+    }
+
+    #[test]
+    fn test_session_over_blocking() {
+        let pit_data = std::fs::read(PIT).unwrap();
+        let image: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+
+        let c = Box::new(Blocking::new(Box::new(host)));
+        assert_eq!(pit_data, block_on(Box::pin(session(c, &image))).unwrap());
+        check_target(target, &image);
+    }
+
+    #[test]
+    fn test_flash_odintar() {
+        const ARCHIVE: &str = "../odintar/testdata/BL_A405FNXXU4CVK1_CL25488227_QB58944467_REV00_user_low_ship.tar.md5";
+        let pit = Pit::deserialize(&std::fs::read(PIT).unwrap()).unwrap();
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+
+        let c = Box::new(Blocking::new(Box::new(host)));
+        let mut archive = File::open(ARCHIVE).unwrap();
+        let archive_len = archive.metadata().unwrap().len();
+        let mut flashed: u64 = 0;
+        let mut cb = |n: u64| flashed += n;
+        block_on(Box::pin(async {
+            let mut sess = AsyncSession::begin(c).await?;
+            sess.flash_odintar(&mut archive, pit, false, &mut Some(&mut cb))
+                .await?;
+            return sess.end(ActionAfter::Nothing).await;
+        }))
+        .unwrap();
+        assert!(flashed > 0 && flashed < archive_len);
+
+        let target = target.join().unwrap().unwrap();
+        let partitions: Vec<&str> = target
+            .log()
+            .flashed
+            .iter()
+            .map(|f| f.partition_name.as_str())
+            .collect();
+        assert_eq!(vec!["BOOTLOADER", "PARAM", "CM", "VBMETA"], partitions);
+    }
+}
@@ -3,35 +3,87 @@ use crate::Result;
 
 use crate::comms::Communicator;
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-const BEGIN_SESSION: u32 = 0x00;
-const SET_PACKET_SIZE: u32 = 0x05;
-const ERASE_USERDATA: u32 = 0x07;
+pub(crate) const BEGIN_SESSION: u32 = 0x00;
+pub(crate) const SET_PACKET_SIZE: u32 = 0x05;
+pub(crate) const ERASE_USERDATA: u32 = 0x07;
+pub(crate) const T_FLASH: u32 = 0x08;
+/// Bit in the target's reply to the session start signalling compression support.
+pub(crate) const COMPRESSION_SUPPORTED: u32 = 0x8000;
 
 /// Maximum number of file parts permitted in one flashing sequence for protocol version 1.
 const V1_MAX_SEQ_PARTS: u32 = 800;
 /// Maximum size of a single packet in a flashing sequence for protocol version 1.
-const V1_MAX_FILE_PART_SIZE: u32 = 128 * 1024; // 128KiB
+pub(crate) const V1_MAX_FILE_PART_SIZE: u32 = 128 * 1024; // 128KiB
 /// Maximum number of file parts permitted in one flashing sequence for protocol version >1.
 const V2PLUS_MAX_SEQ_PARTS: u32 = 30;
 /// Timeout for a transfer for protocol version 1.
-const V1_TIMEOUT: Duration = Duration::from_secs(30);
+pub(crate) const V1_TIMEOUT: Duration = Duration::from_secs(30);
 /// Maximum size of a single packet in a flashing sequence for protocol version >1.
-const V2PLUS_MAX_FILE_PART_SIZE: u32 = 1024 * 1024; // 1MiB
+pub(crate) const V2PLUS_MAX_FILE_PART_SIZE: u32 = 1024 * 1024; // 1MiB
 /// Timeout for a transfer for protocol version >1.
-const V2PLUS_TIMEOUT: Duration = Duration::from_secs(120);
+pub(crate) const V2PLUS_TIMEOUT: Duration = Duration::from_secs(120);
+/// Timeout for the handshake, before the protocol version is known.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Timeout for ending a sequence, when the target writes it to storage.
+const SEQUENCE_END_TIMEOUT: Duration = Duration::from_secs(300);
+/// Timeout for erasing userdata. This can take a while on large storage.
+const ERASE_TIMEOUT: Duration = Duration::from_secs(600);
 /// Highest protocol version we support.
-const MAX_PROTO_VERSION: u32 = 0x04;
+pub(crate) const MAX_PROTO_VERSION: u32 = 0x04;
 
 /// Known protocol versions.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ProtoVersion {
+    /// Version 1, used by old bootloaders. Doesn't support negotiating the file part size.
     V1,
+    /// Version 2.
     V2,
+    /// Version 3, which exchanges empty transfers at the end of PIT transfers.
     V3,
+    /// Version 4, which supports 64-bit total sizes.
     V4,
 }
+
+/// How long to wait for the target in each phase of a session.
+///
+/// Each exchange with the target, such as a command and its reply, has to finish within the timeout of its phase.
+/// Start from `TimeoutPolicy::for_version` to override only some of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeoutPolicy {
+    /// Handshake and session negotiation.
+    pub handshake: Duration,
+    /// Commands not covered by the other phases, such as announcing sizes and ending the session.
+    pub command: Duration,
+    /// Each step of downloading or flashing the PIT.
+    pub pit: Duration,
+    /// Sending a file part until the target acknowledges it.
+    pub part_ack: Duration,
+    /// Ending a sequence, when the target writes it to storage. The last sequence of a partition may take much longer.
+    pub sequence_end: Duration,
+    /// Erasing userdata during a factory reset.
+    pub erase: Duration,
+}
+
+impl TimeoutPolicy {
+    /// The default timeouts for the given protocol version.
+    pub fn for_version(v: ProtoVersion) -> TimeoutPolicy {
+        let transfer = match v {
+            ProtoVersion::V1 => V1_TIMEOUT,
+            _ => V2PLUS_TIMEOUT,
+        };
+        return TimeoutPolicy {
+            handshake: HANDSHAKE_TIMEOUT,
+            command: transfer,
+            pit: transfer,
+            part_ack: transfer,
+            sequence_end: SEQUENCE_END_TIMEOUT.max(transfer),
+            erase: ERASE_TIMEOUT,
+        };
+    }
+}
+
 /// Session parameters negotiated with the target.
 #[derive(Clone, Copy, Debug)]
 pub struct SessionParams {
@@ -45,40 +97,47 @@ pub struct SessionParams {
     pub max_seq_file_parts: u32,
     /// Negotiated maximum sequence total size in bytes.
     pub max_seq_size_bytes: u32,
-    /// Timeout for sending and receiving data.
-    pub timeout: Duration,
+    /// Timeouts of the phases of the session. Change them to override the defaults for the protocol version.
+    pub timeouts: TimeoutPolicy,
 }
 
-fn negotiate_packet_size_and_timeout(
+fn negotiate_packet_size(
     c: &mut Box<dyn Communicator>,
     v: ProtoVersion,
-) -> Result<(OdinInt, Duration)> {
+    deadline: Instant,
+) -> Result<OdinInt> {
     // This fixed size should be safe for proto version 0
     if v == ProtoVersion::V1 {
         log::trace!(target: "SESS", "Setting packet size supported by version 1: {}", V1_MAX_FILE_PART_SIZE);
-        return Ok((OdinInt::from(V1_MAX_FILE_PART_SIZE), V1_TIMEOUT));
+        return Ok(OdinInt::from(V1_MAX_FILE_PART_SIZE));
     }
 
     // Other versions support negotiation (and may, in fact, require it)
     // In future, we could get more creative here.
     log::trace!(target: "SESS", "Version is newer than 1, negotiating packet size {}", V2PLUS_MAX_FILE_PART_SIZE);
-    let p = OdinCmdPacket::with_2_args(
-        OdinCmd::SessionStart,
-        OdinInt::from(SET_PACKET_SIZE),
-        OdinInt::from(V2PLUS_MAX_FILE_PART_SIZE),
-    );
-    p.send(c)?;
+    packet_size_packet().send(c, deadline)?;
 
-    let resp = OdinCmdReply::read(c)?;
+    let resp = OdinCmdReply::read(c, deadline)?;
     if resp.cmd != OdinCmd::SessionStart {
         return Err(
             DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::SessionStart, resp.cmd).into(),
         );
     }
-    if resp.arg != OdinInt::from(0) {
-        return Err(DownloadProtocolError::UnexpectedOdinCmdArg(OdinInt::from(0), resp.arg).into());
+    if resp.arg != OdinInt::from(REPLY_OK) {
+        return Err(
+            DownloadProtocolError::UnexpectedOdinCmdArg(OdinInt::from(REPLY_OK), resp.arg).into(),
+        );
     }
-    return Ok((OdinInt::from(V2PLUS_MAX_FILE_PART_SIZE), V2PLUS_TIMEOUT));
+    return Ok(OdinInt::from(V2PLUS_MAX_FILE_PART_SIZE));
+}
+
+/// Build the command negotiating the file part size, for protocol versions after 1.
+pub(crate) fn packet_size_packet() -> OdinCmdPacket {
+    return OdinCmdPacket::with_2_args(
+        OdinCmd::SessionStart,
+        OdinInt::from(SET_PACKET_SIZE),
+        OdinInt::from(V2PLUS_MAX_FILE_PART_SIZE),
+    );
 }
 
 fn get_max_seq_file_parts(v: ProtoVersion) -> Result<OdinInt> {
@@ -95,15 +154,36 @@ fn get_max_seq_file_parts(v: ProtoVersion) -> Result<OdinInt> {
 /// Returns `Err(())` if version couldn't be determined or is one ragnaroek doesn't know about.
 fn determine_version_and_compression(
     c: &mut Box<dyn Communicator>,
+    deadline: Instant,
 ) -> Result<(ProtoVersion, bool)> {
-    let p = OdinCmdPacket::with_2_args(
+    begin_session_packet().send(c, deadline)?;
+
+    let resp = OdinCmdReply::read(c, deadline)?;
+    return version_and_compression_from_reply(resp);
+}
+
+/// Build the command starting the session, offering the highest protocol version we support.
+pub(crate) fn begin_session_packet() -> OdinCmdPacket {
+    return OdinCmdPacket::with_2_args(
         OdinCmd::SessionStart,
         OdinInt::from(BEGIN_SESSION),
         OdinInt::from(MAX_PROTO_VERSION),
     );
-    p.send(c)?;
+}
 
-    let resp = OdinCmdReply::read(c)?;
+/// Build the command entering T-Flash mode.
+pub(crate) fn tflash_packet() -> OdinCmdPacket {
+    return OdinCmdPacket::with_2_args(
+        OdinCmd::SessionStart,
+        OdinInt::from(BEGIN_SESSION),
+        OdinInt::from(T_FLASH),
+    );
+}
+
+/// Interprets the target's reply to the session start, see `determine_version_and_compression`.
+pub(crate) fn version_and_compression_from_reply(
+    resp: OdinCmdReply,
+) -> Result<(ProtoVersion, bool)> {
     if resp.cmd != OdinCmd::SessionStart {
         return Err(
             DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::SessionStart, resp.cmd).into(),
@@ -114,8 +194,7 @@ fn determine_version_and_compression(
     // It's hard to differentiate between a target not supporting the given version and replying nonsense.
     // To work around that, let's assume that Samsung won't release >100 protocol versions.
     if bl_version > 100 {
-        // TODO: Cleanup
-        panic!("Target responded with bogus version {:?}", resp.arg.inner);
+        return Err(DownloadProtocolError::BogusProtoVersion(resp.arg).into());
     }
 
     // Version 1 is special, because of course it is
@@ -124,7 +203,7 @@ fn determine_version_and_compression(
     }
 
     // If compression is supported, this bit is set
-    let supports_compression: bool = (resp.arg.inner & 0x8000) > 0;
+    let supports_compression: bool = (resp.arg.inner & COMPRESSION_SUPPORTED) > 0;
 
     match bl_version {
         1 => return Ok((ProtoVersion::V1, supports_compression)),
@@ -136,34 +215,55 @@ fn determine_version_and_compression(
 }
 
 /// Begins a session with a target.
-pub(crate) fn begin_session(c: &mut Box<dyn Communicator>) -> Result<SessionParams> {
+///
+/// Uses the given timeouts, or the defaults for the target's protocol version if there are none.
+pub(crate) fn begin_session(
+    c: &mut Box<dyn Communicator>,
+    timeouts: Option<TimeoutPolicy>,
+) -> Result<SessionParams> {
     log::debug!(target: "SESS", "Beginning session");
-    let (proto_version, supports_compression) = determine_version_and_compression(c)?;
+    let handshake = timeouts.map_or(HANDSHAKE_TIMEOUT, |t| t.handshake);
+    let deadline = Instant::now() + handshake;
+    let (proto_version, supports_compression) = determine_version_and_compression(c, deadline)?;
 
-    let (max_file_part_size, timeout) = negotiate_packet_size_and_timeout(c, proto_version)?;
-    let max_seq_file_parts = get_max_seq_file_parts(proto_version)?.inner;
+    let deadline = Instant::now() + handshake;
+    let max_file_part_size = negotiate_packet_size(c, proto_version, deadline)?;
+    let params = session_params(
+        proto_version,
+        supports_compression,
+        max_file_part_size,
+        timeouts.unwrap_or(TimeoutPolicy::for_version(proto_version)),
+    )?;
+    log::debug!(target: "SESS", "Negotiated session params: {:?}", params);
 
-    let params = SessionParams {
+    return Ok(params);
+}
+
+/// Puts the negotiated values together.
+pub(crate) fn session_params(
+    proto_version: ProtoVersion,
+    supports_compression: bool,
+    max_file_part_size: OdinInt,
+    timeouts: TimeoutPolicy,
+) -> Result<SessionParams> {
+    let max_seq_file_parts = get_max_seq_file_parts(proto_version)?.inner;
+    return Ok(SessionParams {
         supports_compression,
         proto_version,
         max_file_part_size: max_file_part_size.inner,
         max_seq_file_parts,
         max_seq_size_bytes: max_seq_file_parts * max_file_part_size.inner,
-        timeout,
-    };
-    log::debug!(target: "SESS", "Negotiated session params: {:?}", params);
-    c.set_timeout(timeout);
-
-    return Ok(params);
+        timeouts,
+    });
 }
 
 /// Performs a factory reset.
-pub(crate) fn factory_reset(c: &mut Box<dyn Communicator>) -> Result<()> {
+pub(crate) fn factory_reset(c: &mut Box<dyn Communicator>, timeout: Duration) -> Result<()> {
     log::info!(target: "SESSION", "Erasing userdata");
-    let p = OdinCmdPacket::with_1_arg(OdinCmd::SessionStart, OdinInt::from(ERASE_USERDATA));
-    p.send(c)?;
+    let deadline = Instant::now() + timeout;
+    erase_userdata_packet().send(c, deadline)?;
 
-    let resp = OdinCmdReply::read(c)?;
+    let resp = OdinCmdReply::read(c, deadline)?;
     if resp.cmd != OdinCmd::SessionStart {
         return Err(
             DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::SessionStart, resp.cmd).into(),
@@ -172,3 +272,8 @@ pub(crate) fn factory_reset(c: &mut Box<dyn Communicator>) -> Result<()> {
     log::info!(target: "SESSION", "Erased userdata OK, erase function status {}", resp.arg);
     return Ok(());
 }
+
+/// Build the command erasing userdata.
+pub(crate) fn erase_userdata_packet() -> OdinCmdPacket {
+    return OdinCmdPacket::with_1_arg(OdinCmd::SessionStart, OdinInt::from(ERASE_USERDATA));
+}
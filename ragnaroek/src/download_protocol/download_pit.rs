@@ -4,48 +4,63 @@ use crate::download_protocol::begin_session::{ProtoVersion, SessionParams};
 use crate::Communicator;
 use crate::Result;
 
-const PIT_CHUNK_SIZE: usize = 500;
+use std::time::{Duration, Instant};
 
-const PIT_FLAG_DUMP: u32 = 0x01;
-const PIT_FLAG_CHUNK: u32 = 0x02;
-const PIT_FLAG_END: u32 = 0x03;
+/// The target sends PITs in chunks of this size.
+pub(crate) const PIT_CHUNK_SIZE: usize = 500;
+/// Real PITs are a few kilobytes, anything larger than this is garbage.
+const MAX_PIT_SIZE: u32 = 1024 * 1024; // 1MiB
 
-const PIT_END_OK: u32 = 0x00;
+pub(crate) const PIT_FLAG_DUMP: u32 = 0x01;
+/// Requests a chunk when dumping, announces the size when flashing.
+pub(crate) const PIT_FLAG_CHUNK: u32 = 0x02;
+pub(crate) const PIT_FLAG_END: u32 = 0x03;
 
 /// Downloads partitioning data from the target.
 pub(crate) fn download_pit(c: &mut Box<dyn Communicator>, p: SessionParams) -> Result<Vec<u8>> {
     log::info!(target: "PIT", "Start PIT download");
-    let total_len: OdinInt = initiate_pit_download(c)?;
-    let total_len: u32 = total_len.into();
-    let total_len: usize = total_len
-        .try_into()
-        .expect("Not trying to run this on a 16-bit platform, are you?");
+    let timeout = p.timeouts.pit;
+    let total_len = pit_len(initiate_pit_download(c, timeout)?)?;
     let mut data: Vec<u8> = Vec::with_capacity(total_len);
 
     let mut chunk_idx: usize = 0;
     while data.len() < total_len {
-        data.extend_from_slice(&fetch_pit_chunk(c, total_len - data.len(), chunk_idx)?);
+        data.extend_from_slice(&fetch_pit_chunk(
+            c,
+            total_len - data.len(),
+            chunk_idx,
+            timeout,
+        )?);
         chunk_idx += 1;
     }
 
     let is_proto_v3plus: bool =
         p.proto_version == ProtoVersion::V3 || p.proto_version == ProtoVersion::V4;
-    end_pit_download(c, is_proto_v3plus)?;
+    end_pit_download(c, is_proto_v3plus, timeout)?;
     log::info!(target: "PIT", "PIT download OK");
 
     return Ok(data);
 }
 
+/// Checks the PIT size announced by the target, so garbage doesn't make us allocate gigabytes.
+pub(crate) fn pit_len(announced: OdinInt) -> Result<usize> {
+    let len: u32 = announced.into();
+    if len > MAX_PIT_SIZE {
+        return Err(DownloadProtocolError::PitTooLarge(announced).into());
+    }
+    return Ok(len.try_into()?);
+}
+
 /// Sends the initial PIT download request packet and checks for an appropriate target response.
 /// Returns either an Error or the amount of bytes the target is about to transfer.
 /// The effects of calling this while a transfer is already in progress are unknown.
-fn initiate_pit_download(c: &mut Box<dyn Communicator>) -> Result<OdinInt> {
+fn initiate_pit_download(c: &mut Box<dyn Communicator>, timeout: Duration) -> Result<OdinInt> {
     log::debug!(target: "PIT", "Initiating PIT download");
-    let p = OdinCmdPacket::with_1_arg(OdinCmd::TransferPIT, OdinInt::from(PIT_FLAG_DUMP));
-    p.send(c)?;
+    let deadline = Instant::now() + timeout;
+    pit_dump_packet().send(c, deadline)?;
 
     // We expect an 8-byte response from the target
-    let resp = OdinCmdReply::read(c)?;
+    let resp = OdinCmdReply::read(c, deadline)?;
     if resp.cmd != OdinCmd::TransferPIT {
         return Err(
             DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::TransferPIT, resp.cmd).into(),
@@ -60,65 +75,86 @@ fn fetch_pit_chunk(
     c: &mut Box<dyn Communicator>,
     total_remaining: usize,
     chunk_idx: usize,
+    timeout: Duration,
 ) -> Result<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
     // Calculate which chunk index to use
     let chunk_idx: u32 = chunk_idx.try_into()?;
     let chunk_idx: OdinInt = chunk_idx.into();
     log::debug!(target: "PIT", "[Chunk {}] Fetching part of remaining {} bytes", chunk_idx, total_remaining);
 
     // Send request
-    let p = OdinCmdPacket::with_2_args(
-        OdinCmd::TransferPIT,
-        OdinInt::from(PIT_FLAG_CHUNK),
-        chunk_idx,
-    );
-    p.send(c)?;
+    pit_chunk_packet(chunk_idx).send(c, deadline)?;
 
     // Read response
     let left = core::cmp::min(total_remaining, PIT_CHUNK_SIZE);
-    let ret = c.recv_exact(left).map_err(|e| e.into());
+    let chunk = c.recv_exact_before(left, deadline)?;
+    if chunk.len() != left {
+        return Err(DownloadProtocolError::ShortRead(left, chunk).into());
+    }
     log::debug!(target: "PIT", "[Chunk {}] Fetching OK", chunk_idx);
-    return ret;
+    return Ok(chunk);
 }
 
 /// Tells the target that the PIT transfer is over and checks for an appropriate target response.
 /// The effects of calling this without initiating a transfer or in the middle of one are unknown.
-fn end_pit_download(c: &mut Box<dyn Communicator>, is_proto_v3plus: bool) -> Result<()> {
+fn end_pit_download(
+    c: &mut Box<dyn Communicator>,
+    is_proto_v3plus: bool,
+    timeout: Duration,
+) -> Result<()> {
     log::debug!(target: "PIT", "Ending PIT download");
+    let deadline = Instant::now() + timeout;
 
     // For whatever reason, if connected via USB the device really wants to send us an empty transfer
     // NOTE: Some protocol versions require these empty transfers, whether it's version 3 exactly is a guess.
     if is_proto_v3plus {
         log::debug!(target: "PIT", "Protocol version >3, exchanging empty transfers");
         log::trace!(target: "PIT", "Receiving empty transfer");
-        c.recv_exact(0)?;
+        c.recv_exact_before(0, deadline)?;
         log::trace!(target: "PIT", "Receiving empty transfer OK");
 
         // And the device expects an empty transfer from us
         log::trace!(target: "PIT", "Sending empty transfer");
-        c.send(&[])?;
+        c.send_before(&[], deadline)?;
         log::trace!(target: "PIT", "Sending empty transfer OK");
     } else {
         log::debug!(target: "PIT", "Protocol version < 3, not exchanging empty transfers");
     }
 
-    let p = OdinCmdPacket::with_1_arg(OdinCmd::TransferPIT, OdinInt::from(PIT_FLAG_END));
-    p.send(c)?;
+    pit_end_packet().send(c, deadline)?;
 
-    let resp = OdinCmdReply::read(c)?;
+    let resp = OdinCmdReply::read(c, deadline)?;
     if resp.cmd != OdinCmd::TransferPIT {
         return Err(
             DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::TransferPIT, resp.cmd).into(),
         );
     }
-    if resp.arg != OdinInt::from(PIT_END_OK) {
-        return Err(DownloadProtocolError::UnexpectedOdinCmdArg(
-            OdinInt::from(PIT_END_OK),
-            resp.arg,
-        )
-        .into());
+    if resp.arg != OdinInt::from(REPLY_OK) {
+        return Err(
+            DownloadProtocolError::UnexpectedOdinCmdArg(OdinInt::from(REPLY_OK), resp.arg).into(),
+        );
     }
     log::debug!(target: "PIT", "Ending PIT download OK");
 
     return Ok(());
 }
+
+/// Build the command starting a PIT download.
+pub(crate) fn pit_dump_packet() -> OdinCmdPacket {
+    return OdinCmdPacket::with_1_arg(OdinCmd::TransferPIT, OdinInt::from(PIT_FLAG_DUMP));
+}
+
+/// Build the command requesting the PIT chunk with the given index.
+pub(crate) fn pit_chunk_packet(chunk_idx: OdinInt) -> OdinCmdPacket {
+    return OdinCmdPacket::with_2_args(
+        OdinCmd::TransferPIT,
+        OdinInt::from(PIT_FLAG_CHUNK),
+        chunk_idx,
+    );
+}
+
+/// Build the command ending a PIT transfer in either direction.
+pub(crate) fn pit_end_packet() -> OdinCmdPacket {
+    return OdinCmdPacket::with_1_arg(OdinCmd::TransferPIT, OdinInt::from(PIT_FLAG_END));
+}
@@ -0,0 +1,700 @@
+//! A software download mode target, speaking the target side of the protocol.
+//!
+//! It's meant for testing flashers end to end, without risking a real device.
+//! Flashed partitions end up in a backing disk image, laid out as described by the target's PIT.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
+
+use either::Either;
+use pit::{Pit, PitEntryV1, PitEntryV2, PitType};
+
+use super::preflight::{block_size, capacity};
+use super::*;
+use crate::shell::SHELL_PREFIX;
+use crate::{Communicator, Result};
+
+/// Reply argument signalling a rejected PIT.
+/// This is the emulator's own choice, the host doesn't rely on it as real targets' failure replies aren't known.
+const REPLY_FAILED: u32 = 0x01;
+/// File part size used until the host negotiates a different one, the only one version 1 supports.
+const DEFAULT_FILE_PART_SIZE: u32 = V1_MAX_FILE_PART_SIZE;
+/// How long the shell waits for more of a command to arrive.
+const SHELL_QUIET_TIME: Duration = Duration::from_millis(50);
+
+/// How the emulated target behaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TargetOptions {
+    /// Protocol version the target speaks, from 1 to 4.
+    pub proto_version: u32,
+    /// Whether the target claims to support compressed transfers.
+    pub supports_compression: bool,
+}
+
+impl Default for TargetOptions {
+    fn default() -> Self {
+        return TargetOptions {
+            proto_version: 4,
+            supports_compression: false,
+        };
+    }
+}
+
+/// Where a partition is located in the backing disk image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionExtent {
+    /// Name of the partition, as given in the PIT.
+    pub name: String,
+    /// Offset of the partition in the disk image, in bytes.
+    pub offset: u64,
+    /// Size of the partition in bytes, `None` if it takes up the rest of the device.
+    pub size: Option<u64>,
+}
+
+/// A file that was flashed completely.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashedFile {
+    /// Partition the file was written to.
+    pub partition_name: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+}
+
+/// What the target saw during a session.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TargetLog {
+    /// Files flashed, in order.
+    pub flashed: Vec<FlashedFile>,
+    /// Why sequences were discarded instead of written, in order.
+    ///
+    /// The host ignores the argument of the reply to a sequence's end, so this is the only place
+    /// where rejected data shows up. The file's size in `flashed` doesn't include it.
+    pub rejected: Vec<String>,
+    /// Whether the host enabled T-Flash mode.
+    pub tflash: bool,
+    /// Whether the host requested a factory reset.
+    pub userdata_erased: bool,
+    /// Whether the host replaced the PIT.
+    pub pit_flashed: bool,
+    /// Action requested when ending the session, see `ActionAfter`.
+    pub end_action: Option<u32>,
+    /// Shell commands received, without the `PROMPT` prefix.
+    pub shell_commands: Vec<String>,
+}
+
+/// A command packet as received from the host.
+struct Packet {
+    cmd: OdinCmd,
+    args: [u32; 7],
+}
+
+/// State of the file currently being flashed.
+#[derive(Default)]
+struct FlashState {
+    total_size: u64,
+    written: u64,
+    /// Data of the current sequence, written out once its partition is known.
+    sequence: Vec<u8>,
+}
+
+/// An emulated download mode target, writing flashed partitions into `disk`.
+pub struct Target<D: Read + Write + Seek> {
+    options: TargetOptions,
+    pit_data: Vec<u8>,
+    pit: Pit,
+    layout: Vec<PartitionExtent>,
+    disk: D,
+    file_part_size: u32,
+    flash: FlashState,
+    log: TargetLog,
+}
+
+impl<D: Read + Write + Seek> Target<D> {
+    /// Create a target with the given PIT, backed by `disk`.
+    pub fn new(pit_data: Vec<u8>, disk: D, options: TargetOptions) -> Result<Target<D>> {
+        if !(1..=4).contains(&options.proto_version) {
+            return Err(DownloadProtocolError::UnknownProtoVersion(OdinInt::from(
+                options.proto_version,
+            ))
+            .into());
+        }
+        let pit = Pit::deserialize(&pit_data)?;
+        return Ok(Target {
+            options,
+            layout: layout(&pit),
+            pit,
+            pit_data,
+            disk,
+            file_part_size: DEFAULT_FILE_PART_SIZE,
+            flash: FlashState::default(),
+            log: TargetLog::default(),
+        });
+    }
+
+    /// The current PIT, which changes if the host flashes a new one.
+    pub fn pit(&self) -> &[u8] {
+        return &self.pit_data;
+    }
+
+    /// Where partitions are located in the disk image.
+    pub fn layout(&self) -> &[PartitionExtent] {
+        return &self.layout;
+    }
+
+    /// Look up where a partition is located in the disk image.
+    pub fn partition(&self, name: &str) -> Option<&PartitionExtent> {
+        return self.layout.iter().find(|p| p.name == name);
+    }
+
+    /// What happened in the sessions so far.
+    pub fn log(&self) -> &TargetLog {
+        return &self.log;
+    }
+
+    /// The backing disk image.
+    pub fn disk_mut(&mut self) -> &mut D {
+        return &mut self.disk;
+    }
+
+    /// Consume the target, returning the backing disk image.
+    pub fn into_disk(self) -> D {
+        return self.disk;
+    }
+
+    /// Serve a host over the given `Communicator`, until it ends the session or disconnects.
+    ///
+    /// Hosts starting with `ODIN` get a download mode session, hosts starting with `PROMPT` get the command shell.
+    /// Empty transfers aren't visible on stream transports, so they are neither expected nor sent.
+    pub fn serve(&mut self, c: &mut Box<dyn Communicator>) -> Result<()> {
+        let hello = c.recv_exact(PING.len())?;
+        if hello == PING {
+            log::debug!(target: "EMU", "Handshake OK");
+            c.send(&PONG)?;
+            return self.serve_session(c);
+        }
+        if SHELL_PREFIX.as_bytes().starts_with(&hello) {
+            return self.serve_shell(c, hello);
+        }
+        return Err(DownloadProtocolError::InvalidMagicHandshake(hello).into());
+    }
+
+    fn serve_session(&mut self, c: &mut Box<dyn Communicator>) -> Result<()> {
+        loop {
+            let p = read_packet(c)?;
+            log::trace!(target: "EMU", "Packet: {:?} {:X?}", p.cmd, p.args);
+            match p.cmd {
+                OdinCmd::SessionStart => self.session_cmd(c, &p)?,
+                OdinCmd::TransferPIT => self.pit_cmd(c, &p)?,
+                OdinCmd::Flash => self.flash_cmd(c, &p)?,
+                OdinCmd::SessionEnd => {
+                    log::debug!(target: "EMU", "Session ended with action {}", p.args[0]);
+                    self.log.end_action = Some(p.args[0]);
+                    reply(c, OdinCmd::SessionEnd, REPLY_OK)?;
+                    return Ok(());
+                }
+                OdinCmd::ChunkTransferOk => return Err(unknown_cmd(&p)),
+            }
+        }
+    }
+
+    fn session_cmd(&mut self, c: &mut Box<dyn Communicator>, p: &Packet) -> Result<()> {
+        match p.args[0] {
+            BEGIN_SESSION if p.args[1] == T_FLASH => {
+                log::debug!(target: "EMU", "T-Flash enabled");
+                self.log.tflash = true;
+                return reply(c, OdinCmd::SessionStart, REPLY_OK);
+            }
+            BEGIN_SESSION => {
+                let version = match (
+                    self.options.proto_version,
+                    self.options.supports_compression,
+                ) {
+                    // Version 1 bootloaders predate version negotiation and reply with zero
+                    (1, false) => 0,
+                    (v, false) => v << 16,
+                    (v, true) => (v << 16) | COMPRESSION_SUPPORTED,
+                };
+                return reply(c, OdinCmd::SessionStart, version);
+            }
+            // Sent to negotiate the session, and again before flashing each file
+            SET_PACKET_SIZE => {
+                log::debug!(target: "EMU", "File part size set to {}", p.args[1]);
+                self.file_part_size = p.args[1];
+                return reply(c, OdinCmd::SessionStart, REPLY_OK);
+            }
+            SET_TOTAL_SIZE => {
+                self.flash = FlashState::default();
+                self.flash.total_size = if self.options.proto_version == 4 {
+                    u64::from(p.args[1]) | (u64::from(p.args[2]) << 32)
+                } else {
+                    u64::from(p.args[1])
+                };
+                log::debug!(target: "EMU", "Expecting {} bytes", self.flash.total_size);
+                return reply(c, OdinCmd::SessionStart, REPLY_OK);
+            }
+            ERASE_USERDATA => {
+                log::debug!(target: "EMU", "Erasing userdata");
+                self.log.userdata_erased = true;
+                return reply(c, OdinCmd::SessionStart, REPLY_OK);
+            }
+            _ => return Err(unknown_cmd(p)),
+        }
+    }
+
+    fn pit_cmd(&mut self, c: &mut Box<dyn Communicator>, p: &Packet) -> Result<()> {
+        match p.args[0] {
+            PIT_FLAG_DUMP => {
+                log::debug!(target: "EMU", "PIT dump of {} bytes", self.pit_data.len());
+                return reply(c, OdinCmd::TransferPIT, self.pit_data.len().try_into()?);
+            }
+            PIT_FLAG_CHUNK => {
+                let start = (p.args[1] as usize)
+                    .saturating_mul(PIT_CHUNK_SIZE)
+                    .min(self.pit_data.len());
+                let end = (start + PIT_CHUNK_SIZE).min(self.pit_data.len());
+                c.send(&self.pit_data[start..end])?;
+                return Ok(());
+            }
+            PIT_FLAG_FLASH => {
+                reply(c, OdinCmd::TransferPIT, REPLY_OK)?;
+
+                // Next come the size and the PIT itself
+                let p = read_packet(c)?;
+                if p.cmd != OdinCmd::TransferPIT || p.args[0] != PIT_FLAG_CHUNK {
+                    return Err(unknown_cmd(&p));
+                }
+                reply(c, OdinCmd::TransferPIT, REPLY_OK)?;
+                let data = c.recv_exact(p.args[1] as usize)?;
+                match Pit::deserialize(&data) {
+                    Ok(pit) => {
+                        log::debug!(target: "EMU", "New PIT flashed");
+                        self.layout = layout(&pit);
+                        self.pit = pit;
+                        self.pit_data = data;
+                        self.log.pit_flashed = true;
+                        return reply(c, OdinCmd::TransferPIT, PIT_RECEIVED);
+                    }
+                    Err(e) => {
+                        log::warn!(target: "EMU", "Rejecting invalid PIT: {e:?}");
+                        return reply(c, OdinCmd::TransferPIT, REPLY_FAILED);
+                    }
+                }
+            }
+            PIT_FLAG_END => return reply(c, OdinCmd::TransferPIT, REPLY_OK),
+            _ => return Err(unknown_cmd(p)),
+        }
+    }
+
+    fn flash_cmd(&mut self, c: &mut Box<dyn Communicator>, p: &Packet) -> Result<()> {
+        match p.args[0] {
+            FLASH_CMD_BEGIN_FLASH => return reply(c, OdinCmd::Flash, REPLY_OK),
+            FLASH_CMD_SEQUENCE_BEGIN => {
+                let len = p.args[1] as usize;
+                let part_size = self.file_part_size as usize;
+                reply(c, OdinCmd::Flash, REPLY_OK)?;
+
+                // The last part is padded to the full part size
+                let mut sequence: Vec<u8> = Vec::with_capacity(len);
+                for idx in 0..len.div_ceil(part_size) {
+                    sequence.extend_from_slice(&c.recv_exact(part_size)?);
+                    reply(c, OdinCmd::ChunkTransferOk, idx.try_into()?)?;
+                }
+                sequence.truncate(len);
+                self.flash.sequence = sequence;
+                return Ok(());
+            }
+            FLASH_CMD_SEQUENCE_END => {
+                let is_modem = p.args[1] != 0;
+                let len = u64::from(p.args[2]);
+                let device_type = p.args[4];
+                // Modem packets lack the partition ID
+                let (partition_id, is_last) = if is_modem {
+                    (None, p.args[5] != 0)
+                } else {
+                    (Some(p.args[5]), p.args[6] != 0)
+                };
+
+                if let Err(e) = self.write_sequence(is_modem, device_type, partition_id, len) {
+                    log::warn!(target: "EMU", "Failed to write sequence: {e}");
+                    self.log.rejected.push(e.to_string());
+                }
+                if is_last {
+                    self.finish_file(is_modem, device_type, partition_id);
+                }
+                return reply(c, OdinCmd::Flash, REPLY_OK);
+            }
+            _ => return Err(unknown_cmd(p)),
+        }
+    }
+
+    /// Find the partition a sequence is meant for.
+    fn find_partition(
+        &self,
+        is_modem: bool,
+        device_type: u32,
+        partition_id: Option<u32>,
+    ) -> Option<usize> {
+        return self.pit.entries().iter().position(|e| {
+            let (pit_type, entry_device_type, entry_id) = match e {
+                Either::Left(e) => (e.pit_type, e.pit_device_type, e.partition_id),
+                Either::Right(e) => (e.pit_type, e.pit_device_type, e.partition_id),
+            };
+            u32::from(entry_device_type) == device_type
+                && match partition_id {
+                    Some(id) => entry_id == id,
+                    None => is_modem && pit_type == PitType::Modem,
+                }
+        });
+    }
+
+    /// Write the current sequence to its partition.
+    fn write_sequence(
+        &mut self,
+        is_modem: bool,
+        device_type: u32,
+        partition_id: Option<u32>,
+        len: u64,
+    ) -> std::io::Result<()> {
+        let idx = self
+            .find_partition(is_modem, device_type, partition_id)
+            .ok_or_else(|| std::io::Error::other("No such partition"))?;
+        let extent = &self.layout[idx];
+        let data = std::mem::take(&mut self.flash.sequence);
+        let data = &data[..(len as usize).min(data.len())];
+        if extent
+            .size
+            .is_some_and(|size| self.flash.written + data.len() as u64 > size)
+        {
+            return Err(std::io::Error::other(format!(
+                "Image overfills partition {}",
+                extent.name
+            )));
+        }
+
+        log::debug!(target: "EMU", "Writing {} bytes to {} at offset {}", data.len(), extent.name, self.flash.written);
+        self.disk
+            .seek(SeekFrom::Start(extent.offset + self.flash.written))?;
+        self.disk.write_all(data)?;
+        self.disk.flush()?;
+        self.flash.written += data.len() as u64;
+        return Ok(());
+    }
+
+    fn finish_file(&mut self, is_modem: bool, device_type: u32, partition_id: Option<u32>) {
+        let partition_name = match self.find_partition(is_modem, device_type, partition_id) {
+            Some(idx) => self.layout[idx].name.clone(),
+            None => return,
+        };
+        if self.flash.written != self.flash.total_size {
+            log::warn!(target: "EMU", "Expected {} bytes for {partition_name}, got {}", self.flash.total_size, self.flash.written);
+        }
+        self.log.flashed.push(FlashedFile {
+            partition_name,
+            size: self.flash.written,
+        });
+        self.flash = FlashState::default();
+    }
+
+    /// Answer shell commands until the host disconnects.
+    fn serve_shell(&mut self, c: &mut Box<dyn Communicator>, mut msg: Vec<u8>) -> Result<()> {
+        loop {
+            // Commands have no length field, so take whatever arrives until the host goes quiet
+            let mut last_data = Instant::now();
+            while last_data.elapsed() < SHELL_QUIET_TIME {
+                let data = c.recv()?;
+                if data.is_empty() {
+                    std::thread::sleep(Duration::from_millis(5));
+                } else {
+                    msg.extend_from_slice(&data);
+                    last_data = Instant::now();
+                }
+            }
+
+            let msg_str = String::from_utf8_lossy(&msg);
+            let cmd = msg_str
+                .strip_prefix(SHELL_PREFIX)
+                .unwrap_or(&msg_str)
+                .trim();
+            log::debug!(target: "EMU", "Shell command: {cmd}");
+            self.log.shell_commands.push(cmd.to_string());
+            let resp = match cmd {
+                "help" => String::from("Commands: help, version, partitions"),
+                "version" => format!("Protocol version {}", self.options.proto_version),
+                "partitions" => self
+                    .layout
+                    .iter()
+                    .map(|p| p.name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(" "),
+                _ => format!("Unknown command: {cmd}"),
+            };
+            c.send(resp.as_bytes())?;
+
+            // Wait for the next command, ending once the host disconnects
+            msg = match c.recv_exact(1) {
+                Ok(data) => data,
+                Err(e) => {
+                    log::debug!(target: "EMU", "Shell closed: {e}");
+                    return Ok(());
+                }
+            };
+        }
+    }
+}
+
+/// Compute where each partition is located in the disk image.
+///
+/// Version 2 PITs give start blocks. Version 1 PITs don't, so their partitions are laid out back to back in PIT order.
+/// Real PITs describe boot areas as overlapping the start of the device, those overlap in the image as well.
+fn layout(pit: &Pit) -> Vec<PartitionExtent> {
+    let mut next_offset: u64 = 0;
+    return pit
+        .entries()
+        .iter()
+        .map(|e| {
+            let size = capacity(e);
+            let (name, offset) = match e {
+                Either::Left(PitEntryV1 { partition_name, .. }) => {
+                    (partition_name.clone(), next_offset)
+                }
+                Either::Right(PitEntryV2 {
+                    partition_name,
+                    start_block,
+                    pit_device_type,
+                    ..
+                }) => (
+                    partition_name.clone(),
+                    u64::from(*start_block) * block_size(*pit_device_type),
+                ),
+            };
+            next_offset = offset + size.unwrap_or(0);
+            return PartitionExtent { name, offset, size };
+        })
+        .collect();
+}
+
+fn read_packet(c: &mut Box<dyn Communicator>) -> Result<Packet> {
+    let p = OdinCmdPacket::from_wire(&c.recv_exact(CMD_PACKET_LEN)?)?;
+    // Arguments not in use are zero
+    let mut args = [0; 7];
+    for (arg, a) in args.iter_mut().zip(p.args()) {
+        *arg = a.into();
+    }
+    return Ok(Packet { cmd: p.cmd(), args });
+}
+
+fn reply(c: &mut Box<dyn Communicator>, cmd: OdinCmd, arg: u32) -> Result<()> {
+    let arg = OdinInt::from(arg);
+    c.send(&OdinCmdReply { cmd, arg }.to_wire())?;
+    return Ok(());
+}
+
+fn unknown_cmd(p: &Packet) -> crate::Error {
+    log::warn!(target: "EMU", "Unknown command {:?} {:X?}", p.cmd, p.args);
+    return DownloadProtocolError::UnknownHostCmd(p.cmd, OdinInt::from(p.args[0])).into();
+}
+
+/// Serve a single host from a new thread, as a target with the test PIT on a scratch disk.
+///
+/// The `Communicator` is made by `connect` on that thread, so wireless targets can connect to the host.
+/// The thread returns the target once the host is done with it, or the error serving the host.
+#[cfg(test)]
+pub(crate) fn spawn_target<C, F>(
+    connect: F,
+    options: TargetOptions,
+) -> std::thread::JoinHandle<Result<Target<std::fs::File>>>
+where
+    C: Communicator + 'static,
+    F: FnOnce() -> C + Send + 'static,
+{
+    let pit_data = std::fs::read("../pit/testdata/A40_EUR_OPEN.pit").unwrap();
+    let disk = scratch_disk();
+    return std::thread::spawn(move || {
+        let mut target = Target::new(pit_data, disk, options)?;
+        let mut c: Box<dyn Communicator> = Box::new(connect());
+        target.serve(&mut c)?;
+        return Ok(target);
+    });
+}
+
+/// Disk images are sparse files, as partitions start gigabytes into the device.
+/// The file is removed right away, so it's gone once closed.
+#[cfg(test)]
+fn scratch_disk() -> std::fs::File {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "ragnaroek-disk-{}-{}.img",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let f = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    return f;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::pipe::Connection as Pipe;
+    use crate::{NetBindListener, NetConnectConnection, NetConnectOptions};
+    use std::io::Cursor;
+
+    const PIT: &str = "../pit/testdata/A40_EUR_OPEN.pit";
+
+    #[test]
+    fn test_session_all_versions() {
+        let pit_data = std::fs::read(PIT).unwrap();
+        let pit = Pit::deserialize(&pit_data).unwrap();
+        let image: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+
+        for proto_version in 1..=4 {
+            let (host, target) = Pipe::pair();
+            let options = TargetOptions {
+                proto_version,
+                supports_compression: proto_version > 2,
+            };
+            let target = spawn_target(move || target, options);
+
+            let mut sess = Session::begin(Box::new(host)).unwrap();
+            assert_eq!(
+                options.supports_compression,
+                sess.params.supports_compression
+            );
+            assert_eq!(pit_data, sess.download_pit(sess.params).unwrap());
+            sess.flash(
+                &image,
+                pit.get_entry_by_name("CM").unwrap(),
+                &mut None::<&mut fn(u64)>,
+            )
+            .unwrap();
+            sess.flash_pit(&pit_data).unwrap();
+            sess.factory_reset().unwrap();
+            sess.end(ActionAfter::RebootOS).unwrap();
+
+            let mut target = target.join().unwrap().unwrap();
+            let log = target.log().clone();
+            assert_eq!(
+                vec![FlashedFile {
+                    partition_name: String::from("CM"),
+                    size: image.len() as u64
+                }],
+                log.flashed
+            );
+            assert!(log.pit_flashed && log.userdata_erased);
+            assert_eq!(Some(ActionAfter::RebootOS as u32), log.end_action);
+
+            let extent = target.partition("CM").unwrap().clone();
+            let disk = target.disk_mut();
+            let mut flashed = vec![0; image.len()];
+            disk.seek(SeekFrom::Start(extent.offset)).unwrap();
+            disk.read_exact(&mut flashed).unwrap();
+            assert_eq!(image, flashed);
+        }
+    }
+
+    #[test]
+    fn test_session_over_tcp() {
+        let mut listener = NetBindListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Targets in wireless mode connect to the host
+        let target = spawn_target(
+            move || {
+                return NetConnectConnection::connect(addr, &NetConnectOptions::default()).unwrap();
+            },
+            TargetOptions::default(),
+        );
+
+        let host = listener.accept().unwrap();
+        let mut sess = Session::begin(Box::new(host)).unwrap();
+        sess.enable_tflash().unwrap();
+        sess.end(ActionAfter::Nothing).unwrap();
+
+        let target = target.join().unwrap().unwrap();
+        assert!(target.log().tflash);
+        assert_eq!(Some(0), target.log().end_action);
+    }
+
+    #[test]
+    fn test_overfill_and_layout() {
+        let pit_data = std::fs::read(PIT).unwrap();
+        let pit = Pit::deserialize(&pit_data).unwrap();
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+
+        let t = Target::new(
+            pit_data.clone(),
+            Cursor::new(Vec::new()),
+            TargetOptions::default(),
+        )
+        .unwrap();
+        let cm = t.partition("CM").unwrap();
+        assert_eq!(147456 * 512, cm.offset);
+        assert_eq!(Some(16384 * 512), cm.size);
+        assert_eq!(None, t.partition("USERDATA").unwrap().size);
+
+        // The target discards data that doesn't fit, without writing any of it.
+        // The host isn't told, so the flash itself succeeds.
+        let vbmeta = t.partition("VBMETA").unwrap().clone();
+        let image = vec![0x55; vbmeta.size.unwrap() as usize + 1];
+        let mut sess = Session::begin(Box::new(host)).unwrap();
+        sess.flash(
+            &image,
+            pit.get_entry_by_name("VBMETA").unwrap(),
+            &mut None::<&mut fn(u64)>,
+        )
+        .unwrap();
+        sess.end(ActionAfter::Nothing).unwrap();
+        let mut target = target.join().unwrap().unwrap();
+        assert_eq!(0, target.log().flashed[0].size);
+        assert_eq!(
+            vec![String::from("Image overfills partition VBMETA")],
+            target.log().rejected
+        );
+        let mut buf = [0; 1];
+        target
+            .disk_mut()
+            .seek(SeekFrom::Start(vbmeta.offset))
+            .unwrap();
+        assert_eq!(0, target.disk_mut().read(&mut buf).unwrap());
+
+        assert!(Target::new(
+            pit_data,
+            Cursor::new(Vec::new()),
+            TargetOptions {
+                proto_version: 5,
+                supports_compression: false
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_shell() {
+        let (mut host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+
+        let mut exchange = |cmd: &str| -> String {
+            host.send(format!("{SHELL_PREFIX}{cmd}").as_bytes())
+                .unwrap();
+            let mut resp: Vec<u8> = Vec::new();
+            while resp.is_empty() {
+                std::thread::sleep(Duration::from_millis(10));
+                resp = host.recv().unwrap();
+            }
+            return String::from_utf8(resp).unwrap();
+        };
+        assert_eq!("Protocol version 4", exchange("version"));
+        assert!(exchange("partitions").contains("VBMETA"));
+        drop(host);
+
+        let target = target.join().unwrap().unwrap();
+        assert_eq!(vec!["version", "partitions"], target.log().shell_commands);
+    }
+}
@@ -2,6 +2,8 @@ use super::*;
 use crate::comms::Communicator;
 use crate::Result;
 
+use std::time::{Duration, Instant};
+
 /// Target mode to reboot into. OS is supported on all devices, the others might not be.
 #[derive(Debug, Clone, Copy)]
 pub enum ActionAfter {
@@ -16,18 +18,27 @@ pub enum ActionAfter {
 }
 
 /// Ends the targets session, with an optional reboot to the OS.
-pub fn end_session(c: &mut Box<dyn Communicator>, after: ActionAfter) -> Result<()> {
+pub fn end_session(
+    c: &mut Box<dyn Communicator>,
+    after: ActionAfter,
+    timeout: Duration,
+) -> Result<()> {
     log::debug!(target: "SESS", "Ending session with action {:?}", after);
+    let deadline = Instant::now() + timeout;
     // Heimdall always first sends a session end, and only then a reboot.
     // Not sure if needed or we could send a reboot request immediately.
-    let p = OdinCmdPacket::with_1_arg(OdinCmd::SessionEnd, OdinInt::from(after as u32));
-    p.send(c)?;
+    end_session_packet(after).send(c, deadline)?;
 
     // We expect an 8-byte response from the target
-    let resp = OdinCmdReply::read(c)?;
+    let resp = OdinCmdReply::read(c, deadline)?;
     if resp.cmd != OdinCmd::SessionEnd {
         return Err(DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::SessionEnd, resp.cmd).into());
     }
     log::debug!(target: "SESS", "Ending session OK");
     return Ok(());
 }
+
+/// Build the command ending the session.
+pub(crate) fn end_session_packet(after: ActionAfter) -> OdinCmdPacket {
+    return OdinCmdPacket::with_1_arg(OdinCmd::SessionEnd, OdinInt::from(after as u32));
+}
@@ -1,58 +1,100 @@
 mod sequence;
 
+#[cfg(feature = "async")]
+pub(crate) use sequence::{end_packet, sequence_begin_packet};
+pub(crate) use sequence::{FLASH_CMD_SEQUENCE_BEGIN, FLASH_CMD_SEQUENCE_END};
+
 use super::begin_session::SessionParams;
 use super::*;
 
 use crate::download_protocol::begin_session::ProtoVersion;
+use crate::error::ResultExt;
 use crate::Communicator;
 use crate::Result;
+use crate::{Error, ErrorContext};
 
 use either::Either;
-use odintar::OdinTar;
+use odintar::{OdinTar, OdinTarError};
 use pit::{Pit, PitEntryV1, PitEntryV2};
 use std::io::{Read, Seek};
+use std::time::{Duration, Instant};
 
-const FLASH_CMD_BEGIN_FLASH: u32 = 0x00;
-const SET_TOTAL_SIZE: u32 = 0x02;
-const SET_FILE_PART_SIZE: u32 = 0x05;
+pub(crate) const FLASH_CMD_BEGIN_FLASH: u32 = 0x00;
+pub(crate) const SET_TOTAL_SIZE: u32 = 0x02;
+pub(crate) const SET_FILE_PART_SIZE: u32 = 0x05;
 
 /// The top-level flash function.
 ///
 /// It chops the file up into flash sequences and sends them all to the target.
+/// The `len` bytes of the file are read from `data` one file part at a time, so only one part is in memory at once.
 ///
 /// `cb` is a callback for e.g. displaying a progress bar.
 pub(crate) fn flash(
     c: &mut Box<dyn Communicator>,
     sp: SessionParams,
-    data: &[u8],
+    data: &mut dyn Read,
+    len: u64,
     pit_entry: Either<PitEntryV1, PitEntryV2>,
     cb: &mut Option<&mut impl FnMut(u64)>,
 ) -> Result<()> {
-    log::info!(target: "FLASH", "Starting flash of {} bytes total", data.len());
+    log::info!(target: "FLASH", "Starting flash of {} bytes total", len);
     let supports_64bit_size: bool = sp.proto_version == ProtoVersion::V4;
     let is_proto_v3plus: bool = sp.proto_version == ProtoVersion::V4;
-    set_total_size(c, data, supports_64bit_size)?;
-    set_file_part_size(c, sp.max_file_part_size)?;
-    start(c)?;
+    let timeouts = sp.timeouts;
+    let partition_name = match &pit_entry {
+        Either::Left(e) => &e.partition_name,
+        Either::Right(e) => &e.partition_name,
+    };
+    let partition = || ErrorContext {
+        partition: Some(partition_name.clone()),
+        ..Default::default()
+    };
+    set_total_size(c, len, supports_64bit_size, timeouts.command).context(|| ErrorContext {
+        command: Some("setting the total size"),
+        ..partition()
+    })?;
+    set_file_part_size(c, sp.max_file_part_size, timeouts.command).context(|| ErrorContext {
+        command: Some("setting the file part size"),
+        ..partition()
+    })?;
+    start(c, timeouts.command).context(|| ErrorContext {
+        command: Some("starting the flash"),
+        ..partition()
+    })?;
 
-    let total_seqs: usize = div_up(data.len(), sp.max_seq_size_bytes as usize);
+    let max_seq_size = u64::from(sp.max_seq_size_bytes);
+    let total_seqs: usize = len.div_ceil(max_seq_size).try_into()?;
     log::debug!(target: "FLASH", "Starting flash file sequence transfers, total sequences: {}", total_seqs);
-    let mut bytes_flashed: usize = 0;
-    for (i, sequence) in data.chunks(sp.max_seq_size_bytes as usize).enumerate() {
-        let sequence_len: u32 = sequence
-            .len()
-            .try_into()
-            .expect("Sequence length too large to fit into u32! This is probably a bug");
+    // The only buffer for file data, reused for every part
+    let mut part: Vec<u8> = vec![0; sp.max_file_part_size as usize];
+    let mut bytes_flashed: u64 = 0;
+    for i in 0..total_seqs {
+        let sequence_len: u32 = (len - bytes_flashed).min(max_seq_size).try_into()?;
+        let sequence_ctx = || ErrorContext {
+            sequence: Some((i + 1, total_seqs)),
+            ..partition()
+        };
         log::debug!(target: "FLASH", "[Sequence {}/{}] Starting transfer of {} bytes", i + 1, total_seqs, sequence_len);
-        sequence::initiate(c, sequence_len)?;
+        sequence::initiate(c, sequence_len, timeouts.command).context(|| ErrorContext {
+            command: Some("beginning the sequence"),
+            ..sequence_ctx()
+        })?;
         log::debug!(target: "FLASH", "[Sequence {}/{}] OK", i + 1, total_seqs);
 
         log::debug!(target: "FLASH", "[Sequence {}/{}] Transferring data", i + 1, total_seqs);
-        sequence::transfer(c, sp.max_file_part_size as usize, data, cb)?;
+        sequence::transfer(
+            c,
+            &mut part,
+            data,
+            sequence_len as usize,
+            timeouts.part_ack,
+            cb,
+        )
+        .context(sequence_ctx)?;
         log::debug!(target: "FLASH", "[Sequence {}/{}] OK", i + 1, total_seqs);
 
-        bytes_flashed += sequence.len();
-        let is_last_sequence = bytes_flashed >= data.len();
+        bytes_flashed += u64::from(sequence_len);
+        let is_last_sequence = bytes_flashed >= len;
         log::debug!(target: "FLASH", "[Sequence {}/{}] Ending transfer", i + 1, total_seqs);
         sequence::end(
             c,
@@ -60,7 +102,12 @@ pub(crate) fn flash(
             OdinInt::from(sequence_len),
             is_last_sequence,
             is_proto_v3plus,
-        )?;
+            timeouts.sequence_end,
+        )
+        .context(|| ErrorContext {
+            command: Some("ending the sequence"),
+            ..sequence_ctx()
+        })?;
         log::debug!(target: "FLASH", "[Sequence {}/{}] OK", i + 1, total_seqs);
     }
     log::info!(target: "FLASH", "Flash OK");
@@ -74,6 +121,9 @@ impl<T: Read + Seek> SeekableReader for T {}
 /// The top-level flash function.
 ///
 /// It calls `flash()` for each component of the Odin TAR file.
+/// The archive is checked against the PIT with `preflight()` first, and rejected as a whole if it doesn't fit.
+///
+/// Plain tar archives without Odin metadata are rejected unless `allow_unverified` is set.
 ///
 /// `cb` is a callback for e.g. displaying a progress bar.
 pub(crate) fn flash_odintar(
@@ -81,26 +131,26 @@ pub(crate) fn flash_odintar(
     sp: SessionParams,
     rdr: &mut dyn SeekableReader,
     pit: Pit,
+    allow_unverified: bool,
     // TODO: Make this filename-aware. For now, it's just called for each file in the archive.
     cb: &mut Option<&mut impl FnMut(u64)>,
 ) -> Result<()> {
     log::info!(target: "FLASH", "Flashing ODIN archive");
 
-    let mut archive = OdinTar::from_reader(rdr);
-    archive.validate().unwrap();
-    let mut archive = archive.archive();
-    let total = archive.entries()?.count();
-
-    // Flash each file in the archive separately, with the basename being the PIT partition.
-    for (i, entry) in archive.entries()?.enumerate() {
-        let mut entry = entry?;
-        let p = entry.path().unwrap();
-        log::info!(target: "FLASH", "[File {}/{}] Flashing file {}", i + 1, total, p.display());
-        let name = p.file_stem().unwrap().to_str().unwrap();
-        let pit_entry = pit.get_entry_by_name(name).unwrap();
-        let mut buf: Vec<u8> = Vec::with_capacity(entry.size().try_into().unwrap());
-        entry.read_to_end(&mut buf)?;
-        flash(c, sp, &buf, pit_entry, cb)?;
+    let (mut archive, report) = open_odintar(rdr, &pit, allow_unverified)?;
+    let total = report.matches.len();
+
+    for (i, m) in report.matches.into_iter().enumerate() {
+        log::info!(target: "FLASH", "[File {}/{}] Flashing file {} to partition {}", i + 1, total, m.entry.name, m.partition_name());
+        let file = || ErrorContext {
+            file: Some(m.entry.name.clone()),
+            ..Default::default()
+        };
+        let mut rdr = archive.entry_reader(&m.entry).context(|| ErrorContext {
+            command: Some("reading the file"),
+            ..file()
+        })?;
+        flash(c, sp, &mut rdr, m.entry.size, m.pit_entry, cb).context(file)?;
         log::info!(target: "FLASH", "[File {}/{}] OK", i + 1, total);
     }
 
@@ -109,84 +159,121 @@ pub(crate) fn flash_odintar(
     return Ok(());
 }
 
-fn div_up(a: usize, b: usize) -> usize {
-    (a + (b - 1)) / b
+/// Validate an Odin TAR archive and check it against the PIT with `preflight()`,
+/// so that nothing is sent unless every file has a partition to go to.
+///
+/// Plain tar archives without Odin metadata are rejected unless `allow_unverified` is set.
+pub(crate) fn open_odintar<R: Read + Seek>(
+    rdr: R,
+    pit: &Pit,
+    allow_unverified: bool,
+) -> Result<(OdinTar<R>, PreflightReport)> {
+    let mut archive = OdinTar::from_reader(rdr);
+    match archive.validate() {
+        Ok(()) => {}
+        Err(OdinTarError::Unverified) if allow_unverified => {
+            log::warn!(target: "FLASH", "Archive has no Odin metadata, flashing it unverified");
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let report = preflight(&mut archive, pit)?;
+    if !report.is_ok() {
+        return Err(Error::PreflightFailed(report));
+    }
+    return Ok((archive, report));
 }
 
 /// Tell the target how much data to expect in total.
 /// TODO: Make work for multiple files (requires reworking flash functionality to accept all at once)
 fn set_total_size(
     c: &mut Box<dyn Communicator>,
-    data: &[u8],
+    len: u64,
     supports_64bit_size: bool,
+    timeout: Duration,
 ) -> Result<()> {
     // TODO: Unclear whether proto version 0 supports this, might need to be conditional
     // FIXME: Might always be 64-bit compatible, need to check sometime w/ very old device"w
-    log::info!(target: "FLASH", "Telling target to expect {} bytes total", data.len());
+    log::info!(target: "FLASH", "Telling target to expect {} bytes total", len);
+    let deadline = Instant::now() + timeout;
+    total_size_packet(len, supports_64bit_size)?.send(c, deadline)?;
+
+    let resp = OdinCmdReply::read(c, deadline)?;
+    if resp.cmd != OdinCmd::SessionStart {
+        return Err(
+            DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::SessionStart, resp.cmd).into(),
+        );
+    }
+    return Ok(());
+}
+
+/// Build the command telling the target how much data to expect in total.
+pub(crate) fn total_size_packet(len: u64, supports_64bit_size: bool) -> Result<OdinCmdPacket> {
     let p: OdinCmdPacket = if supports_64bit_size {
         log::trace!(target: "FLASH", "Target supports 64-bit file sizes, sending that");
-        OdinCmdPacket::with_u64_arg(
-            OdinCmd::SessionStart,
-            OdinInt::from(SET_TOTAL_SIZE),
-            data.len().try_into()?,
-        )
+        OdinCmdPacket::with_u64_arg(OdinCmd::SessionStart, OdinInt::from(SET_TOTAL_SIZE), len)
     } else {
         log::trace!(target: "FLASH", "Target only supports 32-bit file sizes");
-        let len: u32 = data
-            .len()
-            .try_into()
-            .expect("File too large for 32-bit transfer");
+        let len: u32 = match len.try_into() {
+            Ok(len) => len,
+            Err(_) => return Err(DownloadProtocolError::FileTooLarge(len).into()),
+        };
         OdinCmdPacket::with_2_args(
             OdinCmd::SessionStart,
             OdinInt::from(SET_TOTAL_SIZE),
             OdinInt::from(len),
         )
     };
-    p.send(c)?;
-
-    let resp = OdinCmdReply::read(c)?;
-    if resp.cmd != OdinCmd::SessionStart {
-        return Err(
-            DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::SessionStart, resp.cmd).into(),
-        );
-    }
-    return Ok(());
+    return Ok(p);
 }
 
 /// Tell the target how much data to expect per part.
-fn set_file_part_size(c: &mut Box<dyn Communicator>, file_part_len: u32) -> Result<()> {
+fn set_file_part_size(
+    c: &mut Box<dyn Communicator>,
+    file_part_len: u32,
+    timeout: Duration,
+) -> Result<()> {
     log::debug!(target: "FLASH", "Telling target to expect {} bytes per file part", file_part_len);
-    let p = OdinCmdPacket::with_2_args(
-        OdinCmd::SessionStart,
-        OdinInt::from(SET_FILE_PART_SIZE),
-        OdinInt::from(file_part_len),
-    );
-    p.send(c)?;
+    let deadline = Instant::now() + timeout;
+    file_part_size_packet(file_part_len).send(c, deadline)?;
 
-    let resp = OdinCmdReply::read(c)?;
+    let resp = OdinCmdReply::read(c, deadline)?;
     if resp.cmd != OdinCmd::SessionStart {
         return Err(
             DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::SessionStart, resp.cmd).into(),
         );
     }
-    if resp.arg != OdinInt::from(0x00) {
+    if resp.arg != OdinInt::from(REPLY_OK) {
         return Err(
-            DownloadProtocolError::UnexpectedOdinCmdArg(OdinInt::from(0x00), resp.arg).into(),
+            DownloadProtocolError::UnexpectedOdinCmdArg(OdinInt::from(REPLY_OK), resp.arg).into(),
         );
     }
     return Ok(());
 }
 
+/// Build the command telling the target how much data to expect per part.
+pub(crate) fn file_part_size_packet(file_part_len: u32) -> OdinCmdPacket {
+    return OdinCmdPacket::with_2_args(
+        OdinCmd::SessionStart,
+        OdinInt::from(SET_FILE_PART_SIZE),
+        OdinInt::from(file_part_len),
+    );
+}
+
 /// Tell the target we'd like to start transferring.
-fn start(c: &mut Box<dyn Communicator>) -> Result<()> {
+fn start(c: &mut Box<dyn Communicator>, timeout: Duration) -> Result<()> {
     log::debug!(target: "FLASH", "Sending start sequence");
-    let p = OdinCmdPacket::with_1_arg(OdinCmd::Flash, OdinInt::from(FLASH_CMD_BEGIN_FLASH));
-    p.send(c)?;
+    let deadline = Instant::now() + timeout;
+    begin_flash_packet().send(c, deadline)?;
 
-    let resp = OdinCmdReply::read(c)?;
+    let resp = OdinCmdReply::read(c, deadline)?;
     if resp.cmd != OdinCmd::Flash {
         return Err(DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::Flash, resp.cmd).into());
     }
     log::debug!(target: "FLASH", "Start sequence sent OK");
     return Ok(());
 }
+
+/// Build the command telling the target we'd like to start transferring.
+pub(crate) fn begin_flash_packet() -> OdinCmdPacket {
+    return OdinCmdPacket::with_1_arg(OdinCmd::Flash, OdinInt::from(FLASH_CMD_BEGIN_FLASH));
+}
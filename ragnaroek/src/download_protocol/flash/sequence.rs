@@ -1,30 +1,29 @@
 use super::super::*;
+use crate::error::ResultExt;
 use crate::Communicator;
-use crate::Result;
+use crate::{ErrorContext, Result};
 use either::Either;
 use pit::*;
+use std::io::Read;
+use std::time::{Duration, Instant};
 
 // These values are correct for flashing without compression.
-const FLASH_CMD_SEQUENCE_BEGIN: u32 = 0x02;
-const FLASH_CMD_SEQUENCE_END: u32 = 0x03;
+pub(crate) const FLASH_CMD_SEQUENCE_BEGIN: u32 = 0x02;
+pub(crate) const FLASH_CMD_SEQUENCE_END: u32 = 0x03;
 
 /// Tell the target to expect a file sequence (series of packets making up part of the file).
-pub fn initiate(c: &mut Box<dyn Communicator>, len: u32) -> Result<()> {
+pub fn initiate(c: &mut Box<dyn Communicator>, len: u32, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
     // Tell target that we want to flash
-    let p = OdinCmdPacket::with_2_args(
-        OdinCmd::Flash,
-        OdinInt::from(FLASH_CMD_SEQUENCE_BEGIN),
-        OdinInt::from(len),
-    );
-    p.send(c)?;
+    sequence_begin_packet(len).send(c, deadline)?;
 
-    let resp = OdinCmdReply::read(c)?;
+    let resp = OdinCmdReply::read(c, deadline)?;
     if resp.cmd != OdinCmd::Flash {
         return Err(DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::Flash, resp.cmd).into());
     }
 
     // For USB, an empty bulk transfer is expected before the first packet
-    c.send(&[])?;
+    c.send_before(&[], deadline)?;
 
     return Ok(());
 }
@@ -38,7 +37,9 @@ fn send_part(
     file_part: &[u8],
     file_part_idx: OdinInt,
     is_last_part: bool,
+    timeout: Duration,
 ) -> Result<()> {
+    let deadline = Instant::now() + timeout;
     // This is not documented by samsung-loki, but Heimdall does this
     // TODO: Contribute doc, in case this turns out to be correct
     /*)
@@ -46,14 +47,14 @@ fn send_part(
         log::trace!(target: "FLASH", "[File part {}] Last part, not sending empty packet before data", file_part_idx);
     } else {
         log::trace!(target: "FLASH", "[File part {}] Sending empty packet before data", file_part_idx);
-        c.send(&[])?;
+        c.send_before(&[], deadline)?;
     }
     */
 
     log::trace!(target: "FLASH", "[File part {}] Transferring {} bytes", file_part_idx, file_part.len());
-    c.send(file_part)?;
+    c.send_before(file_part, deadline)?;
 
-    let resp = OdinCmdReply::read(c)?;
+    let resp = OdinCmdReply::read(c, deadline)?;
     if resp.cmd != OdinCmd::ChunkTransferOk {
         return Err(
             DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::ChunkTransferOk, resp.cmd).into(),
@@ -69,33 +70,42 @@ fn send_part(
     return Ok(());
 }
 
-/// Send an entire sequence of packets to the target.
+/// Send an entire sequence of packets to the target, reading its `sequence_len` bytes from `data`.
+///
+/// `part` is the buffer for a single file part, as large as the negotiated file part size.
 ///
 /// This should be called once per sequence, after `initiate`.
 pub fn transfer(
     c: &mut Box<dyn Communicator>,
-    max_file_part_size: usize,
-    sequence: &[u8],
+    part: &mut [u8],
+    data: &mut dyn Read,
+    sequence_len: usize,
+    part_timeout: Duration,
     cb: &mut Option<&mut impl FnMut(u64)>,
 ) -> Result<()> {
-    let total_parts = div_up(
-        OdinInt::from(sequence.len() as u32),
-        OdinInt::from(max_file_part_size as u32),
-    );
+    let total_parts: u32 = sequence_len.div_ceil(part.len()).try_into()?;
     log::debug!(target: "FLASH", "Total number of file parts in sequence: {}", total_parts);
-    for (part_idx, part) in sequence.chunks(max_file_part_size).enumerate() {
-        let part_idx: u32 = part_idx.try_into()?;
-        let is_last_part = (total_parts.inner - 1) == part_idx;
+    let mut left = sequence_len;
+    for part_idx in 0..total_parts {
+        let is_last_part = part_idx + 1 == total_parts;
+        let part_ctx = || ErrorContext {
+            part: Some(part_idx),
+            command: Some("sending the file part"),
+            ..Default::default()
+        };
+        let part_len = left.min(part.len());
+        data.read_exact(&mut part[..part_len])
+            .context(|| ErrorContext {
+                command: Some("reading the file part"),
+                ..part_ctx()
+            })?;
         // Last part might have to be padded for this to work
-        if is_last_part {
-            let mut part: Vec<u8> = Vec::from(part);
-            part.resize(max_file_part_size, 0);
-            send_part(c, &part, OdinInt::from(part_idx), is_last_part)?;
-        } else {
-            send_part(c, part, OdinInt::from(part_idx), is_last_part)?;
-        }
+        part[part_len..].fill(0);
+        send_part(c, part, OdinInt::from(part_idx), is_last_part, part_timeout)
+            .context(part_ctx)?;
+        left -= part_len;
         if let Some(cb) = cb {
-            cb(part.len() as u64);
+            cb(part_len as u64);
         }
     }
     return Ok(());
@@ -111,14 +121,50 @@ pub fn end(
     sequence_length_bytes: OdinInt,
     is_last_sequence: bool,
     is_proto_v3plus: bool,
+    timeout: Duration,
 ) -> Result<()> {
+    let deadline = Instant::now() + timeout;
     // It seems like this is only needed for older bootloaders (when flashing via USB)
     if !is_proto_v3plus {
         log::trace!(target: "FLASH", "Sending empty transfer before");
-        c.send(&[])?;
+        c.send_before(&[], deadline)?;
         log::trace!(target: "FLASH", "Empty transfer OK");
     }
 
+    log::trace!(target: "FLASH", "Sending end-of-transfer command");
+    end_packet(pit_entry, sequence_length_bytes, is_last_sequence).send(c, deadline)?;
+    log::trace!(target: "FLASH", "Sending end-of-transfer command OK");
+
+    let resp = OdinCmdReply::read(c, deadline)?;
+    if resp.cmd != OdinCmd::Flash {
+        return Err(DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::Flash, resp.cmd).into());
+    }
+
+    // For USB, an empty bulk transfer is expected after end (for older bootloaders)
+    if !is_proto_v3plus {
+        log::trace!(target: "FLASH", "Sending empty transfer after");
+        c.send_before(&[], deadline)?;
+        log::trace!(target: "FLASH", "Empty transfer OK");
+    }
+
+    return Ok(());
+}
+
+/// Build the command telling the target to expect a sequence of `len` bytes.
+pub(crate) fn sequence_begin_packet(len: u32) -> OdinCmdPacket {
+    return OdinCmdPacket::with_2_args(
+        OdinCmd::Flash,
+        OdinInt::from(FLASH_CMD_SEQUENCE_BEGIN),
+        OdinInt::from(len),
+    );
+}
+
+/// Build the command telling the target to write the sequence to the given partition.
+pub(crate) fn end_packet(
+    pit_entry: &Either<PitEntryV1, PitEntryV2>,
+    sequence_length_bytes: OdinInt,
+    is_last_sequence: bool,
+) -> OdinCmdPacket {
     // AP and modem packets are the same, except for the added partition ID field for AP
     let is_modem: bool;
     let device_type: u32;
@@ -158,28 +204,5 @@ pub fn end(
             OdinInt::from(is_last_sequence),
         )
     };
-
-    log::trace!(target: "FLASH", "Sending end-of-transfer command");
-    p.send(c)?;
-    log::trace!(target: "FLASH", "Sending end-of-transfer command OK");
-
-    let resp = OdinCmdReply::read(c)?;
-    if resp.cmd != OdinCmd::Flash {
-        return Err(DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::Flash, resp.cmd).into());
-    }
-
-    // For USB, an empty bulk transfer is expected after end (for older bootloaders)
-    if !is_proto_v3plus {
-        log::trace!(target: "FLASH", "Sending empty transfer after");
-        c.send(&[])?;
-        log::trace!(target: "FLASH", "Empty transfer OK");
-    }
-
-    return Ok(());
-}
-
-fn div_up(a: OdinInt, b: OdinInt) -> OdinInt {
-    let a: u32 = a.into();
-    let b: u32 = b.into();
-    return OdinInt::from((a + (b - 1)) / b);
+    return p;
 }
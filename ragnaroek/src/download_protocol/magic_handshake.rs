@@ -3,16 +3,21 @@ use crate::Result;
 
 use super::DownloadProtocolError;
 
-const PING: [u8; 4] = [b'O', b'D', b'I', b'N'];
-const PONG: [u8; 4] = [b'L', b'O', b'K', b'E'];
+use std::time::{Duration, Instant};
+
+/// What the host sends to start the handshake.
+pub(crate) const PING: [u8; 4] = [b'O', b'D', b'I', b'N'];
+/// What the target replies to `PING`.
+pub(crate) const PONG: [u8; 4] = [b'L', b'O', b'K', b'E'];
 
 /// This should be invoked on the `Communicator` before any other command.
-pub(crate) fn magic_handshake(c: &mut Box<dyn Communicator>) -> Result<()> {
+pub(crate) fn magic_handshake(c: &mut Box<dyn Communicator>, timeout: Duration) -> Result<()> {
     log::debug!(target: "DL", "Handshaking");
-    c.send(&PING)?;
+    let deadline = Instant::now() + timeout;
+    c.send_before(&PING, deadline)?;
     // Some Samsung devices (Gear Watch 4, maybe more) require an empty bulk transfer to be sent after for handshake to continue.
     // c.send(&PING)?;
-    let resp = c.recv_exact(PONG.len())?;
+    let resp = c.recv_exact_before(PONG.len(), deadline)?;
     if resp != PONG {
         return Err(DownloadProtocolError::InvalidMagicHandshake(resp).into());
     }
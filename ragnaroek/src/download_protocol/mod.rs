@@ -1,11 +1,31 @@
 //! This module is the core of the actual protocol implementation.
 
+#[cfg(feature = "async")]
+mod async_session;
 mod begin_session;
 mod download_pit;
+pub mod emulator;
 mod end_session;
 mod flash;
 mod flash_pit;
 mod magic_handshake;
+mod preflight;
 mod types;
 
+#[cfg(feature = "async")]
+pub use async_session::AsyncSession;
+pub use begin_session::{ProtoVersion, SessionParams, TimeoutPolicy};
+pub use preflight::*;
 pub use types::*;
+
+// Protocol constants, shared by the sessions, the emulator and the dissector
+pub(crate) use begin_session::{
+    BEGIN_SESSION, COMPRESSION_SUPPORTED, ERASE_USERDATA, SET_PACKET_SIZE, T_FLASH,
+    V1_MAX_FILE_PART_SIZE,
+};
+pub(crate) use download_pit::{PIT_CHUNK_SIZE, PIT_FLAG_CHUNK, PIT_FLAG_DUMP, PIT_FLAG_END};
+pub(crate) use flash::{
+    FLASH_CMD_BEGIN_FLASH, FLASH_CMD_SEQUENCE_BEGIN, FLASH_CMD_SEQUENCE_END, SET_TOTAL_SIZE,
+};
+pub(crate) use flash_pit::{PIT_FLAG_FLASH, PIT_RECEIVED};
+pub(crate) use magic_handshake::{PING, PONG};
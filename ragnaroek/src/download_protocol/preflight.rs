@@ -0,0 +1,234 @@
+//! Checks whether an Odin archive fits a target's partitioning before anything is sent to it.
+
+use std::io::{Read, Seek};
+
+use either::Either;
+use odintar::{Compression, IndexEntry, OdinTar};
+use pit::{Pit, PitDeviceType, PitEntryV1, PitEntryV2};
+
+use crate::Result;
+
+/// Size of a block on the storage device, which PIT block counts refer to.
+const EMMC_BLOCK_SIZE: u64 = 512;
+const UFS_BLOCK_SIZE: u64 = 4096;
+
+/// Length of an LZ4 frame header that includes the content size.
+const LZ4_HEADER_LEN: u64 = 14;
+
+/// An archive entry and the partition it would be flashed to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionMatch {
+    /// The archive entry.
+    pub entry: IndexEntry,
+    /// The PIT entry describing the partition the archive entry would be flashed to.
+    pub pit_entry: Either<PitEntryV1, PitEntryV2>,
+    /// Size the image takes up on the target.
+    ///
+    /// For compressed entries whose frames don't record their uncompressed size,
+    /// this is the compressed size and thus only a lower bound.
+    pub image_size: u64,
+    /// Size of the partition, if the PIT states it.
+    pub capacity: Option<u64>,
+}
+
+impl PartitionMatch {
+    /// Name of the partition, as given in the PIT.
+    pub fn partition_name(&self) -> &str {
+        match &self.pit_entry {
+            Either::Left(e) => return &e.partition_name,
+            Either::Right(e) => return &e.partition_name,
+        }
+    }
+
+    /// Returns whether the image is larger than the partition it would be flashed to.
+    pub fn overfills(&self) -> bool {
+        return self.capacity.is_some_and(|c| self.image_size > c);
+    }
+}
+
+/// Result of checking an archive against a PIT.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PreflightReport {
+    /// Archive entries that have a partition, in archive order.
+    pub matches: Vec<PartitionMatch>,
+    /// Archive entries that no partition could be found for.
+    pub unmatched: Vec<IndexEntry>,
+}
+
+impl PreflightReport {
+    /// Matches whose image is larger than the partition.
+    pub fn overfilled(&self) -> impl Iterator<Item = &PartitionMatch> {
+        return self.matches.iter().filter(|m| m.overfills());
+    }
+
+    /// Returns whether every entry has a partition it fits into.
+    pub fn is_ok(&self) -> bool {
+        return self.unmatched.is_empty() && self.overfilled().next().is_none();
+    }
+}
+
+/// Match every entry of the archive against the given PIT.
+///
+/// Entries are matched by the PIT's flash filename first, with the compression extension stripped,
+/// so `boot.img.lz4` matches the partition whose flash filename is `boot.img`.
+/// If that fails, the part of the entry name before the first dot is matched against partition names.
+///
+/// Only the archive's headers and the first bytes of compressed entries are read.
+pub fn preflight<R: Read + Seek>(archive: &mut OdinTar<R>, pit: &Pit) -> Result<PreflightReport> {
+    let index = archive.index()?;
+    let pit_entries = pit.entries();
+    let mut report = PreflightReport::default();
+
+    for entry in index.entries() {
+        let pit_entry = match find_pit_entry(&pit_entries, entry) {
+            Some(pit_entry) => pit_entry.clone(),
+            None => {
+                log::warn!(target: "PREFLIGHT", "No partition found for {}", entry.name);
+                report.unmatched.push(entry.clone());
+                continue;
+            }
+        };
+
+        let image_size = match entry.compression {
+            Compression::Lz4 => {
+                let mut header: Vec<u8> = Vec::new();
+                archive
+                    .entry_reader(entry)?
+                    .take(LZ4_HEADER_LEN)
+                    .read_to_end(&mut header)?;
+                odintar::lz4_content_size(&header).unwrap_or(entry.size)
+            }
+            Compression::None => entry.size,
+        };
+
+        let m = PartitionMatch {
+            entry: entry.clone(),
+            capacity: capacity(&pit_entry),
+            pit_entry,
+            image_size,
+        };
+        if m.overfills() {
+            log::warn!(target: "PREFLIGHT", "{} ({} bytes) overfills partition {} ({} bytes)", entry.name, m.image_size, m.partition_name(), m.capacity.unwrap_or(0));
+        } else {
+            log::debug!(target: "PREFLIGHT", "{} matches partition {}", entry.name, m.partition_name());
+        }
+        report.matches.push(m);
+    }
+
+    return Ok(report);
+}
+
+/// Find the PIT entry for the given archive entry.
+fn find_pit_entry<'a>(
+    pit_entries: &'a [Either<PitEntryV1, PitEntryV2>],
+    entry: &IndexEntry,
+) -> Option<&'a Either<PitEntryV1, PitEntryV2>> {
+    let file_name = entry.uncompressed_name();
+    let by_file_name = pit_entries.iter().find(|e| {
+        let flash_filename = match e {
+            Either::Left(e) => &e.flash_filename,
+            Either::Right(e) => &e.flash_filename,
+        };
+        !flash_filename.is_empty() && flash_filename.eq_ignore_ascii_case(file_name)
+    });
+    if by_file_name.is_some() {
+        return by_file_name;
+    }
+
+    let base_name = file_name.split('.').next().unwrap_or(file_name);
+    return pit_entries.iter().find(|e| {
+        let partition_name = match e {
+            Either::Left(e) => &e.partition_name,
+            Either::Right(e) => &e.partition_name,
+        };
+        partition_name.eq_ignore_ascii_case(base_name)
+    });
+}
+
+/// Size of a block on the given kind of storage device in bytes.
+pub(crate) fn block_size(device_type: PitDeviceType) -> u64 {
+    match device_type {
+        PitDeviceType::Ufs => return UFS_BLOCK_SIZE,
+        _ => return EMMC_BLOCK_SIZE,
+    }
+}
+
+/// Size of the partition described by the PIT entry in bytes, if known.
+pub(crate) fn capacity(pit_entry: &Either<PitEntryV1, PitEntryV2>) -> Option<u64> {
+    let (device_type, block_count) = match pit_entry {
+        Either::Left(e) => (e.pit_device_type, e.block_count),
+        Either::Right(e) => (e.pit_device_type, e.block_num),
+    };
+    // Partitions that take up the rest of the device have no size in the PIT
+    if block_count == 0 {
+        return None;
+    }
+    return Some(u64::from(block_count) * block_size(device_type));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+
+    const ARCHIVE: &str =
+        "../odintar/testdata/BL_A405FNXXU4CVK1_CL25488227_QB58944467_REV00_user_low_ship.tar.md5";
+    const PIT: &str = "../pit/testdata/A40_EUR_OPEN.pit";
+
+    #[test]
+    fn test_preflight() {
+        let pit = Pit::deserialize(&std::fs::read(PIT).unwrap()).unwrap();
+        let mut archive = OdinTar::from_reader(File::open(ARCHIVE).unwrap());
+
+        let report = preflight(&mut archive, &pit).unwrap();
+        assert!(report.is_ok());
+        let partitions: Vec<&str> = report.matches.iter().map(|m| m.partition_name()).collect();
+        assert_eq!(vec!["BOOTLOADER", "PARAM", "CM", "VBMETA"], partitions);
+    }
+
+    #[test]
+    fn test_preflight_unmatched() {
+        use odintar::{ArchiveEdit, NewContents};
+        use std::io::Cursor;
+
+        let pit = Pit::deserialize(&std::fs::read(PIT).unwrap()).unwrap();
+        let mut edit = ArchiveEdit::new();
+        edit.add(
+            "nonexistent.bin",
+            NewContents::new(Cursor::new(vec![0; 100]), false),
+        );
+        let mut out: Vec<u8> = Vec::new();
+        OdinTar::from_reader(File::open(ARCHIVE).unwrap())
+            .rewrite(edit, &mut out)
+            .unwrap();
+        let mut archive = OdinTar::from_reader(Cursor::new(out));
+
+        let report = preflight(&mut archive, &pit).unwrap();
+        assert!(!report.is_ok());
+        let unmatched: Vec<&str> = report.unmatched.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(vec!["nonexistent.bin"], unmatched);
+        assert_eq!(4, report.matches.len());
+        assert_eq!(0, report.overfilled().count());
+    }
+
+    #[test]
+    fn test_preflight_overfilled() {
+        // Shrink the CM partition to a single block
+        let mut pit_data = std::fs::read(PIT).unwrap();
+        let name_offset = pit_data.windows(3).position(|w| w == b"CM\0").unwrap();
+        // The name follows nine 32-bit fields, the block count is the seventh
+        let block_count_offset = name_offset - 36 + 24;
+        pit_data[block_count_offset..block_count_offset + 4].copy_from_slice(&1u32.to_le_bytes());
+        let pit = Pit::deserialize(&pit_data).unwrap();
+        let mut archive = OdinTar::from_reader(File::open(ARCHIVE).unwrap());
+
+        let report = preflight(&mut archive, &pit).unwrap();
+        assert!(!report.is_ok());
+        assert!(report.unmatched.is_empty());
+        let overfilled: Vec<&str> = report.overfilled().map(|m| m.partition_name()).collect();
+        assert_eq!(vec!["CM"], overfilled);
+        let cm = report.overfilled().next().unwrap();
+        assert_eq!(Some(EMMC_BLOCK_SIZE), cm.capacity);
+        assert!(cm.image_size > EMMC_BLOCK_SIZE);
+    }
+}
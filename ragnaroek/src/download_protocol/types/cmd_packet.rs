@@ -3,14 +3,15 @@ use super::*;
 use crate::{Communicator, Result};
 
 use core::fmt;
+use std::time::Instant;
 
 /// Seems like all Odin command packets are exactly 1024 bytes long
-const CMD_PACKET_LEN: usize = 1024;
+pub(crate) const CMD_PACKET_LEN: usize = 1024;
 
 /// Structure of all command packets.
 /// These are always sent flasher -> target.
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct OdinCmdPacket {
+pub struct OdinCmdPacket {
     cmd: OdinCmd,
     arg1: OdinInt,
     arg2: Option<OdinInt>,
@@ -36,12 +37,14 @@ impl OdinCmdPacket {
         };
     }
 
+    /// Construct a packet with two arguments.
     pub fn with_2_args(kind: OdinCmd, arg1: OdinInt, arg2: OdinInt) -> OdinCmdPacket {
         let mut p = OdinCmdPacket::with_1_arg(kind, arg1);
         p.arg2 = Some(arg2);
         return p;
     }
 
+    /// Construct a packet with three arguments.
     pub fn with_3_args(
         kind: OdinCmd,
         arg1: OdinInt,
@@ -53,6 +56,7 @@ impl OdinCmdPacket {
         return p;
     }
 
+    /// Construct a packet with four arguments.
     pub fn with_4_args(
         kind: OdinCmd,
         arg1: OdinInt,
@@ -65,6 +69,7 @@ impl OdinCmdPacket {
         return p;
     }
 
+    /// Construct a packet with five arguments.
     pub fn with_5_args(
         kind: OdinCmd,
         arg1: OdinInt,
@@ -78,6 +83,7 @@ impl OdinCmdPacket {
         return p;
     }
 
+    /// Construct a packet with six arguments.
     pub fn with_6_args(
         kind: OdinCmd,
         arg1: OdinInt,
@@ -92,6 +98,7 @@ impl OdinCmdPacket {
         return p;
     }
 
+    /// Construct a packet with seven arguments.
     #[allow(clippy::too_many_arguments)]
     pub fn with_7_args(
         kind: OdinCmd,
@@ -120,8 +127,54 @@ impl OdinCmdPacket {
         return OdinCmdPacket::with_3_args(kind, arg1, first.into(), second.into());
     }
 
-    /// Send the constructed packet in the proper format over the given `Communicator`.
-    pub(crate) fn send(&self, comm: &mut Box<dyn Communicator>) -> Result<()> {
+    /// Parse a packet in its wire format.
+    ///
+    /// The wire format doesn't tell which arguments are in use, so trailing zero arguments are dropped.
+    /// Missing bytes, such as in truncated captures, are treated as zeros.
+    pub fn from_wire(data: &[u8]) -> Result<OdinCmdPacket> {
+        let int_at = |i: usize| -> OdinInt {
+            let mut buf: [u8; 4] = [0; 4];
+            for (j, b) in buf.iter_mut().enumerate() {
+                *b = data.get(i * 4 + j).copied().unwrap_or(0);
+            }
+            return OdinInt::from_wire(buf);
+        };
+        let cmd: OdinCmd = int_at(0).try_into()?;
+        let args: Vec<OdinInt> = (1..8).map(int_at).collect();
+        let used = args.iter().rposition(|a| a.inner != 0).map_or(1, |i| i + 1);
+        let arg = |i: usize| -> Option<OdinInt> { return (i < used).then_some(args[i]) };
+        return Ok(OdinCmdPacket {
+            cmd,
+            arg1: args[0],
+            arg2: arg(1),
+            arg3: arg(2),
+            arg4: arg(3),
+            arg5: arg(4),
+            arg6: arg(5),
+            arg7: arg(6),
+        });
+    }
+
+    /// The command this packet carries.
+    pub fn cmd(&self) -> OdinCmd {
+        return self.cmd;
+    }
+
+    /// The arguments in use, starting with the first.
+    pub fn args(&self) -> Vec<OdinInt> {
+        let mut args: Vec<OdinInt> = vec![self.arg1];
+        args.extend(
+            [
+                self.arg2, self.arg3, self.arg4, self.arg5, self.arg6, self.arg7,
+            ]
+            .into_iter()
+            .map_while(|a| a),
+        );
+        return args;
+    }
+
+    /// Serialize the packet into the format sent to the target, padded to the packet size.
+    pub(crate) fn to_wire(&self) -> Vec<u8> {
         let mut buf: Vec<u8> = Vec::with_capacity(CMD_PACKET_LEN);
 
         let cmd_int: OdinInt = self.cmd.into();
@@ -149,9 +202,13 @@ impl OdinCmdPacket {
 
         // Has to be padded to minimum packet size
         buf.resize(CMD_PACKET_LEN, 0x00);
+        return buf;
+    }
 
+    /// Send the constructed packet in the proper format over the given `Communicator`, before the deadline.
+    pub(crate) fn send(&self, comm: &mut Box<dyn Communicator>, deadline: Instant) -> Result<()> {
         log::trace!(target: "CMD", "{}", self);
-        match comm.send(&buf) {
+        match comm.send_before(&self.to_wire(), deadline) {
             Ok(_) => Ok(()),
             Err(e) => Err(e.into()),
         }
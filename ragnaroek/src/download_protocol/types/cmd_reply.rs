@@ -2,43 +2,58 @@ use super::*;
 use crate::{Communicator, Result};
 
 use core::fmt;
+use std::time::Instant;
+
+/// Target replies to commands are always this long.
+pub(crate) const CMD_REPLY_LEN: usize = 8;
+/// Reply argument signalling success, for the commands that report one.
+pub(crate) const REPLY_OK: u32 = 0x00;
 
 /// Structure of the target's 8-byte reply to some of the command packets.
-#[derive(Debug, Clone, Copy)]
-pub(crate) struct OdinCmdReply {
-    pub(crate) cmd: OdinCmd,
-    pub(crate) arg: OdinInt,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OdinCmdReply {
+    /// Command the target replies to.
+    pub cmd: OdinCmd,
+    /// Result of the command, its meaning depends on the command.
+    pub arg: OdinInt,
 }
 
 impl OdinCmdReply {
     /// Read the reply from the given `Communicator`.
-    /// Blocks until the complete reply could be read.
-    pub(crate) fn read(c: &mut Box<dyn Communicator>) -> Result<OdinCmdReply> {
-        let buf = c.recv_exact(8)?;
-
-        // TODO: DRY
-        let mut cmd_buf: [u8; 4] = [0; 4];
-        cmd_buf[0] = buf[0];
-        cmd_buf[1] = buf[1];
-        cmd_buf[2] = buf[2];
-        cmd_buf[3] = buf[3];
-        let cmd_int = OdinInt::from_wire(cmd_buf);
-        let cmd: OdinCmd = cmd_int
-            .try_into()
-            .expect("Target returned unknown Odin command");
-
-        // TODO: DRY
-        let mut arg_buf: [u8; 4] = [0; 4];
-        arg_buf[0] = buf[4];
-        arg_buf[1] = buf[5];
-        arg_buf[2] = buf[6];
-        arg_buf[3] = buf[7];
-        let arg = OdinInt::from_wire(arg_buf);
-
-        let reply = OdinCmdReply { cmd, arg };
+    /// Blocks until the complete reply could be read or the deadline passes.
+    pub(crate) fn read(c: &mut Box<dyn Communicator>, deadline: Instant) -> Result<OdinCmdReply> {
+        let reply = OdinCmdReply::from_received(c.recv_exact_before(CMD_REPLY_LEN, deadline)?)?;
         log::trace!(target: "CMD", "{}", reply);
         return Ok(reply);
     }
+
+    /// Parse a reply as received, which may be shorter than 8 bytes if the target misbehaves.
+    pub(crate) fn from_received(buf: Vec<u8>) -> Result<OdinCmdReply> {
+        let buf: [u8; CMD_REPLY_LEN] = match buf.try_into() {
+            Ok(buf) => buf,
+            Err(buf) => return Err(DownloadProtocolError::ShortRead(CMD_REPLY_LEN, buf).into()),
+        };
+        return OdinCmdReply::from_wire(buf);
+    }
+
+    /// Parse a reply in its wire format.
+    pub fn from_wire(buf: [u8; CMD_REPLY_LEN]) -> Result<OdinCmdReply> {
+        let cmd_int = OdinInt::from_wire([buf[0], buf[1], buf[2], buf[3]]);
+        let cmd: OdinCmd = match cmd_int.try_into() {
+            Ok(cmd) => cmd,
+            Err(_) => return Err(DownloadProtocolError::InvalidReply(buf.to_vec()).into()),
+        };
+        let arg = OdinInt::from_wire([buf[4], buf[5], buf[6], buf[7]]);
+        return Ok(OdinCmdReply { cmd, arg });
+    }
+
+    /// Serialize the reply into the format sent by the target.
+    pub(crate) fn to_wire(self) -> [u8; CMD_REPLY_LEN] {
+        let mut buf: [u8; CMD_REPLY_LEN] = [0; CMD_REPLY_LEN];
+        buf[..4].copy_from_slice(&OdinInt::from(self.cmd).to_wire());
+        buf[4..].copy_from_slice(&self.arg.to_wire());
+        return buf;
+    }
 }
 
 impl fmt::Display for OdinCmdReply {
@@ -1,5 +1,7 @@
 use super::{OdinCmd, OdinInt};
 
+use core::fmt;
+
 /// Error type returned when the protocol is violated.
 #[derive(Debug, Clone, PartialEq)]
 pub enum DownloadProtocolError {
@@ -27,6 +29,90 @@ pub enum DownloadProtocolError {
     ///
     /// The arguments are the expected packet number and the actual packet number.
     UnexpectedFlashPacket(OdinInt, OdinInt),
+    /// Host sent a command an emulated target doesn't know.
+    ///
+    /// The arguments are the command and its first argument, which usually selects the operation.
+    UnknownHostCmd(OdinCmd, OdinInt),
     /// The downloaded PIT file is invalid.
     InvalidPitFile(pit::PitError),
+    /// Target sent fewer bytes than expected.
+    ///
+    /// The arguments are the expected length and the data that arrived.
+    ShortRead(usize, Vec<u8>),
+    /// Target sent a reply with an unknown OdinCmd identifier.
+    ///
+    /// The argument is the raw reply.
+    InvalidReply(Vec<u8>),
+    /// Target replied to the session start with a version that doesn't make sense.
+    ///
+    /// The argument is the raw reply argument.
+    BogusProtoVersion(OdinInt),
+    /// Target announced a PIT larger than any real one, which is most likely garbage.
+    ///
+    /// The argument is the announced size.
+    PitTooLarge(OdinInt),
+    /// File is too large for the target's protocol version, which only supports 32-bit sizes.
+    ///
+    /// The argument is the file size.
+    FileTooLarge(u64),
+}
+
+impl fmt::Display for DownloadProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DownloadProtocolError::*;
+        match self {
+            UnknownProtoVersion(v) => write!(f, "target speaks unknown protocol version {v}"),
+            InvalidOdinCmd(cmd) => write!(f, "invalid command 0x{cmd:X}"),
+            UnexpectedOdinCmdArg(expected, actual) => write!(
+                f,
+                "target replied with argument 0x{actual:X}, expected 0x{expected:X}"
+            ),
+            UnexpectedOdinCmd(expected, actual) => write!(
+                f,
+                "target replied to command {actual:?}, expected {expected:?}"
+            ),
+            InvalidMagicHandshake(reply) => {
+                write!(f, "target replied to the handshake with {reply:02X?}")
+            }
+            ReportedPacketFlashFailure => write!(f, "target reported a failure to flash a part"),
+            UnexpectedFlashPacket(expected, actual) => {
+                write!(f, "target acked part {actual}, expected {expected}")
+            }
+            UnknownHostCmd(cmd, arg) => {
+                write!(
+                    f,
+                    "host sent unknown command {cmd:?} with argument 0x{arg:X}"
+                )
+            }
+            InvalidPitFile(_) => write!(f, "target sent an invalid PIT"),
+            ShortRead(expected, data) => write!(
+                f,
+                "target sent {} bytes, expected {expected}: {data:02X?}",
+                data.len()
+            ),
+            InvalidReply(reply) => {
+                write!(
+                    f,
+                    "target sent a reply with an unknown command: {reply:02X?}"
+                )
+            }
+            BogusProtoVersion(arg) => {
+                write!(f, "target replied with bogus protocol version 0x{arg:X}")
+            }
+            PitTooLarge(len) => write!(f, "target announced a PIT of {len} bytes"),
+            FileTooLarge(len) => write!(
+                f,
+                "file of {len} bytes is too large for the target's protocol version"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DownloadProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DownloadProtocolError::InvalidPitFile(e) => Some(e),
+            _ => None,
+        }
+    }
 }
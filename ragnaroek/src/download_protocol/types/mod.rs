@@ -6,8 +6,8 @@ mod odin_int;
 mod session;
 
 pub use cmd::*;
-pub(crate) use cmd_packet::*;
-pub(crate) use cmd_reply::*;
+pub use cmd_packet::*;
+pub use cmd_reply::*;
 pub use error::*;
 pub use odin_int::*;
 pub use session::*;
@@ -6,13 +6,15 @@ use super::super::download_pit::*;
 pub use super::super::end_session::ActionAfter;
 use super::super::end_session::*;
 use super::super::flash::*;
+use super::super::flash_pit::*;
 use super::super::magic_handshake::*;
 use crate::download_protocol::*;
-use crate::Communicator;
+use crate::error::ResultExt;
 use crate::Result;
+use crate::{Communicator, ErrorContext, Metrics, MetricsHandle};
 
-const BEGIN_SESSION: u32 = 0x00;
-const T_FLASH: u32 = 0x08;
+use std::io::Read;
+use std::time::Instant;
 
 /// This module's main type.
 /// Manages the communications lifecycle with the target for the download protocol.
@@ -20,6 +22,7 @@ pub struct Session {
     c: Box<dyn Communicator>,
     /// Session parameters, such as sizes of various transfers and the protocol version.
     pub params: SessionParams,
+    metrics: Option<MetricsHandle>,
 }
 
 // The actual logic is much too complex to include it here.
@@ -28,24 +31,53 @@ impl Session {
     /// Create a new `Session` and negotiate connection parameters with the target.
     /// Consumes the `Communicator` to enforce exclusive access.
     /// If the `Communicator` has been used to send data to the target before, the behavior of target is undefined.
-    pub fn begin(mut c: Box<dyn Communicator>) -> Result<Self> {
-        magic_handshake(&mut c)?;
-        let params = begin_session(&mut c)?;
-        return Ok(Session { c, params });
+    pub fn begin(c: Box<dyn Communicator>) -> Result<Self> {
+        return Session::begin_inner(c, None);
+    }
+
+    /// Like `begin`, but with the given timeouts instead of the defaults for the target's protocol version.
+    ///
+    /// To override only some of them, change `params.timeouts` after `begin` instead.
+    /// The handshake is over by then, though.
+    pub fn begin_with_timeouts(c: Box<dyn Communicator>, timeouts: TimeoutPolicy) -> Result<Self> {
+        return Session::begin_inner(c, Some(timeouts));
+    }
+
+    fn begin_inner(mut c: Box<dyn Communicator>, timeouts: Option<TimeoutPolicy>) -> Result<Self> {
+        let handshake = timeouts.map_or(HANDSHAKE_TIMEOUT, |t| t.handshake);
+        magic_handshake(&mut c, handshake).context(|| ErrorContext::command("handshaking"))?;
+        let params = begin_session(&mut c, timeouts)
+            .context(|| ErrorContext::command("beginning the session"))?;
+        return Ok(Session {
+            c,
+            params,
+            metrics: None,
+        });
+    }
+
+    /// Like `begin`, but measures all traffic with the target, including the handshake. See `metrics`.
+    pub fn begin_with_metrics(c: Box<dyn Communicator>) -> Result<Self> {
+        let m = Metrics::new(c);
+        let handle = m.handle();
+        let mut sess = Session::begin(Box::new(m))?;
+        sess.metrics = Some(handle);
+        return Ok(sess);
+    }
+
+    /// Handle to the traffic measurements, if the `Session` was started with `begin_with_metrics`.
+    /// The handle stays usable after the `Session` ended.
+    pub fn metrics(&self) -> Option<MetricsHandle> {
+        return self.metrics.clone();
     }
 
     /// Enter T-Flash download mode (write to microSD card).
     /// Call this after `begin` and before `end`.
     pub fn enable_tflash(&mut self) -> Result<()> {
         log::debug!(target: "SESS", "Enabling T-Flash mode");
-        let p = OdinCmdPacket::with_2_args(
-            OdinCmd::SessionStart,
-            OdinInt::from(BEGIN_SESSION),
-            OdinInt::from(T_FLASH),
-        );
-        p.send(&mut self.c)?;
+        let deadline = Instant::now() + self.params.timeouts.command;
+        tflash_packet().send(&mut self.c, deadline)?;
 
-        let resp = OdinCmdReply::read(&mut self.c)?;
+        let resp = OdinCmdReply::read(&mut self.c, deadline)?;
         if resp.cmd != OdinCmd::SessionStart {
             return Err(
                 DownloadProtocolError::UnexpectedOdinCmd(OdinCmd::SessionStart, resp.cmd).into(),
@@ -53,10 +85,12 @@ impl Session {
         }
 
         // Anything other than 0x00 indicates failure.
-        if resp.arg != OdinInt::from(0x00) {
-            return Err(
-                DownloadProtocolError::UnexpectedOdinCmdArg(OdinInt::from(0x00), resp.arg).into(),
-            );
+        if resp.arg != OdinInt::from(REPLY_OK) {
+            return Err(DownloadProtocolError::UnexpectedOdinCmdArg(
+                OdinInt::from(REPLY_OK),
+                resp.arg,
+            )
+            .into());
         }
         log::debug!(target: "SESS", "T-Flash mode enabled");
         return Ok(());
@@ -64,13 +98,21 @@ impl Session {
 
     /// End the `Session` and do cleanup.
     pub fn end(mut self, after: ActionAfter) -> Result<()> {
-        end_session(&mut self.c, after)?;
+        end_session(&mut self.c, after, self.params.timeouts.command)
+            .context(|| ErrorContext::command("ending the session"))?;
         return Ok(());
     }
 
     /// Download partitioning data from the target. Does not parse or validate the data.
     pub fn download_pit(&mut self, p: SessionParams) -> Result<Vec<u8>> {
-        return download_pit(&mut self.c, p);
+        return download_pit(&mut self.c, p)
+            .context(|| ErrorContext::command("downloading the PIT"));
+    }
+
+    /// Upload partitioning data to the target, replacing its PIT. Does not parse or validate the data.
+    pub fn flash_pit(&mut self, pit: &[u8]) -> Result<()> {
+        return flash_pit(&mut self.c, self.params, pit)
+            .context(|| ErrorContext::command("flashing the PIT"));
     }
 
     /// Flash a file to the target.
@@ -82,26 +124,216 @@ impl Session {
         pit_entry: Either<PitEntryV1, PitEntryV2>,
         cb: &mut Option<&mut impl FnMut(u64)>,
     ) -> Result<()> {
-        return flash(&mut self.c, self.params, data, pit_entry, cb);
+        return self.flash_from_reader(&mut &data[..], data.len() as u64, pit_entry, cb);
+    }
+
+    /// Flash a file of `len` bytes, read from `data`, to the target.
+    ///
+    /// Only one file part is held in memory at a time, so files larger than the host's memory can be flashed.
+    ///
+    /// `cb` is an optional callback, called after each file part is transferred with the number of bytes transferred since the last call.
+    pub fn flash_from_reader(
+        &mut self,
+        data: &mut dyn Read,
+        len: u64,
+        pit_entry: Either<PitEntryV1, PitEntryV2>,
+        cb: &mut Option<&mut impl FnMut(u64)>,
+    ) -> Result<()> {
+        return flash(&mut self.c, self.params, data, len, pit_entry, cb);
     }
 
     /// The top-level flash function.
     ///
     /// It calls `flash()` for each component of the Odin TAR file.
     ///
+    /// Plain tar archives without Odin metadata can't be verified and are rejected,
+    /// unless `allow_unverified` is set.
+    ///
     /// `cb` is a callback for e.g. displaying a progress bar.
     pub fn flash_odintar(
         &mut self,
         rdr: &mut dyn SeekableReader,
         pit: Pit,
+        allow_unverified: bool,
         // TODO: Make this filename-aware. For now, it's just called for each file in the archive.
         cb: &mut Option<&mut impl FnMut(u64)>,
     ) -> Result<()> {
-        return flash_odintar(&mut self.c, self.params, rdr, pit, cb);
+        return flash_odintar(&mut self.c, self.params, rdr, pit, allow_unverified, cb);
     }
 
     /// Factory reset user data on the target.
     pub fn factory_reset(&mut self) -> Result<()> {
-        return factory_reset(&mut self.c);
+        return factory_reset(&mut self.c, self.params.timeouts.erase)
+            .context(|| ErrorContext::command("erasing userdata"));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::fault::{Fault, FaultPlan, Injector, Trigger};
+    use crate::comms::pipe::Connection as Pipe;
+    use crate::download_protocol::emulator::{spawn_target, TargetOptions};
+    use crate::error::{Error, TransferError};
+
+    use std::time::Duration;
+
+    /// Begin a session with a target that answers with the given replies, then download the PIT.
+    fn run_with_replies(replies: &[[u8; 8]], plan: FaultPlan) -> DownloadProtocolError {
+        let (host, mut target) = Pipe::pair();
+        target.send(b"LOKE").unwrap();
+        for reply in replies {
+            target.send(reply).unwrap();
+        }
+        let mut c: Box<dyn Communicator> = Box::new(Injector::new(Box::new(host), plan));
+        c.set_timeout(Duration::from_millis(200));
+        let result = Session::begin(c).and_then(|mut sess| sess.download_pit(sess.params));
+        match result.as_ref().map_err(Error::without_context) {
+            Err(Error::TransferError(TransferError::DownloadProtocol(e))) => return e.clone(),
+            other => panic!("Expected a protocol error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_malformed_replies() {
+        // Protocol version 1, which skips packet size negotiation
+        let v1: [u8; 8] = [0x64, 0, 0, 0, 0, 0, 0, 0];
+
+        let unknown_cmd: [u8; 8] = [0x99, 0, 0, 0, 1, 2, 3, 4];
+        assert_eq!(
+            DownloadProtocolError::InvalidReply(unknown_cmd.to_vec()),
+            run_with_replies(&[unknown_cmd], FaultPlan::default())
+        );
+
+        let truncated = FaultPlan {
+            scheduled: vec![(Trigger::Reply(0), Fault::Truncate { len: 3 })],
+            ..Default::default()
+        };
+        assert_eq!(
+            DownloadProtocolError::ShortRead(8, v1[..3].to_vec()),
+            run_with_replies(&[v1], truncated)
+        );
+
+        let huge_pit: [u8; 8] = [0x65, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(
+            DownloadProtocolError::PitTooLarge(OdinInt::from(u32::MAX)),
+            run_with_replies(&[v1, huge_pit], FaultPlan::default())
+        );
+
+        let short_pit: [u8; 8] = [0x65, 0, 0, 0, 0x10, 0, 0, 0];
+        let empty_chunk = FaultPlan {
+            scheduled: vec![(Trigger::Recv(3), Fault::ZeroLength)],
+            ..Default::default()
+        };
+        assert_eq!(
+            DownloadProtocolError::ShortRead(0x10, Vec::new()),
+            run_with_replies(&[v1, short_pit], empty_chunk)
+        );
+    }
+
+    #[test]
+    fn test_error_context() {
+        const PIT: &str = "../pit/testdata/A40_EUR_OPEN.pit";
+        let pit = pit::Pit::deserialize(&std::fs::read(PIT).unwrap()).unwrap();
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+
+        // Replies: begin session, packet size, total size, file part size, start, sequence begin, first part
+        let plan = FaultPlan {
+            scheduled: vec![(
+                Trigger::Reply(6),
+                Fault::Corrupt {
+                    offset: 4,
+                    mask: 0x01,
+                },
+            )],
+            ..Default::default()
+        };
+        let c: Box<dyn Communicator> = Box::new(Injector::new(Box::new(host), plan));
+        let mut sess = Session::begin(c).unwrap();
+        let e = sess
+            .flash(
+                &[0x5A; 1000],
+                pit.get_entry_by_name("CM").unwrap(),
+                &mut None::<&mut fn(u64)>,
+            )
+            .unwrap_err();
+        drop(sess);
+        // The target fails along with the host
+        let _ = target.join().unwrap();
+
+        let mut chain: Vec<String> = vec![e.to_string()];
+        let mut source = std::error::Error::source(&e);
+        while let Some(s) = source {
+            chain.push(s.to_string());
+            source = s.source();
+        }
+        assert_eq!(
+            "sequence 1/1 of CM, part 0, while sending the file part: target acked part 1, expected 0",
+            chain.join(": ")
+        );
+        assert!(matches!(
+            e.without_context(),
+            Error::TransferError(TransferError::DownloadProtocol(
+                DownloadProtocolError::UnexpectedFlashPacket(_, _)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_flash_from_reader() {
+        use std::io::{Seek, SeekFrom};
+
+        const PIT: &str = "../pit/testdata/A40_EUR_OPEN.pit";
+        let pit = pit::Pit::deserialize(&std::fs::read(PIT).unwrap()).unwrap();
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+
+        let mut sess = Session::begin(Box::new(host)).unwrap();
+        // Shorter sequences, so a small image takes several of them
+        sess.params.max_seq_file_parts = 2;
+        sess.params.max_seq_size_bytes = 2 * sess.params.max_file_part_size;
+        let image: Vec<u8> = (0..5 * sess.params.max_file_part_size + 123)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut flashed: u64 = 0;
+        let mut cb = |n: u64| flashed += n;
+        sess.flash_from_reader(
+            &mut &image[..],
+            image.len() as u64,
+            pit.get_entry_by_name("CM").unwrap(),
+            &mut Some(&mut cb),
+        )
+        .unwrap();
+        sess.end(ActionAfter::Nothing).unwrap();
+        assert_eq!(image.len() as u64, flashed);
+
+        let mut target = target.join().unwrap().unwrap();
+        assert_eq!(
+            image.len() as u64,
+            target.log().flashed.last().unwrap().size
+        );
+        let offset = target.partition("CM").unwrap().offset;
+        let disk = target.disk_mut();
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        let mut written = vec![0; image.len()];
+        disk.read_exact(&mut written).unwrap();
+        assert!(written == image, "Flashed data differs from the image");
+
+        // A reader running dry before the announced length fails the flash
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(move || target, TargetOptions::default());
+        let mut sess = Session::begin(Box::new(host)).unwrap();
+        let e = sess
+            .flash_from_reader(
+                &mut &image[..1000],
+                image.len() as u64,
+                pit.get_entry_by_name("CM").unwrap(),
+                &mut None::<&mut fn(u64)>,
+            )
+            .unwrap_err();
+        assert_eq!(Some("reading the file part"), e.context().unwrap().command);
+        drop(sess);
+        let _ = target.join().unwrap();
     }
 }
@@ -1,6 +1,7 @@
-use crate::download_protocol::DownloadProtocolError;
+use crate::download_protocol::{DownloadProtocolError, PreflightReport};
 use crate::upload_protocol::UploadProtocolError;
 
+use core::fmt;
 use core::result;
 use std::io;
 use std::num::TryFromIntError;
@@ -18,6 +19,140 @@ pub enum Error {
     PitError(PitError),
     /// Error encountered while talking to the target.
     TransferError(TransferError),
+    /// An archive doesn't fit the target's partitioning. Nothing was flashed.
+    PreflightFailed(PreflightReport),
+    /// Another error, with what was going on when it happened.
+    WithContext(ErrorContext, Box<Error>),
+}
+
+impl Error {
+    /// Attach context to the error. Fields already set by more specific context are kept.
+    pub fn in_context(self, ctx: ErrorContext) -> Error {
+        match self {
+            Error::WithContext(inner_ctx, e) => {
+                return Error::WithContext(inner_ctx.or(ctx), e);
+            }
+            e => return Error::WithContext(ctx, Box::new(e)),
+        }
+    }
+
+    /// What was going on when the error happened, if known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Error::WithContext(ctx, _) => return Some(ctx),
+            _ => return None,
+        }
+    }
+
+    /// The error without any attached context.
+    pub fn without_context(&self) -> &Error {
+        match self {
+            Error::WithContext(_, e) => return e.without_context(),
+            e => return e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PitError(e) => write!(f, "{e}"),
+            Error::TransferError(e) => write!(f, "{e}"),
+            Error::PreflightFailed(report) => write!(
+                f,
+                "archive doesn't fit the target's partitioning: {} files without a partition, {} too large",
+                report.unmatched.len(),
+                report.overfilled().count()
+            ),
+            Error::WithContext(ctx, _) => write!(f, "{ctx}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    /// Wrapped errors are transparent, so each message appears once in the chain.
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::PitError(e) => e.source(),
+            Error::TransferError(e) => e.source(),
+            Error::PreflightFailed(_) => None,
+            Error::WithContext(_, e) => Some(e.as_ref()),
+        }
+    }
+}
+
+/// What was going on when an error happened, such as which partition was being flashed.
+///
+/// Formats as e.g. `sequence 3/7 of SYSTEM, part 12`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ErrorContext {
+    /// Name of the file being flashed, e.g. of an archive entry.
+    pub file: Option<String>,
+    /// Name of the partition being flashed.
+    pub partition: Option<String>,
+    /// Number of the sequence being transferred, counting from 1, and the total number of sequences.
+    pub sequence: Option<(usize, usize)>,
+    /// Index of the file part being transferred within its sequence.
+    pub part: Option<u32>,
+    /// The command that was running, e.g. `ending sequence`.
+    pub command: Option<&'static str>,
+}
+
+impl ErrorContext {
+    /// Context of the given command.
+    pub fn command(command: &'static str) -> ErrorContext {
+        return ErrorContext {
+            command: Some(command),
+            ..Default::default()
+        };
+    }
+
+    /// Fill the fields not set in `self` from `other`.
+    fn or(self, other: ErrorContext) -> ErrorContext {
+        return ErrorContext {
+            file: self.file.or(other.file),
+            partition: self.partition.or(other.partition),
+            sequence: self.sequence.or(other.sequence),
+            part: self.part.or(other.part),
+            command: self.command.or(other.command),
+        };
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts: Vec<String> = Vec::new();
+        if let Some(file) = &self.file {
+            parts.push(format!("file {file}"));
+        }
+        match (self.sequence, &self.partition) {
+            (Some((i, total)), Some(partition)) => {
+                parts.push(format!("sequence {i}/{total} of {partition}"))
+            }
+            (Some((i, total)), None) => parts.push(format!("sequence {i}/{total}")),
+            (None, Some(partition)) => parts.push(format!("partition {partition}")),
+            (None, None) => {}
+        }
+        if let Some(part) = self.part {
+            parts.push(format!("part {part}"));
+        }
+        if let Some(command) = self.command {
+            parts.push(format!("while {command}"));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Attaches `ErrorContext` to the error of a `Result`.
+pub(crate) trait ResultExt<T> {
+    /// Attach the context returned by `ctx` if this is an error.
+    fn context(self, ctx: impl FnOnce() -> ErrorContext) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for result::Result<T, E> {
+    fn context(self, ctx: impl FnOnce() -> ErrorContext) -> Result<T> {
+        return self.map_err(|e| e.into().in_context(ctx()));
+    }
 }
 
 /// Ragnaroek's top-level result type.
@@ -40,6 +175,35 @@ pub enum TransferError {
     OdinTar(OdinTarError),
 }
 
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Io(_) => write!(f, "I/O error"),
+            TransferError::DownloadProtocol(e) => write!(f, "{e}"),
+            TransferError::UploadProtocol(e) => write!(f, "{e}"),
+            TransferError::IntegerConversion(_) => {
+                write!(
+                    f,
+                    "integer conversion failed, this is probably a bug in ragnaroek"
+                )
+            }
+            TransferError::OdinTar(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransferError::Io(e) => Some(e.as_ref()),
+            TransferError::DownloadProtocol(e) => e.source(),
+            TransferError::UploadProtocol(e) => e.source(),
+            TransferError::IntegerConversion(e) => Some(e),
+            TransferError::OdinTar(e) => e.source(),
+        }
+    }
+}
+
 impl From<io::Error> for TransferError {
     fn from(e: io::Error) -> Self {
         return TransferError::Io(Arc::new(e));
@@ -64,6 +228,12 @@ impl From<TryFromIntError> for TransferError {
     }
 }
 
+impl From<OdinTarError> for TransferError {
+    fn from(e: OdinTarError) -> Self {
+        return TransferError::OdinTar(e);
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         let e = TransferError::Io(Arc::new(e));
@@ -97,6 +267,12 @@ impl From<TransferError> for Error {
     }
 }
 
+impl From<OdinTarError> for Error {
+    fn from(e: OdinTarError) -> Self {
+        return Error::from(TransferError::OdinTar(e));
+    }
+}
+
 impl From<TryFromIntError> for Error {
     fn from(e: TryFromIntError) -> Self {
         return Error::from(TransferError::IntegerConversion(e));
@@ -9,14 +9,45 @@
 //! It aims to support both wired (via USB) and wireless (via Wi-Fi) operation.
 
 mod comms;
+pub mod dissect;
 pub mod download_protocol;
 mod error;
 pub mod shell;
 pub mod upload_protocol;
 
+#[cfg(feature = "async")]
+pub use comms::async_io::net::Connection as AsyncNetConnection;
+#[cfg(feature = "async")]
+pub use comms::async_io::net::Listener as AsyncNetListener;
+#[cfg(feature = "async")]
+pub use comms::async_io::usb::Connection as AsyncUsbConnection;
+#[cfg(feature = "async")]
+pub use comms::async_io::{AsyncCommunicator, Blocking as AsyncBlocking, BoxFuture};
+pub use comms::capture::{Capture, CaptureLinkType};
+pub use comms::fault::{
+    Fault, FaultPlan, InjectedFault, Injector as FaultInjector, RandomFaults,
+    Trigger as FaultTrigger,
+};
+pub use comms::metrics::{
+    DirectionStats, LatencyStats, Metrics, MetricsHandle, MetricsSnapshot, ThroughputSample,
+};
 pub use comms::net_bind::Connection as NetBindConnection;
 pub use comms::net_bind::Listener as NetBindListener;
+pub use comms::net_connect::ConnectOptions as NetConnectOptions;
 pub use comms::net_connect::Connection as NetConnectConnection;
+pub use comms::pipe::Connection as PipeConnection;
+pub use comms::relay::Connection as RelayConnection;
+pub use comms::relay::{man_in_the_middle, MitmStats, Relay, RELAY_PORT};
+#[cfg(unix)]
+pub use comms::serial::Connection as SerialConnection;
+pub use comms::transcript::{
+    Recorder as TranscriptRecorder, Replayer as TranscriptReplayer, Transcript, TranscriptError,
+    TranscriptEvent, TranscriptRecord,
+};
 pub use comms::usb::Connection as UsbConnection;
-pub use comms::Communicator;
-pub use error::{Error, Result};
+pub use comms::usb::{
+    list_devices as list_usb_devices, wait_for_device as wait_for_usb_device, UsbDeviceInfo,
+    UsbDeviceSelector, UsbInterfaceInfo,
+};
+pub use comms::{CancelHandle, Communicator, WIRELESS_PORT, WIRELESS_TARGET_IP};
+pub use error::{Error, ErrorContext, Result, TransferError};
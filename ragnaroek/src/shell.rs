@@ -6,11 +6,13 @@ use std::{thread, time::Duration};
 use crate::{error::*, Communicator};
 
 const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Prefix of every shell command.
+pub(crate) const SHELL_PREFIX: &str = "PROMPT ";
 
 /// Sends a command to the bootloader's interactive shell.
 /// Returns target's response if it was received, otherwise `Ok(None)`.
 pub fn exchange_cmd(c: &mut Box<dyn Communicator>, cmd: &str) -> Result<Option<String>> {
-    let cmd = format!("PROMPT {cmd}");
+    let cmd = format!("{SHELL_PREFIX}{cmd}");
     log::info!(target: "SHELL", "Command: {}", cmd);
     c.send(cmd.as_bytes())?;
 
@@ -0,0 +1,355 @@
+//! A software upload mode target, serving memory dumps from image files.
+//!
+//! It speaks the target side of the protocol as implemented by this module,
+//! so upload mode support can be tested without crashing a device first.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use super::transfer::TRANSFER_MAX_SIZE;
+use super::*;
+
+/// Memory images the target serves data from.
+pub trait MemoryImage: Read + Seek + Send {}
+impl<T: Read + Seek + Send> MemoryImage for T {}
+
+/// A memory region listed in the probe table.
+pub struct MemoryRegion {
+    /// Name shown in the probe table, at most 11 bytes long.
+    pub name: String,
+    /// Type shown in the probe table.
+    pub partition_type: u32,
+    /// Address the region starts at.
+    pub start_addr: u64,
+    /// Contents of the region. Its length determines the region's size.
+    pub image: Box<dyn MemoryImage>,
+}
+
+/// How the emulated target looks to the host.
+pub struct TargetConfig {
+    /// Device name shown in the probe table, at most 14 bytes long.
+    pub device_name: String,
+    /// Whether the target reports 32- or 64-bit addresses.
+    pub bitness: Bitness,
+    /// Memory regions, in probe table order.
+    pub regions: Vec<MemoryRegion>,
+}
+
+/// What the target saw during a session.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TargetLog {
+    /// How often the host requested the probe table.
+    pub probes: usize,
+    /// Memory ranges transferred, as start and end address.
+    pub transfers: Vec<(u64, u64)>,
+    /// Whether the host ended the session properly.
+    pub ended: bool,
+}
+
+/// A region and its size, as computed when the target is created.
+struct Region {
+    region: MemoryRegion,
+    len: u64,
+}
+
+/// An emulated upload mode target.
+///
+/// End addresses, in the probe table as well as in transfer requests, are exclusive.
+/// Memory not covered by any region reads as zeros.
+pub struct Target {
+    device_name: String,
+    bitness: Bitness,
+    regions: Vec<Region>,
+    log: TargetLog,
+}
+
+impl Target {
+    /// Create a target from the given configuration.
+    pub fn new(config: TargetConfig) -> Result<Target> {
+        let prefix_len = match config.bitness {
+            Bitness::ThirtyTwo => 0,
+            Bitness::SixtyFour => 1,
+        };
+        if config.device_name.len() + prefix_len >= DEVICE_NAME_LEN {
+            return Err(UploadProtocolError::InvalidEmulatorConfig("Device name too long").into());
+        }
+
+        let mut regions: Vec<Region> = Vec::with_capacity(config.regions.len());
+        for mut region in config.regions {
+            if region.name.len() >= PARTITION_NAME_LEN {
+                return Err(
+                    UploadProtocolError::InvalidEmulatorConfig("Region name too long").into(),
+                );
+            }
+            let len = region.image.seek(SeekFrom::End(0))?;
+            let end_addr = region.start_addr.checked_add(len).ok_or(
+                UploadProtocolError::InvalidEmulatorConfig("Region exceeds address space"),
+            )?;
+            if matches!(config.bitness, Bitness::ThirtyTwo) && end_addr > u64::from(u32::MAX) {
+                return Err(UploadProtocolError::InvalidEmulatorConfig(
+                    "Region exceeds 32-bit address space",
+                )
+                .into());
+            }
+            regions.push(Region { region, len });
+        }
+
+        return Ok(Target {
+            device_name: config.device_name,
+            bitness: config.bitness,
+            regions,
+            log: TargetLog::default(),
+        });
+    }
+
+    /// What happened in the sessions so far.
+    pub fn log(&self) -> &TargetLog {
+        return &self.log;
+    }
+
+    /// The probe table, as sent to the host.
+    ///
+    /// It starts with the device name, prefixed by `+` on 64-bit targets.
+    /// One entry per region follows, then an all-zero entry terminating the table.
+    pub fn probe_table(&self) -> Vec<u8> {
+        let mut table: Vec<u8> = Vec::new();
+        let device_name = match self.bitness {
+            Bitness::ThirtyTwo => self.device_name.clone(),
+            Bitness::SixtyFour => format!("+{}", self.device_name),
+        };
+        push_padded(&mut table, device_name.as_bytes(), DEVICE_NAME_LEN);
+
+        let terminator = Region {
+            region: MemoryRegion {
+                name: String::new(),
+                partition_type: 0,
+                start_addr: 0,
+                image: Box::new(std::io::empty()),
+            },
+            len: 0,
+        };
+        for r in self.regions.iter().chain(std::iter::once(&terminator)) {
+            table.extend_from_slice(&r.region.partition_type.to_le_bytes());
+            push_padded(&mut table, r.region.name.as_bytes(), PARTITION_NAME_LEN);
+            let end_addr = r.region.start_addr + r.len;
+            match self.bitness {
+                Bitness::ThirtyTwo => {
+                    // Addresses were checked to fit when the target was created
+                    table.extend_from_slice(&(r.region.start_addr as u32).to_le_bytes());
+                    table.extend_from_slice(&(end_addr as u32).to_le_bytes());
+                }
+                Bitness::SixtyFour => {
+                    // Partition info, meaning unknown
+                    table.extend_from_slice(&0u64.to_le_bytes());
+                    table.extend_from_slice(&r.region.start_addr.to_le_bytes());
+                    table.extend_from_slice(&end_addr.to_le_bytes());
+                }
+            }
+        }
+        return table;
+    }
+
+    /// Read memory from the regions, filling gaps with zeros.
+    fn read_memory(&mut self, start_addr: u64, end_addr: u64) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = vec![0; (end_addr - start_addr) as usize];
+        for r in self.regions.iter_mut() {
+            let region_start = r.region.start_addr;
+            let region_end = region_start + r.len;
+            let from = start_addr.max(region_start);
+            let to = end_addr.min(region_end);
+            if from >= to {
+                continue;
+            }
+            r.region.image.seek(SeekFrom::Start(from - region_start))?;
+            let dst = (from - start_addr) as usize..(to - start_addr) as usize;
+            r.region.image.read_exact(&mut data[dst])?;
+        }
+        return Ok(data);
+    }
+
+    /// Serve a host over the given `Communicator`, until it ends the session.
+    ///
+    /// Hosts disconnecting without sending the postamble cause an error.
+    pub fn serve(&mut self, c: &mut Box<dyn Communicator>) -> Result<()> {
+        let p = c.recv_exact(PACKET_LEN)?;
+        if !p.starts_with(PREAMBLE) {
+            return Err(UploadProtocolError::UnexpectedHostPacket.into());
+        }
+        log::debug!(target: "EMU", "Handshake OK");
+        c.send(ACKNOWLEDGMENT)?;
+
+        // Addresses arrive as separate packets ahead of the transfer command
+        let mut addrs: Vec<u64> = Vec::new();
+        loop {
+            let p = c.recv_exact(PACKET_LEN)?;
+            if p.starts_with(PROBE) {
+                log::debug!(target: "EMU", "Sending probe table");
+                self.log.probes += 1;
+                c.send(&self.probe_table())?;
+            } else if p.starts_with(DATAXFER) {
+                let (start_addr, end_addr) = match addrs[..] {
+                    [.., start, end] => (start, end),
+                    _ => return Err(UploadProtocolError::UnexpectedHostPacket.into()),
+                };
+                addrs.clear();
+                if end_addr < start_addr {
+                    return Err(
+                        UploadProtocolError::EndAddrBeforeStartAddr(start_addr, end_addr).into(),
+                    );
+                }
+                if end_addr - start_addr > TRANSFER_MAX_SIZE as u64 {
+                    return Err(UploadProtocolError::TransferTooLarge(end_addr - start_addr).into());
+                }
+                log::debug!(target: "EMU", "Transferring 0x{start_addr:X}..0x{end_addr:X}");
+                let data = self.read_memory(start_addr, end_addr)?;
+                c.send(&data)?;
+                self.log.transfers.push((start_addr, end_addr));
+            } else if p.starts_with(POSTAMBLE) {
+                log::debug!(target: "EMU", "Session ended");
+                self.log.ended = true;
+                return Ok(());
+            } else {
+                let addr = match self.bitness {
+                    Bitness::ThirtyTwo => u64::from(u32::from_le_bytes([p[0], p[1], p[2], p[3]])),
+                    Bitness::SixtyFour => {
+                        u64::from_le_bytes([p[0], p[1], p[2], p[3], p[4], p[5], p[6], p[7]])
+                    }
+                };
+                log::trace!(target: "EMU", "Address 0x{addr:X}");
+                addrs.push(addr);
+            }
+        }
+    }
+}
+
+/// Append `data` to `buf`, NUL-padded to `len` bytes.
+fn push_padded(buf: &mut Vec<u8>, data: &[u8], len: usize) {
+    let start = buf.len();
+    buf.extend_from_slice(data);
+    buf.resize(start + len, 0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comms::pipe::Connection as Pipe;
+    use crate::{NetBindListener, NetConnectConnection, NetConnectOptions};
+    use std::io::Cursor;
+
+    fn config(bitness: Bitness) -> TargetConfig {
+        let dram: Vec<u8> = (0..0x100000).map(|i| (i % 253) as u8).collect();
+        return TargetConfig {
+            device_name: String::from("EMULATED"),
+            bitness,
+            regions: vec![
+                MemoryRegion {
+                    name: String::from("IRAM"),
+                    partition_type: 1,
+                    start_addr: 0x2000,
+                    image: Box::new(Cursor::new(vec![0xAA; 0x1000])),
+                },
+                MemoryRegion {
+                    name: String::from("DRAM"),
+                    partition_type: 2,
+                    start_addr: 0x8000_0000,
+                    image: Box::new(Cursor::new(dram)),
+                },
+            ],
+        };
+    }
+
+    fn spawn_target<C: Communicator + 'static>(
+        c: C,
+        bitness: Bitness,
+    ) -> std::thread::JoinHandle<Result<Target>> {
+        let mut target = Target::new(config(bitness)).unwrap();
+        return std::thread::spawn(move || {
+            let mut c: Box<dyn Communicator> = Box::new(c);
+            target.serve(&mut c)?;
+            return Ok(target);
+        });
+    }
+
+    /// Request a transfer the way the host does, with one packet per address.
+    fn request(c: &mut Box<dyn Communicator>, addrs: [[u8; 8]; 2], addr_len: usize) {
+        send_packet(c, &addrs[0][..addr_len]).unwrap();
+        send_packet(c, &addrs[1][..addr_len]).unwrap();
+        send_packet(c, DATAXFER).unwrap();
+    }
+
+    #[test]
+    fn test_probe_and_transfer() {
+        for (bitness, addr_len) in [(Bitness::ThirtyTwo, 4), (Bitness::SixtyFour, 8)] {
+            let (host, target) = Pipe::pair();
+            let target = spawn_target(target, bitness);
+            let mut c: Box<dyn Communicator> = Box::new(host);
+
+            handshake(&mut c).unwrap();
+            let table = probe(&mut c).unwrap();
+            assert_eq!(bitness, table.bitness());
+            assert_eq!("EMULATED", table.device_name());
+            assert_eq!(
+                vec![("IRAM", 0x2000, 0x3000), ("DRAM", 0x8000_0000, 0x8010_0000)],
+                table.regions()
+            );
+
+            // A transfer spanning unmapped memory and the start of DRAM
+            let start: u64 = 0x8000_0000 - 0x10;
+            let end: u64 = start + TRANSFER_MAX_SIZE as u64;
+            request(&mut c, [start.to_le_bytes(), end.to_le_bytes()], addr_len);
+            let data = c.recv_exact(TRANSFER_MAX_SIZE).unwrap();
+            assert_eq!(vec![0; 0x10], data[..0x10]);
+            assert_eq!(
+                (0..0x20).map(|i| (i % 253) as u8).collect::<Vec<u8>>(),
+                data[0x10..0x30]
+            );
+
+            end_session(&mut c).unwrap();
+            let target = target.join().unwrap().unwrap();
+            assert_eq!(1, target.log().probes);
+            assert_eq!(vec![(start, end)], target.log().transfers);
+            assert!(target.log().ended);
+        }
+    }
+
+    #[test]
+    fn test_transfer_limits() {
+        let (host, target) = Pipe::pair();
+        let target = spawn_target(target, Bitness::SixtyFour);
+        let mut c: Box<dyn Communicator> = Box::new(host);
+        handshake(&mut c).unwrap();
+        let start: u64 = 0x8000_0000;
+        let end: u64 = start + TRANSFER_MAX_SIZE as u64 + 1;
+        request(&mut c, [start.to_le_bytes(), end.to_le_bytes()], 8);
+        assert!(matches!(
+            target.join().unwrap().err().unwrap(),
+            crate::Error::TransferError(crate::error::TransferError::UploadProtocol(
+                UploadProtocolError::TransferTooLarge(_)
+            ))
+        ));
+
+        let mut config = config(Bitness::ThirtyTwo);
+        config.regions[1].start_addr = 0xFFFF_F000;
+        assert!(Target::new(config).is_err());
+    }
+
+    #[test]
+    fn test_session_over_tcp() {
+        // Hosts connect to targets in upload mode
+        let mut listener = NetBindListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let target = std::thread::spawn(move || {
+            let c = listener.accept().unwrap();
+            return spawn_target(c, Bitness::SixtyFour).join().unwrap();
+        });
+
+        let mut c: Box<dyn Communicator> =
+            Box::new(NetConnectConnection::connect(addr, &NetConnectOptions::default()).unwrap());
+        handshake(&mut c).unwrap();
+        let start: u64 = 0x2000;
+        let end: u64 = 0x2010;
+        request(&mut c, [start.to_le_bytes(), end.to_le_bytes()], 8);
+        assert_eq!(vec![0xAA; 0x10], c.recv_exact(0x10).unwrap());
+        end_session(&mut c).unwrap();
+        assert!(target.join().unwrap().unwrap().log().ended);
+    }
+}
@@ -1,3 +1,5 @@
+use core::fmt;
+
 /// Error caused by the device violating our assumptions about
 /// how a device in upload mode ought to behave.
 #[derive(Clone, Copy, Debug)]
@@ -7,4 +9,41 @@ pub enum UploadProtocolError {
     /// Start address occured before end address.
     /// First value is given start address, second given end address.
     EndAddrBeforeStartAddr(u64, u64),
+    /// A transfer larger than the target allows was requested.
+    /// The value is the requested size.
+    TransferTooLarge(u64),
+    /// The target's probe table couldn't be parsed, for the given reason.
+    MalformedProbeTable(&'static str),
+    /// The host sent a packet the emulated target didn't expect.
+    UnexpectedHostPacket,
+    /// The emulated target's configuration is invalid, for the given reason.
+    InvalidEmulatorConfig(&'static str),
 }
+
+impl fmt::Display for UploadProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadProtocolError::MissingAck => write!(f, "target didn't acknowledge the packet"),
+            UploadProtocolError::EndAddrBeforeStartAddr(start, end) => {
+                write!(f, "end address {end:#X} is before start address {start:#X}")
+            }
+            UploadProtocolError::TransferTooLarge(size) => {
+                write!(
+                    f,
+                    "transfer of {size} bytes is larger than the target allows"
+                )
+            }
+            UploadProtocolError::MalformedProbeTable(why) => {
+                write!(f, "target sent a malformed probe table: {why}")
+            }
+            UploadProtocolError::UnexpectedHostPacket => {
+                write!(f, "host sent a packet the emulated target didn't expect")
+            }
+            UploadProtocolError::InvalidEmulatorConfig(why) => {
+                write!(f, "invalid emulated target configuration: {why}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for UploadProtocolError {}
@@ -7,8 +7,8 @@ use crate::Result;
 
 use super::UploadProtocolError;
 
-const PREAMBLE: &[u8] = b"PrEaMbLe\0";
-const ACKNOWLEDGMENT: &[u8] = b"AcKnOwLeDgMeNt\0";
+pub(crate) const PREAMBLE: &[u8] = b"PrEaMbLe\0";
+pub(crate) const ACKNOWLEDGMENT: &[u8] = b"AcKnOwLeDgMeNt\0";
 
 /// Handshake with the target.
 /// This must be called before performing any other upload mode operations.
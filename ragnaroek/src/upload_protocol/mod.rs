@@ -1,6 +1,7 @@
 //! Module implementing memory dumping via upload mode.
 //! Heavily based on https://github.com/bkerler/sboot_dump.
 
+pub mod emulator;
 mod end_session;
 mod error;
 mod handshake;
@@ -12,6 +13,12 @@ pub use error::*;
 pub use handshake::handshake;
 pub use probe::*;
 
+// Protocol constants, shared by the host side, the emulator and the dissector
+pub(crate) use end_session::POSTAMBLE;
+pub(crate) use handshake::{ACKNOWLEDGMENT, PREAMBLE};
+pub(crate) use probe::{DEVICE_NAME_LEN, PARTITION_NAME_LEN, PROBE};
+pub(crate) use transfer::DATAXFER;
+
 use crate::Communicator;
 use crate::Result;
 
@@ -19,7 +26,7 @@ use crate::Result;
 const PACKET_LEN: usize = 1024;
 
 /// Targets can have a different bitness, which changes the length of memory addresses.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Bitness {
     /// Target has 32-bit addresses
     ThirtyTwo,
@@ -29,7 +36,7 @@ pub enum Bitness {
 
 /// Sends the given packet to the target.
 /// Adds padding if needed.
-fn send_packet(c: &mut Box<dyn Communicator>, data: &[u8]) -> Result<()> {
+pub(crate) fn send_packet(c: &mut Box<dyn Communicator>, data: &[u8]) -> Result<()> {
     let mut padded: Vec<u8> = vec![0; PACKET_LEN];
     for (i, byte) in data.iter().enumerate() {
         padded[i] = *byte;
@@ -1,13 +1,15 @@
-use std::ffi::CString;
-
 use super::*;
 use crate::Communicator;
 
 use either::*;
 
-const DEVICE_NAME_LEN: usize = 16;
-const PARTITION_NAME_LEN: usize = 12;
-const PROBE: &[u8] = b"PrObE\0";
+pub(crate) const DEVICE_NAME_LEN: usize = 16;
+pub(crate) const PARTITION_NAME_LEN: usize = 12;
+pub(crate) const PROBE: &[u8] = b"PrObE\0";
+/// Length of a 32-bit probe table entry
+const ENTRY_32_LEN: usize = 4 + PARTITION_NAME_LEN + 2 * 4;
+/// Length of a 64-bit probe table entry
+const ENTRY_64_LEN: usize = 4 + PARTITION_NAME_LEN + 3 * 8;
 
 /// Data structure holding information the target returns about itself.
 #[derive(Debug, Clone)]
@@ -38,81 +40,100 @@ struct ProbeEntry64 {
 }
 
 impl ProbeTable {
-    fn deserialize(data: &[u8]) -> ProbeTable {
-        let bitness = match data[0] {
-            b'+' => Bitness::SixtyFour,
-            _ => Bitness::ThirtyTwo,
-        };
-        // TODO: Advance data here or leave bitness as part of name?
-
-        // TODO: Read as non-NULL terminated
-        let (device_name, data) = read_string_and_advance(data, DEVICE_NAME_LEN);
-
-        // Keep reading an unknown amount of entries
-        let entries = match bitness {
-            Bitness::ThirtyTwo => {
-                let (entries_32, _) = read_probe_entries_32_and_advance(data);
-                Left(entries_32)
-            }
-            Bitness::SixtyFour => {
-                let (entries_64, _) = read_probe_entries_64_and_advance(data);
-                Right(entries_64)
-            }
-        };
+    /// Whether the target has 32- or 64-bit addresses.
+    pub fn bitness(&self) -> Bitness {
+        return self.bitness;
+    }
+
+    /// Name of the target, without the bitness prefix.
+    pub fn device_name(&self) -> &str {
+        return &self.device_name;
+    }
 
-        let table = ProbeTable {
-            bitness,
-            device_name,
-            entries,
+    /// Memory regions of the target, as name, start and end address.
+    pub fn regions(&self) -> Vec<(&str, u64, u64)> {
+        return match &self.entries {
+            Left(entries) => entries
+                .iter()
+                .map(|e| {
+                    (
+                        e.partition_name.as_str(),
+                        u64::from(e.start_addr),
+                        u64::from(e.end_addr),
+                    )
+                })
+                .collect(),
+            Right(entries) => entries
+                .iter()
+                .map(|e| (e.partition_name.as_str(), e.start_addr, e.end_addr))
+                .collect(),
         };
-        return table;
     }
 }
 
-/// Read as many 32-bit probe table entries as possible.
-fn read_probe_entries_32_and_advance(data: &[u8]) -> (Vec<ProbeEntry32>, &[u8]) {
-    let mut data = data;
-    let mut entries: Vec<ProbeEntry32> = Vec::new();
+/// Read the table's header, returning the target's bitness and name.
+fn read_header(data: &[u8]) -> Result<(Bitness, String)> {
+    if data.len() != DEVICE_NAME_LEN {
+        return Err(UploadProtocolError::MalformedProbeTable("Header too short").into());
+    }
+    let (bitness, data) = match data[0] {
+        b'+' => (Bitness::SixtyFour, &data[1..]),
+        _ => (Bitness::ThirtyTwo, data),
+    };
+    let (device_name, _) = read_string_and_advance(data, data.len())?;
+    return Ok((bitness, device_name));
+}
 
+/// Receive 32-bit probe table entries up to the terminating one.
+fn recv_probe_entries_32(c: &mut Box<dyn Communicator>) -> Result<Vec<ProbeEntry32>> {
+    let mut entries: Vec<ProbeEntry32> = Vec::new();
     loop {
-        let (entry, remaining_data) = ProbeEntry32::deserialize(data).unwrap();
-        data = remaining_data;
+        let data = c.recv_exact(ENTRY_32_LEN)?;
+        let (entry, _) = ProbeEntry32::deserialize(&data)?;
 
         if (entry.start_addr == 0 && entry.end_addr == 0) || entry.start_addr < 20 {
             break;
         }
+        if entry.end_addr < entry.start_addr {
+            return Err(
+                UploadProtocolError::MalformedProbeTable("Region ends before it starts").into(),
+            );
+        }
 
         entries.push(entry);
     }
 
-    return (entries, data);
+    return Ok(entries);
 }
 
-/// Read as many 64-bit probe table entries as possible.
-fn read_probe_entries_64_and_advance(data: &[u8]) -> (Vec<ProbeEntry64>, &[u8]) {
-    let mut data = data;
+/// Receive 64-bit probe table entries up to the terminating one.
+fn recv_probe_entries_64(c: &mut Box<dyn Communicator>) -> Result<Vec<ProbeEntry64>> {
     let mut entries: Vec<ProbeEntry64> = Vec::new();
-
     loop {
-        let (entry, remaining_data) = ProbeEntry64::deserialize(data).unwrap();
-        data = remaining_data;
+        let data = c.recv_exact(ENTRY_64_LEN)?;
+        let (entry, _) = ProbeEntry64::deserialize(&data)?;
 
         if (entry.start_addr == 0 && entry.end_addr == 0) || entry.start_addr < 20 {
             break;
         }
+        if entry.end_addr < entry.start_addr {
+            return Err(
+                UploadProtocolError::MalformedProbeTable("Region ends before it starts").into(),
+            );
+        }
 
         entries.push(entry);
     }
 
-    return (entries, data);
+    return Ok(entries);
 }
 
 impl ProbeEntry32 {
     fn deserialize(data: &[u8]) -> Result<(ProbeEntry32, &[u8])> {
-        let (partition_type, data) = read_u32_and_advance(data);
-        let (partition_name, data) = read_string_and_advance(data, PARTITION_NAME_LEN);
-        let (start_addr, data) = read_u32_and_advance(data);
-        let (end_addr, data) = read_u32_and_advance(data);
+        let (partition_type, data) = read_u32_and_advance(data)?;
+        let (partition_name, data) = read_string_and_advance(data, PARTITION_NAME_LEN)?;
+        let (start_addr, data) = read_u32_and_advance(data)?;
+        let (end_addr, data) = read_u32_and_advance(data)?;
 
         let pe = ProbeEntry32 {
             partition_type,
@@ -126,11 +147,11 @@ impl ProbeEntry32 {
 
 impl ProbeEntry64 {
     fn deserialize(data: &[u8]) -> Result<(ProbeEntry64, &[u8])> {
-        let (partition_type, data) = read_u32_and_advance(data);
-        let (partition_name, data) = read_string_and_advance(data, PARTITION_NAME_LEN);
-        let (partition_info, data) = read_u64_and_advance(data);
-        let (start_addr, data) = read_u64_and_advance(data);
-        let (end_addr, data) = read_u64_and_advance(data);
+        let (partition_type, data) = read_u32_and_advance(data)?;
+        let (partition_name, data) = read_string_and_advance(data, PARTITION_NAME_LEN)?;
+        let (partition_info, data) = read_u64_and_advance(data)?;
+        let (start_addr, data) = read_u64_and_advance(data)?;
+        let (end_addr, data) = read_u64_and_advance(data)?;
 
         let pe = ProbeEntry64 {
             partition_type,
@@ -146,58 +167,55 @@ impl ProbeEntry64 {
 // TODO: DRY this into a small parser-combinator module,
 // as this code is very similar to the PIT deserializer code.
 
-fn read_u32_and_advance(data: &[u8]) -> (u32, &[u8]) {
-    let mut int: [u8; 4] = [0; 4];
-    for (i, b) in data[0..3].iter().enumerate() {
-        int[i] = *b;
+/// Split `len` bytes off the front of `data`, failing if there aren't enough.
+fn take(data: &[u8], len: usize) -> Result<(&[u8], &[u8])> {
+    if data.len() < len {
+        return Err(UploadProtocolError::MalformedProbeTable("Entry too short").into());
     }
-
-    let data = &data[4..];
-    return (u32::from_le_bytes(int), data);
+    return Ok(data.split_at(len));
 }
 
-fn read_u64_and_advance(data: &[u8]) -> (u64, &[u8]) {
-    let mut int: [u8; 8] = [0; 8];
-    for (i, b) in data[0..7].iter().enumerate() {
-        int[i] = *b;
-    }
-
-    let data = &data[8..];
-    return (u64::from_le_bytes(int), data);
+fn read_u32_and_advance(data: &[u8]) -> Result<(u32, &[u8])> {
+    let (int, data) = take(data, 4)?;
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(int);
+    return Ok((u32::from_le_bytes(bytes), data));
 }
 
-fn read_string_and_advance(data: &[u8], max_len: usize) -> (String, &[u8]) {
-    let data = &data[0..max_len];
-    // C String constructor fails on seeing a NULL-byte; filter them out
-    let str_data: Vec<u8> = data.iter().take_while(|x| **x != 0).copied().collect();
-    let c_str = CString::new(str_data.clone()).unwrap();
-    let c_str_len = c_str.clone().into_bytes_with_nul().len();
-    if c_str_len > max_len {
-        panic!("String too long!");
-    }
+fn read_u64_and_advance(data: &[u8]) -> Result<(u64, &[u8])> {
+    let (int, data) = take(data, 8)?;
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(int);
+    return Ok((u64::from_le_bytes(bytes), data));
+}
 
-    let s = c_str.into_string().unwrap();
-    let data = &data[str_data.len()..];
-    return (s, data);
+/// Read a string from a field of `len` bytes.
+/// It ends at the first NUL byte, strings filling the entire field aren't terminated.
+fn read_string_and_advance(data: &[u8], len: usize) -> Result<(String, &[u8])> {
+    let (field, data) = take(data, len)?;
+    let str_data: Vec<u8> = field.iter().take_while(|x| **x != 0).copied().collect();
+    let s = String::from_utf8(str_data)
+        .map_err(|_| UploadProtocolError::MalformedProbeTable("Name is not valid UTF-8"))?;
+    return Ok((s, data));
 }
 
 /// Probes the target for information about it.
 ///
 /// Handshaking and termination must be performed before and after calling this, respectively.
 pub fn probe(c: &mut Box<dyn Communicator>) -> Result<ProbeTable> {
-    /*
-    TODO: Because we don't know how much to read yet,
-    we really should just pass in the communicator rather than doing this brittle crap.
-    */
-    let mut data: Vec<u8> = Vec::new();
-    loop {
-        let new_data = c.recv_exact(4);
-        match new_data {
-            Ok(mut new_data) => data.append(&mut new_data),
-            Err(_) => break,
-        }
-    }
-
-    let table = ProbeTable::deserialize(&data);
-    return Ok(table);
+    send_packet(c, PROBE)?;
+
+    // The table's length isn't known up front, so receive it entry by entry until the terminator
+    let header = c.recv_exact(DEVICE_NAME_LEN)?;
+    let (bitness, device_name) = read_header(&header)?;
+    let entries = match bitness {
+        Bitness::ThirtyTwo => Left(recv_probe_entries_32(c)?),
+        Bitness::SixtyFour => Right(recv_probe_entries_64(c)?),
+    };
+
+    return Ok(ProbeTable {
+        bitness,
+        device_name,
+        entries,
+    });
 }
@@ -2,8 +2,8 @@ use super::send_packet;
 use super::Bitness;
 use crate::{Communicator, Result};
 
-const DATAXFER: &[u8] = b"DaTaXfEr\0";
-const TRANSFER_MAX_SIZE: usize = 0x80000; // 512KiB
+pub(crate) const DATAXFER: &[u8] = b"DaTaXfEr\0";
+pub(crate) const TRANSFER_MAX_SIZE: usize = 0x80000; // 512KiB
 
 /// Dump target memory in upload mode.
 ///